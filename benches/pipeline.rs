@@ -0,0 +1,425 @@
+//! Timings of the hot paths: content detection, the crop/scale/pad step and a full
+//! decode-normalize-encode-save, on synthetic images shaped like typical inputs. The
+//! resize runs with both the quality and the `FAST_PATH_PIXELS` filter. A large BMP
+//! replacing its own source compares the temp-file rename with `IN_PLACE_SAFE`. A batch
+//! of files goes through the staged pipeline once with a single worker per stage, like the
+//! old one-thread loop, and once with one worker per core in every stage.
+//!
+//! Measured with criterion, so its arguments apply: a name fragment runs only the
+//! matching cases, and `--save-baseline`/`--baseline <name>` compare against a local
+//! criterion baseline. On top of that, the median of every case that ran is compared
+//! with `baseline.txt` next to this file, and the bench fails when a case got slower than
+//! `BENCH_TOLERANCE_PCT` (default 20). `BENCH_SAVE_BASELINE=1` records the current
+//! medians there instead.
+
+use criterion::{BatchSize, BenchmarkGroup, Criterion, measurement::WallTime};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use ni_service_rs::{config, pipeline, processing};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    thread,
+    time::SystemTime,
+};
+
+fn main() -> ExitCode {
+    let started = SystemTime::now();
+    let work_dir = env::temp_dir().join(format!("ni-watcher-bench-{}", std::process::id()));
+    fs::create_dir_all(&work_dir).expect("Failed to create the bench folder");
+
+    let mut criterion = Criterion::default().sample_size(10).configure_from_args();
+    benches(&mut criterion, &work_dir);
+    criterion.final_summary();
+    let _ = fs::remove_dir_all(&work_dir);
+
+    check_baseline(started)
+}
+
+fn benches(c: &mut Criterion, work_dir: &Path) {
+    let photo = DynamicImage::ImageRgb8(product_photo(4000, 3000));
+    let cutout = DynamicImage::ImageRgba8(cutout(2000, 2000));
+    let two_pass = processing::NormalizeOptions {
+        two_pass_downscale: true,
+        ..default_options()
+    };
+    let adaptive = processing::NormalizeOptions {
+        adaptive_tolerance: true,
+        ..default_options()
+    };
+    let fast = processing::NormalizeOptions {
+        filter: config::ResizeFilter::Triangle,
+        ..default_options()
+    };
+
+    let mut group = c.benchmark_group("content_bounds");
+    on_copy(&mut group, "photo-12mp", &photo, |image| {
+        processing::content_bounds(&image, &default_options());
+    });
+    on_copy(&mut group, "photo-12mp-adaptive", &photo, |image| {
+        processing::content_bounds(&image, &adaptive);
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("normalize");
+    on_copy(&mut group, "photo-12mp", &photo, |image| {
+        processing::normalize(image, &default_options());
+    });
+    on_copy(&mut group, "photo-12mp-two-pass", &photo, |image| {
+        processing::normalize(image, &two_pass);
+    });
+    on_copy(&mut group, "photo-12mp-fast-path", &photo, |image| {
+        processing::normalize(image, &fast);
+    });
+    on_copy(&mut group, "cutout-4mp", &cutout, |image| {
+        processing::normalize(image, &default_options());
+    });
+    group.finish();
+    report_quality(
+        &photo,
+        &[
+            ("photo-12mp", &default_options()),
+            ("photo-12mp-two-pass", &two_pass),
+            ("photo-12mp-fast-path", &fast),
+        ],
+    );
+
+    let source = work_dir.join("photo.png");
+    let encoded = encode(&product_photo(3000, 2000), ImageFormat::Png);
+    let large = work_dir.join("large.bmp");
+    let large_encoded = encode(&product_photo(4000, 3000), ImageFormat::Bmp);
+
+    let mut group = c.benchmark_group("process_and_save");
+    on_file(&mut group, "photo-6mp-jpg", &source, &encoded, save_as_jpeg);
+    on_file(
+        &mut group,
+        "bmp-12mp-temp-rename",
+        &large,
+        &large_encoded,
+        |source| replace_bmp(source, false),
+    );
+    on_file(
+        &mut group,
+        "bmp-12mp-in-place",
+        &large,
+        &large_encoded,
+        |source| replace_bmp(source, true),
+    );
+    group.finish();
+
+    let batch = work_dir.join("batch");
+    let batch_encoded = encode(&product_photo(2000, 1500), ImageFormat::Png);
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut group = c.benchmark_group("pipeline");
+    for (name, workers) in [("8-photos-workers-1", 1), ("8-photos-workers-n", cores)] {
+        let counts = pipeline::WorkerCounts {
+            decode: workers,
+            process: workers,
+            encode: workers,
+        };
+        on_batch(&mut group, name, &batch, &batch_encoded, 8, counts);
+    }
+    group.finish();
+}
+
+/// A case that times `work` on a fresh copy of `image`; the copy is made untimed.
+fn on_copy(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    image: &DynamicImage,
+    work: impl Fn(DynamicImage),
+) {
+    group.bench_function(name, |b| {
+        b.iter_batched(|| image.clone(), &work, BatchSize::LargeInput)
+    });
+}
+
+/// A case that times `work` on `source`, which is written with `bytes` untimed before
+/// each run.
+fn on_file(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    source: &Path,
+    bytes: &[u8],
+    work: impl Fn(&Path),
+) {
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || fs::write(source, bytes).expect("Failed to write the source image"),
+            |()| work(source),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+/// A case that runs `count` copies of `bytes` through the pipeline with `counts` workers
+/// per stage and waits for all of them. The copies are written to `folder` untimed before
+/// each run, which also clears the outputs of the previous one.
+fn on_batch(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    folder: &Path,
+    bytes: &[u8],
+    count: usize,
+    counts: pipeline::WorkerCounts,
+) {
+    let write_batch = || {
+        let _ = fs::remove_dir_all(folder);
+        fs::create_dir_all(folder).expect("Failed to create the batch folder");
+        (0..count)
+            .map(|i| {
+                let source = folder.join(format!("photo-{}.png", i));
+                fs::write(&source, bytes).expect("Failed to write the source image");
+                source
+            })
+            .collect::<Vec<_>>()
+    };
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            write_batch,
+            |sources| {
+                let (pipeline, workers) = pipeline::start(counts, None, 50, 10);
+                for source in sources {
+                    pipeline.submit(source);
+                }
+                drop(pipeline);
+                workers.join();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+/// Prints how close each `normalize` variant comes to a Lanczos3 reference downscale of
+/// `photo`, as PSNR in dB; higher is closer. Informational only, the baseline holds times.
+fn report_quality(photo: &DynamicImage, variants: &[(&str, &processing::NormalizeOptions)]) {
+    let reference = processing::NormalizeOptions {
+        filter: config::ResizeFilter::Lanczos3,
+        ..default_options()
+    };
+    let reference = processing::normalize(photo.clone(), &reference).to_rgb8();
+    for (name, options) in variants {
+        let output = processing::normalize(photo.clone(), options).to_rgb8();
+        let squared: f64 = output
+            .as_raw()
+            .iter()
+            .zip(reference.as_raw())
+            .map(|(a, b)| (f64::from(*a) - f64::from(*b)).powi(2))
+            .sum();
+        let mse = squared / output.as_raw().len() as f64;
+        let psnr = 10.0 * (255.0 * 255.0 / mse.max(f64::EPSILON)).log10();
+        println!(
+            "normalize/{:<32} PSNR {:.2} dB against a Lanczos3 downscale",
+            name, psnr
+        );
+    }
+}
+
+fn encode(image: &RgbImage, format: ImageFormat) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    DynamicImage::ImageRgb8(image.clone())
+        .write_to(&mut std::io::Cursor::new(&mut encoded), format)
+        .expect("Failed to encode the source image");
+    encoded
+}
+
+fn save_as_jpeg(source: &Path) {
+    let values = HashMap::from([("OUTPUT_FORMAT".to_string(), "jpg".to_string())]);
+    let outputs = config::with_values(&values, || processing::process_and_save(source, 50, 10))
+        .expect("process_and_save failed");
+    for output in outputs {
+        let _ = fs::remove_file(output);
+    }
+}
+
+/// Compares the median of every case measured since `started` with `baseline.txt`, or
+/// records them there with `BENCH_SAVE_BASELINE=1`.
+fn check_baseline(started: SystemTime) -> ExitCode {
+    let baseline_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/baseline.txt");
+    let tolerance: f64 = env::var("BENCH_TOLERANCE_PCT")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(20.0);
+    let medians = measured_since(started);
+
+    if env::var("BENCH_SAVE_BASELINE").is_ok_and(|value| value == "1") {
+        let mut baseline = read_baseline(&baseline_path);
+        baseline.extend(medians.iter().map(|(name, ms)| (name.clone(), *ms)));
+        let text: String = baseline
+            .iter()
+            .map(|(name, ms)| format!("{} {:.2}\n", name, ms))
+            .collect();
+        fs::write(&baseline_path, text).expect("Failed to write the baseline");
+        println!("Baseline saved to {:?}", baseline_path);
+        return ExitCode::SUCCESS;
+    }
+
+    let baseline = read_baseline(&baseline_path);
+    let mut regressions = Vec::new();
+    for (name, ms) in &medians {
+        match baseline.get(name) {
+            Some(&base) => {
+                let change = (ms / base - 1.0) * 100.0;
+                println!(
+                    "{:<40} {:>10.2} ms  (baseline {:.2} ms, {:+.1}%)",
+                    name, ms, base, change
+                );
+                if change > tolerance {
+                    regressions.push(name.as_str());
+                }
+            }
+            None => println!("{:<40} {:>10.2} ms  (no baseline)", name, ms),
+        }
+    }
+    if regressions.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        eprintln!(
+            "Slower than the baseline by more than {}%: {}",
+            tolerance,
+            regressions.join(", ")
+        );
+        ExitCode::FAILURE
+    }
+}
+
+/// Medians in milliseconds, by `group/case`, from the criterion estimates written since
+/// `started`, so cases skipped by a filter keep their old numbers out of the comparison.
+fn measured_since(started: SystemTime) -> BTreeMap<String, f64> {
+    let mut medians = BTreeMap::new();
+    let groups = fs::read_dir(criterion_dir())
+        .into_iter()
+        .flatten()
+        .flatten();
+    for group in groups {
+        for case in fs::read_dir(group.path()).into_iter().flatten().flatten() {
+            let estimates = case.path().join("new/estimates.json");
+            let fresh = fs::metadata(&estimates)
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(|modified| modified >= started);
+            let median = fresh
+                .then(|| fs::read_to_string(&estimates).ok())
+                .flatten()
+                .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+                .and_then(|value| value["median"]["point_estimate"].as_f64());
+            if let Some(ns) = median {
+                let name = format!(
+                    "{}/{}",
+                    group.file_name().to_string_lossy(),
+                    case.file_name().to_string_lossy()
+                );
+                medians.insert(name, ns / 1_000_000.0);
+            }
+        }
+    }
+    medians
+}
+
+/// Where criterion keeps its results: `CRITERION_HOME`, or `criterion` in the target
+/// directory.
+fn criterion_dir() -> PathBuf {
+    if let Some(home) = env::var_os("CRITERION_HOME") {
+        return PathBuf::from(home);
+    }
+    env::var_os("CARGO_TARGET_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(env!("CARGO_MANIFEST_DIR")).join("target"))
+        .join("criterion")
+}
+
+fn read_baseline(path: &Path) -> BTreeMap<String, f64> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (name, ms) = line.split_once(' ')?;
+            Some((name.to_string(), ms.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Normalizes a 12 MP BMP onto a canvas of the same size, so the ~36 MB output replaces
+/// its source, with or without `IN_PLACE_SAFE`.
+fn replace_bmp(source: &Path, in_place: bool) {
+    let values = HashMap::from([
+        ("OUTPUT_FORMAT".to_string(), "bmp".to_string()),
+        ("TARGET_WIDTH".to_string(), "4000".to_string()),
+        ("TARGET_HEIGHT".to_string(), "3000".to_string()),
+        ("IN_PLACE_SAFE".to_string(), u8::from(in_place).to_string()),
+    ]);
+    config::with_values(&values, || processing::process_and_save(source, 50, 10))
+        .expect("process_and_save failed");
+}
+
+/// The options `processing::decode` resolves with no settings given.
+fn default_options() -> processing::NormalizeOptions {
+    processing::NormalizeOptions {
+        size: config::DEFAULT_TARGET_SIZE,
+        pad: 50,
+        padding_overflow: config::PaddingOverflow::Error,
+        tol: 10,
+        crop_sides: config::CropSides::ALL,
+        crop_margin: 0,
+        channel_tolerance: None,
+        adaptive_tolerance: false,
+        noise_tolerance: None,
+        deskew: None,
+        gravity: config::Gravity::Center,
+        align: config::Align::BoundingBox,
+        center_remainder: config::CenterRemainder::End,
+        two_pass_downscale: false,
+        filter: config::ResizeFilter::Gaussian,
+        feather_edges: false,
+        background: config::Background::White,
+        mask: config::Mask::None,
+        bit_depth: config::BitDepth::Eight,
+        sharpen: None,
+    }
+}
+
+/// A product shot: a shaded, slightly noisy ellipse on a white backdrop with wide
+/// margins, like a studio photo before cropping.
+fn product_photo(width: u32, height: u32) -> RgbImage {
+    let mut noise = Noise(0x2545_f491);
+    let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+    let (rx, ry) = (width as f32 * 0.3, height as f32 * 0.35);
+    RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = ((x as f32 - cx) / rx, (y as f32 - cy) / ry);
+        let jitter = noise.next();
+        if dx * dx + dy * dy <= 1.0 {
+            let shade = (dx * 60.0 + dy * 40.0) as i32;
+            Rgb([
+                (150 + shade + jitter).clamp(0, 255) as u8,
+                (90 + shade / 2 + jitter).clamp(0, 255) as u8,
+                (60 + jitter).clamp(0, 255) as u8,
+            ])
+        } else {
+            let white = (252 + jitter / 4).clamp(0, 255) as u8;
+            Rgb([white; 3])
+        }
+    })
+}
+
+/// A cut-out: an opaque disc with soft edges on a fully transparent background.
+fn cutout(width: u32, height: u32) -> RgbaImage {
+    let (cx, cy, r) = (width as f32 / 2.0, height as f32 / 2.0, width as f32 * 0.35);
+    RgbaImage::from_fn(width, height, |x, y| {
+        let distance = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt();
+        let alpha = ((r - distance) * 8.0).clamp(0.0, 255.0) as u8;
+        Rgba([40, 110, 200, alpha])
+    })
+}
+
+/// Deterministic noise in `-8..8`, so every run and machine measures the same pixels.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> i32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 % 16) as i32 - 8
+    }
+}
@@ -0,0 +1,83 @@
+//! Feeds arbitrary bytes through the decoder and `processing::normalize`. The first byte
+//! picks the options, so gravity, alignment, backgrounds and bit depths are all covered.
+
+#![no_main]
+
+use image::io::{Limits, Reader};
+use libfuzzer_sys::fuzz_target;
+use ni_service_rs::{config, processing};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&selector, bytes)) = data.split_first() else {
+        return;
+    };
+    let Ok(mut reader) = Reader::new(Cursor::new(bytes)).with_guessed_format() else {
+        return;
+    };
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(64 * 1024 * 1024);
+    reader.limits(limits);
+    let Ok(image) = reader.decode() else {
+        return;
+    };
+    if image.width() == 0 || image.height() == 0 {
+        // Rejected by `processing::decode` before this point.
+        return;
+    }
+
+    let opts = processing::NormalizeOptions {
+        size: (64, 48),
+        pad: u32::from(selector % 4) * 5,
+        padding_overflow: config::PaddingOverflow::Error,
+        tol: selector.wrapping_mul(37),
+        crop_sides: if selector & 0x10 == 0 {
+            config::CropSides::ALL
+        } else {
+            config::CropSides::NONE
+        },
+        crop_margin: u32::from(selector % 3) * 4,
+        channel_tolerance: None,
+        adaptive_tolerance: selector & 0x20 != 0,
+        noise_tolerance: (selector & 0x02 != 0).then_some(4),
+        deskew: (selector % 7 == 0).then_some(5.0),
+        gravity: [
+            config::Gravity::Center,
+            config::Gravity::Top,
+            config::Gravity::Bottom,
+            config::Gravity::Left,
+            config::Gravity::Right,
+        ][usize::from(selector % 5)],
+        align: if selector & 0x40 == 0 {
+            config::Align::BoundingBox
+        } else {
+            config::Align::Centroid
+        },
+        center_remainder: config::CenterRemainder::End,
+        two_pass_downscale: selector & 0x80 != 0,
+        filter: config::ResizeFilter::Gaussian,
+        feather_edges: selector & 0x01 != 0,
+        background: [
+            config::Background::White,
+            config::Background::Transparent,
+            config::Background::Color([12, 34, 56]),
+            config::Background::Auto,
+        ][usize::from(selector % 4)],
+        mask: if selector & 0x08 == 0 {
+            config::Mask::None
+        } else {
+            config::Mask::Circle
+        },
+        bit_depth: if selector & 0x04 == 0 {
+            config::BitDepth::Eight
+        } else {
+            config::BitDepth::Sixteen
+        },
+        sharpen: (selector & 0x02 != 0).then_some(config::Sharpen {
+            amount: 0.5,
+            radius: 1.0,
+        }),
+    };
+    let normalized = processing::normalize(image, &opts);
+    assert_eq!((normalized.width(), normalized.height()), opts.size);
+});
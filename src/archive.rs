@@ -0,0 +1,385 @@
+//! `HANDLE_ZIP`: a `.zip` dropped into the watch folder is unpacked to a temporary
+//! folder, every image in it is normalized there, and the outputs are moved next to the
+//! archive or packed into a new archive with `ZIP_REPACK`.
+//!
+//! Archives are read with the `zip` crate. Stored and deflated entries are unpacked,
+//! which covers what the archive tools of Windows and macOS write; encrypted entries and
+//! other compression methods fail the archive.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufReader, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zip::{
+    CompressionMethod, DateTime, ZipArchive, ZipWriter, read::ZipFile, result::ZipError,
+    write::SimpleFileOptions,
+};
+
+use crate::{config, processing};
+
+/// Entries read from one archive at most.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Distinguishes temporary folders of archives unpacked in the same millisecond.
+static UNPACKED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `path` is an archive to unpack: a `.zip` while `HANDLE_ZIP` is enabled.
+pub fn is_archive(path: &Path) -> bool {
+    config::flag("HANDLE_ZIP")
+        && path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Normalizes every image in `archive` and delivers the outputs, then removes the
+/// archive. The temporary folder is removed either way; an archive that could not be
+/// handled completely is quarantined. Returns the local output paths.
+pub fn process(archive: &Path, pad: u32, tol: u8) -> Result<Vec<PathBuf>, String> {
+    let dir = unpack_dir(archive)?;
+    let result = process_in(archive, &dir, pad, tol);
+    if let Some(root) = dir.parent()
+        && let Err(e) = fs::remove_dir_all(root)
+    {
+        log::warn!("Failed to remove temporary folder {:?}: {}", root, e);
+    }
+
+    match result {
+        Ok(outputs) => {
+            fs::remove_file(archive)
+                .map_err(|e| format!("Failed to remove archive {:?}: {}", archive, e))?;
+            Ok(outputs)
+        }
+        Err(e) => {
+            processing::quarantine(archive, &e)?;
+            Err(e)
+        }
+    }
+}
+
+fn process_in(archive: &Path, dir: &Path, pad: u32, tol: u8) -> Result<Vec<PathBuf>, String> {
+    let files = extract(archive, dir)?;
+    log::info!("Unpacked {} images from {:?}", files.len(), archive);
+
+    let mut outputs = Vec::new();
+    let mut failures = Vec::new();
+    for file in files {
+        match processing::process_and_save(&file, pad, tol) {
+            Ok(written) => outputs.extend(written),
+            // Left out of the outputs; decoding logged why.
+            Err(processing::ProcessError::Skipped(_)) => {}
+            Err(e) => {
+                log::error!("Error processing {:?} from {:?}: {}", file, archive, e);
+                failures.push(e.to_string());
+            }
+        }
+    }
+
+    let delivered = if config::flag("ZIP_REPACK") {
+        repack(archive, &outputs)?
+    } else {
+        move_outputs(archive, outputs)?
+    };
+    if !failures.is_empty() {
+        return Err(format!(
+            "{} images in the archive failed: {}",
+            failures.len(),
+            failures.join("; ")
+        ));
+    }
+    Ok(delivered)
+}
+
+/// A fresh folder under `TEMP_DIR` (or the system temp folder) named like the archive's
+/// folder, so profiles select the same settings as for images dropped next to it.
+fn unpack_dir(archive: &Path) -> Result<PathBuf, String> {
+    let base = config::var("TEMP_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let root = base.join(format!(
+        "ni-watcher-zip-{}-{}",
+        millis,
+        UNPACKED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let folder = archive
+        .parent()
+        .and_then(Path::file_name)
+        .map(OsString::from)
+        .unwrap_or_else(|| "archive".into());
+    let dir = root.join(folder);
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create temporary folder {:?}: {}", dir, e))?;
+    Ok(dir)
+}
+
+/// Moves the outputs next to the archive, honoring `ON_CONFLICT`. `keep-extension` acts
+/// like `rename` here, since the images came out of the archive.
+fn move_outputs(archive: &Path, outputs: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
+    let on_conflict = config::on_conflict()?;
+    let mut delivered = Vec::new();
+    for output in outputs {
+        let Some(file_name) = output.file_name() else {
+            continue;
+        };
+        let mut target = archive.with_file_name(file_name);
+        let reserved = on_conflict != config::OnConflict::Overwrite;
+        if reserved {
+            match processing::reserve_output(&target, on_conflict)? {
+                Some(reserved_path) => target = reserved_path,
+                None => {
+                    log::warn!(
+                        "Output {:?} already exists; keeping it (ON_CONFLICT=skip)",
+                        target
+                    );
+                    continue;
+                }
+            }
+        }
+        processing::mark_output(&target);
+        if let Err(e) = processing::rename_with_retries(&output, &target) {
+            if reserved {
+                let _ = fs::remove_file(&target);
+            }
+            return Err(e.into());
+        }
+        log::info!("Final processed image saved: {:?}", target);
+        delivered.push(target);
+    }
+    Ok(delivered)
+}
+
+/// Packs the outputs into `<archive stem>-normalized.zip` next to the archive.
+fn repack(archive: &Path, outputs: &[PathBuf]) -> Result<Vec<PathBuf>, String> {
+    if outputs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut file_name = archive.file_stem().unwrap_or_default().to_os_string();
+    file_name.push("-normalized.zip");
+    let target = archive.with_file_name(file_name);
+    processing::save_bytes(&pack(outputs)?, &target)?;
+    log::info!("Packed {} outputs into {:?}", outputs.len(), target);
+    Ok(vec![target])
+}
+
+/// Writes the images of `archive` to `dir` under their file names, dropping folders
+/// inside the archive, and returns their paths. Other entries are skipped. The archive
+/// and its unpacked images may not exceed `ZIP_MAX_BYTES` each.
+fn extract(archive: &Path, dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let max_bytes = config::zip_max_bytes()?;
+    let archive_size = fs::metadata(archive)
+        .map_err(|e| format!("Failed to read metadata of {:?}: {}", archive, e))?
+        .len();
+    if archive_size > max_bytes {
+        return Err(format!(
+            "Archive {:?} is larger than ZIP_MAX_BYTES ({} bytes)",
+            archive, max_bytes
+        ));
+    }
+    let file = File::open(archive).map_err(|e| format!("Failed to read {:?}: {}", archive, e))?;
+    let mut zip = ZipArchive::new(BufReader::new(file))
+        .map_err(|e| format!("Failed to open archive {:?}: {}", archive, e))?;
+    if zip.len() > MAX_ENTRIES {
+        return Err(format!(
+            "Archive {:?} has more than {} entries",
+            archive, MAX_ENTRIES
+        ));
+    }
+
+    let mut unpacked = 0u64;
+    let mut files = Vec::new();
+    for index in 0..zip.len() {
+        let mut entry = zip
+            .by_index(index)
+            .map_err(|e| format!("Failed to read entry {} of {:?}: {}", index, archive, e))?;
+        let entry_name = entry.name().to_string();
+        let Some(name) = entry_file_name(&entry_name).filter(|_| entry.is_file()) else {
+            continue;
+        };
+        let target = unique_path(dir, name);
+        if !processing::is_image_file(&target) {
+            log::info!("Skipping {:?} in {:?}: not an image", entry_name, archive);
+            continue;
+        }
+        unpacked += entry.size();
+        if unpacked > max_bytes {
+            return Err(format!(
+                "Archive {:?} unpacks to more than ZIP_MAX_BYTES ({} bytes)",
+                archive, max_bytes
+            ));
+        }
+        let bytes = read_entry(&mut entry).map_err(|e| {
+            format!(
+                "Failed to unpack {:?} from {:?}: {}",
+                entry_name, archive, e
+            )
+        })?;
+        fs::write(&target, bytes).map_err(|e| format!("Failed to write {:?}: {}", target, e))?;
+        files.push(target);
+    }
+    Ok(files)
+}
+
+/// The contents of `entry`, read no further than its declared size so an entry that
+/// inflates to more than the directory says cannot fill the disk. The checksum is
+/// verified as the end is reached.
+fn read_entry(entry: &mut ZipFile<'_>) -> Result<Vec<u8>, String> {
+    let size = entry.size();
+    let mut bytes = Vec::new();
+    entry
+        .take(size + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+    if bytes.len() as u64 != size {
+        return Err("size does not match the directory".to_string());
+    }
+    Ok(bytes)
+}
+
+/// The last path component of an entry name, or `None` for folders and for the resource
+/// forks macOS adds (`__MACOSX/`, `._name`).
+fn entry_file_name(name: &str) -> Option<&str> {
+    if name.starts_with("__MACOSX/") {
+        return None;
+    }
+    let file_name = name.rsplit(['/', '\\']).next()?;
+    (!file_name.is_empty() && file_name != ".." && !file_name.starts_with("._"))
+        .then_some(file_name)
+}
+
+/// `dir/name`, or `dir/<stem>-N.<ext>` when entries from different folders share a name.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    (1..)
+        .map(|n| dir.join(format!("{}-{}.{}", stem, n, ext)))
+        .find(|candidate| !candidate.exists())
+        .expect("an unused name exists")
+}
+
+/// Builds an archive of `files`, stored without compression since the images already
+/// are compressed.
+fn pack(files: &[PathBuf]) -> Result<Vec<u8>, String> {
+    let failed = |e: ZipError| format!("Failed to pack the outputs: {}", e);
+    // Entries carry the earliest DOS date, 1980-01-01, so the same outputs always produce
+    // the same archive.
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .last_modified_time(DateTime::default())
+        .large_file(false);
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for file in files {
+        let data = fs::read(file).map_err(|e| format!("Failed to read {:?}: {}", file, e))?;
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        writer.start_file(name, options).map_err(failed)?;
+        writer
+            .write_all(&data)
+            .map_err(|e| format!("Failed to pack {:?}: {}", file, e))?;
+    }
+    Ok(writer.finish().map_err(failed)?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zip::write::FileOptions;
+
+    /// An empty folder of its own under the system temp folder.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "ni-watcher-test-zip-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a deflated archive with `entries` (name, contents) to `path`.
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut writer = ZipWriter::new(File::create(path).unwrap());
+        let options: FileOptions<()> =
+            FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, data) in entries {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn extract_flattens_folders_and_skips_other_entries() {
+        let dir = scratch_dir("extract");
+        let archive = dir.join("photos.zip");
+        write_zip(
+            &archive,
+            &[
+                ("a/one.jpg", b"first"),
+                ("b/one.jpg", b"second"),
+                ("notes.txt", b"not an image"),
+                ("__MACOSX/a/._one.jpg", b"fork"),
+                ("._two.png", b"fork"),
+            ],
+        );
+        let unpacked = dir.join("unpacked");
+        fs::create_dir_all(&unpacked).unwrap();
+        let files = extract(&archive, &unpacked);
+        let contents: Vec<_> = files
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|file| fs::read(file).unwrap())
+            .collect();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            files.unwrap(),
+            vec![unpacked.join("one.jpg"), unpacked.join("one-1.jpg")]
+        );
+        assert_eq!(contents, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn damaged_archives_are_rejected() {
+        let dir = scratch_dir("damaged");
+        let first = dir.join("first.png");
+        fs::write(&first, b"first output").unwrap();
+        let packed = pack(&[first]).unwrap();
+        let unpacked = dir.join("unpacked");
+        fs::create_dir_all(&unpacked).unwrap();
+
+        let truncated = dir.join("truncated.zip");
+        fs::write(&truncated, &packed[..packed.len() / 2]).unwrap();
+        let truncated = extract(&truncated, &unpacked);
+
+        // Stored entries keep their bytes as is, so flipping one breaks the checksum.
+        let corrupt = dir.join("corrupt.zip");
+        let mut bytes = packed.clone();
+        let at = bytes
+            .windows(12)
+            .position(|window| window == b"first output")
+            .unwrap();
+        bytes[at] ^= 0xff;
+        fs::write(&corrupt, bytes).unwrap();
+        let corrupt = extract(&corrupt, &unpacked);
+
+        let intact = dir.join("intact.zip");
+        fs::write(&intact, &packed).unwrap();
+        let intact = extract(&intact, &unpacked);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(truncated.is_err());
+        assert!(corrupt.unwrap_err().contains("Failed to unpack"));
+        assert_eq!(intact.unwrap(), vec![unpacked.join("first.png")]);
+    }
+}
@@ -0,0 +1,449 @@
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::{config, events, processing};
+
+/// Lists the image files directly inside `folder`, sorted by name. Temp files left by an
+/// interrupted run (`.normalized.`, `_tmp`), `MASTER_FORMAT` masters, `PREVIEW` previews
+/// and `OUTPUT_NAMING=sequence` outputs are skipped.
+pub fn collect_images(folder: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        fs::read_dir(folder).map_err(|e| format!("Failed to read folder {:?}: {}", folder, e))?;
+
+    let mut images: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && processing::is_image_file(path))
+        .filter(|path| {
+            !processing::file_name_contains(path, ".normalized.")
+                && !processing::file_name_contains(path, "_tmp")
+                && !processing::is_master(path)
+                && !processing::is_preview(path)
+                && !processing::is_sequence_output(path)
+        })
+        .collect();
+    images.sort();
+    Ok(images)
+}
+
+/// How often a batch run logs how far it got.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Logs `N/M files done` for a batch run of `total` files, at most every
+/// `PROGRESS_INTERVAL`, so short runs stay quiet.
+struct Progress {
+    total: usize,
+    last_report: Instant,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Progress {
+            total,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Called with the number of files finished so far, before starting the next one.
+    fn update(&mut self, done: usize) {
+        if self.last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        log::info!(
+            "Progress: {}/{} files done ({}%)",
+            done,
+            self.total,
+            done * 100 / self.total.max(1)
+        );
+    }
+}
+
+/// Re-normalizes every image in `folder` with the current settings, bypassing the
+/// watcher's recently-processed cache. The file list is captured before the first file is
+/// touched, and files written as outputs during the run are skipped, so each original is
+/// processed exactly once and outputs are never fed back in. Returns the number of failures.
+pub fn reprocess(folder: &Path, pad: u32, tol: u8) -> Result<usize, String> {
+    let images = collect_images(folder)?;
+    log::info!("Reprocessing {} files in {:?}", images.len(), folder);
+
+    let mut progress = Progress::new(images.len());
+    let mut outputs = HashSet::new();
+    let mut failures = 0;
+    for (done, path) in images.into_iter().enumerate() {
+        progress.update(done);
+        if outputs.contains(&path) {
+            log::info!(
+                "Skipping {:?}: it was written as an output during this run",
+                path
+            );
+            continue;
+        }
+        match processing::process_and_save(&path, pad, tol) {
+            Ok(written) => {
+                log::info!("File processed successfully: {:?}", path);
+                events::emit(&path, Ok(&written));
+                outputs.extend(written);
+            }
+            Err(processing::ProcessError::Skipped(reason)) => events::skipped(&path, &reason),
+            Err(err) => {
+                log::error!("Error processing file {:?}: {}", path, err);
+                events::emit(&path, Err(err.message()));
+                failures += 1;
+            }
+        }
+    }
+
+    processing::finish_deletions();
+    log::info!("Reprocessing finished with {} failures.", failures);
+    Ok(failures)
+}
+
+/// Byte totals for one source format in a size report.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SizeTotals {
+    pub files: usize,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+}
+
+impl SizeTotals {
+    fn add(&mut self, other: SizeTotals) {
+        self.files += other.files;
+        self.input_bytes += other.input_bytes;
+        self.output_bytes += other.output_bytes;
+    }
+
+    fn saved_percent(&self) -> f64 {
+        if self.input_bytes == 0 {
+            return 0.0;
+        }
+        (1.0 - self.output_bytes as f64 / self.input_bytes as f64) * 100.0
+    }
+}
+
+/// Input and output sizes of a dry run over a folder, grouped by source extension.
+#[derive(Debug, Default)]
+pub struct SizeReport {
+    pub formats: BTreeMap<String, SizeTotals>,
+    pub failures: usize,
+}
+
+impl SizeReport {
+    pub fn total(&self) -> SizeTotals {
+        let mut total = SizeTotals::default();
+        for totals in self.formats.values() {
+            total.add(*totals);
+        }
+        total
+    }
+}
+
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<8} {:>7} {:>15} {:>15} {:>8}",
+            "format", "files", "input bytes", "output bytes", "saved"
+        )?;
+        let rows = self
+            .formats
+            .iter()
+            .map(|(ext, totals)| (ext.as_str(), totals));
+        let total = self.total();
+        for (name, totals) in rows.chain([("total", &total)]) {
+            writeln!(
+                f,
+                "{:<8} {:>7} {:>15} {:>15} {:>7.1}%",
+                name,
+                totals.files,
+                totals.input_bytes,
+                totals.output_bytes,
+                totals.saved_percent()
+            )?;
+        }
+        if self.failures > 0 {
+            writeln!(f, "{} files could not be processed", self.failures)?;
+        }
+        Ok(())
+    }
+}
+
+/// Normalizes every image in `folder` in memory and totals the source and output sizes
+/// per source format. Nothing is written, moved or quarantined.
+pub fn size_report(folder: &Path, pad: u32, tol: u8) -> Result<SizeReport, String> {
+    let images = collect_images(folder)?;
+    log::info!("Measuring {} files in {:?}", images.len(), folder);
+
+    let mut progress = Progress::new(images.len());
+    let mut report = SizeReport::default();
+    for (done, path) in images.into_iter().enumerate() {
+        progress.update(done);
+        let input_bytes = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                log::error!("Failed to read metadata of {:?}: {}", path, e);
+                report.failures += 1;
+                continue;
+            }
+        };
+        match processing::dry_run(&path, pad, tol) {
+            Ok(encoded) => {
+                let ext = path
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+                    .unwrap_or_default();
+                report.formats.entry(ext).or_default().add(SizeTotals {
+                    files: 1,
+                    input_bytes,
+                    output_bytes: encoded.iter().map(|bytes| bytes.len() as u64).sum(),
+                });
+            }
+            Err(err) => {
+                log::error!("Error measuring file {:?}: {}", path, err);
+                report.failures += 1;
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// One input of a manifest: a source file and the settings that apply to it only.
+#[derive(Debug)]
+pub struct ManifestRow {
+    pub path: PathBuf,
+    pub options: HashMap<String, String>,
+}
+
+/// Reads a manifest listing the files to process, in order. `.csv` manifests have a
+/// header row starting with `path` followed by setting keys, e.g.
+/// `path,OUTPUT_FORMAT,TARGET_WIDTH`; empty cells leave a setting unset. Fields are split
+/// on commas and cannot be quoted. Anything else is read as a JSON array of objects with
+/// a `path` and setting keys, e.g. `[{"path": "a.jpg", "OUTPUT_FORMAT": "png"}]`.
+/// Relative paths are resolved against the manifest's folder.
+pub fn read_manifest(manifest: &Path) -> Result<Vec<ManifestRow>, String> {
+    let content = fs::read_to_string(manifest)
+        .map_err(|e| format!("Failed to read manifest {:?}: {}", manifest, e))?;
+    let is_csv = manifest
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let rows = if is_csv {
+        parse_csv_manifest(&content)
+    } else {
+        parse_json_manifest(&content)
+    }
+    .map_err(|e| format!("Invalid manifest {:?}: {}", manifest, e))?;
+
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    Ok(rows
+        .into_iter()
+        .map(|row| ManifestRow {
+            path: base.join(row.path),
+            options: row.options,
+        })
+        .collect())
+}
+
+fn parse_csv_manifest(content: &str) -> Result<Vec<ManifestRow>, String> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let columns: Vec<&str> = header.split(',').map(str::trim).collect();
+    if !columns[0].eq_ignore_ascii_case("path") {
+        return Err("the first column must be \"path\"".to_string());
+    }
+    for key in &columns[1..] {
+        check_setting_key(key)?;
+    }
+
+    lines
+        .map(|(index, line)| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != columns.len() {
+                return Err(format!(
+                    "line {} has {} fields, expected {}",
+                    index + 1,
+                    fields.len(),
+                    columns.len()
+                ));
+            }
+            Ok(ManifestRow {
+                path: PathBuf::from(fields[0]),
+                options: columns[1..]
+                    .iter()
+                    .zip(&fields[1..])
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn parse_json_manifest(content: &str) -> Result<Vec<ManifestRow>, String> {
+    let value: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let Value::Array(entries) = value else {
+        return Err("expected a JSON array of objects".to_string());
+    };
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let Value::Object(fields) = entry else {
+                return Err(format!("entry {} is not an object", index));
+            };
+            let mut path = None;
+            let mut options = HashMap::new();
+            for (key, value) in fields {
+                let value = match value {
+                    Value::String(text) => text,
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    Value::Null => continue,
+                    other => {
+                        return Err(format!(
+                            "entry {}: {} must be a string, number or boolean, got {}",
+                            index, key, other
+                        ));
+                    }
+                };
+                if key == "path" {
+                    path = Some(PathBuf::from(value));
+                } else {
+                    check_setting_key(&key)?;
+                    options.insert(key, value);
+                }
+            }
+            let path = path.ok_or_else(|| format!("entry {} has no \"path\"", index))?;
+            Ok(ManifestRow { path, options })
+        })
+        .collect()
+}
+
+fn check_setting_key(key: &str) -> Result<(), String> {
+    if config::SETTINGS.iter().any(|setting| setting.key == key) {
+        Ok(())
+    } else {
+        Err(format!("unknown setting {:?}", key))
+    }
+}
+
+/// Outcome of one manifest row.
+#[derive(Debug)]
+pub enum RowStatus {
+    /// Processed; holds the local output paths.
+    Processed(Vec<PathBuf>),
+    /// Left in place on purpose, e.g. already normalized; holds the reason.
+    Skipped(String),
+    Failed(processing::ProcessError),
+}
+
+/// Processes exactly the files listed in `manifest`, in order, each with its row's
+/// settings on top of the current configuration, and writes one result per row to
+/// `<manifest>.result.json`. Rows are independent: a failed row does not stop the run.
+/// Returns the statuses in manifest order.
+pub fn run_manifest(
+    manifest: &Path,
+    pad: u32,
+    tol: u8,
+) -> Result<Vec<(PathBuf, RowStatus)>, String> {
+    let rows = read_manifest(manifest)?;
+    log::info!(
+        "Processing {} files from manifest {:?}",
+        rows.len(),
+        manifest
+    );
+
+    let mut progress = Progress::new(rows.len());
+    let mut results = Vec::new();
+    for (done, row) in rows.into_iter().enumerate() {
+        progress.update(done);
+        let status = config::with_values(&row.options, || {
+            processing::process_and_save(&row.path, pad, tol)
+        });
+        let status = match status {
+            Ok(outputs) => {
+                log::info!("File processed successfully: {:?}", row.path);
+                events::emit(&row.path, Ok(&outputs));
+                RowStatus::Processed(outputs)
+            }
+            Err(processing::ProcessError::Skipped(reason)) => {
+                events::skipped(&row.path, &reason);
+                RowStatus::Skipped(reason)
+            }
+            Err(err) => {
+                log::error!("Error processing file {:?}: {}", row.path, err);
+                events::emit(&row.path, Err(err.message()));
+                RowStatus::Failed(err)
+            }
+        };
+        results.push((row.path, status));
+    }
+    processing::finish_deletions();
+
+    let report: Vec<Value> = results
+        .iter()
+        .map(|(path, status)| match status {
+            RowStatus::Processed(outputs) => json!({
+                "path": path,
+                "status": "processed",
+                "outputs": outputs,
+            }),
+            RowStatus::Skipped(reason) => json!({
+                "path": path,
+                "status": "skipped",
+                "reason": reason,
+            }),
+            RowStatus::Failed(error) => json!({
+                "path": path,
+                "status": "failed",
+                "error": error.message(),
+                "kind": error.kind(),
+            }),
+        })
+        .collect();
+    let mut result_path = manifest.as_os_str().to_owned();
+    result_path.push(".result.json");
+    let result_path = PathBuf::from(result_path);
+    let report = serde_json::to_string_pretty(&report)
+        .map_err(|e| format!("Failed to serialize manifest results: {}", e))?;
+    fs::write(&result_path, report + "\n")
+        .map_err(|e| format!("Failed to write manifest results {:?}: {}", result_path, e))?;
+    log::info!("Manifest results written to {:?}", result_path);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_manifest_rows_record_the_error_kind() {
+        let dir =
+            std::env::temp_dir().join(format!("ni-watcher-test-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("broken.png"), b"not an image").unwrap();
+        let manifest = dir.join("manifest.csv");
+        fs::write(&manifest, "path\nbroken.png\n").unwrap();
+
+        let results = run_manifest(&manifest, 0, 10).unwrap();
+        let report = fs::read_to_string(dir.join("manifest.csv.result.json")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let [(path, RowStatus::Failed(processing::ProcessError::Permanent(_)))] = &results[..]
+        else {
+            panic!("unexpected results {:?}", results);
+        };
+        assert_eq!(path.file_name().unwrap(), "broken.png");
+        let report: Value = serde_json::from_str(&report).unwrap();
+        assert_eq!(report[0]["status"], "failed");
+        assert_eq!(report[0]["kind"], "permanent");
+    }
+}
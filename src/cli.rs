@@ -0,0 +1,136 @@
+use clap::{Arg, ArgAction, Command};
+use std::{collections::HashMap, path::PathBuf};
+
+use ni_service_rs::config;
+
+pub struct Cli {
+    pub console: bool,
+    pub print_config: bool,
+    pub capabilities: bool,
+    pub status: bool,
+    pub reprocess: Option<PathBuf>,
+    pub size_report: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub histogram: Option<PathBuf>,
+    pub histogram_png: Option<PathBuf>,
+}
+
+fn command() -> Command {
+    let mut command = Command::new("ni-service")
+        .about("Watches a folder and normalizes new images")
+        .arg(
+            Arg::new("console")
+                .long("console")
+                .action(ArgAction::SetTrue)
+                .help("Run in the foreground instead of as a Windows service"),
+        )
+        .arg(
+            Arg::new("print-config")
+                .long("print-config")
+                .action(ArgAction::SetTrue)
+                .help("Print the effective configuration as JSON and exit"),
+        )
+        .arg(
+            Arg::new("capabilities")
+                .long("capabilities")
+                .action(ArgAction::SetTrue)
+                .help("Print the supported formats and processing options of this build as JSON and exit"),
+        )
+        .arg(
+            Arg::new("status")
+                .long("status")
+                .action(ArgAction::SetTrue)
+                .help("Query the running service and print its status; exits non-zero if it is unreachable"),
+        )
+        .arg(
+            Arg::new("reprocess")
+                .long("reprocess")
+                .value_name("FOLDER")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Re-normalize every image in FOLDER with the current settings and exit"),
+        )
+        .arg(
+            Arg::new("size-report")
+                .long("size-report")
+                .value_name("FOLDER")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("reprocess")
+                .help("Normalize every image in FOLDER in memory and report input vs output bytes"),
+        )
+        .arg(
+            Arg::new("manifest")
+                .long("manifest")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(["reprocess", "size-report"])
+                .help("Process exactly the files listed in FILE (JSON or CSV), in order, and exit"),
+        )
+        .arg(
+            Arg::new("histogram")
+                .long("histogram")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with_all(["reprocess", "size-report", "manifest"])
+                .help("Print the luma histogram, content threshold and bounding box of FILE and exit"),
+        )
+        .arg(
+            Arg::new("histogram-png")
+                .long("histogram-png")
+                .value_name("OUT")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("histogram")
+                .help("With --histogram, also render the histogram as a PNG to OUT"),
+        )
+        .arg(
+            Arg::new("size")
+                .long("size")
+                .value_name("WxH")
+                .help("Output canvas size, e.g. 800x600 (sets TARGET_WIDTH and TARGET_HEIGHT)"),
+        );
+
+    for setting in config::SETTINGS {
+        let mut arg = Arg::new(setting.key)
+            .long(setting.flag)
+            .value_name("VALUE")
+            .help(format!("{} [env: {}]", setting.help, setting.key));
+        if setting.toggle {
+            arg = arg.num_args(0..=1).default_missing_value("1");
+        }
+        command = command.arg(arg);
+    }
+    command
+}
+
+/// Parses the command line and installs every given setting as an override, so flags
+/// take precedence over the environment and `.env`.
+pub fn parse() -> Cli {
+    let matches = command().get_matches();
+    let mut overrides = HashMap::new();
+
+    for setting in config::SETTINGS {
+        if let Some(value) = matches.get_one::<String>(setting.key) {
+            overrides.insert(setting.key.to_string(), value.clone());
+        }
+    }
+
+    if let Some(size) = matches.get_one::<String>("size") {
+        let (width, height) = size
+            .split_once(['x', 'X'])
+            .unwrap_or((size.as_str(), size.as_str()));
+        overrides.insert("TARGET_WIDTH".to_string(), width.trim().to_string());
+        overrides.insert("TARGET_HEIGHT".to_string(), height.trim().to_string());
+    }
+
+    config::set_overrides(overrides);
+    Cli {
+        console: matches.get_flag("console"),
+        print_config: matches.get_flag("print-config"),
+        capabilities: matches.get_flag("capabilities"),
+        status: matches.get_flag("status"),
+        reprocess: matches.get_one::<PathBuf>("reprocess").cloned(),
+        size_report: matches.get_one::<PathBuf>("size-report").cloned(),
+        manifest: matches.get_one::<PathBuf>("manifest").cloned(),
+        histogram: matches.get_one::<PathBuf>("histogram").cloned(),
+        histogram_png: matches.get_one::<PathBuf>("histogram-png").cloned(),
+    }
+}
@@ -0,0 +1,190 @@
+//! CMYK JPEGs, as exported by print workflows. Their four channels hold ink amounts that
+//! the `image` crate maps to RGB as if they were always stored inverted, the way Adobe
+//! applications write them. Files without Adobe's APP14 marker store plain ink amounts
+//! and would come out as a color negative, so `CMYK_JPEG=convert` decodes all CMYK
+//! JPEGs here instead.
+//!
+//! An embedded CMYK ICC profile, which print exports nearly always carry, is used for
+//! the conversion to sRGB; without one, the plain `(1 - ink) * (1 - black)` formula is.
+
+use image::{DynamicImage, RgbImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// A four-channel JPEG found by `probe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CmykJpeg {
+    /// Whether an Adobe APP14 marker says the channels are stored inverted.
+    pub adobe: bool,
+}
+
+/// Reads the markers of `path` up to its frame header and reports whether it is a JPEG
+/// with four color channels (CMYK or YCCK). Anything else, including files that are not
+/// JPEGs or cannot be read, is `None`.
+pub fn probe(path: &Path) -> Option<CmykJpeg> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let mut marker = [0u8; 2];
+    reader.read_exact(&mut marker).ok()?;
+    if marker != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut adobe = false;
+    loop {
+        reader.read_exact(&mut marker).ok()?;
+        if marker[0] != 0xFF {
+            return None;
+        }
+        // Fill bytes may precede a marker.
+        while marker[1] == 0xFF {
+            reader.read_exact(&mut marker[1..]).ok()?;
+        }
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length).ok()?;
+        let length = usize::from(u16::from_be_bytes(length)).checked_sub(2)?;
+        let mut segment = vec![0; length];
+        reader.read_exact(&mut segment).ok()?;
+        match marker[1] {
+            0xEE if segment.starts_with(b"Adobe") => adobe = true,
+            // Frame headers (SOF0-SOF15 except DHT, JPG and DAC): precision, height,
+            // width, then the number of components.
+            0xC0..=0xCF if !matches!(marker[1], 0xC4 | 0xC8 | 0xCC) => {
+                return (segment.get(5) == Some(&4)).then_some(CmykJpeg { adobe });
+            }
+            // The image data starts before any frame header was seen.
+            0xDA => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Decodes the CMYK JPEG at `path` to RGB, undoing the inversion the decoder applies to
+/// files without an Adobe marker.
+pub fn decode(path: &Path, cmyk: CmykJpeg) -> Result<DynamicImage, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut decoder = jpeg_decoder::Decoder::new(BufReader::new(file));
+    let pixels = decoder.decode().map_err(|e| e.to_string())?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| "missing frame header".to_string())?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Err(format!("expected CMYK pixels, got {:?}", info.pixel_format));
+    }
+    // The decoder returns ink amounts (0 is no ink) for Adobe's inverted storage.
+    let ink = if cmyk.adobe {
+        pixels
+    } else {
+        pixels.into_iter().map(|value| 255 - value).collect()
+    };
+
+    let rgb = match decoder.icc_profile().map(|icc| with_profile(&ink, &icc)) {
+        Some(Ok(rgb)) => rgb,
+        Some(Err(e)) => {
+            log::warn!(
+                "Ignoring the CMYK profile of {:?}, which cannot be used: {}",
+                path,
+                e
+            );
+            naive_rgb(&ink)
+        }
+        None => naive_rgb(&ink),
+    };
+    RgbImage::from_raw(u32::from(info.width), u32::from(info.height), rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "decoded data does not match the image size".to_string())
+}
+
+/// Converts ink amounts to sRGB through the embedded CMYK profile.
+fn with_profile(ink: &[u8], icc: &[u8]) -> Result<Vec<u8>, String> {
+    let profile = ColorProfile::new_from_slice(icc).map_err(|e| e.to_string())?;
+    if profile.color_space != DataColorSpace::Cmyk {
+        return Err(format!("it is for {:?}, not CMYK", profile.color_space));
+    }
+    let mut rgb = vec![0; ink.len() / 4 * 3];
+    profile
+        .create_transform_8bit(
+            Layout::Rgba,
+            &ColorProfile::new_srgb(),
+            Layout::Rgb,
+            TransformOptions::default(),
+        )
+        .and_then(|transform| transform.transform(ink, &mut rgb))
+        .map_err(|e| e.to_string())?;
+    Ok(rgb)
+}
+
+/// Converts ink amounts to RGB without color management.
+fn naive_rgb(ink: &[u8]) -> Vec<u8> {
+    ink.chunks_exact(4)
+        .flat_map(|pixel| {
+            let white = 255 - u16::from(pixel[3]);
+            [0, 1, 2].map(|channel| ((255 - u16::from(pixel[channel])) * white / 255) as u8)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A 16x16 JPEG of plain red ink (no cyan, full magenta and yellow, no black), stored
+    /// inverted with an Adobe marker or, with `adobe` false, as plain ink amounts without one.
+    fn red_cmyk_jpeg(adobe: bool) -> Vec<u8> {
+        // The encoder inverts what it is given and always adds the Adobe marker.
+        let ink = [0u8, 255, 255, 0];
+        let pixel = if adobe {
+            ink
+        } else {
+            ink.map(|value| 255 - value)
+        };
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, 95)
+            .encode(
+                &pixel.repeat(16 * 16),
+                16,
+                16,
+                jpeg_encoder::ColorType::Cmyk,
+            )
+            .unwrap();
+        if !adobe {
+            let at = jpeg
+                .windows(9)
+                .position(|segment| segment == b"\xFF\xEE\x00\x0EAdobe")
+                .unwrap();
+            jpeg.drain(at..at + 16);
+        }
+        jpeg
+    }
+
+    #[test]
+    fn cmyk_jpegs_decode_to_the_same_colors_with_and_without_the_adobe_marker() {
+        let dir = std::env::temp_dir().join(format!("ni-watcher-test-cmyk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut decoded = Vec::new();
+        for adobe in [true, false] {
+            let path = dir.join(format!("red-{}.jpg", adobe));
+            fs::write(&path, red_cmyk_jpeg(adobe)).unwrap();
+            let found = probe(&path);
+            decoded.push((found, found.map(|found| decode(&path, found))));
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        for ((found, image), adobe) in decoded.into_iter().zip([true, false]) {
+            assert_eq!(found, Some(CmykJpeg { adobe }));
+            let image = image.unwrap().unwrap().to_rgb8();
+            assert_eq!(image.dimensions(), (16, 16));
+            let [r, g, b] = image.get_pixel(8, 8).0;
+            assert!(
+                r > 240 && g < 15 && b < 15,
+                "adobe {}: {:?}",
+                adobe,
+                (r, g, b)
+            );
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! `CONVERT_TO_SRGB`: converts sources that embed an ICC profile (Adobe RGB, Display P3,
+//! ProPhoto and the like) to sRGB right after decoding. Outputs are written without a
+//! profile, so viewers read them as sRGB; without the conversion the source's pixel values
+//! would be shown in the wrong color space and look washed out or oversaturated.
+//!
+//! Sources without a profile are taken to be sRGB already and left untouched, as are
+//! profiles for other color spaces. Grayscale pixels are mapped to RGB without color
+//! management, and CMYK JPEGs are converted with their profile by `cmyk` already.
+
+use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageDecoder, ImageFormat};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use std::{fs::File, io::BufReader, path::Path};
+
+/// Returns `img`, decoded from `path`, converted to sRGB from the ICC profile embedded in
+/// the file. A profile that cannot be used is logged and the pixels are kept as decoded.
+pub fn to_srgb(path: &Path, img: DynamicImage) -> DynamicImage {
+    let Some(icc) = embedded_profile(path) else {
+        return img;
+    };
+    let profile = match ColorProfile::new_from_slice(&icc) {
+        Ok(profile) => profile,
+        Err(e) => {
+            log::warn!(
+                "Ignoring the ICC profile of {:?}, which cannot be read: {}",
+                path,
+                e
+            );
+            return img;
+        }
+    };
+    if profile.color_space != DataColorSpace::Rgb {
+        log::debug!(
+            "Keeping the colors of {:?}: its ICC profile is for {:?}, not RGB",
+            path,
+            profile.color_space
+        );
+        return img;
+    }
+    match convert(&img, &profile) {
+        Ok(converted) => {
+            log::info!("Converted {:?} from its ICC profile to sRGB", path);
+            converted
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to convert {:?} to sRGB, keeping its colors: {}",
+                path,
+                e
+            );
+            img
+        }
+    }
+}
+
+/// The ICC profile of the formats whose decoders expose one. Only the headers are read.
+fn embedded_profile(path: &Path) -> Option<Vec<u8>> {
+    let format = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .format()?;
+    let file = BufReader::new(File::open(path).ok()?);
+    match format {
+        ImageFormat::Jpeg => JpegDecoder::new(file).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(file).ok()?.icc_profile(),
+        ImageFormat::Tiff => TiffDecoder::new(file).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(file).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+/// Transforms the pixels of `img` at its own depth, keeping alpha as is. Grayscale images
+/// with an RGB profile are widened to RGB first.
+fn convert(img: &DynamicImage, profile: &ColorProfile) -> Result<DynamicImage, moxcms::CmsError> {
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let color = img.color();
+    let high_depth = color.bytes_per_pixel() > color.channel_count();
+    Ok(match (high_depth, color.has_alpha()) {
+        (false, false) => {
+            let mut pixels = img.to_rgb8();
+            let source = pixels.as_raw().clone();
+            profile
+                .create_transform_8bit(Layout::Rgb, &srgb, Layout::Rgb, options)?
+                .transform(&source, &mut pixels)?;
+            DynamicImage::ImageRgb8(pixels)
+        }
+        (false, true) => {
+            let mut pixels = img.to_rgba8();
+            let source = pixels.as_raw().clone();
+            profile
+                .create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgba, options)?
+                .transform(&source, &mut pixels)?;
+            DynamicImage::ImageRgba8(pixels)
+        }
+        (true, false) => {
+            let mut pixels = img.to_rgb16();
+            let source = pixels.as_raw().clone();
+            profile
+                .create_transform_16bit(Layout::Rgb, &srgb, Layout::Rgb, options)?
+                .transform(&source, &mut pixels)?;
+            DynamicImage::ImageRgb16(pixels)
+        }
+        (true, true) => {
+            let mut pixels = img.to_rgba16();
+            let source = pixels.as_raw().clone();
+            profile
+                .create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgba, options)?
+                .transform(&source, &mut pixels)?;
+            DynamicImage::ImageRgba16(pixels)
+        }
+    })
+}
@@ -0,0 +1,2523 @@
+use once_cell::sync::OnceCell;
+use serde_json::{Value, json};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread::LocalKey,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::presets;
+
+pub const DEFAULT_TARGET_SIZE: (u32, u32) = (800, 800);
+
+/// Output formats understood by the encode stage, keyed by their canonical extension.
+pub const OUTPUT_FORMATS: [&str; 6] = ["jpg", "png", "gif", "bmp", "tiff", "webp"];
+
+/// Source extensions picked up when `IMAGE_EXTENSIONS` is not set.
+pub const DEFAULT_IMAGE_EXTENSIONS: [&str; 7] =
+    ["png", "jpg", "jpeg", "bmp", "gif", "tiff", "webp"];
+
+/// A setting that can be given as an environment variable or a command-line flag.
+pub struct Setting {
+    pub key: &'static str,
+    pub flag: &'static str,
+    pub help: &'static str,
+    /// Toggles may be passed as a bare flag (`--eventlog`), which means `1`.
+    pub toggle: bool,
+}
+
+const fn setting(key: &'static str, flag: &'static str, help: &'static str) -> Setting {
+    Setting {
+        key,
+        flag,
+        help,
+        toggle: false,
+    }
+}
+
+const fn toggle(key: &'static str, flag: &'static str, help: &'static str) -> Setting {
+    Setting {
+        key,
+        flag,
+        help,
+        toggle: true,
+    }
+}
+
+/// Every setting with a command-line flag. Per-format keys such as `TARGET_WIDTH_PNG`,
+/// `SHARE_PASSWORD` and the standard AWS credential variables are environment-only.
+pub const SETTINGS: &[Setting] = &[
+    setting("PRESET", "preset", "Built-in option set: amazon or avatar"),
+    setting("WATCH_FOLDER", "watch-folder", "Folder to monitor"),
+    setting("WATCH_MODE", "watch-mode", "native or poll"),
+    setting(
+        "POLL_INTERVAL_MS",
+        "poll-interval-ms",
+        "Scan interval in poll mode",
+    ),
+    setting(
+        "SHARE_USER",
+        "share-user",
+        "User connecting to a UNC watch folder; SHARE_PASSWORD is environment-only",
+    ),
+    toggle(
+        "STARTUP_SCAN",
+        "startup-scan",
+        "Queue images already in the watch folder at startup",
+    ),
+    setting(
+        "SCAN_SINCE",
+        "scan-since",
+        "Startup scan only takes files modified after this UTC time or last-run",
+    ),
+    setting(
+        "IMAGE_EXTENSIONS",
+        "image-extensions",
+        "Comma-separated source extensions",
+    ),
+    toggle(
+        "SNIFF_EXTENSIONLESS",
+        "sniff-extensionless",
+        "Process files without an extension whose contents are an image",
+    ),
+    setting(
+        "IGNORE_LIST_FILE",
+        "ignore-list-file",
+        "Text file of file names and paths never to process",
+    ),
+    setting(
+        "OUTPUT_FORMAT",
+        "output-format",
+        "jpg, png, gif, bmp, tiff or webp",
+    ),
+    toggle(
+        "REPLACE_IN_PLACE",
+        "replace-in-place",
+        "Overwrite each source with its output in the source's own format and name",
+    ),
+    setting(
+        "MASTER_FORMAT",
+        "master-format",
+        "Lossless format of a master written next to each output: png, tiff or webp",
+    ),
+    setting(
+        "MASTER_SUFFIX",
+        "master-suffix",
+        "Added to the name of MASTER_FORMAT outputs",
+    ),
+    toggle(
+        "PREVIEW",
+        "preview",
+        "Write a small <stem>.preview.<ext> right after decoding, before the full output",
+    ),
+    setting(
+        "PREVIEW_SIZE",
+        "preview-size",
+        "Longer side of the PREVIEW canvas in pixels",
+    ),
+    setting(
+        "FALLBACK_FORMAT",
+        "fallback-format",
+        "Output format written when encoding to OUTPUT_FORMAT fails",
+    ),
+    setting("JPEG_QUALITY", "jpeg-quality", "JPEG quality from 1 to 100"),
+    setting(
+        "JPEG_SUBSAMPLING",
+        "jpeg-subsampling",
+        "JPEG chroma subsampling: 444, 422 or 420",
+    ),
+    setting(
+        "GIF_SPEED",
+        "gif-speed",
+        "GIF palette speed from 1 (best) to 30 (fastest)",
+    ),
+    toggle(
+        "GIF_TRANSPARENCY",
+        "gif-transparency",
+        "Keep a transparent index in GIF outputs (default on)",
+    ),
+    setting(
+        "OUTPUT_DPI",
+        "output-dpi",
+        "Resolution tagged on JPEG, PNG and TIFF outputs",
+    ),
+    setting(
+        "CMYK_JPEG",
+        "cmyk-jpeg",
+        "CMYK JPEGs: convert, skip or quarantine",
+    ),
+    toggle(
+        "CONVERT_TO_SRGB",
+        "convert-to-srgb",
+        "Convert sources with an embedded ICC profile to sRGB",
+    ),
+    setting("OUTPUT_NAMING", "output-naming", "stem, hash or sequence"),
+    setting(
+        "SEQUENCE_WIDTH",
+        "sequence-width",
+        "Digits of OUTPUT_NAMING=sequence names",
+    ),
+    setting(
+        "SEQUENCE_START",
+        "sequence-start",
+        "First number of OUTPUT_NAMING=sequence",
+    ),
+    setting(
+        "ON_CONFLICT",
+        "on-conflict",
+        "When the output name is taken: keep-extension, overwrite, skip or rename",
+    ),
+    setting(
+        "PROFILES",
+        "profiles",
+        "Comma-separated NAME=GLOB subfolder profiles",
+    ),
+    setting(
+        "JOBS",
+        "jobs",
+        "Comma-separated names of jobs, each watching its own JOB_<NAME>_WATCH_FOLDER",
+    ),
+    setting("TARGET_WIDTH", "target-width", "Output canvas width"),
+    setting("TARGET_HEIGHT", "target-height", "Output canvas height"),
+    setting(
+        "SIZES",
+        "sizes",
+        "Comma-separated widths or WxH sizes to write per input",
+    ),
+    setting(
+        "MAX_OUTPUT_PIXELS",
+        "max-output-pixels",
+        "Largest output canvas area in pixels",
+    ),
+    setting("PADDING", "padding", "Margin kept around the content"),
+    setting(
+        "PADDING_OVERFLOW",
+        "padding-overflow",
+        "error or clamp when the padding leaves no room for the content",
+    ),
+    setting(
+        "CROP_SIDES",
+        "crop-sides",
+        "Edges whitespace may be trimmed from",
+    ),
+    setting(
+        "CROP_MARGIN_PX",
+        "crop-margin-px",
+        "Pixels of the source kept around the detected content",
+    ),
+    setting(
+        "SCREENSHOT_MODE",
+        "screenshot-mode",
+        "Keep screenshots uncropped: off, auto or always",
+    ),
+    setting(
+        "CHANNEL_TOLERANCE",
+        "channel-tolerance",
+        "Per-channel background tolerance, R,G,B",
+    ),
+    setting(
+        "CHANNEL_BACKGROUND",
+        "channel-background",
+        "Background color for CHANNEL_TOLERANCE, RRGGBB",
+    ),
+    toggle(
+        "ADAPTIVE_TOLERANCE",
+        "adaptive-tolerance",
+        "Derive the background and tolerance from each image's border",
+    ),
+    setting(
+        "NOISE_TOLERANCE",
+        "noise-tolerance",
+        "Smallest cluster of content pixels that counts for the crop",
+    ),
+    setting(
+        "DESKEW",
+        "deskew",
+        "Straighten content rotated by up to this many degrees",
+    ),
+    setting(
+        "BACKGROUND",
+        "background",
+        "Canvas color: white, transparent, auto or RRGGBB",
+    ),
+    setting("MASK", "mask", "none or circle"),
+    setting("BIT_DEPTH", "bit-depth", "Bits per output channel: 8 or 16"),
+    setting(
+        "SHARPEN_AMOUNT",
+        "sharpen-amount",
+        "Unsharp mask strength after downscaling, 0 to 5",
+    ),
+    setting(
+        "SHARPEN_RADIUS",
+        "sharpen-radius",
+        "Unsharp mask radius in pixels, 0.1 to 10",
+    ),
+    setting("GRAVITY", "gravity", "center, top, bottom, left or right"),
+    setting(
+        "ALIGN",
+        "align",
+        "Center the bounding box (bbox) or the visual weight (centroid)",
+    ),
+    setting(
+        "CENTER_REMAINDER",
+        "center-remainder",
+        "Side given an odd leftover pixel: end or start",
+    ),
+    setting(
+        "MIN_CONTENT_RATIO",
+        "min-content-ratio",
+        "Smallest content share of a non-empty image",
+    ),
+    setting(
+        "EMPTY_IMAGE_ACTION",
+        "empty-image-action",
+        "skip or quarantine",
+    ),
+    setting(
+        "MIN_INPUT_DIMENSION",
+        "min-input-dimension",
+        "Smallest long edge in pixels of a processed source",
+    ),
+    setting(
+        "SMALL_IMAGE_ACTION",
+        "small-image-action",
+        "skip or quarantine",
+    ),
+    setting(
+        "QUARANTINE_FOLDER",
+        "quarantine-folder",
+        "Where undeliverable files are moved",
+    ),
+    setting(
+        "TEMP_DIR",
+        "temp-dir",
+        "Where intermediate files are written",
+    ),
+    toggle(
+        "SPEC_FILES",
+        "spec-files",
+        "Read per-image overrides from <stem>.spec",
+    ),
+    toggle(
+        "TWO_PASS_DOWNSCALE",
+        "two-pass-downscale",
+        "Halve large images with a box filter before the final resize",
+    ),
+    setting(
+        "FAST_PATH_PIXELS",
+        "fast-path-pixels",
+        "Sources with more pixels are resized with the faster FAST_PATH_FILTER",
+    ),
+    setting(
+        "FAST_PATH_FILTER",
+        "fast-path-filter",
+        "Resize filter of the fast path: nearest, triangle, catmull-rom, gaussian or lanczos3",
+    ),
+    toggle(
+        "FEATHER_EDGES",
+        "feather-edges",
+        "Blend the content onto the canvas with a 1px feathered edge",
+    ),
+    toggle(
+        "CHECK_TRUNCATION",
+        "check-truncation",
+        "Retry JPEG/PNG sources whose end marker is missing",
+    ),
+    toggle(
+        "VERIFY_OUTPUT",
+        "verify-output",
+        "Re-decode outputs before the source is removed",
+    ),
+    toggle(
+        "IN_PLACE_SAFE",
+        "in-place-safe",
+        "Overwrite same-format sources on local disks directly",
+    ),
+    setting(
+        "DELETE_DELAY_SEC",
+        "delete-delay-sec",
+        "Grace period before a processed source is removed",
+    ),
+    setting(
+        "READ_ONLY_SOURCES",
+        "read-only-sources",
+        "Read-only processed sources: fail, clear or keep",
+    ),
+    toggle(
+        "EMBED_PARAMS",
+        "embed-params",
+        "Record the processing options in PNG and JPEG outputs",
+    ),
+    toggle(
+        "PRESERVE_MTIME",
+        "preserve-mtime",
+        "Copy the source modification time onto outputs",
+    ),
+    toggle(
+        "PRESERVE_PERMISSIONS",
+        "preserve-permissions",
+        "Give outputs the source's mode and, where permitted, owner (Unix)",
+    ),
+    setting(
+        "RENAME_COOLDOWN_MS",
+        "rename-cooldown-ms",
+        "Window in which events after a rename into place are coalesced",
+    ),
+    setting(
+        "RECENTLY_PROCESSED_MS",
+        "recently-processed-ms",
+        "How long repeated events for a handled file are ignored",
+    ),
+    setting(
+        "RECENTLY_PROCESSED_MAX",
+        "recently-processed-max",
+        "Most files remembered for RECENTLY_PROCESSED_MS",
+    ),
+    setting(
+        "RENAME_RETRIES",
+        "rename-retries",
+        "Retries of the final rename",
+    ),
+    setting(
+        "RENAME_RETRY_DELAY_MS",
+        "rename-retry-delay-ms",
+        "Delay before the first rename retry",
+    ),
+    setting(
+        "PROCESS_RETRIES",
+        "process-retries",
+        "Reruns of a file whose output could not be written for a transient reason",
+    ),
+    setting(
+        "PROCESS_RETRY_DELAY_MS",
+        "process-retry-delay-ms",
+        "Delay before the first rerun",
+    ),
+    setting("OUTPUT_TARGET", "output-target", "local, s3, both or pipe"),
+    setting(
+        "OUTPUT_PIPE",
+        "output-pipe",
+        "FIFO or named pipe written by OUTPUT_TARGET=pipe",
+    ),
+    setting(
+        "OUTPUT_PIPE_WAIT_MS",
+        "output-pipe-wait-ms",
+        "How long an output waits for a pipe reader",
+    ),
+    setting("S3_BUCKET", "s3-bucket", "Destination bucket for S3 output"),
+    setting("S3_PREFIX", "s3-prefix", "Key prefix for S3 uploads"),
+    setting(
+        "S3_ENDPOINT",
+        "s3-endpoint",
+        "Endpoint URL of an S3-compatible service",
+    ),
+    setting("PDF_DPI", "pdf-dpi", "Resolution used to rasterize PDFs"),
+    setting(
+        "DECODE_WORKERS",
+        "decode-workers",
+        "Threads decoding source images",
+    ),
+    setting(
+        "DECODE_THREADS",
+        "decode-threads",
+        "Threads each decode worker splits a JPEG across",
+    ),
+    setting(
+        "PROCESS_WORKERS",
+        "process-workers",
+        "Threads cropping, scaling and padding",
+    ),
+    setting(
+        "ENCODE_WORKERS",
+        "encode-workers",
+        "Threads encoding and saving outputs",
+    ),
+    setting(
+        "RATE_LIMIT_PER_MIN",
+        "rate-limit-per-min",
+        "Maximum files processed per minute",
+    ),
+    setting(
+        "LOAD_THRESHOLD",
+        "load-threshold",
+        "CPU load in percent above which new files wait",
+    ),
+    setting(
+        "LOAD_SAMPLE_MS",
+        "load-sample-ms",
+        "How often the CPU load is measured for LOAD_THRESHOLD",
+    ),
+    setting(
+        "MAX_OPEN_FILES",
+        "max-open-files",
+        "Maximum files read or written at once",
+    ),
+    toggle(
+        "SINGLE_THREAD",
+        "single-thread",
+        "Process files in order on the main thread, for debugging",
+    ),
+    setting(
+        "BATCH_WINDOW_MS",
+        "batch-window-ms",
+        "Collect files for this long after the first event, then process them as one batch",
+    ),
+    setting(
+        "THREAD_PRIORITY",
+        "thread-priority",
+        "low or normal priority for threads that process images",
+    ),
+    toggle(
+        "HANDLE_ZIP",
+        "handle-zip",
+        "Unpack .zip archives and normalize the images inside",
+    ),
+    toggle(
+        "ZIP_REPACK",
+        "zip-repack",
+        "Pack the outputs of an archive into <name>-normalized.zip",
+    ),
+    setting(
+        "ZIP_MAX_BYTES",
+        "zip-max-bytes",
+        "Largest archive, and largest total of its images, to unpack",
+    ),
+    toggle(
+        "MONTAGE",
+        "montage",
+        "Combine files arriving together into contact sheets",
+    ),
+    setting(
+        "MONTAGE_WINDOW_MS",
+        "montage-window-ms",
+        "How long a contact sheet collects files",
+    ),
+    setting(
+        "MONTAGE_COLUMNS",
+        "montage-columns",
+        "Cells per row of a contact sheet",
+    ),
+    toggle(
+        "THUMBNAIL_INDEX",
+        "thumbnail-index",
+        "Keep sources and maintain thumbnails/ with an index.json instead",
+    ),
+    toggle(
+        "MIRROR_DELETES",
+        "mirror-deletes",
+        "Remove the outputs of a source deleted during DELETE_DELAY_SEC",
+    ),
+    toggle("LOG_COMPRESS", "log-compress", "Gzip rotated log files"),
+    toggle(
+        "DAILY_SUMMARY",
+        "daily-summary",
+        "Keep per-day counts in logs/summary-YYYY-MM-DD.json",
+    ),
+    toggle(
+        "STDOUT_EVENTS",
+        "stdout-events",
+        "Print one JSON line per finished file to stdout",
+    ),
+    setting(
+        "POST_PROCESS_CMD",
+        "post-process-cmd",
+        "Command run after each saved output; {input} and {output} are substituted",
+    ),
+    toggle("EVENTLOG", "eventlog", "Also log to the Windows Event Log"),
+    setting(
+        "EVENTLOG_LEVEL",
+        "eventlog-level",
+        "Minimum level sent to the Event Log",
+    ),
+    toggle(
+        "DEGRADE_ON_LOG_FAILURE",
+        "degrade-on-log-failure",
+        "Report an exit code while logging fails",
+    ),
+    setting(
+        "STATUS_PORT",
+        "status-port",
+        "Loopback port answering --status queries; 0 disables",
+    ),
+    setting(
+        "IDLE_SHUTDOWN_SEC",
+        "idle-shutdown-sec",
+        "Exit after this many seconds without files; 0 disables",
+    ),
+];
+
+/// Values given on the command line; they take precedence over the environment.
+static OVERRIDES: OnceCell<HashMap<String, String>> = OnceCell::new();
+
+/// Installs command-line values. Only the first call has an effect.
+pub fn set_overrides(values: HashMap<String, String>) {
+    let _ = OVERRIDES.set(values);
+}
+
+thread_local! {
+    /// Profile whose `PROFILE_<NAME>_<KEY>` values shadow the global settings on this thread.
+    static ACTIVE_PROFILE: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Job whose `JOB_<NAME>_<KEY>` values shadow the global settings on this thread.
+    static ACTIVE_JOB: RefCell<Option<String>> = const { RefCell::new(None) };
+    /// Per-file values, e.g. from a manifest row, that shadow everything else on this thread.
+    static SCOPED_VALUES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Returns the value of a setting, treating empty values as unset. Values installed by
+/// `with_values` win; while a profile is active, `PROFILE_<NAME>_<KEY>` takes precedence
+/// over `KEY`, and so does `JOB_<NAME>_<KEY>` on the threads of a job; values from the
+/// selected `PRESET` apply last.
+pub fn var(key: &str) -> Option<String> {
+    if let Some(value) = SCOPED_VALUES.with(|scoped| scoped.borrow().get(key).cloned()) {
+        return Some(value);
+    }
+    let scoped = |scope: &'static LocalKey<RefCell<Option<String>>>, prefix: &str| {
+        scope.with(|active| {
+            active
+                .borrow()
+                .as_ref()
+                .and_then(|name| global_var(&format!("{}_{}_{}", prefix, name, key)))
+        })
+    };
+    scoped(&ACTIVE_PROFILE, "PROFILE")
+        .or_else(|| scoped(&ACTIVE_JOB, "JOB"))
+        .or_else(|| global_var(key))
+        .or_else(|| {
+            (key != "PRESET")
+                .then(|| var("PRESET"))
+                .flatten()
+                .and_then(|preset| presets::value(&preset, key))
+        })
+}
+
+fn global_var(key: &str) -> Option<String> {
+    OVERRIDES
+        .get()
+        .and_then(|overrides| overrides.get(key).cloned())
+        .or_else(|| env::var(key).ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Parses a setting, returning `Ok(None)` when it is not set.
+pub fn parse<T: FromStr>(key: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    match var(key) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("Invalid value {:?} for {}: {}", value, key, e)),
+        None => Ok(None),
+    }
+}
+
+/// Reads a boolean toggle; `1`, `true`, `yes` and `on` enable it.
+pub fn flag(key: &str) -> bool {
+    var(key).is_some_and(|value| {
+        matches!(
+            value.to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    })
+}
+
+/// The file listing names and paths never to process (`IGNORE_LIST_FILE`), which must
+/// be readable at startup.
+pub fn ignore_list_file() -> Result<Option<PathBuf>, String> {
+    let Some(path) = var("IGNORE_LIST_FILE").map(PathBuf::from) else {
+        return Ok(None);
+    };
+    std::fs::read_to_string(&path)
+        .map_err(|e| format!("Cannot read IGNORE_LIST_FILE {:?}: {}", path, e))?;
+    Ok(Some(path))
+}
+
+/// Parses `IMAGE_EXTENSIONS`, a comma-separated list that replaces the default set.
+/// Entries are lowercased and may be written with or without a leading dot.
+pub fn image_extensions() -> Result<Vec<String>, String> {
+    let Some(value) = var("IMAGE_EXTENSIONS") else {
+        let mut extensions: Vec<String> = DEFAULT_IMAGE_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect();
+        if cfg!(feature = "pdf") {
+            extensions.push("pdf".to_string());
+        }
+        if cfg!(feature = "heif") {
+            extensions.extend(["heic".to_string(), "heif".to_string()]);
+        }
+        return Ok(extensions);
+    };
+
+    value
+        .split(',')
+        .map(|ext| {
+            let ext = ext.trim().trim_start_matches('.').to_ascii_lowercase();
+            if ext.is_empty() {
+                Err(format!(
+                    "IMAGE_EXTENSIONS contains an empty entry: {:?}",
+                    value
+                ))
+            } else {
+                Ok(ext)
+            }
+        })
+        .collect()
+}
+
+/// A set of setting overrides applied to files in matching subfolders.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    /// Uppercased name used in `PROFILE_<NAME>_<KEY>`.
+    pub name: String,
+    /// Glob (`*`, `?`) matched case-insensitively against the parent folder name.
+    pub pattern: String,
+}
+
+/// Parses `PROFILES`, a comma-separated list of `NAME=GLOB` entries. A bare `NAME`
+/// matches the subfolder of that name. The first matching profile wins.
+pub fn profiles() -> Result<Vec<Profile>, String> {
+    let Some(value) = global_var("PROFILES") else {
+        return Ok(Vec::new());
+    };
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, pattern) = entry.split_once('=').unwrap_or((entry, entry));
+            let (name, pattern) = (name.trim(), pattern.trim());
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!(
+                    "Invalid profile name {:?} in PROFILES; use letters, digits and _",
+                    name
+                ));
+            }
+            if pattern.is_empty() {
+                return Err(format!("Profile {} in PROFILES has an empty pattern", name));
+            }
+            Ok(Profile {
+                name: name.to_ascii_uppercase(),
+                pattern: pattern.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Selects the profile for a source file by the name of the folder it sits in.
+pub fn profile_for(path: &Path) -> Result<Option<String>, String> {
+    let Some(folder) = path.parent().and_then(Path::file_name) else {
+        return Ok(None);
+    };
+    // Invalid sequences become U+FFFD, which only wildcards match.
+    let folder = folder.to_string_lossy();
+    Ok(profiles()?
+        .into_iter()
+        .find(|profile| glob_match(&profile.pattern, &folder))
+        .map(|profile| profile.name))
+}
+
+/// Runs `f` with `profile`'s settings in effect on the current thread.
+pub fn with_profile<T>(profile: Option<&str>, f: impl FnOnce() -> T) -> T {
+    let previous = ACTIVE_PROFILE.with(|active| active.replace(profile.map(str::to_string)));
+    let _restore = Restore {
+        key: &ACTIVE_PROFILE,
+        previous: Some(previous),
+    };
+    f()
+}
+
+/// An independent watch folder with its own settings, run next to the other jobs in one
+/// process with its own watcher and pipeline.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchJob {
+    /// Uppercased name used in `JOB_<NAME>_<KEY>` and in the names of its threads.
+    pub name: String,
+    pub watch_folder: PathBuf,
+}
+
+/// Parses `JOBS`, a comma-separated list of job names. Each job needs its own
+/// `JOB_<NAME>_WATCH_FOLDER`; no folder may be watched by two jobs, also not as a
+/// subfolder of another job's.
+pub fn jobs() -> Result<Vec<WatchJob>, String> {
+    let Some(value) = global_var("JOBS") else {
+        return Ok(Vec::new());
+    };
+
+    let mut jobs: Vec<WatchJob> = Vec::new();
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "Invalid job name {:?} in JOBS; use letters, digits and _",
+                name
+            ));
+        }
+        let name = name.to_ascii_uppercase();
+        if jobs.iter().any(|job| job.name == name) {
+            return Err(format!("Job {} is listed twice in JOBS", name));
+        }
+        let watch_folder = global_var(&format!("JOB_{}_WATCH_FOLDER", name))
+            .map(PathBuf::from)
+            .ok_or_else(|| format!("Job {} needs JOB_{}_WATCH_FOLDER", name, name))?;
+        if let Some(other) = jobs.iter().find(|job| {
+            job.watch_folder.starts_with(&watch_folder)
+                || watch_folder.starts_with(&job.watch_folder)
+        }) {
+            return Err(format!(
+                "Jobs {} and {} watch the same folder tree ({:?} and {:?})",
+                other.name, name, other.watch_folder, watch_folder
+            ));
+        }
+        jobs.push(WatchJob { name, watch_folder });
+    }
+    Ok(jobs)
+}
+
+/// Runs `f` with `job`'s settings in effect on the current thread.
+pub fn with_job<T>(job: Option<&str>, f: impl FnOnce() -> T) -> T {
+    let previous = ACTIVE_JOB.with(|active| active.replace(job.map(str::to_string)));
+    let _restore = Restore {
+        key: &ACTIVE_JOB,
+        previous: Some(previous),
+    };
+    f()
+}
+
+/// The job whose settings are in effect on the current thread, so threads started on its
+/// behalf can enter it too.
+pub fn current_job() -> Option<String> {
+    ACTIVE_JOB.with(|active| active.borrow().clone())
+}
+
+/// Runs `f` with `values` (setting key to value) taking precedence over every other
+/// source on the current thread. Empty values are ignored.
+pub fn with_values<T>(values: &HashMap<String, String>, f: impl FnOnce() -> T) -> T {
+    let values = values
+        .iter()
+        .map(|(key, value)| (key.clone(), value.trim().to_string()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    let previous = SCOPED_VALUES.with(|scoped| scoped.replace(values));
+    let _restore = Restore {
+        key: &SCOPED_VALUES,
+        previous: Some(previous),
+    };
+    f()
+}
+
+/// Puts a thread-local scope back when dropped, also when `f` panics and the panic is
+/// caught further up, so the worker's next file does not inherit it.
+struct Restore<T: 'static> {
+    key: &'static LocalKey<RefCell<T>>,
+    previous: Option<T>,
+}
+
+impl<T> Drop for Restore<T> {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            self.key.with(|value| *value.borrow_mut() = previous);
+        }
+    }
+}
+
+/// Case-insensitive glob match supporting `*` (any run) and `?` (one character).
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the folder to monitor, defaulting to `ni_watch` next to the executable.
+pub fn watch_folder() -> PathBuf {
+    var("WATCH_FOLDER")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| crate::current_exe_dir().join("ni_watch"))
+}
+
+/// How changes in the watch folder are detected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchMode {
+    /// Native notifications (ReadDirectoryChangesW / inotify).
+    Native,
+    /// Periodic directory scans, for network shares where notifications are unreliable.
+    Poll(Duration),
+}
+
+/// Parses `WATCH_MODE` (`native` or `poll`; default `native`) and `POLL_INTERVAL_MS`
+/// (default 2000).
+pub fn watch_mode() -> Result<WatchMode, String> {
+    match var("WATCH_MODE").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("native") => Ok(WatchMode::Native),
+        Some("poll") => match parse::<u64>("POLL_INTERVAL_MS")?.unwrap_or(2000) {
+            0 => Err("POLL_INTERVAL_MS must be greater than 0".to_string()),
+            ms => Ok(WatchMode::Poll(Duration::from_millis(ms))),
+        },
+        Some(other) => Err(format!("Unknown WATCH_MODE {:?}", other)),
+    }
+}
+
+/// Credentials for connecting to the share that holds a UNC watch folder.
+#[derive(Clone)]
+pub struct ShareCredentials {
+    /// `DOMAIN\user` or `user@domain`.
+    pub user: String,
+    /// `None` uses the user's default password, e.g. one saved in Credential Manager.
+    pub password: Option<String>,
+}
+
+/// Parses `SHARE_USER` and `SHARE_PASSWORD`, used to connect to a UNC watch folder that
+/// the service account cannot open by itself.
+pub fn share_credentials() -> Result<Option<ShareCredentials>, String> {
+    let password = var("SHARE_PASSWORD");
+    match var("SHARE_USER") {
+        Some(user) => Ok(Some(ShareCredentials { user, password })),
+        None if password.is_some() => Err("SHARE_PASSWORD requires SHARE_USER".to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Which files the startup scan queues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanSince {
+    /// Files modified after this time.
+    Time(SystemTime),
+    /// Files modified after the previous instance on this folder shut down cleanly.
+    LastRun,
+}
+
+/// Parses `SCAN_SINCE`: `last-run`, or a UTC timestamp such as `2024-05-01`,
+/// `2024-05-01T08:30` or `2024-05-01T08:30:00Z`. Only meaningful with `STARTUP_SCAN`.
+pub fn scan_since() -> Result<Option<ScanSince>, String> {
+    let Some(value) = var("SCAN_SINCE") else {
+        return Ok(None);
+    };
+    if !flag("STARTUP_SCAN") {
+        return Err("SCAN_SINCE requires STARTUP_SCAN".to_string());
+    }
+    if value.eq_ignore_ascii_case("last-run") {
+        return Ok(Some(ScanSince::LastRun));
+    }
+    parse_timestamp(&value)
+        .map(|time| Some(ScanSince::Time(time)))
+        .ok_or_else(|| {
+            format!(
+                "Invalid SCAN_SINCE {:?}; expected last-run or a UTC time like 2024-05-01T08:30:00Z",
+                value
+            )
+        })
+}
+
+/// Parses `YYYY-MM-DD[(T| )HH:MM[:SS]][Z]` as UTC.
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let value = value.strip_suffix(['Z', 'z']).unwrap_or(value);
+    let (date, time) = match value.split_once(['T', 't', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+
+    let mut date = date.splitn(3, '-').map(str::parse::<u32>);
+    let (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) = (date.next(), date.next(), date.next())
+    else {
+        return None;
+    };
+    if !(1970..=9999).contains(&year) || !(1..=12).contains(&month) {
+        return None;
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+    if day == 0 || day > month_days[month as usize - 1] {
+        return None;
+    }
+
+    let seconds_of_day = match time {
+        None => 0,
+        Some(time) => {
+            let parts: Vec<&str> = time.split(':').collect();
+            if !(2..=3).contains(&parts.len()) {
+                return None;
+            }
+            let mut hms = [0u64; 3];
+            for (slot, part) in hms.iter_mut().zip(&parts) {
+                *slot = part.parse().ok()?;
+            }
+            if hms[0] > 23 || hms[1] > 59 || hms[2] > 59 {
+                return None;
+            }
+            hms[0] * 3600 + hms[1] * 60 + hms[2]
+        }
+    };
+
+    let days_before_year: u64 = (1970..year)
+        .map(|y| {
+            if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) {
+                366
+            } else {
+                365
+            }
+        })
+        .sum();
+    let days_before_month: u64 = month_days[..month as usize - 1]
+        .iter()
+        .map(|&d| d as u64)
+        .sum();
+    let days = days_before_year + days_before_month + (day as u64 - 1);
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + seconds_of_day))
+}
+
+/// Validates `PRESET` against the built-in presets.
+pub fn preset() -> Result<Option<&'static presets::Preset>, String> {
+    match var("PRESET") {
+        None => Ok(None),
+        Some(name) => presets::find(&name).map(Some).ok_or_else(|| {
+            let known: Vec<&str> = presets::PRESETS.iter().map(|p| p.name).collect();
+            format!(
+                "Unknown PRESET {:?}; expected one of {}",
+                name,
+                known.join(", ")
+            )
+        }),
+    }
+}
+
+/// JPEG encoder quality (`JPEG_QUALITY`, 1-100, default 75).
+pub fn jpeg_quality() -> Result<u8, String> {
+    match parse::<u8>("JPEG_QUALITY")? {
+        None => Ok(75),
+        Some(quality @ 1..=100) => Ok(quality),
+        Some(quality) => Err(format!(
+            "JPEG_QUALITY must be between 1 and 100, got {}",
+            quality
+        )),
+    }
+}
+
+/// Chroma subsampling of JPEG outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JpegSubsampling {
+    /// Full color resolution, for text and graphics.
+    Full,
+    /// Color at half the horizontal resolution.
+    Half,
+    /// Color at half the resolution in both directions, the usual choice for photos.
+    Quarter,
+}
+
+impl JpegSubsampling {
+    pub fn name(self) -> &'static str {
+        match self {
+            JpegSubsampling::Full => "4:4:4",
+            JpegSubsampling::Half => "4:2:2",
+            JpegSubsampling::Quarter => "4:2:0",
+        }
+    }
+}
+
+/// Parses `JPEG_SUBSAMPLING` (`444`, `422` or `420`, colons optional; default `444`).
+pub fn jpeg_subsampling() -> Result<JpegSubsampling, String> {
+    match var("JPEG_SUBSAMPLING")
+        .map(|v| v.replace(':', ""))
+        .as_deref()
+    {
+        None | Some("444") => Ok(JpegSubsampling::Full),
+        Some("422") => Ok(JpegSubsampling::Half),
+        Some("420") => Ok(JpegSubsampling::Quarter),
+        Some(_) => Err(format!(
+            "Unknown JPEG_SUBSAMPLING {:?}; expected 444, 422 or 420",
+            var("JPEG_SUBSAMPLING").unwrap_or_default()
+        )),
+    }
+}
+
+/// Resolution written into the metadata of JPEG, PNG and TIFF outputs (`OUTPUT_DPI`,
+/// 1-65535). Unset leaves each encoder's default.
+pub fn output_dpi() -> Result<Option<u16>, String> {
+    match parse::<u16>("OUTPUT_DPI")? {
+        Some(0) => Err("OUTPUT_DPI must be at least 1".to_string()),
+        dpi => Ok(dpi),
+    }
+}
+
+/// How GIF outputs are quantized to their 256-color palette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GifOptions {
+    /// NeuQuant sampling speed, 1 (best palette, slowest) to 30 (fastest).
+    pub speed: i32,
+    /// Keep a transparent palette index for transparent pixels instead of compositing
+    /// them onto white.
+    pub transparency: bool,
+}
+
+/// Parses `GIF_SPEED` (1-30, default 10) and `GIF_TRANSPARENCY` (default on).
+pub fn gif_options() -> Result<GifOptions, String> {
+    let speed = match parse::<i32>("GIF_SPEED")? {
+        None => 10,
+        Some(speed @ 1..=30) => speed,
+        Some(speed) => {
+            return Err(format!("GIF_SPEED must be between 1 and 30, got {}", speed));
+        }
+    };
+    let transparency = match var("GIF_TRANSPARENCY") {
+        None => true,
+        Some(_) => flag("GIF_TRANSPARENCY"),
+    };
+    Ok(GifOptions {
+        speed,
+        transparency,
+    })
+}
+
+/// Returns the lowercased `OUTPUT_FORMAT` extension (default `jpg`).
+pub fn output_format() -> String {
+    var("OUTPUT_FORMAT")
+        .unwrap_or_else(|| "jpg".to_string())
+        .to_lowercase()
+}
+
+/// `REPLACE_IN_PLACE`: whether every source is overwritten by its output, written in the
+/// source's own format under its own name instead of in `OUTPUT_FORMAT`. Settings that
+/// would add a second file or change the name cannot be combined with it.
+pub fn replace_in_place() -> Result<bool, String> {
+    if !flag("REPLACE_IN_PLACE") {
+        return Ok(false);
+    }
+    for other in ["SIZES", "MASTER_FORMAT", "FALLBACK_FORMAT"] {
+        if var(other).is_some() {
+            return Err(format!(
+                "REPLACE_IN_PLACE cannot be combined with {}",
+                other
+            ));
+        }
+    }
+    for other in [
+        "PREVIEW",
+        "MONTAGE",
+        "THUMBNAIL_INDEX",
+        "SNIFF_EXTENSIONLESS",
+    ] {
+        if flag(other) {
+            return Err(format!(
+                "REPLACE_IN_PLACE cannot be combined with {}",
+                other
+            ));
+        }
+    }
+    if output_naming()? != OutputNaming::Stem {
+        return Err("REPLACE_IN_PLACE needs OUTPUT_NAMING=stem".to_string());
+    }
+    let target = output_target()?;
+    if target.s3 || target.pipe {
+        return Err("REPLACE_IN_PLACE needs OUTPUT_TARGET=local".to_string());
+    }
+    Ok(true)
+}
+
+/// Lossless formats `MASTER_FORMAT` may name.
+pub const MASTER_FORMATS: [&str; 3] = ["png", "tiff", "webp"];
+
+/// A lossless master written from the same processed image as each output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Master {
+    pub ext: String,
+    /// Added to the output's stem, e.g. `.master` for `photo.master.png`.
+    pub suffix: String,
+}
+
+/// Parses `MASTER_FORMAT` (`png`, `tiff` or `webp`, and not `OUTPUT_FORMAT` itself) and
+/// `MASTER_SUFFIX` (default `.master`). Needs `OUTPUT_NAMING=stem` or `sequence`. `None` when no master
+/// is written.
+pub fn master() -> Result<Option<Master>, String> {
+    let Some(ext) = var("MASTER_FORMAT").map(|v| v.to_lowercase()) else {
+        return Ok(None);
+    };
+    if !MASTER_FORMATS.contains(&ext.as_str()) {
+        return Err(format!(
+            "MASTER_FORMAT must be a lossless format ({}), got {:?}",
+            MASTER_FORMATS.join(", "),
+            ext
+        ));
+    }
+    if ext == output_format() {
+        return Err(format!(
+            "MASTER_FORMAT {} is the OUTPUT_FORMAT already; pick the lossy format for OUTPUT_FORMAT",
+            ext
+        ));
+    }
+    // Masters are told apart from sources by their suffix, which hash names do not carry.
+    if output_naming()? == OutputNaming::Hash {
+        return Err("MASTER_FORMAT needs OUTPUT_NAMING=stem or sequence".to_string());
+    }
+    let suffix = var("MASTER_SUFFIX").unwrap_or_else(|| ".master".to_string());
+    if suffix.contains(['/', '\\']) {
+        return Err(format!(
+            "MASTER_SUFFIX {:?} must not contain a path separator",
+            suffix
+        ));
+    }
+    Ok(Some(Master { ext, suffix }))
+}
+
+/// Longer side in pixels of the quick preview written for each source with `PREVIEW`
+/// (`PREVIEW_SIZE`, default 64). `None` when no preview is written.
+pub fn preview_size() -> Result<Option<u32>, String> {
+    if !flag("PREVIEW") {
+        return Ok(None);
+    }
+    // Contact sheets and thumbnails are not written next to the source as usual.
+    for other in ["MONTAGE", "THUMBNAIL_INDEX"] {
+        if flag(other) {
+            return Err(format!("PREVIEW cannot be combined with {}", other));
+        }
+    }
+    match parse("PREVIEW_SIZE")? {
+        Some(0) => Err("PREVIEW_SIZE must be at least 1".to_string()),
+        size => Ok(Some(size.unwrap_or(64))),
+    }
+}
+
+/// Parses `FALLBACK_FORMAT`, the output format tried when encoding to `OUTPUT_FORMAT`
+/// fails. Unset keeps the failure.
+pub fn fallback_format() -> Result<Option<String>, String> {
+    let ext = var("FALLBACK_FORMAT").map(|v| v.to_lowercase());
+    if let Some(ext) = &ext {
+        check_output_format("FALLBACK_FORMAT", ext)?;
+    }
+    Ok(ext)
+}
+
+/// Checks that `ext`, the value of `key`, names a format this build can write, so a typo
+/// fails at startup instead of at the first file.
+fn check_output_format(key: &str, ext: &str) -> Result<(), String> {
+    match ext {
+        "jpeg" => Ok(()),
+        ext if OUTPUT_FORMATS.contains(&ext) => Ok(()),
+        "avif" => Err(format!(
+            "{}=avif is not supported: this build has no AVIF encoder; use webp, jpg or png",
+            key
+        )),
+        other => Err(format!(
+            "Unknown {} {:?}; use one of {}",
+            key,
+            other,
+            OUTPUT_FORMATS.join(", ")
+        )),
+    }
+}
+
+/// Renders a resolved setting for `--print-config`, showing why it is invalid if it is.
+pub fn describe<T: Into<Value>>(value: Result<T, String>) -> Value {
+    match value {
+        Ok(value) => value.into(),
+        Err(e) => json!({ "invalid": e }),
+    }
+}
+
+/// Reports whether a secret is set without revealing it.
+pub fn redacted(key: &str) -> Value {
+    var(key).map_or(Value::Null, |_| Value::from("<redacted>"))
+}
+
+/// Maps format aliases onto the key used for per-format settings (`jpeg` -> `JPG`).
+pub fn format_key(ext: &str) -> String {
+    match ext.to_ascii_lowercase().as_str() {
+        "jpeg" => "JPG".to_string(),
+        "tif" => "TIFF".to_string(),
+        other => other.to_ascii_uppercase(),
+    }
+}
+
+/// Resolves the target canvas size for an output format.
+///
+/// `TARGET_WIDTH_<FORMAT>`/`TARGET_HEIGHT_<FORMAT>` take precedence, then the global
+/// `TARGET_WIDTH`/`TARGET_HEIGHT`, then [`DEFAULT_TARGET_SIZE`].
+pub fn target_size(ext: &str) -> Result<(u32, u32), String> {
+    let key = format_key(ext);
+    let width = match parse::<u32>(&format!("TARGET_WIDTH_{key}"))? {
+        Some(width) => width,
+        None => parse("TARGET_WIDTH")?.unwrap_or(DEFAULT_TARGET_SIZE.0),
+    };
+    let height = match parse::<u32>(&format!("TARGET_HEIGHT_{key}"))? {
+        Some(height) => height,
+        None => parse("TARGET_HEIGHT")?.unwrap_or(DEFAULT_TARGET_SIZE.1),
+    };
+    Ok((width, height))
+}
+
+/// Margin kept between the content and the canvas edge (`PADDING`, default `default`).
+pub fn padding(default: u32) -> Result<u32, String> {
+    Ok(parse("PADDING")?.unwrap_or(default))
+}
+
+/// What to do when the padding leaves no room for the content (`PADDING_OVERFLOW`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaddingOverflow {
+    /// Reject the setting at startup, or the file when a spec file or profile is at fault.
+    Error,
+    /// Reduce the padding to [`max_padding`] and log it.
+    Clamp,
+}
+
+/// Parses `PADDING_OVERFLOW` (`error` or `clamp`; default `error`).
+pub fn padding_overflow() -> Result<PaddingOverflow, String> {
+    match var("PADDING_OVERFLOW")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("error") => Ok(PaddingOverflow::Error),
+        Some("clamp") => Ok(PaddingOverflow::Clamp),
+        Some(other) => Err(format!("Unknown PADDING_OVERFLOW {:?}", other)),
+    }
+}
+
+/// The largest padding a `size` canvas takes while keeping at least a quarter of each
+/// side, and never less than a pixel, for the content. 0 for an empty canvas.
+pub fn max_padding(size: (u32, u32)) -> u32 {
+    let limit = |side: u32| side.saturating_sub(side.div_ceil(4).max(1)) / 2;
+    limit(size.0).min(limit(size.1))
+}
+
+/// One rendition requested by `SIZES`: its canvas size and padding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputSize {
+    pub size: (u32, u32),
+    pub pad: u32,
+}
+
+/// Parses `SIZES`, a comma-separated list of widths (`400,800,1600`) or `WxH` sizes. A
+/// bare width keeps the aspect ratio of `base`, and the padding is scaled with the
+/// width so every rendition has the same framing. Widths name the outputs, so they must
+/// be unique. Empty when unset.
+pub fn sizes(base: (u32, u32), pad: u32) -> Result<Vec<OutputSize>, String> {
+    let Some(value) = var("SIZES") else {
+        return Ok(Vec::new());
+    };
+    let scale = |side: u32, to: u32, from: u32| -> u32 {
+        let from = u64::from(from.max(1));
+        ((u64::from(side) * u64::from(to) + from / 2) / from) as u32
+    };
+
+    let mut sizes: Vec<OutputSize> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid =
+            |e: std::num::ParseIntError| format!("Invalid size {:?} in SIZES: {}", entry, e);
+        let (width, height) = match entry.split_once(['x', 'X']) {
+            Some((width, height)) => (
+                width.trim().parse().map_err(invalid)?,
+                height.trim().parse().map_err(invalid)?,
+            ),
+            None => {
+                let width: u32 = entry.parse().map_err(invalid)?;
+                (width, scale(base.1, width, base.0))
+            }
+        };
+        let pad = scale(pad, width, base.0);
+        let overflows = width.min(height) <= pad.saturating_mul(2);
+        if width == 0 || height == 0 || (overflows && padding_overflow()? == PaddingOverflow::Error)
+        {
+            return Err(format!(
+                "Size {}x{} in SIZES must exceed twice its padding ({})",
+                width, height, pad
+            ));
+        }
+        if let Some(max) = max_output_pixels()?
+            && u64::from(width) * u64::from(height) > max
+        {
+            return Err(format!(
+                "Size {}x{} in SIZES exceeds MAX_OUTPUT_PIXELS ({})",
+                width, height, max
+            ));
+        }
+        if sizes.iter().any(|other| other.size.0 == width) {
+            return Err(format!("Width {} appears twice in SIZES", width));
+        }
+        sizes.push(OutputSize {
+            size: (width, height),
+            pad,
+        });
+    }
+    Ok(sizes)
+}
+
+/// Resampling filter of the final resize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    pub fn name(self) -> &'static str {
+        match self {
+            ResizeFilter::Nearest => "nearest",
+            ResizeFilter::Triangle => "triangle",
+            ResizeFilter::CatmullRom => "catmull-rom",
+            ResizeFilter::Gaussian => "gaussian",
+            ResizeFilter::Lanczos3 => "lanczos3",
+        }
+    }
+}
+
+/// Resize settings for sources above a pixel count, which would otherwise hold up the
+/// pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FastPath {
+    /// Sources with more pixels than this take the fast path.
+    pub pixels: u64,
+    pub filter: ResizeFilter,
+}
+
+/// Parses `FAST_PATH_PIXELS` and `FAST_PATH_FILTER` (default `triangle`). Sources above
+/// the threshold are resized with the fast filter; `None` keeps the quality path for
+/// every file.
+pub fn fast_path() -> Result<Option<FastPath>, String> {
+    let filter = match var("FAST_PATH_FILTER")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("triangle") => ResizeFilter::Triangle,
+        Some("nearest") => ResizeFilter::Nearest,
+        Some("catmull-rom") => ResizeFilter::CatmullRom,
+        Some("gaussian") => ResizeFilter::Gaussian,
+        Some("lanczos3") => ResizeFilter::Lanczos3,
+        Some(other) => {
+            return Err(format!(
+                "Unknown FAST_PATH_FILTER {:?}; expected nearest, triangle, catmull-rom, gaussian or lanczos3",
+                other
+            ));
+        }
+    };
+    match parse::<u64>("FAST_PATH_PIXELS")? {
+        Some(0) => Err("FAST_PATH_PIXELS must be at least 1".to_string()),
+        Some(pixels) => Ok(Some(FastPath { pixels, filter })),
+        None if var("FAST_PATH_FILTER").is_some() => {
+            Err("FAST_PATH_FILTER requires FAST_PATH_PIXELS".to_string())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Parses `MAX_OUTPUT_PIXELS`, the largest area in pixels an output canvas may have.
+pub fn max_output_pixels() -> Result<Option<u64>, String> {
+    match parse::<u64>("MAX_OUTPUT_PIXELS")? {
+        Some(0) => Err("MAX_OUTPUT_PIXELS must be at least 1".to_string()),
+        pixels => Ok(pixels),
+    }
+}
+
+/// Edges that `bounding_box` may move inward when trimming whitespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CropSides {
+    pub left: bool,
+    pub top: bool,
+    pub right: bool,
+    pub bottom: bool,
+}
+
+impl CropSides {
+    pub const ALL: CropSides = CropSides {
+        left: true,
+        top: true,
+        right: true,
+        bottom: true,
+    };
+
+    pub const NONE: CropSides = CropSides {
+        left: false,
+        top: false,
+        right: false,
+        bottom: false,
+    };
+
+    /// Resets the edges that may not be cropped back to the original image bounds.
+    pub fn apply(
+        self,
+        bounds: (u32, u32, u32, u32),
+        dimensions: (u32, u32),
+    ) -> (u32, u32, u32, u32) {
+        let (l, t, r, b) = bounds;
+        (
+            if self.left { l } else { 0 },
+            if self.top { t } else { 0 },
+            if self.right { r } else { dimensions.0 },
+            if self.bottom { b } else { dimensions.1 },
+        )
+    }
+}
+
+/// Parses `CROP_SIDES`, a comma-separated subset of `left,top,right,bottom` (default: all).
+pub fn crop_sides() -> Result<CropSides, String> {
+    let Some(value) = var("CROP_SIDES") else {
+        return Ok(CropSides::ALL);
+    };
+    if value.eq_ignore_ascii_case("all") {
+        return Ok(CropSides::ALL);
+    }
+
+    let mut sides = CropSides::NONE;
+    for side in value.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match side.to_ascii_lowercase().as_str() {
+            "left" => sides.left = true,
+            "top" => sides.top = true,
+            "right" => sides.right = true,
+            "bottom" => sides.bottom = true,
+            other => return Err(format!("Unknown side {:?} in CROP_SIDES", other)),
+        }
+    }
+    Ok(sides)
+}
+
+/// Parses `CROP_MARGIN_PX`, how far the detected content box is widened on each cropped
+/// side before cropping (default 0).
+pub fn crop_margin() -> Result<u32, String> {
+    Ok(parse("CROP_MARGIN_PX")?.unwrap_or(0))
+}
+
+/// When screenshots keep their full frame instead of being cropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScreenshotMode {
+    Off,
+    /// Only images that look like screenshots, by file name or flat UI areas.
+    Auto,
+    /// Every image, for folders that only receive screenshots.
+    Always,
+}
+
+/// Parses `SCREENSHOT_MODE` (`off`, `auto` or `always`; default `off`).
+pub fn screenshot_mode() -> Result<ScreenshotMode, String> {
+    match var("SCREENSHOT_MODE")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("off") => Ok(ScreenshotMode::Off),
+        Some("auto") => Ok(ScreenshotMode::Auto),
+        Some("always") => Ok(ScreenshotMode::Always),
+        Some(other) => Err(format!("Unknown SCREENSHOT_MODE {:?}", other)),
+    }
+}
+
+/// Background detection that compares each RGB channel separately, for tinted (warm or
+/// cool white) backgrounds that a single luma threshold misdetects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelTolerance {
+    pub background: [u8; 3],
+    pub tolerance: [u8; 3],
+}
+
+impl ChannelTolerance {
+    /// A pixel is content when any channel deviates from the background by more than
+    /// that channel's tolerance.
+    pub fn is_content(&self, rgb: [u8; 3]) -> bool {
+        (0..3).any(|i| rgb[i].abs_diff(self.background[i]) > self.tolerance[i])
+    }
+}
+
+/// Parses `CHANNEL_TOLERANCE` (`R,G,B`, or one value for all channels) and
+/// `CHANNEL_BACKGROUND` (hex `RRGGBB`, default `FFFFFF`). Returns `None` when
+/// `CHANNEL_TOLERANCE` is not set, which keeps the luma detector.
+pub fn channel_tolerance() -> Result<Option<ChannelTolerance>, String> {
+    let Some(value) = var("CHANNEL_TOLERANCE") else {
+        return Ok(None);
+    };
+    let values = value
+        .split(',')
+        .map(|v| {
+            v.trim()
+                .parse::<u8>()
+                .map_err(|e| format!("Invalid CHANNEL_TOLERANCE {:?}: {}", value, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let tolerance = match values[..] {
+        [all] => [all; 3],
+        [r, g, b] => [r, g, b],
+        _ => {
+            return Err(format!(
+                "CHANNEL_TOLERANCE must be one value or R,G,B, got {:?}",
+                value
+            ));
+        }
+    };
+
+    let background = match var("CHANNEL_BACKGROUND") {
+        None => [255; 3],
+        Some(hex) => hex_color(&hex)
+            .ok_or_else(|| format!("Invalid CHANNEL_BACKGROUND {:?}; use RRGGBB", hex))?,
+    };
+    Ok(Some(ChannelTolerance {
+        background,
+        tolerance,
+    }))
+}
+
+/// Reads `ADAPTIVE_TOLERANCE`, which replaces the fixed luma tolerance and is therefore
+/// not combined with the per-channel detector.
+pub fn adaptive_tolerance() -> Result<bool, String> {
+    let adaptive = flag("ADAPTIVE_TOLERANCE");
+    if adaptive && var("CHANNEL_TOLERANCE").is_some() {
+        return Err("ADAPTIVE_TOLERANCE cannot be combined with CHANNEL_TOLERANCE".to_string());
+    }
+    Ok(adaptive)
+}
+
+/// Parses `NOISE_TOLERANCE`, the smallest group of touching content pixels that counts
+/// toward the crop box (1-10000). Unset, `0` or `1` keep every content pixel.
+pub fn noise_tolerance() -> Result<Option<u32>, String> {
+    match parse::<u32>("NOISE_TOLERANCE")? {
+        Some(pixels) if pixels > 10_000 => Err(format!(
+            "NOISE_TOLERANCE must be at most 10000 pixels, got {}",
+            pixels
+        )),
+        Some(pixels) if pixels > 1 => Ok(Some(pixels)),
+        _ => Ok(None),
+    }
+}
+
+/// Largest `DESKEW` correction; scans are rarely off by more, and photos tilted further
+/// are usually meant that way.
+pub const MAX_DESKEW_DEGREES: f32 = 15.0;
+
+/// Parses `DESKEW`, the largest rotation in degrees (up to 15) that straightening skewed
+/// content may apply. Unset or `0` leaves images as they are.
+pub fn deskew() -> Result<Option<f32>, String> {
+    match parse::<f32>("DESKEW")? {
+        Some(degrees) if !(0.0..=MAX_DESKEW_DEGREES).contains(&degrees) => Err(format!(
+            "DESKEW must be between 0 and {} degrees, got {}",
+            MAX_DESKEW_DEGREES, degrees
+        )),
+        Some(degrees) if degrees > 0.0 => Ok(Some(degrees)),
+        _ => Ok(None),
+    }
+}
+
+/// Where the scaled content sits on the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+}
+
+impl Gravity {
+    /// Computes the top-left offset of `content` on `canvas`. Content pushed to an edge
+    /// keeps `pad` pixels from it, and offsets are clamped so it never leaves the canvas.
+    /// When centering leaves an odd number of free pixels, `remainder` picks the side
+    /// that gets the extra one.
+    pub fn offsets(
+        self,
+        canvas: (u32, u32),
+        content: (u32, u32),
+        pad: u32,
+        remainder: CenterRemainder,
+    ) -> (u32, u32) {
+        let free_x = canvas.0.saturating_sub(content.0);
+        let free_y = canvas.1.saturating_sub(content.1);
+        let (center_x, center_y) = (remainder.center(free_x), remainder.center(free_y));
+        match self {
+            Gravity::Center => (center_x, center_y),
+            Gravity::Top => (center_x, pad.min(free_y)),
+            Gravity::Bottom => (center_x, free_y.saturating_sub(pad)),
+            Gravity::Left => (pad.min(free_x), center_y),
+            Gravity::Right => (free_x.saturating_sub(pad), center_y),
+        }
+    }
+}
+
+/// Parses `GRAVITY` (`center`, `top`, `bottom`, `left` or `right`; default `center`).
+pub fn gravity() -> Result<Gravity, String> {
+    match var("GRAVITY").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("center") => Ok(Gravity::Center),
+        Some("top") => Ok(Gravity::Top),
+        Some("bottom") => Ok(Gravity::Bottom),
+        Some("left") => Ok(Gravity::Left),
+        Some("right") => Ok(Gravity::Right),
+        Some(other) => Err(format!("Unknown GRAVITY {:?}", other)),
+    }
+}
+
+/// What is centered on the axes `GRAVITY` leaves centered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    BoundingBox,
+    /// The content's center of mass, weighted by darkness.
+    Centroid,
+}
+
+/// Parses `ALIGN` (`bbox` or `centroid`; default `bbox`).
+pub fn align() -> Result<Align, String> {
+    match var("ALIGN").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("bbox") => Ok(Align::BoundingBox),
+        Some("centroid") => Ok(Align::Centroid),
+        Some(other) => Err(format!("Unknown ALIGN {:?}", other)),
+    }
+}
+
+/// What fills the canvas around the content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Background {
+    White,
+    /// Keeps the source's alpha channel; needs an output format with transparency.
+    Transparent,
+    /// A fixed color given as `RRGGBB`.
+    Color([u8; 3]),
+    /// The median color of the four corners of the cropped source, so the padding
+    /// continues its backdrop.
+    Auto,
+}
+
+impl Background {
+    /// The setting value this was parsed from.
+    pub fn name(self) -> String {
+        match self {
+            Background::White => "white".to_string(),
+            Background::Transparent => "transparent".to_string(),
+            Background::Color([r, g, b]) => format!("{:02X}{:02X}{:02X}", r, g, b),
+            Background::Auto => "auto".to_string(),
+        }
+    }
+}
+
+/// Parses `BACKGROUND` (`white`, `transparent`, `auto` or a hex `RRGGBB` color; default
+/// `white`).
+pub fn background() -> Result<Background, String> {
+    match var("BACKGROUND").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("white") => Ok(Background::White),
+        Some("auto") => Ok(Background::Auto),
+        Some("transparent") => {
+            let format = output_format();
+            if matches!(format.as_str(), "jpg" | "jpeg" | "bmp") {
+                return Err(format!(
+                    "BACKGROUND=transparent needs an output format with transparency, not {}",
+                    format
+                ));
+            }
+            Ok(Background::Transparent)
+        }
+        Some(other) => hex_color(other).map(Background::Color).ok_or_else(|| {
+            format!(
+                "Unknown BACKGROUND {:?}; use white, transparent, auto or RRGGBB",
+                other
+            )
+        }),
+    }
+}
+
+/// Parses a hex `RRGGBB` color, with or without a leading `#`.
+fn hex_color(value: &str) -> Option<[u8; 3]> {
+    let digits = value.trim_start_matches('#');
+    let channel = |i: usize| {
+        digits
+            .get(i..i + 2)
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+    };
+    match (digits.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Some([r, g, b]),
+        _ => None,
+    }
+}
+
+/// Bits per channel of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    /// Keeps 16-bit sources at full precision; needs PNG or TIFF output.
+    Sixteen,
+}
+
+impl BitDepth {
+    pub fn bits(self) -> u8 {
+        match self {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        }
+    }
+}
+
+/// Parses `BIT_DEPTH` (`8` or `16`; default `8`).
+pub fn bit_depth() -> Result<BitDepth, String> {
+    match var("BIT_DEPTH").as_deref().map(str::trim) {
+        None | Some("8") => Ok(BitDepth::Eight),
+        Some("16") => {
+            let format = output_format();
+            if !matches!(format.as_str(), "png" | "tiff") {
+                return Err(format!(
+                    "BIT_DEPTH=16 needs png or tiff output, not {}",
+                    format
+                ));
+            }
+            Ok(BitDepth::Sixteen)
+        }
+        Some(other) => Err(format!("Unknown BIT_DEPTH {:?}", other)),
+    }
+}
+
+/// Unsharp mask applied after downscaling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    /// Strength of the added edge contrast, 0.01 to 5.
+    pub amount: f32,
+    /// Gaussian sigma in pixels that decides which detail counts as an edge, 0.1 to 10.
+    pub radius: f32,
+}
+
+/// Parses `SHARPEN_AMOUNT` (off when unset or `0`) and `SHARPEN_RADIUS` (default 1).
+pub fn sharpen() -> Result<Option<Sharpen>, String> {
+    let amount = parse::<f32>("SHARPEN_AMOUNT")?.unwrap_or(0.0);
+    let radius = parse::<f32>("SHARPEN_RADIUS")?.unwrap_or(1.0);
+    if !(0.0..=5.0).contains(&amount) {
+        return Err(format!(
+            "SHARPEN_AMOUNT must be between 0 and 5, got {}",
+            amount
+        ));
+    }
+    if !(0.1..=10.0).contains(&radius) {
+        return Err(format!(
+            "SHARPEN_RADIUS must be between 0.1 and 10, got {}",
+            radius
+        ));
+    }
+    Ok((amount > 0.0).then_some(Sharpen { amount, radius }))
+}
+
+/// Shape the finished canvas is cut to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mask {
+    None,
+    /// The ellipse inscribed in the canvas; the corners get the background.
+    Circle,
+}
+
+/// Parses `MASK` (`none` or `circle`; default `none`).
+pub fn mask() -> Result<Mask, String> {
+    match var("MASK").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("none") => Ok(Mask::None),
+        Some("circle") => Ok(Mask::Circle),
+        Some(other) => Err(format!("Unknown MASK {:?}", other)),
+    }
+}
+
+/// Which side of centered content gets the extra background pixel when the free space
+/// is odd.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CenterRemainder {
+    /// Right or bottom.
+    End,
+    /// Left or top.
+    Start,
+}
+
+impl CenterRemainder {
+    /// Offset that centers content in `free` pixels of space.
+    fn center(self, free: u32) -> u32 {
+        match self {
+            CenterRemainder::End => free / 2,
+            CenterRemainder::Start => free.div_ceil(2),
+        }
+    }
+}
+
+/// Parses `CENTER_REMAINDER` (`end` or `start`; default `end`).
+pub fn center_remainder() -> Result<CenterRemainder, String> {
+    match var("CENTER_REMAINDER")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("end") => Ok(CenterRemainder::End),
+        Some("start") => Ok(CenterRemainder::Start),
+        Some(other) => Err(format!("Unknown CENTER_REMAINDER {:?}", other)),
+    }
+}
+
+/// How output files are named.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputNaming {
+    /// `<source stem>.<ext>`
+    Stem,
+    /// `<blake3 of the encoded bytes>.<ext>`
+    Hash,
+    /// `<zero-padded counter>.<ext>`, see [`sequence`].
+    Sequence,
+}
+
+/// Parses `OUTPUT_NAMING` (`stem`, `hash` or `sequence`; default `stem`).
+pub fn output_naming() -> Result<OutputNaming, String> {
+    match var("OUTPUT_NAMING")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("stem") => Ok(OutputNaming::Stem),
+        Some("hash") => Ok(OutputNaming::Hash),
+        Some("sequence") => Ok(OutputNaming::Sequence),
+        Some(other) => Err(format!("Unknown OUTPUT_NAMING {:?}", other)),
+    }
+}
+
+/// Counter of `OUTPUT_NAMING=sequence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sequence {
+    /// Digits the number is zero-padded to; larger numbers simply get more.
+    pub width: usize,
+    /// Number of the first output when no counter is recorded for the folder yet.
+    pub start: u64,
+}
+
+/// Parses `SEQUENCE_WIDTH` (1 to 20, default 4) and `SEQUENCE_START` (default 1).
+pub fn sequence() -> Result<Sequence, String> {
+    let width = parse::<usize>("SEQUENCE_WIDTH")?.unwrap_or(4);
+    if !(1..=20).contains(&width) {
+        return Err(format!(
+            "SEQUENCE_WIDTH must be between 1 and 20, got {}",
+            width
+        ));
+    }
+    Ok(Sequence {
+        width,
+        start: parse("SEQUENCE_START")?.unwrap_or(1),
+    })
+}
+
+/// Where the encode stage delivers encoded images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutputTarget {
+    pub local: bool,
+    pub s3: bool,
+    /// Streamed to `OUTPUT_PIPE`.
+    pub pipe: bool,
+}
+
+/// Parses `OUTPUT_TARGET` (`local`, `s3`, `both` or `pipe`; default `local`).
+pub fn output_target() -> Result<OutputTarget, String> {
+    let target = match var("OUTPUT_TARGET")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("local") => OutputTarget {
+            local: true,
+            s3: false,
+            pipe: false,
+        },
+        Some("s3") => OutputTarget {
+            local: false,
+            s3: true,
+            pipe: false,
+        },
+        Some("both") => OutputTarget {
+            local: true,
+            s3: true,
+            pipe: false,
+        },
+        Some("pipe") => OutputTarget {
+            local: false,
+            s3: false,
+            pipe: true,
+        },
+        Some(other) => return Err(format!("Unknown OUTPUT_TARGET {:?}", other)),
+    };
+
+    if target.s3 {
+        if !cfg!(feature = "s3") {
+            return Err("OUTPUT_TARGET requires S3 but the s3 feature is not compiled in".into());
+        }
+        if var("S3_BUCKET").is_none() {
+            return Err("OUTPUT_TARGET requires S3 but S3_BUCKET is not set".into());
+        }
+    }
+    if target.pipe {
+        output_pipe()?;
+        output_pipe_wait()?;
+    }
+    Ok(target)
+}
+
+/// The FIFO or named pipe that `OUTPUT_TARGET=pipe` writes to (`OUTPUT_PIPE`).
+pub fn output_pipe() -> Result<PathBuf, String> {
+    var("OUTPUT_PIPE")
+        .map(PathBuf::from)
+        .ok_or_else(|| "OUTPUT_TARGET=pipe requires OUTPUT_PIPE".to_string())
+}
+
+/// How long an output waits for a reader to connect to `OUTPUT_PIPE`
+/// (`OUTPUT_PIPE_WAIT_MS`, default 30000).
+pub fn output_pipe_wait() -> Result<Duration, String> {
+    Ok(Duration::from_millis(
+        parse("OUTPUT_PIPE_WAIT_MS")?.unwrap_or(30_000),
+    ))
+}
+
+/// Scheduling priority of the threads that process images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadPriority {
+    Normal,
+    /// Below normal on Windows, nice 10 on Unix.
+    Low,
+}
+
+/// Parses `THREAD_PRIORITY` (`low` or `normal`; default `normal`).
+pub fn thread_priority() -> Result<ThreadPriority, String> {
+    match var("THREAD_PRIORITY")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("normal") => Ok(ThreadPriority::Normal),
+        Some("low") => Ok(ThreadPriority::Low),
+        Some(other) => Err(format!(
+            "Unknown THREAD_PRIORITY {:?}; expected low or normal",
+            other
+        )),
+    }
+}
+
+/// Maximum files processed per minute (`RATE_LIMIT_PER_MIN`; unset means unlimited).
+pub fn rate_limit_per_min() -> Result<Option<u32>, String> {
+    match parse::<u32>("RATE_LIMIT_PER_MIN")? {
+        Some(0) => Err("RATE_LIMIT_PER_MIN must be at least 1".to_string()),
+        limit => Ok(limit),
+    }
+}
+
+/// CPU load in percent above which new files wait (`LOAD_THRESHOLD`, 1-100; unset means
+/// never).
+pub fn load_threshold() -> Result<Option<u8>, String> {
+    match parse::<u8>("LOAD_THRESHOLD")? {
+        Some(percent) if !(1..=100).contains(&percent) => Err(format!(
+            "LOAD_THRESHOLD must be between 1 and 100 percent, got {}",
+            percent
+        )),
+        percent => Ok(percent),
+    }
+}
+
+/// How often the CPU load is measured for `LOAD_THRESHOLD` (`LOAD_SAMPLE_MS`, at least
+/// 100, default 2000).
+pub fn load_sample() -> Result<Duration, String> {
+    match parse::<u64>("LOAD_SAMPLE_MS")? {
+        Some(ms) if ms < 100 => Err(format!("LOAD_SAMPLE_MS must be at least 100, got {}", ms)),
+        ms => Ok(Duration::from_millis(ms.unwrap_or(2000))),
+    }
+}
+
+/// Maximum files read or written at once across all workers (`MAX_OPEN_FILES`; unset
+/// means unlimited).
+pub fn max_open_files() -> Result<Option<usize>, String> {
+    match parse::<usize>("MAX_OPEN_FILES")? {
+        Some(0) => Err("MAX_OPEN_FILES must be at least 1".to_string()),
+        limit => Ok(limit),
+    }
+}
+
+/// Threads each decode worker uses for one JPEG (`DECODE_THREADS`; unset shares one
+/// thread per core between all decode workers).
+pub fn decode_threads() -> Result<Option<usize>, String> {
+    match parse::<usize>("DECODE_THREADS")? {
+        Some(0) => Err("DECODE_THREADS must be at least 1".to_string()),
+        threads => Ok(threads),
+    }
+}
+
+/// Upper bound in bytes for an archive and for the images unpacked from it
+/// (`ZIP_MAX_BYTES`, default 512 MiB).
+pub fn zip_max_bytes() -> Result<u64, String> {
+    match parse("ZIP_MAX_BYTES")? {
+        Some(0) => Err("ZIP_MAX_BYTES must be at least 1".to_string()),
+        max => Ok(max.unwrap_or(512 * 1024 * 1024)),
+    }
+}
+
+/// How long a processed source is kept before it is removed (`DELETE_DELAY_SEC`,
+/// default 0).
+pub fn delete_delay() -> Result<Duration, String> {
+    Ok(Duration::from_secs(parse("DELETE_DELAY_SEC")?.unwrap_or(0)))
+}
+
+/// `MIRROR_DELETES` only acts on sources whose removal `DELETE_DELAY_SEC` defers. Without
+/// a delay anywhere, globally or in a profile, it could never do anything and is rejected.
+pub fn mirror_deletes() -> Result<bool, String> {
+    if !flag("MIRROR_DELETES") {
+        return Ok(false);
+    }
+    let mut delays = vec![delete_delay()?];
+    for profile in profiles()? {
+        delays.push(with_profile(Some(&profile.name), delete_delay)?);
+    }
+    if delays.iter().all(Duration::is_zero) {
+        return Err("MIRROR_DELETES needs DELETE_DELAY_SEC: without a delay, sources are removed as soon as they are processed and there is nothing to mirror".to_string());
+    }
+    Ok(true)
+}
+
+/// What happens to a processed source that is read-only, which Windows refuses to remove
+/// or replace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadOnlySources {
+    /// Remove it anyway and report the failure.
+    Fail,
+    /// Clear the read-only attribute, then remove or replace it.
+    Clear,
+    /// Leave it in place next to its output.
+    Keep,
+}
+
+/// Parses `READ_ONLY_SOURCES` (`fail`, `clear` or `keep`; default `fail`).
+pub fn read_only_sources() -> Result<ReadOnlySources, String> {
+    match var("READ_ONLY_SOURCES")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("fail") => Ok(ReadOnlySources::Fail),
+        Some("clear") => Ok(ReadOnlySources::Clear),
+        Some("keep") => Ok(ReadOnlySources::Keep),
+        Some(other) => Err(format!("Unknown READ_ONLY_SOURCES {:?}", other)),
+    }
+}
+
+/// How long a contact sheet collects files after the first one arrives
+/// (`MONTAGE_WINDOW_MS`, default 10000).
+pub fn montage_window() -> Result<Duration, String> {
+    match parse("MONTAGE_WINDOW_MS")? {
+        Some(0) => Err("MONTAGE_WINDOW_MS must be at least 1".to_string()),
+        window => Ok(Duration::from_millis(window.unwrap_or(10_000))),
+    }
+}
+
+/// How long files are collected after the first event before they are processed as one
+/// batch (`BATCH_WINDOW_MS`; unset or `0` processes each file after its own debounce).
+pub fn batch_window() -> Result<Option<Duration>, String> {
+    let window = match parse("BATCH_WINDOW_MS")? {
+        None | Some(0) => return Ok(None),
+        Some(ms) => Duration::from_millis(ms),
+    };
+    for other in ["MONTAGE", "SINGLE_THREAD"] {
+        if flag(other) {
+            return Err(format!("BATCH_WINDOW_MS cannot be combined with {}", other));
+        }
+    }
+    Ok(Some(window))
+}
+
+/// Cells per row of a contact sheet (`MONTAGE_COLUMNS`, default 4).
+pub fn montage_columns() -> Result<u32, String> {
+    match parse("MONTAGE_COLUMNS")? {
+        Some(0) => Err("MONTAGE_COLUMNS must be at least 1".to_string()),
+        columns => Ok(columns.unwrap_or(4)),
+    }
+}
+
+/// Loopback port of the status endpoint (`STATUS_PORT`, default 47820; `0` disables).
+pub fn status_port() -> Result<Option<u16>, String> {
+    match parse("STATUS_PORT")? {
+        Some(0) => Ok(None),
+        port => Ok(Some(port.unwrap_or(47820))),
+    }
+}
+
+/// Splits `POST_PROCESS_CMD` into the program and its arguments. Arguments are separated
+/// by whitespace; double quotes group an argument that contains spaces. No shell is
+/// involved, so placeholders are substituted into single arguments and need no quoting.
+pub fn post_process_cmd() -> Result<Option<Vec<String>>, String> {
+    let Some(value) = var("POST_PROCESS_CMD") else {
+        return Ok(None);
+    };
+    let mut args = Vec::new();
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => args.extend(current.take()),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(format!("Unbalanced quote in POST_PROCESS_CMD {:?}", value));
+    }
+    args.extend(current);
+    if args.is_empty() {
+        return Err("POST_PROCESS_CMD is empty".to_string());
+    }
+    Ok(Some(args))
+}
+
+/// Whether `THUMBNAIL_INDEX` is set, rejecting the modes that consume or bundle sources.
+pub fn thumbnail_index() -> Result<bool, String> {
+    if !flag("THUMBNAIL_INDEX") {
+        return Ok(false);
+    }
+    for other in ["MONTAGE", "HANDLE_ZIP"] {
+        if flag(other) {
+            return Err(format!("THUMBNAIL_INDEX cannot be combined with {}", other));
+        }
+    }
+    Ok(true)
+}
+
+/// How long the service may sit without files before it exits (`IDLE_SHUTDOWN_SEC`;
+/// unset or `0` disables).
+pub fn idle_shutdown() -> Result<Option<Duration>, String> {
+    Ok(parse("IDLE_SHUTDOWN_SEC")?
+        .filter(|&secs| secs > 0)
+        .map(Duration::from_secs))
+}
+
+/// How long events for a file that was just renamed into place are folded into the
+/// rename (`RENAME_COOLDOWN_MS`, default 1000; `0` disables).
+pub fn rename_cooldown() -> Result<Duration, String> {
+    Ok(Duration::from_millis(
+        parse("RENAME_COOLDOWN_MS")?.unwrap_or(1000),
+    ))
+}
+
+/// How long repeated events for a path are ignored after the first one
+/// (`RECENTLY_PROCESSED_MS`, default 2000).
+pub fn recently_processed_window() -> Result<Duration, String> {
+    Ok(Duration::from_millis(
+        parse("RECENTLY_PROCESSED_MS")?.unwrap_or(2000),
+    ))
+}
+
+/// Most paths remembered for `RECENTLY_PROCESSED_MS` (`RECENTLY_PROCESSED_MAX`, default
+/// 10000); beyond that the oldest are forgotten early.
+pub fn recently_processed_max() -> Result<usize, String> {
+    match parse("RECENTLY_PROCESSED_MAX")? {
+        Some(0) => Err("RECENTLY_PROCESSED_MAX must be at least 1".to_string()),
+        max => Ok(max.unwrap_or(10_000)),
+    }
+}
+
+/// Retries of the final rename while the target is locked (`RENAME_RETRIES`, default 5).
+pub fn rename_retries() -> Result<u32, String> {
+    Ok(parse("RENAME_RETRIES")?.unwrap_or(5))
+}
+
+/// Delay before the first rename retry (`RENAME_RETRY_DELAY_MS`, default 200).
+pub fn rename_retry_delay_ms() -> Result<u64, String> {
+    Ok(parse("RENAME_RETRY_DELAY_MS")?.unwrap_or(200))
+}
+
+/// Reruns of the whole operation after a transient write failure (`PROCESS_RETRIES`,
+/// 0-10, default 0).
+pub fn process_retries() -> Result<u32, String> {
+    match parse("PROCESS_RETRIES")? {
+        Some(retries) if retries > 10 => Err(format!(
+            "PROCESS_RETRIES must be at most 10, got {}",
+            retries
+        )),
+        retries => Ok(retries.unwrap_or(0)),
+    }
+}
+
+/// Delay before the first rerun (`PROCESS_RETRY_DELAY_MS`, default 1000).
+pub fn process_retry_delay_ms() -> Result<u64, String> {
+    Ok(parse("PROCESS_RETRY_DELAY_MS")?.unwrap_or(1000))
+}
+
+/// What happens when the output name is already taken by a file other than the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Write `<stem>.<source extension>.<ext>` instead, e.g. `photo.png.jpg`.
+    KeepExtension,
+    Overwrite,
+    /// Keep the existing file and leave the source unprocessed.
+    Skip,
+    /// Write to the first free `<stem>-<n>.<ext>`.
+    Rename,
+}
+
+impl OnConflict {
+    pub fn name(self) -> &'static str {
+        match self {
+            OnConflict::KeepExtension => "keep-extension",
+            OnConflict::Overwrite => "overwrite",
+            OnConflict::Skip => "skip",
+            OnConflict::Rename => "rename",
+        }
+    }
+}
+
+/// Parses `ON_CONFLICT` (`keep-extension`, `overwrite`, `skip` or `rename`; default
+/// `keep-extension`).
+pub fn on_conflict() -> Result<OnConflict, String> {
+    match var("ON_CONFLICT")
+        .map(|v| v.to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("keep-extension") => Ok(OnConflict::KeepExtension),
+        Some("overwrite") => Ok(OnConflict::Overwrite),
+        Some("skip") => Ok(OnConflict::Skip),
+        Some("rename") => Ok(OnConflict::Rename),
+        Some(other) => Err(format!("Unknown ON_CONFLICT {:?}", other)),
+    }
+}
+
+/// Parses `MIN_CONTENT_RATIO`, the smallest share of the image the detected content may cover.
+pub fn min_content_ratio() -> Result<Option<f32>, String> {
+    match parse::<f32>("MIN_CONTENT_RATIO")? {
+        Some(ratio) if !(0.0..=1.0).contains(&ratio) => Err(format!(
+            "MIN_CONTENT_RATIO must be between 0 and 1, got {}",
+            ratio
+        )),
+        ratio => Ok(ratio),
+    }
+}
+
+/// Parses `EMPTY_IMAGE_ACTION` (`skip` or `quarantine`; default `skip`).
+pub fn quarantine_empty_images() -> Result<bool, String> {
+    quarantine_action("EMPTY_IMAGE_ACTION")
+}
+
+/// Parses `MIN_INPUT_DIMENSION`, the smallest long edge in pixels a source may have.
+pub fn min_input_dimension() -> Result<Option<u32>, String> {
+    parse("MIN_INPUT_DIMENSION")
+}
+
+/// What happens to JPEGs with four color channels, as exported by print workflows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmykJpeg {
+    /// Convert them to RGB, through their CMYK profile if they embed one.
+    Convert,
+    /// Leave them in place.
+    Skip,
+    /// Move them to the quarantine folder.
+    Quarantine,
+}
+
+/// Parses `CMYK_JPEG` (`convert`, `skip` or `quarantine`; default `convert`).
+pub fn cmyk_jpeg() -> Result<CmykJpeg, String> {
+    match var("CMYK_JPEG").map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("convert") => Ok(CmykJpeg::Convert),
+        Some("skip") => Ok(CmykJpeg::Skip),
+        Some("quarantine") => Ok(CmykJpeg::Quarantine),
+        Some(other) => Err(format!("Unknown CMYK_JPEG {:?}", other)),
+    }
+}
+
+/// Parses `SMALL_IMAGE_ACTION` (`skip` or `quarantine`; default `skip`).
+pub fn quarantine_small_images() -> Result<bool, String> {
+    quarantine_action("SMALL_IMAGE_ACTION")
+}
+
+fn quarantine_action(key: &str) -> Result<bool, String> {
+    match var(key).map(|v| v.to_ascii_lowercase()).as_deref() {
+        None | Some("skip") => Ok(false),
+        Some("quarantine") => Ok(true),
+        Some(other) => Err(format!("Unknown {} {:?}", key, other)),
+    }
+}
+
+/// Validates every setting that would otherwise only fail once the first file is processed,
+/// globally and under each profile.
+pub fn validate(pad: u32) -> Result<(), String> {
+    image_extensions()?;
+    ignore_list_file()?;
+    watch_mode()?;
+    share_credentials()?;
+    scan_since()?;
+    rename_cooldown()?;
+    recently_processed_window()?;
+    recently_processed_max()?;
+    rate_limit_per_min()?;
+    load_threshold()?;
+    load_sample()?;
+    thread_priority()?;
+    batch_window()?;
+    max_open_files()?;
+    decode_threads()?;
+    zip_max_bytes()?;
+    montage_window()?;
+    montage_columns()?;
+    status_port()?;
+    idle_shutdown()?;
+    post_process_cmd()?;
+    thumbnail_index()?;
+    mirror_deletes()?;
+    validate_settings(pad)?;
+    for profile in profiles()? {
+        with_profile(Some(&profile.name), || validate_settings(pad))
+            .map_err(|e| format!("Profile {}: {}", profile.name, e))?;
+    }
+    for job in jobs()? {
+        with_job(Some(&job.name), || validate_job(pad))
+            .map_err(|e| format!("Job {}: {}", job.name, e))?;
+    }
+    Ok(())
+}
+
+/// Settings read on behalf of a job. Jobs hand their files to their own pipeline, so the
+/// modes that process on the main thread are not available.
+fn validate_job(pad: u32) -> Result<(), String> {
+    for mode in ["MONTAGE", "SINGLE_THREAD"] {
+        if flag(mode) {
+            return Err(format!("{} cannot be combined with JOBS", mode));
+        }
+    }
+    if batch_window()?.is_some() {
+        return Err("BATCH_WINDOW_MS cannot be combined with JOBS".to_string());
+    }
+    image_extensions()?;
+    watch_mode()?;
+    share_credentials()?;
+    scan_since()?;
+    rate_limit_per_min()?;
+    thread_priority()?;
+    mirror_deletes()?;
+    validate_settings(pad)?;
+    for profile in profiles()? {
+        with_profile(Some(&profile.name), || validate_settings(pad))
+            .map_err(|e| format!("Profile {}: {}", profile.name, e))?;
+    }
+    Ok(())
+}
+
+/// Settings that a profile may override.
+fn validate_settings(pad: u32) -> Result<(), String> {
+    preset()?;
+    validate_target_sizes(padding(pad)?)?;
+    sizes(target_size(&output_format())?, padding(pad)?)?;
+    jpeg_quality()?;
+    jpeg_subsampling()?;
+    check_output_format("OUTPUT_FORMAT", &output_format())?;
+    max_output_pixels()?;
+    fast_path()?;
+    replace_in_place()?;
+    master()?;
+    preview_size()?;
+    fallback_format()?;
+    gif_options()?;
+    output_dpi()?;
+    background()?;
+    mask()?;
+    bit_depth()?;
+    sharpen()?;
+    crop_sides()?;
+    crop_margin()?;
+    screenshot_mode()?;
+    channel_tolerance()?;
+    adaptive_tolerance()?;
+    noise_tolerance()?;
+    deskew()?;
+    gravity()?;
+    align()?;
+    center_remainder()?;
+    output_target()?;
+    output_naming()?;
+    sequence()?;
+    on_conflict()?;
+    rename_retries()?;
+    rename_retry_delay_ms()?;
+    process_retries()?;
+    process_retry_delay_ms()?;
+    min_content_ratio()?;
+    quarantine_empty_images()?;
+    cmyk_jpeg()?;
+    min_input_dimension()?;
+    quarantine_small_images()?;
+    delete_delay()?;
+    read_only_sources()?;
+    Ok(())
+}
+
+/// Checks that the resolved size of every output format leaves room for the padding,
+/// unless `PADDING_OVERFLOW=clamp` reduces it per file.
+fn validate_target_sizes(pad: u32) -> Result<(), String> {
+    let mut errors = Vec::new();
+    let clamp = padding_overflow()? == PaddingOverflow::Clamp;
+    for ext in OUTPUT_FORMATS {
+        match target_size(ext) {
+            Ok((width, height)) if width == 0 || height == 0 => errors.push(format!(
+                "Target size {}x{} for {} has no pixels",
+                width, height, ext
+            )),
+            Ok((width, height)) if !clamp && width.min(height) <= pad.saturating_mul(2) => errors
+                .push(format!(
+                    "Target size {}x{} for {} must exceed twice the padding ({})",
+                    width, height, ext, pad
+                )),
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mirror_deletes_needs_a_delete_delay() {
+        let mirror = |delay: &str| {
+            let values = HashMap::from([
+                ("MIRROR_DELETES".to_string(), "1".to_string()),
+                ("DELETE_DELAY_SEC".to_string(), delay.to_string()),
+            ]);
+            with_values(&values, mirror_deletes)
+        };
+        assert!(mirror("0").unwrap_err().contains("DELETE_DELAY_SEC"));
+        assert_eq!(mirror("30"), Ok(true));
+    }
+
+    #[test]
+    fn target_size_prefers_the_format_settings_over_the_global_ones() {
+        let values = HashMap::from([
+            ("TARGET_WIDTH".to_string(), "800".to_string()),
+            ("TARGET_HEIGHT".to_string(), "600".to_string()),
+            ("TARGET_WIDTH_WEBP".to_string(), "400".to_string()),
+            ("TARGET_HEIGHT_JPG".to_string(), "300".to_string()),
+        ]);
+        with_values(&values, || {
+            assert_eq!(target_size("webp"), Ok((400, 600)));
+            assert_eq!(target_size("jpeg"), Ok((800, 300)));
+            assert_eq!(target_size("png"), Ok((800, 600)));
+        });
+    }
+}
@@ -0,0 +1,97 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use simplelog::{Config, SharedLogger};
+use std::{ffi::OsStr, io, iter, os::windows::ffi::OsStrExt, ptr};
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    EventSourceHandle, RegisterEventSourceW, ReportEventW,
+};
+
+/// Forwards log records to the Windows Event Log under the service's event source.
+pub struct EventLogLogger {
+    handle: EventSourceHandle,
+    level: LevelFilter,
+    config: Config,
+}
+
+impl EventLogLogger {
+    pub fn new(source: &str, level: LevelFilter, config: Config) -> io::Result<Box<Self>> {
+        let source = to_wide(source);
+        // SAFETY: `source` is a NUL-terminated UTF-16 string that outlives the call.
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        if handle == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Box::new(EventLogLogger {
+            handle,
+            level,
+            config,
+        }))
+    }
+}
+
+impl Log for EventLogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let event_type = match record.level() {
+            Level::Error => EVENTLOG_ERROR_TYPE,
+            Level::Warn => EVENTLOG_WARNING_TYPE,
+            Level::Info | Level::Debug | Level::Trace => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = to_wide(&record.args().to_string());
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle stays registered until drop and `strings` points to one
+        // NUL-terminated UTF-16 string that outlives the call.
+        unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl SharedLogger for EventLogLogger {
+    fn level(&self) -> LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        Some(&self.config)
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+impl Drop for EventLogLogger {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by `RegisterEventSourceW` and is released once.
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
+
+fn to_wide(value: &str) -> Vec<u16> {
+    OsStr::new(value)
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect()
+}
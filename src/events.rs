@@ -0,0 +1,149 @@
+//! Hand-offs to other tools once a file is finished: `STDOUT_EVENTS` writes one JSON line
+//! on stdout per file, and `POST_PROCESS_CMD` runs a command per output. Logs never go to
+//! stdout, which keeps the stream clean. `DAILY_SUMMARY` counts every outcome reported here.
+
+use serde_json::json;
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::{config, summary};
+
+/// Reports the outcome for `path`: runs `POST_PROCESS_CMD` after a success, and writes
+/// `{"path": ..., "status": "processed", "outputs": [...]}` or
+/// `{"path": ..., "status": "failed", "error": ...}` when `STDOUT_EVENTS` is enabled.
+pub fn emit(path: &Path, result: Result<&[PathBuf], &str>) {
+    summary::record(result);
+    if config::flag("STDOUT_EVENTS") {
+        write_event(path, result);
+    }
+    if let Ok(outputs) = result {
+        post_process(path, outputs);
+    }
+}
+
+/// Reports a file that was left in place on purpose, such as one that is already
+/// normalized: `{"path": ..., "status": "skipped", "reason": ...}` with `STDOUT_EVENTS`.
+/// It is not a failure, and `POST_PROCESS_CMD` does not run.
+pub fn skipped(path: &Path, reason: &str) {
+    summary::record_skipped();
+    if config::flag("STDOUT_EVENTS") {
+        write_line(
+            path,
+            json!({
+                "path": path,
+                "status": "skipped",
+                "reason": reason,
+            }),
+        );
+    }
+}
+
+fn write_event(path: &Path, result: Result<&[PathBuf], &str>) {
+    let event = match result {
+        Ok(outputs) => json!({
+            "path": path,
+            "status": "processed",
+            "outputs": outputs,
+        }),
+        Err(error) => json!({
+            "path": path,
+            "status": "failed",
+            "error": error,
+        }),
+    };
+    write_line(path, event);
+}
+
+fn write_line(path: &Path, event: serde_json::Value) {
+    // One locked write per line, so lines from concurrent workers never interleave.
+    let mut stdout = io::stdout().lock();
+    if let Err(e) = writeln!(stdout, "{}", event).and_then(|()| stdout.flush()) {
+        log::warn!("Failed to write event for {:?} to stdout: {}", path, e);
+    }
+}
+
+/// Runs `POST_PROCESS_CMD` once per output of `input`, or once with an empty `{output}`
+/// when everything was uploaded. The command runs on the calling thread and its exit code
+/// and output are logged; a failing command never fails the file itself.
+fn post_process(input: &Path, outputs: &[PathBuf]) {
+    let args = match config::post_process_cmd() {
+        Ok(Some(args)) => args,
+        Ok(None) => return,
+        Err(e) => {
+            log::error!("Not running the post-process command: {}", e);
+            return;
+        }
+    };
+    let empty = PathBuf::new();
+    let outputs: Vec<&PathBuf> = if outputs.is_empty() {
+        vec![&empty]
+    } else {
+        outputs.iter().collect()
+    };
+
+    for output in outputs {
+        let mut args = args.iter().map(|arg| substitute(arg, input, output));
+        let program = args.next().unwrap_or_default();
+        let result = Command::new(&program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output();
+        match result {
+            Ok(result) => {
+                let stdout = String::from_utf8_lossy(&result.stdout);
+                let stderr = String::from_utf8_lossy(&result.stderr);
+                let captured: String = [stdout.trim(), stderr.trim()]
+                    .into_iter()
+                    .filter(|text| !text.is_empty())
+                    .map(|text| format!("\n{}", text))
+                    .collect();
+                if result.status.success() {
+                    log::info!(
+                        "Post-process command for {:?} succeeded.{}",
+                        output,
+                        captured
+                    );
+                } else {
+                    log::warn!(
+                        "Post-process command for {:?} failed ({}).{}",
+                        output,
+                        result.status,
+                        captured
+                    );
+                }
+            }
+            Err(e) => log::warn!(
+                "Failed to start post-process command {:?} for {:?}: {}",
+                program,
+                output,
+                e
+            ),
+        }
+    }
+}
+
+/// Replaces `{input}` and `{output}` in one argument, keeping paths that are not valid
+/// Unicode intact.
+fn substitute(arg: &str, input: &Path, output: &Path) -> OsString {
+    let mut substituted = OsString::new();
+    let mut rest = arg;
+    loop {
+        let next = [("{input}", input), ("{output}", output)]
+            .into_iter()
+            .filter_map(|(placeholder, path)| {
+                rest.find(placeholder).map(|at| (at, placeholder, path))
+            })
+            .min_by_key(|&(at, _, _)| at);
+        let Some((at, placeholder, path)) = next else {
+            substituted.push(rest);
+            return substituted;
+        };
+        substituted.push(&rest[..at]);
+        substituted.push(path);
+        rest = &rest[at + placeholder.len()..];
+    }
+}
@@ -0,0 +1,33 @@
+use image::{DynamicImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+use std::{fs, path::Path};
+
+/// Decodes the primary image of a HEIC/HEIF file, applying its rotation and mirroring.
+pub fn decode(path: &Path) -> Result<DynamicImage, String> {
+    // Read through `std::fs` since libheif only opens files by UTF-8 name.
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read HEIF {:?}: {}", path, e))?;
+    let context = HeifContext::read_from_bytes(&bytes)
+        .map_err(|e| format!("Failed to open HEIF {:?}: {}", path, e))?;
+    let handle = context
+        .primary_image_handle()
+        .map_err(|e| format!("HEIF {:?} has no primary image: {}", path, e))?;
+    let image = LibHeif::new()
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(|e| format!("Failed to decode HEIF {:?}: {}", path, e))?;
+
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or_else(|| format!("Decoded HEIF {:?} has no RGBA plane", path))?;
+
+    // Rows may be padded past `width * 4`, so copy them one by one.
+    let row_bytes = plane.width as usize * 4;
+    let mut pixels = Vec::with_capacity(row_bytes * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+
+    RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| format!("Decoded HEIF {:?} has an unexpected size", path))
+}
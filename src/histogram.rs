@@ -0,0 +1,195 @@
+//! `--histogram`: reports the luma distribution of one image together with the content
+//! threshold and bounding box the crop would use, to help tune `TOLERANCE`,
+//! `ADAPTIVE_TOLERANCE` and `CHANNEL_TOLERANCE` when cropping misbehaves.
+
+use image::{DynamicImage, Rgb, RgbImage};
+use std::{fmt, path::Path};
+
+use crate::processing;
+
+/// Lumas per row of the printed histogram.
+const BUCKET: usize = 8;
+/// Width of the longest bar in the printed histogram.
+const BAR_WIDTH: u64 = 50;
+/// Width of one luma value and height of the rendered histogram, in pixels.
+const PNG_COLUMN: u32 = 2;
+const PNG_HEIGHT: u32 = 128;
+
+/// How `content_bounds` tells content from background for this image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Threshold {
+    /// Luma below this value is content (`255 - TOLERANCE`).
+    Fixed(u8),
+    /// `ADAPTIVE_TOLERANCE`: luma further than `tol` from the border background.
+    Adaptive { background: u8, tol: u8 },
+    /// `CHANNEL_TOLERANCE` compares channels, not luma.
+    Channels,
+}
+
+impl Threshold {
+    /// Whether a pixel of this luma counts as content; `None` for `Channels`.
+    pub fn is_content(&self, luma: u8) -> Option<bool> {
+        match *self {
+            Threshold::Fixed(threshold) => Some(luma < threshold),
+            Threshold::Adaptive { background, tol } => Some(luma.abs_diff(background) > tol),
+            Threshold::Channels => None,
+        }
+    }
+}
+
+pub struct Histogram {
+    /// Pixel count per luma, with transparent pixels flattened onto white.
+    pub counts: [u64; 256],
+    pub threshold: Threshold,
+    /// `(left, top, right, bottom)` of the detected content, exclusive like
+    /// `content_bounds`.
+    pub bounds: (u32, u32, u32, u32),
+    pub size: (u32, u32),
+}
+
+/// Decodes `path` with the settings its profile and spec file select and measures it as
+/// the crop would, without writing anything.
+pub fn analyze(path: &Path, pad: u32, tol: u8) -> Result<Histogram, String> {
+    let job = processing::inspect(path, pad, tol)?;
+    let opts = &job.output.normalize;
+    let bounds = processing::content_bounds(&job.image, opts);
+
+    let image = if job.image.color().has_alpha() {
+        processing::flatten_alpha(job.image, processing::WHITE)
+    } else {
+        job.image
+    };
+    let gray = image.to_luma8();
+    let mut counts = [0u64; 256];
+    for pixel in gray.pixels() {
+        counts[pixel[0] as usize] += 1;
+    }
+
+    let threshold = if opts.channel_tolerance.is_some() {
+        Threshold::Channels
+    } else if opts.adaptive_tolerance {
+        let (background, tol) = processing::border_background(&gray);
+        Threshold::Adaptive { background, tol }
+    } else {
+        Threshold::Fixed(255 - opts.tol)
+    };
+
+    Ok(Histogram {
+        counts,
+        threshold,
+        bounds,
+        size: gray.dimensions(),
+    })
+}
+
+impl Histogram {
+    /// Draws the histogram on a log scale, one column per luma: content lumas dark,
+    /// background lumas light, and the threshold as a red line.
+    pub fn render(&self) -> DynamicImage {
+        let mut image = RgbImage::from_pixel(256 * PNG_COLUMN, PNG_HEIGHT, Rgb([255; 3]));
+        let max = (*self.counts.iter().max().unwrap_or(&0) as f64).ln_1p();
+        for (luma, &count) in self.counts.iter().enumerate() {
+            let color = match self.threshold.is_content(luma as u8) {
+                Some(true) => Rgb([40, 40, 40]),
+                Some(false) => Rgb([170, 170, 170]),
+                None => Rgb([100, 100, 100]),
+            };
+            let height = if max > 0.0 {
+                ((count as f64).ln_1p() / max * f64::from(PNG_HEIGHT)).round() as u32
+            } else {
+                0
+            };
+            for x in luma as u32 * PNG_COLUMN..(luma as u32 + 1) * PNG_COLUMN {
+                for y in PNG_HEIGHT - height..PNG_HEIGHT {
+                    image.put_pixel(x, y, color);
+                }
+            }
+        }
+        let edges = match self.threshold {
+            Threshold::Fixed(threshold) => vec![threshold],
+            Threshold::Adaptive { background, tol } => vec![
+                background.saturating_sub(tol),
+                background.saturating_add(tol),
+            ],
+            Threshold::Channels => Vec::new(),
+        };
+        for edge in edges {
+            let x = u32::from(edge) * PNG_COLUMN;
+            for y in 0..PNG_HEIGHT {
+                image.put_pixel(x, y, Rgb([220, 0, 0]));
+            }
+        }
+        DynamicImage::ImageRgb8(image)
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: u64 = self.counts.iter().sum();
+        let buckets: Vec<u64> = self
+            .counts
+            .chunks(BUCKET)
+            .map(|chunk| chunk.iter().sum())
+            .collect();
+        let max = buckets.iter().copied().max().unwrap_or(0).max(1);
+        writeln!(f, "Luma      Pixels       Share")?;
+        for (i, &count) in buckets.iter().enumerate() {
+            let bar = (count * BAR_WIDTH).div_ceil(max) as usize;
+            let row = format!(
+                "{:>3}-{:<3} {:>10} {:>10.2}% {}",
+                i * BUCKET,
+                (i + 1) * BUCKET - 1,
+                count,
+                count as f64 * 100.0 / total.max(1) as f64,
+                "#".repeat(bar)
+            );
+            writeln!(f, "{}", row.trim_end())?;
+        }
+
+        match self.threshold {
+            Threshold::Fixed(threshold) => {
+                writeln!(f, "Threshold: luma below {} is content", threshold)?
+            }
+            Threshold::Adaptive { background, tol } => writeln!(
+                f,
+                "Threshold: border background luma {} \u{b1} {} (ADAPTIVE_TOLERANCE); lumas outside are content",
+                background, tol
+            )?,
+            Threshold::Channels => writeln!(
+                f,
+                "Threshold: per channel (CHANNEL_TOLERANCE); the luma split does not apply"
+            )?,
+        }
+        if self.threshold != Threshold::Channels {
+            let content: u64 = (0..=u8::MAX)
+                .filter(|&luma| self.threshold.is_content(luma) == Some(true))
+                .map(|luma| self.counts[luma as usize])
+                .sum();
+            writeln!(
+                f,
+                "Content pixels: {} ({:.2}%)",
+                content,
+                content as f64 * 100.0 / total.max(1) as f64
+            )?;
+        }
+
+        let (left, top, right, bottom) = self.bounds;
+        let (width, height) = self.size;
+        if right <= left || bottom <= top {
+            writeln!(f, "Bounding box: no content found in {}x{}", width, height)
+        } else {
+            writeln!(
+                f,
+                "Bounding box: left {}, top {}, right {}, bottom {} ({}x{} of {}x{})",
+                left,
+                top,
+                right,
+                bottom,
+                right - left,
+                bottom - top,
+                width,
+                height
+            )
+        }
+    }
+}
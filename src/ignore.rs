@@ -0,0 +1,89 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Files listed in `IGNORE_LIST_FILE`, one per line. An entry without a path separator is
+/// a file name and matches in every folder; anything else is a path, relative to the watch
+/// folder unless absolute. Empty lines and lines starting with `#` are skipped.
+///
+/// The file is checked for changes on every lookup and read again when its modification
+/// time or size changed, so edits apply without a restart.
+pub struct IgnoreList {
+    file: PathBuf,
+    base: PathBuf,
+    /// Modification time and size when the file was last read; `None` before the first
+    /// read or while the file is missing.
+    stamp: Option<(SystemTime, u64)>,
+    loaded: bool,
+    names: HashSet<OsString>,
+    paths: HashSet<PathBuf>,
+}
+
+impl IgnoreList {
+    pub fn new(file: PathBuf, base: PathBuf) -> Self {
+        IgnoreList {
+            file,
+            base,
+            stamp: None,
+            loaded: false,
+            names: HashSet::new(),
+            paths: HashSet::new(),
+        }
+    }
+
+    /// Whether `path` is listed, by its full path or its file name.
+    pub fn contains(&mut self, path: &Path) -> bool {
+        self.refresh();
+        self.paths.contains(path)
+            || path
+                .file_name()
+                .is_some_and(|name| self.names.contains(name))
+    }
+
+    /// Reads the file again if it changed since the last read. A file that cannot be read
+    /// empties the list until it can be read again.
+    pub fn refresh(&mut self) {
+        let stamp = fs::metadata(&self.file)
+            .ok()
+            .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+        if self.loaded && stamp == self.stamp {
+            return;
+        }
+        self.loaded = true;
+        self.stamp = stamp;
+        self.names.clear();
+        self.paths.clear();
+
+        let text = match fs::read_to_string(&self.file) {
+            Ok(text) => text,
+            Err(e) => {
+                log::warn!(
+                    "Failed to read IGNORE_LIST_FILE {:?}: {}. No files are ignored by it until it can be read.",
+                    self.file,
+                    e
+                );
+                return;
+            }
+        };
+        for entry in text.lines().map(str::trim) {
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            if entry.contains(['/', '\\']) {
+                self.paths.insert(self.base.join(entry));
+            } else {
+                self.names.insert(OsString::from(entry));
+            }
+        }
+        log::info!(
+            "Loaded IGNORE_LIST_FILE {:?}: {} file names and {} paths.",
+            self.file,
+            self.names.len(),
+            self.paths.len()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+#[cfg(unix)]
+use std::fs::{File, TryLockError};
+#[cfg(windows)]
+use std::ptr;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(windows)]
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, ERROR_ALREADY_EXISTS, GetLastError, HANDLE},
+    System::Threading::CreateMutexW,
+};
+
+use crate::service::SERVICE_NAME;
+
+/// Held for as long as this process watches a folder, so a second instance on the same
+/// folder cannot fight over its temp-to-final renames. The OS releases it when the
+/// process exits, even after a crash, so there is no stale lock to clean up.
+///
+/// On Windows this is a named mutex; elsewhere it is an exclusive lock on a file in the
+/// executable's directory.
+pub struct InstanceLock {
+    #[cfg(windows)]
+    handle: HANDLE,
+    #[cfg(unix)]
+    _file: File,
+}
+
+/// Identifies a watch folder regardless of how its path was spelled.
+fn folder_key(watch_folder: &Path) -> String {
+    let folder = watch_folder
+        .canonicalize()
+        .unwrap_or_else(|_| watch_folder.to_path_buf());
+    let key = blake3::hash(folder.to_string_lossy().to_lowercase().as_bytes());
+    format!("{}-{}", SERVICE_NAME, &key.to_hex()[..32])
+}
+
+/// A per-folder file in the executable's directory, e.g. `ni-watcher-<hash>.lock`.
+fn state_file(watch_folder: &Path, extension: &str) -> PathBuf {
+    ni_service_rs::current_exe_dir().join(format!("{}.{}", folder_key(watch_folder), extension))
+}
+
+/// When the previous instance on `watch_folder` shut down cleanly, if one did.
+pub fn last_run(watch_folder: &Path) -> Option<SystemTime> {
+    let text = fs::read_to_string(state_file(watch_folder, "last-run")).ok()?;
+    let secs = text.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Records `at` as the end of this run, for the next start's `SCAN_SINCE=last-run`.
+pub fn record_last_run(watch_folder: &Path, at: SystemTime) -> io::Result<()> {
+    let secs = at.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    fs::write(state_file(watch_folder, "last-run"), format!("{secs}\n"))
+}
+
+#[cfg(unix)]
+impl InstanceLock {
+    /// Acquires the lock for `watch_folder`, returning `Ok(None)` if another instance
+    /// already holds it.
+    pub fn acquire(watch_folder: &Path) -> io::Result<Option<Self>> {
+        let file = File::create(state_file(watch_folder, "lock"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(InstanceLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl InstanceLock {
+    /// Acquires the lock for `watch_folder`, returning `Ok(None)` if another instance
+    /// already holds it.
+    pub fn acquire(watch_folder: &Path) -> io::Result<Option<Self>> {
+        // `Global\` spans sessions, so the service (session 0) and a console instance see
+        // the same mutex. Creating it needs SeCreateGlobalPrivilege, which services and
+        // administrators have; other users fall back to their own session.
+        let suffix = folder_key(watch_folder);
+        let (handle, already_exists) = create_mutex(&format!("Global\\{suffix}"))
+            .or_else(|e| {
+                log::warn!(
+                    "Cannot create a global instance lock ({}); only instances in this session are detected.",
+                    e
+                );
+                create_mutex(&format!("Local\\{suffix}"))
+            })?;
+        if already_exists {
+            // SAFETY: the handle was just returned by `CreateMutexW` and is closed once.
+            unsafe { CloseHandle(handle) };
+            return Ok(None);
+        }
+        Ok(Some(InstanceLock { handle }))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // SAFETY: the handle was returned by `CreateMutexW` and is released once.
+        unsafe {
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+#[cfg(windows)]
+/// Opens or creates a named mutex, reporting whether it already existed.
+fn create_mutex(name: &str) -> io::Result<(HANDLE, bool)> {
+    let wide: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+    // SAFETY: `wide` is a NUL-terminated UTF-16 string that outlives the call, and the
+    // last error is read right after `CreateMutexW` on the same thread.
+    let (handle, error) = unsafe {
+        let handle = CreateMutexW(ptr::null(), 0, wide.as_ptr());
+        (handle, GetLastError())
+    };
+    if handle == 0 {
+        return Err(io::Error::from_raw_os_error(error as i32));
+    }
+    Ok((handle, error == ERROR_ALREADY_EXISTS))
+}
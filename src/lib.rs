@@ -0,0 +1,41 @@
+//! Image normalization used by the ni-watcher service: whitespace cropping, scaling and
+//! padding onto a fixed canvas, configured through environment variables.
+//!
+//! The service binary drives it through [`pipeline`]; embedders can call
+//! [`processing::process_and_save`] directly or [`nonblocking::process_file`] from async code.
+
+pub mod archive;
+pub mod batch;
+mod cmyk;
+mod color;
+pub mod config;
+pub mod events;
+#[cfg(feature = "heif")]
+mod heif;
+pub mod histogram;
+pub mod load;
+pub mod metrics;
+pub mod montage;
+pub mod nonblocking;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod pipe;
+pub mod pipeline;
+pub mod presets;
+pub mod priority;
+pub mod processing;
+#[cfg(feature = "s3")]
+mod s3;
+mod sequence;
+pub mod summary;
+pub mod thumbnails;
+
+use std::{env, path::PathBuf};
+
+/// Directory of the running executable, where `.env`, logs and bundled libraries live.
+pub fn current_exe_dir() -> PathBuf {
+    env::current_exe()
+        .ok()
+        .and_then(|path| path.parent().map(PathBuf::from))
+        .expect("Unable to determine the executable directory")
+}
@@ -0,0 +1,156 @@
+//! `LOAD_THRESHOLD`: holds back new files while the machine's CPU is busier than the
+//! threshold, so the service gives way to other work on a shared machine during spikes.
+//! Files already in progress always finish; new ones wait in the queue until the load
+//! drops again.
+//!
+//! Load is the share of time all cores were busy between two samples, taken at most every
+//! `LOAD_SAMPLE_MS` and shared by every thread. It is measured from `/proc/stat` on Linux
+//! and `GetSystemTimes` on Windows; elsewhere it is unknown and nothing is held back.
+
+use once_cell::sync::Lazy;
+use std::{
+    io,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// Window a fresh measurement covers when the last sample is out of date.
+const FRESH_WINDOW: Duration = Duration::from_millis(250);
+
+struct Sampler {
+    /// Busy and total CPU time at the last sample.
+    times: Option<(u64, u64)>,
+    sampled: Option<Instant>,
+    /// Load in percent between the last two samples.
+    load: Option<f64>,
+    paused: bool,
+    unsupported_logged: bool,
+}
+
+static SAMPLER: Lazy<Mutex<Sampler>> = Lazy::new(|| {
+    Mutex::new(Sampler {
+        times: None,
+        sampled: None,
+        load: None,
+        paused: false,
+        unsupported_logged: false,
+    })
+});
+
+/// Whether new files should wait because the load is above `LOAD_THRESHOLD`. Logs when
+/// processing pauses and resumes.
+pub fn is_paused() -> bool {
+    let (Ok(Some(threshold)), Ok(interval)) = (config::load_threshold(), config::load_sample())
+    else {
+        return false;
+    };
+    let mut sampler = SAMPLER.lock().unwrap();
+    if sampler.sampled.is_none_or(|at| at.elapsed() >= interval) {
+        // After a quiet period the last sample would average a spike away, so the load
+        // is measured over a fresh short window instead.
+        if sampler
+            .sampled
+            .is_none_or(|at| at.elapsed() >= 2 * interval)
+            && let Ok(times) = cpu_times()
+        {
+            sampler.times = Some(times);
+            thread::sleep(FRESH_WINDOW);
+        }
+        sampler.sampled = Some(Instant::now());
+        match cpu_times() {
+            Ok((busy, total)) => {
+                if let Some((last_busy, last_total)) = sampler.times
+                    && total > last_total
+                {
+                    let busy = busy.saturating_sub(last_busy) as f64;
+                    sampler.load = Some(busy * 100.0 / (total - last_total) as f64);
+                }
+                sampler.times = Some((busy, total));
+            }
+            Err(e) if !sampler.unsupported_logged => {
+                sampler.unsupported_logged = true;
+                log::warn!(
+                    "Cannot measure CPU load, so LOAD_THRESHOLD has no effect: {}",
+                    e
+                );
+            }
+            Err(_) => {}
+        }
+    }
+
+    let Some(load) = sampler.load else {
+        return false;
+    };
+    let paused = load > f64::from(threshold);
+    if paused != sampler.paused {
+        sampler.paused = paused;
+        if paused {
+            log::info!(
+                "CPU load is {:.0}%, above LOAD_THRESHOLD ({}%); pausing new files.",
+                load,
+                threshold
+            );
+        } else {
+            log::info!("CPU load is down to {:.0}%; resuming.", load);
+        }
+    }
+    paused
+}
+
+/// Blocks the calling worker while `is_paused`, checking again every sampling interval.
+pub fn wait_until_below_threshold() {
+    while is_paused() {
+        thread::sleep(config::load_sample().unwrap_or(Duration::from_secs(2)));
+    }
+}
+
+/// Busy and total CPU time of all cores since boot, in jiffies.
+#[cfg(target_os = "linux")]
+fn cpu_times() -> io::Result<(u64, u64)> {
+    let stat = std::fs::read_to_string("/proc/stat")?;
+    let times: Vec<u64> = stat
+        .lines()
+        .next()
+        .filter(|line| line.starts_with("cpu "))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no cpu line in /proc/stat"))?
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    // user, nice, system, idle, iowait, irq, softirq, steal; guest time is part of user.
+    let total: u64 = times.iter().take(8).sum();
+    let idle = times.iter().skip(3).take(2).sum::<u64>();
+    Ok((total - idle, total))
+}
+
+/// Busy and total CPU time of all cores since boot, in 100 ns units.
+#[cfg(windows)]
+fn cpu_times() -> io::Result<(u64, u64)> {
+    use windows_sys::Win32::{Foundation::FILETIME, System::Threading::GetSystemTimes};
+
+    let zero = FILETIME {
+        dwLowDateTime: 0,
+        dwHighDateTime: 0,
+    };
+    let (mut idle, mut kernel, mut user) = (zero, zero, zero);
+    // SAFETY: the three pointers refer to live, writable `FILETIME`s.
+    if unsafe { GetSystemTimes(&mut idle, &mut kernel, &mut user) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let value =
+        |time: FILETIME| (u64::from(time.dwHighDateTime) << 32) | u64::from(time.dwLowDateTime);
+    // Kernel time includes idle time.
+    let total = value(kernel) + value(user);
+    Ok((total - value(idle), total))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn cpu_times() -> io::Result<(u64, u64)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "CPU times are only read on Linux and Windows",
+    ))
+}
@@ -0,0 +1,180 @@
+use flate2::{Compression, write::GzEncoder};
+use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Logs to stderr for one-shot command-line runs, keeping stdout free for results.
+pub fn init_console() {
+    let _ = TermLogger::init(
+        LevelFilter::Info,
+        simplelog::Config::default(),
+        TerminalMode::Stderr,
+        ColorChoice::Auto,
+    );
+}
+
+static WRITE_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true while the log file cannot be written (e.g. the disk is full).
+pub fn write_failed() -> bool {
+    WRITE_FAILED.load(Ordering::SeqCst)
+}
+
+/// The active log file. `WriteLogger` discards write errors, so failures are reported here
+/// and the record is echoed to stderr instead of being lost.
+pub struct LogFileWriter {
+    file: File,
+}
+
+impl Write for LogFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file.write_all(buf) {
+            Ok(()) => {
+                if WRITE_FAILED.swap(false, Ordering::SeqCst) {
+                    eprintln!("Log file is writable again.");
+                }
+            }
+            Err(e) => {
+                if !WRITE_FAILED.swap(true, Ordering::SeqCst) {
+                    eprintln!("Failed to write to log file: {e}. Falling back to stderr.");
+                }
+                io::stderr().write_all(buf)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.file.flush().is_err() {
+            WRITE_FAILED.store(true, Ordering::SeqCst);
+        }
+        io::stderr().flush()
+    }
+}
+
+pub struct RollingFileLogger;
+
+impl RollingFileLogger {
+    /// Opens `log0.txt` in `base`, rotating it first once it reached `max_size`. With
+    /// `compress`, rotated files are gzipped (`log1.txt.gz`); the active file stays plain.
+    pub fn open(base: &Path, max_size: usize, max_files: usize, compress: bool) -> LogFileWriter {
+        let current_log = base.join("log0.txt");
+        if let Ok(metadata) = fs::metadata(&current_log)
+            && metadata.len() as usize >= max_size
+        {
+            Self::rotate(base, max_files, compress);
+        }
+        Self::cleanup(base, max_files);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&current_log)
+            .expect("Failed to open log file");
+        LogFileWriter { file }
+    }
+
+    /// Shifts every rotated file up by one, plain or compressed, then moves `log0.txt` to
+    /// `log1.txt` or compresses it into `log1.txt.gz`.
+    fn rotate(base: &Path, max_files: usize, compress: bool) {
+        for i in (1..max_files).rev() {
+            for ext in ["txt", "txt.gz"] {
+                let src = base.join(format!("log{i}.{ext}"));
+                let dst = base.join(format!("log{}.{ext}", i + 1));
+                if src.exists() {
+                    let _ = fs::rename(src, dst);
+                }
+            }
+        }
+
+        let current_log = base.join("log0.txt");
+        if compress {
+            let compressed = base.join("log1.txt.gz");
+            match Self::compress(&current_log, &compressed) {
+                Ok(()) => {
+                    let _ = fs::remove_file(&current_log);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("Failed to compress {current_log:?}: {e}. Keeping it uncompressed.");
+                    let _ = fs::remove_file(&compressed);
+                }
+            }
+        }
+        let _ = fs::rename(current_log, base.join("log1.txt"));
+    }
+
+    fn compress(src: &Path, dst: &Path) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(File::create(dst)?, Compression::default());
+        io::copy(&mut File::open(src)?, &mut encoder)?;
+        encoder.finish()?.sync_all()
+    }
+
+    fn cleanup(base: &Path, max_files: usize) {
+        for ext in ["txt", "txt.gz"] {
+            let _ = fs::remove_file(base.join(format!("log{max_files}.{ext}")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `/dev/full` fails every write with `ENOSPC`, like a log disk that filled up.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_log_disk_falls_back_to_stderr_and_recovers() {
+        let full = OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let mut writer = LogFileWriter { file: full };
+        let record = b"Error processing file \"a.png\": decode failed\n";
+        // The record is not lost: the writer reports it written and echoes it to stderr.
+        assert_eq!(writer.write(record).unwrap(), record.len());
+        assert!(write_failed());
+
+        let dir = std::env::temp_dir().join(format!("ni-watcher-test-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut writer = RollingFileLogger::open(&dir, 1 << 20, 3, false);
+        writer.write_all(b"written\n").unwrap();
+        assert!(!write_failed());
+        assert_eq!(
+            fs::read_to_string(dir.join("log0.txt")).unwrap(),
+            "written\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn rotated_logs_are_gzipped_and_readable() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = std::env::temp_dir().join(format!("ni-watcher-test-gzip-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let gunzip = |name: &str| {
+            let mut text = String::new();
+            GzDecoder::new(File::open(dir.join(name)).unwrap())
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+
+        for record in ["first\n", "second\n", "third\n"] {
+            let mut writer = RollingFileLogger::open(&dir, 4, 3, true);
+            writer.write_all(record.as_bytes()).unwrap();
+        }
+        // Opening once more rotates "third" out of the active file.
+        drop(RollingFileLogger::open(&dir, 4, 3, true));
+
+        assert_eq!(fs::read_to_string(dir.join("log0.txt")).unwrap(), "");
+        assert_eq!(gunzip("log1.txt.gz"), "third\n");
+        assert_eq!(gunzip("log2.txt.gz"), "second\n");
+        assert!(!dir.join("log3.txt.gz").exists());
+        assert!(!dir.join("log1.txt").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,108 +1,217 @@
-use image::io::Reader as ImageReader;
-use image::{imageops::FilterType, DynamicImage, GenericImage, GenericImageView, Rgba};
-use log;
+// `print_config` lists every setting in one `json!` literal.
+#![recursion_limit = "512"]
+
+mod cli;
+#[cfg(windows)]
+mod eventlog;
+mod ignore;
+mod instance;
+mod logging;
+mod recent;
+mod service;
+mod share;
+mod status;
+
+use ni_service_rs::{
+    archive, batch, config, current_exe_dir, events, histogram, load, metrics, montage, pipeline,
+    presets, priority, processing, summary, thumbnails,
+};
+
 use notify::{
-    event::{EventKind, ModifyKind},
-    Config, Event, RecommendedWatcher, RecursiveMode, Watcher,
+    Config, Event, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{EventKind, MetadataKind, ModifyKind, RenameMode},
 };
 use once_cell::sync::Lazy;
-use simplelog::{CombinedLogger, LevelFilter, WriteLogger};
+use serde_json::json;
+use simplelog::{CombinedLogger, LevelFilter, SharedLogger, WriteLogger};
 use std::{
-    collections::{HashMap, HashSet},
-    env,
-    fs::{self, OpenOptions},
+    collections::HashMap,
+    env, fs,
     path::{Path, PathBuf},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc::channel,
-        Mutex,
-    },
+    sync::{Mutex, atomic::Ordering, mpsc::channel},
     thread,
-    time::{Duration, Instant},
-};
-use windows_service::service::{
-    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
-use windows_service::service_dispatcher;
 
-static SHUTDOWN: Lazy<AtomicBool> = Lazy::new(|| AtomicBool::new(false));
-static RECENTLY_PROCESSED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+const PADDING: u32 = 50;
+const TOLERANCE: u8 = 10;
 
-trait ServiceStatusExt {
-    fn running() -> Self;
-    fn stopped() -> Self;
-}
+/// Paths that were just handed on, so repeated events for them are ignored. The settings
+/// were checked by `config::validate` before the first event arrives.
+static RECENTLY_PROCESSED: Lazy<Mutex<recent::RecentPaths>> = Lazy::new(|| {
+    Mutex::new(recent::RecentPaths::new(
+        config::recently_processed_window().unwrap_or_default(),
+        config::recently_processed_max().unwrap_or(1),
+    ))
+});
+/// `IGNORE_LIST_FILE`, if set; entries that are paths are relative to the watch folder.
+static IGNORE_LIST: Lazy<Option<Mutex<ignore::IgnoreList>>> = Lazy::new(|| {
+    config::ignore_list_file()
+        .ok()
+        .flatten()
+        .map(|file| Mutex::new(ignore::IgnoreList::new(file, config::watch_folder())))
+});
+/// Files waiting for their debounce to elapse, with the time of their latest event.
+static PENDING_FILES: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Quiet period after the last event for a file before it is processed.
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// How often the debounce thread looks for files whose quiet period is over.
+const DEBOUNCE_SWEEP: Duration = Duration::from_millis(100);
+/// How often a watch folder connected with `SHARE_USER` is checked for a dropped share.
+const SHARE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the service logs its throughput.
+const METRICS_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// Service-specific exit code reported while running if the log file cannot be written.
+const LOG_FAILURE_EXIT_CODE: u32 = 1;
+
+fn main() -> service::RunResult {
+    load_env();
+    let cli = cli::parse();
 
-impl ServiceStatusExt for ServiceStatus {
-    fn running() -> Self {
-        ServiceStatus {
-            service_type: ServiceType::OWN_PROCESS,
-            current_state: ServiceState::Running,
-            controls_accepted: ServiceControlAccept::STOP,
-            exit_code: ServiceExitCode::Win32(0),
-            checkpoint: 0,
-            wait_hint: Duration::from_secs(0),
-            process_id: None,
+    if cli.print_config {
+        print_config();
+        return Ok(());
+    }
+    if cli.capabilities {
+        print_capabilities();
+        return Ok(());
+    }
+
+    if let Some(folder) = cli.reprocess {
+        logging::init_console();
+        let result =
+            config::validate(PADDING).and_then(|()| batch::reprocess(&folder, PADDING, TOLERANCE));
+        match result {
+            Ok(0) => return Ok(()),
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
         }
     }
 
-    fn stopped() -> Self {
-        ServiceStatus {
-            service_type: ServiceType::OWN_PROCESS,
-            current_state: ServiceState::Stopped,
-            controls_accepted: ServiceControlAccept::empty(),
-            exit_code: ServiceExitCode::Win32(0),
-            checkpoint: 0,
-            wait_hint: Duration::from_secs(0),
-            process_id: None,
+    if let Some(folder) = cli.size_report {
+        logging::init_console();
+        let result = config::validate(PADDING)
+            .and_then(|()| batch::size_report(&folder, PADDING, TOLERANCE));
+        match result {
+            Ok(report) => {
+                print!("{report}");
+                if report.failures > 0 {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
         }
     }
-}
 
-fn main() -> windows_service::Result<()> {
-    load_env();
+    if let Some(file) = cli.histogram {
+        logging::init_console();
+        let result = config::validate(PADDING).and_then(|()| {
+            let histogram = histogram::analyze(&file, PADDING, TOLERANCE)?;
+            if let Some(out) = &cli.histogram_png {
+                histogram
+                    .render()
+                    .save(out)
+                    .map_err(|e| format!("Failed to write {:?}: {}", out, e))?;
+            }
+            Ok(histogram)
+        });
+        match result {
+            Ok(histogram) => {
+                print!("{histogram}");
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let console_mode = env::var("NI_CONSOLE").is_ok() || env::args().any(|arg| arg == "--console");
+    if cli.status {
+        match config::status_port().and_then(|port| {
+            port.ok_or_else(|| "STATUS_PORT is 0; the status endpoint is disabled".to_string())
+        }) {
+            Ok(port) => match status::query(port) {
+                Ok(response) => {
+                    print!("{}", status::describe(&response));
+                    return Ok(());
+                }
+                Err(e) => eprintln!("{e}"),
+            },
+            Err(e) => eprintln!("{e}"),
+        }
+        std::process::exit(1);
+    }
 
-    if console_mode {
-        if let Err(error) = run_service() {
-            eprintln!("Service encountered a critical error: {error}");
+    if let Some(manifest) = cli.manifest {
+        logging::init_console();
+        let result = config::validate(PADDING)
+            .and_then(|()| batch::run_manifest(&manifest, PADDING, TOLERANCE));
+        match result {
+            Ok(results) => {
+                let failures = results
+                    .iter()
+                    .filter(|(_, status)| matches!(status, batch::RowStatus::Failed(_)))
+                    .count();
+                log::info!("Manifest finished with {} failures.", failures);
+                if failures > 0 {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
         }
-        Ok(())
-    } else {
-        log::info!("Starting service in non-console mode.");
-        service_dispatcher::start("ni-watcher", ffi_service_main)
     }
-}
 
-extern "system" fn ffi_service_main(_argc: u32, _argv: *mut *mut u16) {
-    let _ = run_service();
+    let console_mode = env::var("NI_CONSOLE").is_ok() || cli.console;
+    if let Err(error) = service::run(console_mode, run_service) {
+        eprintln!("Service encountered a critical error: {error}");
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
-fn run_service() -> Result<(), Box<dyn std::error::Error>> {
-    let status_handle = service_control_handler::register("ni-watcher", |control| match control {
-        ServiceControl::Stop => {
-            log::info!("Received Stop command. Shutting down...");
-            SHUTDOWN.store(true, Ordering::SeqCst);
-            ServiceControlHandlerResult::NoError
-        }
-        _ => {
-            log::warn!("Received unsupported service control command.");
-            ServiceControlHandlerResult::NotImplemented
-        }
-    })
-    .ok();
+/// A folder the service watches: `WATCH_FOLDER`, or one per job with `JOBS`.
+struct Watched {
+    /// The job whose settings apply to this folder; `None` for the global settings.
+    job: Option<String>,
+    folder: PathBuf,
+    /// Where its files go in worker mode.
+    pipeline: Option<pipeline::Pipeline>,
+}
 
+fn run_service(host: &dyn service::ServiceHost) -> service::RunResult {
     let exe_dir = current_exe_dir();
-    let watch_dir = PathBuf::from(env::var("WATCH_FOLDER").unwrap_or_else(|_| {
-        log::warn!("WATCH_FOLDER environment variable not set. Using default directory.");
-        exe_dir.join("ni_watch").to_string_lossy().into_owned()
-    }));
-    fs::create_dir_all(&watch_dir).map_err(|e| {
-        log::error!("Failed to create watch directory {:?}: {}", watch_dir, e);
-        e
-    })?;
+    let jobs = config::jobs()?;
+    let mut watched: Vec<Watched> = if jobs.is_empty() {
+        vec![Watched {
+            job: None,
+            folder: config::watch_folder(),
+            pipeline: None,
+        }]
+    } else {
+        jobs.into_iter()
+            .map(|job| Watched {
+                job: Some(job.name),
+                folder: job.watch_folder,
+                pipeline: None,
+            })
+            .collect()
+    };
+    let with_jobs = watched[0].job.is_some();
+    let watch_dir = watched[0].folder.clone();
 
     let log_dir = exe_dir.join("logs");
     fs::create_dir_all(&log_dir).map_err(|e| {
@@ -110,358 +219,1155 @@ fn run_service() -> Result<(), Box<dyn std::error::Error>> {
         e
     })?;
 
-    CombinedLogger::init(vec![WriteLogger::new(
+    // Worker threads are named after their job, so show the thread on every line.
+    let log_config = if with_jobs {
+        simplelog::ConfigBuilder::new()
+            .set_thread_level(LevelFilter::Error)
+            .set_thread_mode(simplelog::ThreadLogMode::Names)
+            .build()
+    } else {
+        simplelog::Config::default()
+    };
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![WriteLogger::new(
         LevelFilter::Info,
-        simplelog::Config::default(),
-        RollingFileLogger::new(&log_dir, 5 * 1024 * 1024, 3),
-    )])?;
-    log::info!("Service initialized. Watching folder: {:?}", watch_dir);
+        log_config,
+        logging::RollingFileLogger::open(
+            &log_dir,
+            5 * 1024 * 1024,
+            3,
+            config::flag("LOG_COMPRESS"),
+        ),
+    )];
+    let eventlog_error = if config::flag("EVENTLOG") {
+        let level = config::parse("EVENTLOG_LEVEL")?.unwrap_or(LevelFilter::Info);
+        add_eventlog_logger(&mut loggers, level).err()
+    } else {
+        None
+    };
+    CombinedLogger::init(loggers)?;
+    if let Some(e) = eventlog_error {
+        log::warn!("Failed to register Windows Event Log source: {}", e);
+    }
 
-    if let Some(handle) = &status_handle {
-        handle.set_service_status(ServiceStatus::running())?;
-        log::info!("Service status set to Running.");
+    // A UNC watch folder may need its share connected before it can be created.
+    let mut connections = Vec::new();
+    for (index, Watched { job, folder, .. }) in watched.iter().enumerate() {
+        let connection = config::with_job(job.as_deref(), || share::Connection::open(folder))
+            .map_err(|e| {
+                log::error!("{}", e);
+                e
+            })?;
+        if let Some(connection) = connection {
+            connections.push((index, connection));
+        }
+    }
+    for Watched { folder, .. } in &watched {
+        fs::create_dir_all(folder).map_err(|e| {
+            log::error!("Failed to create watch directory {:?}: {}", folder, e);
+            e
+        })?;
     }
 
-    let (tx, rx) = channel();
-    let mut watcher = RecommendedWatcher::new(tx, Config::default()).map_err(|e| {
-        log::error!("Failed to initialize file watcher: {}", e);
+    let mut _instance_locks = Vec::new();
+    for Watched { job, folder, .. } in &watched {
+        match instance::InstanceLock::acquire(folder) {
+            Ok(Some(lock)) => _instance_locks.push(lock),
+            Ok(None) => {
+                log::error!(
+                    "Another instance is already watching {:?}; exiting.",
+                    folder
+                );
+                host.set_stopped()?;
+                return Ok(());
+            }
+            Err(e) => {
+                log::error!("Failed to acquire the instance lock: {}", e);
+                return Err(e.into());
+            }
+        }
+        match job {
+            Some(job) => log::info!(
+                "Service initialized. Job {} watching folder: {:?}",
+                job,
+                folder
+            ),
+            None => log::info!("Service initialized. Watching folder: {:?}", folder),
+        }
+    }
+
+    config::validate(PADDING).map_err(|e| {
+        log::error!("Invalid configuration: {}", e);
         e
     })?;
-    watcher.watch(&watch_dir, RecursiveMode::NonRecursive)?;
 
-    while !SHUTDOWN.load(Ordering::SeqCst) {
+    if let Some(list) = &*IGNORE_LIST {
+        list.lock().unwrap().refresh();
+    }
+
+    host.set_running()?;
+
+    if let Some(port) = config::status_port()?
+        && let Err(e) = status::serve(port, watch_dir.clone(), || {
+            PENDING_FILES.lock().unwrap().len()
+        })
+    {
+        log::warn!(
+            "Failed to open the status endpoint on port {}: {}. --status will not reach this instance.",
+            port,
+            e
+        );
+    }
+
+    let (tx, rx) = channel();
+    // Profiles are selected by subfolder, so only descend into the tree when they are used.
+    let recursive_mode = if config::profiles()?.is_empty() {
+        RecursiveMode::NonRecursive
+    } else {
+        RecursiveMode::Recursive
+    };
+    // Every folder has its own watcher, in its job's `WATCH_MODE`; all feed one channel.
+    let mut watchers = Vec::new();
+    for Watched { job, folder, .. } in &watched {
+        let mut watcher = config::with_job(job.as_deref(), || start_watcher(tx.clone()))?;
+        watcher.watch(folder, recursive_mode)?;
+        watchers.push(watcher);
+    }
+    drop(tx);
+
+    let rename_cooldown = config::rename_cooldown()?;
+    let single_thread = config::flag("SINGLE_THREAD");
+    let batch_window = config::batch_window()?;
+    let mut batch_opened = None;
+    let montage_columns = config::montage_columns()?;
+    let mut montage = if config::flag("MONTAGE") {
+        let window = config::montage_window()?;
+        log::info!(
+            "MONTAGE is set; combining files that arrive within {:?} into contact sheets.",
+            window
+        );
+        Some(montage::Collector::new(window))
+    } else {
+        None
+    };
+    let mut workers = Vec::new();
+    if montage.is_some() {
+        priority::apply_to_current_thread();
+    } else if single_thread {
+        log::info!("SINGLE_THREAD is set; processing files in order on the main thread.");
+        priority::apply_to_current_thread();
+    } else {
+        // Jobs split the worker counts between them rather than each taking all of them.
+        let counts = pipeline::WorkerCounts::from_env()?.share(watched.len());
+        for Watched { job, pipeline, .. } in &mut watched {
+            let (started, job_workers) = config::with_job(job.as_deref(), || {
+                config::rate_limit_per_min()
+                    .map(|rate_limit| pipeline::start(counts, rate_limit, PADDING, TOLERANCE))
+            })?;
+            *pipeline = Some(started);
+            workers.push(job_workers);
+        }
+        if let Some(window) = batch_window {
+            log::info!(
+                "BATCH_WINDOW_MS is set; collecting files for {:?} after the first event and processing them as a batch.",
+                window
+            );
+        }
+    }
+    // `BATCH_WINDOW_MS` cannot be combined with `JOBS`, so batches use the only pipeline.
+    let pipeline = watched[0].pipeline.clone();
+    // Without a batch window, one thread hands each file on once its debounce elapsed.
+    let debounce = match &pipeline {
+        Some(_) if batch_window.is_none() => Some(start_debounce(&watched)?),
+        _ => None,
+    };
+
+    for Watched {
+        job,
+        folder,
+        pipeline,
+    } in &watched
+    {
+        config::with_job(job.as_deref(), || {
+            if config::flag("STARTUP_SCAN") {
+                let since = config::scan_since()?;
+                startup_scan(
+                    folder,
+                    recursive_mode == RecursiveMode::Recursive,
+                    since,
+                    pipeline.as_ref(),
+                );
+            }
+            Ok::<_, String>(())
+        })?;
+    }
+
+    let degrade_on_log_failure = config::flag("DEGRADE_ON_LOG_FAILURE");
+    let mut degraded = false;
+    let mut last_metrics_report = Instant::now();
+    let idle_shutdown = config::idle_shutdown()?;
+    let mut last_activity = Instant::now();
+    let mut last_finished = 0;
+    let mut share_check_due = Instant::now() + SHARE_CHECK_INTERVAL;
+
+    while !service::SHUTDOWN.load(Ordering::SeqCst) {
+        if last_metrics_report.elapsed() >= METRICS_REPORT_INTERVAL {
+            last_metrics_report = Instant::now();
+            let snapshot = metrics::METRICS.snapshot();
+            log::info!(
+                "Throughput: {} files in the last minute ({} processed, {} failed since start).",
+                snapshot.per_minute,
+                snapshot.processed,
+                snapshot.failed
+            );
+            summary::roll();
+        }
+
+        if degrade_on_log_failure && degraded != logging::write_failed() {
+            degraded = !degraded;
+            let result = if degraded {
+                host.set_degraded(LOG_FAILURE_EXIT_CODE)
+            } else {
+                host.set_running()
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to update service status: {e}");
+            }
+        }
+
         match rx.recv_timeout(Duration::from_millis(500)) {
             Ok(Ok(event)) => {
-                handle_file_event(event, Duration::from_secs(2));
+                let target = event.paths.first().and_then(|path| {
+                    watched
+                        .iter()
+                        .find(|watched| path.starts_with(&watched.folder))
+                });
+                let target = target.unwrap_or(&watched[0]);
+                config::with_job(target.job.as_deref(), || {
+                    handle_file_event(event, rename_cooldown)
+                });
+            }
+            Ok(Err(e)) => {
+                log::warn!("Error receiving file event: {}", e);
+                // The watcher may have failed because a share dropped; check right away.
+                share_check_due = Instant::now();
             }
-            Ok(Err(e)) => log::warn!("Error receiving file event: {}", e),
             Err(_) => {}
         }
+
+        if !connections.is_empty() && Instant::now() >= share_check_due {
+            share_check_due = Instant::now() + SHARE_CHECK_INTERVAL;
+            for (index, connection) in &mut connections {
+                let Watched {
+                    job,
+                    folder,
+                    pipeline,
+                } = &watched[*index];
+                if connection.check(folder) {
+                    config::with_job(job.as_deref(), || {
+                        rewatch(
+                            watchers[*index].as_mut(),
+                            folder,
+                            recursive_mode,
+                            pipeline.as_ref(),
+                        )
+                    });
+                }
+            }
+        }
+
+        if let Some(collector) = &mut montage {
+            let (archives, files): (Vec<_>, Vec<_>) = take_due_files()
+                .into_iter()
+                .partition(|path| archive::is_archive(path));
+            archives.iter().for_each(|path| process_archive(path));
+            collector.add(files);
+            if let Some(files) = collector.take_due() {
+                write_montage(&files, &watch_dir, montage_columns);
+            }
+        } else if single_thread {
+            process_due_files();
+        } else if let (Some(window), Some(pipeline)) = (batch_window, &pipeline)
+            && let Some(files) = take_batch(&mut batch_opened, window)
+        {
+            run_batch(files, pipeline.clone());
+        }
+        let pending_deletions = processing::delete_due_sources();
+
+        if let Some(idle) = idle_shutdown {
+            let snapshot = metrics::METRICS.snapshot();
+            let finished = snapshot.processed + snapshot.failed;
+            let busy = finished != last_finished
+                || snapshot.in_flight > 0
+                || pending_deletions > 0
+                || !PENDING_FILES.lock().unwrap().is_empty()
+                || montage
+                    .as_ref()
+                    .is_some_and(|collector| !collector.is_empty());
+            if busy {
+                last_finished = finished;
+                last_activity = Instant::now();
+            } else if last_activity.elapsed() >= idle {
+                log::info!("No files for {:?}; shutting down.", idle);
+                service::SHUTDOWN.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    let stopped_at = SystemTime::now();
+    if let Some(files) = montage.as_mut().and_then(montage::Collector::take) {
+        write_montage(&files, &watch_dir, montage_columns);
     }
 
-    if let Some(handle) = &status_handle {
-        handle.set_service_status(ServiceStatus::stopped())?;
-        log::info!("Service status set to Stopped.");
+    drop(pipeline);
+    if let Some(debounce) = debounce
+        && debounce.join().is_err()
+    {
+        log::error!("The debounce thread panicked.");
+    }
+    let folders: Vec<PathBuf> = watched.into_iter().map(|watched| watched.folder).collect();
+    for workers in workers {
+        workers.join();
     }
+    let pending = processing::delete_due_sources();
+    if pending > 0 {
+        log::info!(
+            "Leaving {} processed sources in place since their DELETE_DELAY_SEC has not ended.",
+            pending
+        );
+    }
+    for folder in &folders {
+        if let Err(e) = instance::record_last_run(folder, stopped_at) {
+            log::warn!("Failed to record the end of this run: {}", e);
+        }
+    }
+
+    host.set_stopped()?;
     log::info!("Service has stopped.");
     Ok(())
 }
 
-fn handle_file_event(event: Event, debounce_duration: Duration) {
-    static PENDING_FILES: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
-        Lazy::new(|| Mutex::new(HashMap::new()));
+/// Watches `folder` again after its share was reconnected, since the old watch ended with
+/// the connection. With `STARTUP_SCAN`, files that arrived meanwhile are queued as well.
+fn rewatch(
+    watcher: &mut dyn Watcher,
+    folder: &Path,
+    recursive_mode: RecursiveMode,
+    pipeline: Option<&pipeline::Pipeline>,
+) {
+    let _ = watcher.unwatch(folder);
+    if let Err(e) = watcher.watch(folder, recursive_mode) {
+        log::error!("Failed to watch {:?} again: {}", folder, e);
+        return;
+    }
+    if config::flag("STARTUP_SCAN") {
+        startup_scan(
+            folder,
+            recursive_mode == RecursiveMode::Recursive,
+            config::scan_since().ok().flatten(),
+            pipeline,
+        );
+    }
+}
 
-    match event.kind {
-        EventKind::Create(_) | EventKind::Modify(ModifyKind::Any) => {
-            let paths = event.paths;
-            let now = Instant::now();
+/// Starts a watcher in the `WATCH_MODE` of the current job that sends its events to `tx`.
+fn start_watcher(
+    tx: std::sync::mpsc::Sender<notify::Result<Event>>,
+) -> Result<Box<dyn Watcher>, String> {
+    let watcher: notify::Result<Box<dyn Watcher>> = match config::watch_mode()? {
+        config::WatchMode::Native => {
+            log::info!("Using native file system notifications.");
+            RecommendedWatcher::new(tx, Config::default()).map(|w| Box::new(w) as _)
+        }
+        config::WatchMode::Poll(interval) => {
+            log::info!("Polling the watch folder every {:?}.", interval);
+            PollWatcher::new(tx, Config::default().with_poll_interval(interval))
+                .map(|w| Box::new(w) as _)
+        }
+    };
+    watcher.map_err(|e| {
+        log::error!("Failed to initialize file watcher: {}", e);
+        e.to_string()
+    })
+}
 
-            for path in paths {
-                if should_ignore(&path) || !is_image_file(&path) {
-                    continue;
-                }
+/// Queues the files of `event` in `PENDING_FILES`, where they wait until no event came
+/// for `DEBOUNCE`. The debounce thread, or the main loop outside worker mode, takes them
+/// from there.
+fn handle_file_event(event: Event, rename_cooldown: Duration) {
+    static RENAMED_INTO_PLACE: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
+        Lazy::new(|| Mutex::new(HashMap::new()));
 
-                let mut pending_files = PENDING_FILES.lock().unwrap();
-                pending_files.insert(path.clone(), now);
-
-                let debounce_duration = debounce_duration.clone();
-                let path_clone = path.clone();
-                thread::spawn(move || {
-                    thread::sleep(debounce_duration);
-                    let mut pending_files = PENDING_FILES.lock().unwrap();
-
-                    if let Some(&last_event_time) = pending_files.get(&path_clone) {
-                        if now == last_event_time {
-                            pending_files.remove(&path_clone);
-                            if let Err(err) = process_and_save(&path_clone, (800, 800), 50, 10) {
-                                log::error!("Error processing file {:?}: {}", path_clone, err);
-                            } else {
-                                log::info!("File processed successfully: {:?}", path_clone);
-                            }
-                        }
-                    }
-                });
+    let now = Instant::now();
+
+    // A temp file that was renamed away before its debounce ran must not be processed.
+    let renamed_away = renamed_from(&event);
+    let removed = match event.kind {
+        EventKind::Remove(_) => event.paths.as_slice(),
+        _ => &[],
+    };
+    if config::flag("MIRROR_DELETES") {
+        for path in removed {
+            processing::mirror_delete(path);
+        }
+    }
+    if config::flag("THUMBNAIL_INDEX") {
+        for path in removed.iter().chain(&renamed_away) {
+            if !thumbnails::is_thumbnail(path)
+                && let Err(e) = thumbnails::remove(path)
+            {
+                log::warn!(
+                    "Failed to update the thumbnails of removed {:?}: {}",
+                    path,
+                    e
+                );
             }
         }
-        _ => {
-            log::debug!("Ignoring unrelated event: {:?}", event.kind);
+    }
+    if !renamed_away.is_empty() {
+        let mut pending_files = PENDING_FILES.lock().unwrap();
+        for path in renamed_away {
+            if pending_files.remove(&path).is_some() {
+                log::info!(
+                    "Dropping {:?}: it was renamed away before processing.",
+                    path
+                );
+            }
         }
     }
-}
 
-fn load_env() {
-    let env_file = current_exe_dir().join(".env");
-    match dotenvy::from_path(&env_file) {
-        Ok(_) => println!(".env loaded from {:?}", env_file),
-        Err(e) => println!("Warning: failed to load .env from {:?}: {}", env_file, e),
+    let is_rename = matches!(
+        event.kind,
+        EventKind::Modify(ModifyKind::Name(
+            RenameMode::To | RenameMode::Both | RenameMode::Any
+        ))
+    );
+    let paths = event_paths(event);
+
+    {
+        let mut renamed = RENAMED_INTO_PLACE.lock().unwrap();
+        renamed.retain(|_, at| now.duration_since(*at) < rename_cooldown);
+        if is_rename {
+            for path in &paths {
+                renamed.insert(path.clone(), now);
+            }
+        }
     }
-}
 
-fn current_exe_dir() -> PathBuf {
-    env::current_exe()
-        .ok()
-        .and_then(|path| path.parent().map(PathBuf::from))
-        .expect("Unable to determine the executable directory")
-}
+    for path in paths {
+        if !is_rename && RENAMED_INTO_PLACE.lock().unwrap().contains_key(&path) {
+            log::debug!(
+                "Coalescing {:?} into the rename that just completed it.",
+                path
+            );
+            continue;
+        }
+        if should_ignore(&path) {
+            continue;
+        }
+        if !cfg!(feature = "heif") && processing::is_heif(&path) {
+            log::warn!(
+                "Skipping {:?}: HEIF support is not compiled in (build with --features heif).",
+                path
+            );
+            continue;
+        }
+        if !processing::is_image_file(&path) && !archive::is_archive(&path) {
+            continue;
+        }
 
-fn is_image_file(path: &Path) -> bool {
-    if let Some(ext) = path.extension() {
-        matches!(
-            ext.to_string_lossy().to_ascii_lowercase().as_str(),
-            "png" | "jpg" | "jpeg" | "bmp" | "gif" | "tiff" | "webp"
-        )
-    } else {
-        false
+        PENDING_FILES.lock().unwrap().insert(path, now);
     }
 }
 
-fn should_ignore(path: &Path) -> bool {
-    if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-        if file_name.contains("_tmp") {
-            log::info!("Ignoring temporary file: {:?}", path);
-            return true;
-        }
-        if file_name.contains(".normalized.") {
-            log::info!("Ignoring processed file: {:?}", path);
-            return true;
+/// Worker mode: a single thread hands the files whose debounce has elapsed to the
+/// pipeline of their folder, in the order of their last event. Submitting blocks while a
+/// pipeline is saturated; meanwhile new events only update `PENDING_FILES`, one entry per
+/// path, so a burst of events adds no threads. Archives are unpacked on this thread.
+/// It stops once the service shuts down.
+fn start_debounce(watched: &[Watched]) -> Result<thread::JoinHandle<()>, String> {
+    let routes: Vec<(Option<String>, PathBuf, pipeline::Pipeline)> = watched
+        .iter()
+        .filter_map(|watched| {
+            let pipeline = watched.pipeline.clone()?;
+            Some((watched.job.clone(), watched.folder.clone(), pipeline))
+        })
+        .collect();
+    thread::Builder::new()
+        .name("debounce".to_string())
+        .spawn(move || {
+            while !service::SHUTDOWN.load(Ordering::SeqCst) {
+                for path in take_due_files() {
+                    let (job, _, pipeline) = routes
+                        .iter()
+                        .find(|(_, folder, _)| path.starts_with(folder))
+                        .unwrap_or(&routes[0]);
+                    config::with_job(job.as_deref(), || {
+                        if archive::is_archive(&path) {
+                            priority::apply_to_current_thread();
+                            process_archive(&path);
+                        } else {
+                            pipeline.submit(path);
+                        }
+                    });
+                }
+                thread::sleep(DEBOUNCE_SWEEP);
+            }
+        })
+        .map_err(|e| format!("Failed to start the debounce thread: {}", e))
+}
+
+/// `STARTUP_SCAN`: queues the files already in the watch folder, limited to those modified
+/// after `since`. Everything the watcher would skip is skipped here too. Worker mode hands
+/// the files to the pipeline directly since they are already complete; in `SINGLE_THREAD`
+/// and `MONTAGE` mode they go through `PENDING_FILES` like watcher events.
+fn startup_scan(
+    watch_dir: &Path,
+    recursive: bool,
+    since: Option<config::ScanSince>,
+    pipeline: Option<&pipeline::Pipeline>,
+) {
+    let since = match since {
+        None => None,
+        Some(config::ScanSince::Time(time)) => Some(time),
+        Some(config::ScanSince::LastRun) => match instance::last_run(watch_dir) {
+            Some(time) => Some(time),
+            None => {
+                log::info!(
+                    "SCAN_SINCE=last-run: no earlier clean shutdown is recorded for {:?}; skipping the startup scan.",
+                    watch_dir
+                );
+                return;
+            }
+        },
+    };
+
+    let mut files = Vec::new();
+    let mut folders = vec![watch_dir.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let entries = match fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("Startup scan cannot read {:?}: {}", folder, e);
+                continue;
+            }
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_dir() {
+                if recursive {
+                    folders.push(path);
+                }
+                continue;
+            }
+            if !processing::is_image_file(&path) && !archive::is_archive(&path) {
+                continue;
+            }
+            if let Some(since) = since
+                && !metadata.modified().is_ok_and(|modified| modified > since)
+            {
+                continue;
+            }
+            files.push(path);
         }
     }
+    files.sort();
+    files.retain(|path| !should_ignore(path));
+    log::info!(
+        "Startup scan queued {} files in {:?}.",
+        files.len(),
+        watch_dir
+    );
 
-    let mut recently_processed = RECENTLY_PROCESSED.lock().unwrap();
-    let path_str = path.to_string_lossy().to_string();
+    let now = Instant::now();
+    for path in files {
+        match pipeline {
+            Some(_) if archive::is_archive(&path) => process_archive(&path),
+            Some(pipeline) => pipeline.submit(path),
+            None => {
+                PENDING_FILES.lock().unwrap().insert(path, now);
+            }
+        }
+    }
+}
 
-    if recently_processed.contains(&path_str) {
-        log::info!("Ignoring recently processed file: {:?}", path);
-        return true;
+/// Returns the paths of an event that may hold a finished file:
+/// - `Create(_)`
+/// - `Modify(Any | Data(_) | Other)` and `Modify(Metadata(_))` except access-time changes
+/// - `Modify(Name(To))` and the destination of `Modify(Name(Both))`, so files that
+///   atomic-save editors rename into place are picked up
+/// - `Modify(Name(Any))` for paths that still exist, since backends that cannot tell the
+///   two sides of a rename apart report both that way
+///
+/// `Access(_)`, `Remove(_)` and the source side of renames are ignored here;
+/// `handle_file_event` uses `Remove(_)` for `MIRROR_DELETES`, and both of the latter to
+/// drop thumbnails with `THUMBNAIL_INDEX`.
+fn event_paths(event: Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Any | ModifyKind::Data(_) | ModifyKind::Other)
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event.paths,
+        EventKind::Modify(ModifyKind::Metadata(kind)) if kind != MetadataKind::AccessTime => {
+            event.paths
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            event.paths.into_iter().skip(1).collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => event
+            .paths
+            .into_iter()
+            .filter(|path| path.exists())
+            .collect(),
+        _ => {
+            log::debug!("Ignoring unrelated event: {:?}", event.kind);
+            Vec::new()
+        }
     }
+}
 
-    recently_processed.insert(path_str.clone());
-    thread::spawn(move || {
-        thread::sleep(Duration::from_secs(2));
-        let mut recently_processed = RECENTLY_PROCESSED.lock().unwrap();
-        recently_processed.remove(&path_str);
-    });
+#[cfg(windows)]
+fn add_eventlog_logger(
+    loggers: &mut Vec<Box<dyn SharedLogger>>,
+    level: LevelFilter,
+) -> Result<(), String> {
+    let logger =
+        eventlog::EventLogLogger::new(service::SERVICE_NAME, level, simplelog::Config::default())
+            .map_err(|e| e.to_string())?;
+    loggers.push(logger);
+    Ok(())
+}
 
-    false
+#[cfg(not(windows))]
+fn add_eventlog_logger(
+    _loggers: &mut Vec<Box<dyn SharedLogger>>,
+    _level: LevelFilter,
+) -> Result<(), String> {
+    Err("the Event Log is only available on Windows".to_string())
 }
 
-fn process_and_save(path: &PathBuf, size: (u32, u32), pad: u32, tol: u8) -> Result<(), String> {
-    if !path.exists() {
-        log::error!("File not found: {:?}", path);
-        return Err(format!("File not found: {:?}", path));
+/// `SINGLE_THREAD` mode: processes the files whose debounce has elapsed on the calling
+/// thread, oldest event first, so runs are reproducible.
+fn process_due_files() {
+    // Files stay pending while `LOAD_THRESHOLD` holds them back, so events keep coming in.
+    if load::is_paused() {
+        return;
     }
+    for path in take_due_files() {
+        if archive::is_archive(&path) {
+            process_archive(&path);
+            continue;
+        }
+        metrics::METRICS.record_submitted();
+        match processing::process_and_save(&path, PADDING, TOLERANCE) {
+            Ok(outputs) => {
+                metrics::METRICS.record_processed();
+                log::info!("File processed successfully: {:?}", path);
+                events::emit(&path, Ok(&outputs));
+            }
+            Err(processing::ProcessError::Skipped(reason)) => {
+                metrics::METRICS.record_skipped();
+                events::skipped(&path, &reason);
+            }
+            Err(err) => {
+                metrics::METRICS.record_failed();
+                log::error!("Error processing file {:?}: {}", path, err);
+                events::emit(&path, Err(err.message()));
+            }
+        }
+    }
+}
 
-    let output_ext_lc = env::var("OUTPUT_FORMAT")
-        .unwrap_or_else(|_| "jpg".to_string())
-        .to_lowercase();
+/// `BATCH_WINDOW_MS`: the window opens once a file is pending and, when it closes, every
+/// file whose debounce has elapsed forms the batch. Files still being written stay pending
+/// and open the next window.
+fn take_batch(opened: &mut Option<Instant>, window: Duration) -> Option<Vec<PathBuf>> {
+    if PENDING_FILES.lock().unwrap().is_empty() {
+        return None;
+    }
+    if opened.get_or_insert_with(Instant::now).elapsed() < window {
+        return None;
+    }
+    *opened = None;
+    let files = take_due_files();
+    (!files.is_empty()).then_some(files)
+}
 
-    let format = match output_ext_lc.as_str() {
-        "jpg" | "jpeg" => image::ImageFormat::Jpeg,
-        "png" => image::ImageFormat::Png,
-        "gif" => image::ImageFormat::Gif,
-        "bmp" => image::ImageFormat::Bmp,
-        "tiff" => image::ImageFormat::Tiff,
-        "webp" => image::ImageFormat::WebP,
-        other => {
-            log::error!("Unsupported output format: {}", other);
-            return Err(format!("Unsupported output format: {}", other));
-        }
-    };
+/// Hands a batch to the pipeline on its own thread, so the main loop keeps receiving
+/// events, and logs its progress in steps of a tenth. Batches run one after another. On
+/// shutdown the files not yet handed on are left for the next start.
+fn run_batch(files: Vec<PathBuf>, pipeline: pipeline::Pipeline) {
+    static BATCH: Mutex<()> = Mutex::new(());
 
-    let stem = path
-        .file_stem()
-        .ok_or_else(|| {
-            log::error!("Missing filename stem in {:?}", path);
-            format!("Missing filename stem in {:?}", path)
-        })?
-        .to_str()
-        .ok_or_else(|| {
-            log::error!("Non-UTF8 filename in {:?}", path);
-            format!("Non-UTF8 filename in {:?}", path)
-        })?;
+    let spawned = thread::Builder::new()
+        .name("batch".to_string())
+        .spawn(move || {
+            let _batch = BATCH.lock().unwrap();
+            let total = files.len() as u64;
+            let started = Instant::now();
+            let before = metrics::METRICS.snapshot();
+            let finished = || {
+                let now = metrics::METRICS.snapshot();
+                (now.processed - before.processed, now.failed - before.failed)
+            };
+            let step = (total / 10).max(1);
+            let mut reported = 0;
+            let mut report = || {
+                let (processed, failed) = finished();
+                let done = (processed + failed).min(total);
+                if done / step > reported / step && done < total {
+                    reported = done;
+                    log::info!("Batch progress: {}/{} files finished.", done, total);
+                }
+            };
 
-    log::info!("Processing file: {:?}", path);
-
-    const MAX_RETRIES: u32 = 5;
-    const RETRY_DELAY_MS: u64 = 200;
-    let mut retries = 0;
-
-    let img = loop {
-        match ImageReader::open(path) {
-            Ok(reader) => match reader.decode() {
-                Ok(img) => break img,
-                Err(e) if retries < MAX_RETRIES => {
-                    retries += 1;
-                    log::warn!(
-                        "Failed to decode image {:?} on attempt {}: {}. Retrying...",
-                        path,
-                        retries,
-                        e
+            log::info!("Processing a batch of {} files.", total);
+            let mut submitted = 0;
+            for path in files {
+                if service::SHUTDOWN.load(Ordering::SeqCst) {
+                    log::info!(
+                        "Shutting down; leaving {} files of the batch unprocessed.",
+                        total - submitted
                     );
-                    thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                    break;
                 }
-                Err(e) => {
-                    log::error!(
-                        "Failed to decode image {:?} after {} attempts: {}",
-                        path,
-                        retries,
-                        e
-                    );
-                    return Err(format!("Failed to decode image {:?}: {}", path, e));
+                submitted += 1;
+                if archive::is_archive(&path) {
+                    priority::apply_to_current_thread();
+                    process_archive(&path);
+                } else {
+                    pipeline.submit(path);
                 }
-            },
-            Err(e) if retries < MAX_RETRIES => {
-                retries += 1;
-                log::warn!(
-                    "Failed to open image {:?} on attempt {}: {}. Retrying...",
-                    path,
-                    retries,
-                    e
-                );
-                thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                report();
             }
-            Err(e) => {
-                log::error!(
-                    "Failed to open image {:?} after {} attempts: {}",
-                    path,
-                    retries,
-                    e
-                );
-                return Err(format!("Failed to open image {:?}: {}", path, e));
+            drop(pipeline);
+            loop {
+                let (processed, failed) = finished();
+                if processed + failed >= submitted || metrics::METRICS.snapshot().in_flight == 0 {
+                    break;
+                }
+                report();
+                thread::sleep(Duration::from_millis(200));
             }
-        }
-    };
+            let (processed, failed) = finished();
+            log::info!(
+                "Batch of {} files finished in {:.1?}: {} processed, {} failed.",
+                total,
+                started.elapsed(),
+                processed,
+                failed
+            );
+        });
+    if let Err(e) = spawned {
+        log::error!("Failed to start the batch thread: {}", e);
+    }
+}
 
-    let processed_image = process_image(img, size, pad, tol);
-    log::info!("Image processed successfully: {:?}", path);
+/// `HANDLE_ZIP`: normalizes the images of an archive on the calling thread. Archives
+/// bypass the pipeline since each one expands into many files.
+fn process_archive(path: &Path) {
+    metrics::METRICS.record_submitted();
+    match archive::process(path, PADDING, TOLERANCE) {
+        Ok(outputs) => {
+            metrics::METRICS.record_processed();
+            log::info!(
+                "Archive processed successfully: {:?} ({} outputs)",
+                path,
+                outputs.len()
+            );
+            events::emit(path, Ok(&outputs));
+        }
+        Err(err) => {
+            metrics::METRICS.record_failed();
+            log::error!("Error processing archive {:?}: {}", path, err);
+            events::emit(path, Err(&err));
+        }
+    }
+}
 
-    let tmp_filename = format!("{}.normalized.{}", stem, &output_ext_lc);
-    let tmp_path = path.with_file_name(&tmp_filename);
+/// `MONTAGE` mode: writes the files collected in one window as a contact sheet.
+fn write_montage(files: &[PathBuf], folder: &Path, columns: u32) {
+    metrics::METRICS.record_submitted();
+    match montage::compose(files, folder, columns, PADDING, TOLERANCE) {
+        Ok(sheet) => {
+            metrics::METRICS.record_processed();
+            for file in files {
+                events::emit(file, Ok(std::slice::from_ref(&sheet)));
+            }
+        }
+        Err(err) => {
+            metrics::METRICS.record_failed();
+            log::error!("Error writing contact sheet of {:?}: {}", files, err);
+            for file in files {
+                events::emit(file, Err(&err));
+            }
+        }
+    }
+}
 
-    let tmp_file = fs::File::create(&tmp_path)
-        .map_err(|e| format!("Failed to create temp file {:?}: {}", tmp_path, e))?;
+/// Removes the files whose debounce has elapsed from `PENDING_FILES` and returns them,
+/// oldest event first.
+fn take_due_files() -> Vec<PathBuf> {
+    let mut due: Vec<(PathBuf, Instant)> = {
+        let mut pending_files = PENDING_FILES.lock().unwrap();
+        let due: Vec<_> = pending_files
+            .iter()
+            .filter(|(_, last_event)| last_event.elapsed() >= DEBOUNCE)
+            .map(|(path, last_event)| (path.clone(), *last_event))
+            .collect();
+        for (path, _) in &due {
+            pending_files.remove(path);
+        }
+        due
+    };
+    due.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    due.into_iter().map(|(path, _)| path).collect()
+}
 
-    if let Err(e) = processed_image.write_to(&mut std::io::BufWriter::new(tmp_file), format) {
-        log::error!("Failed to write image in {:?} format: {}", format, e);
-        return Err(format!("Failed to write image to {:?}: {}", tmp_path, e));
+/// Returns the old paths of renames: `Modify(Name(From))`, the source of
+/// `Modify(Name(Both))`, and paths of `Modify(Name(Any))` that no longer exist.
+fn renamed_from(event: &Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::From)) => event.paths.clone(),
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            event.paths.iter().take(1).cloned().collect()
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => event
+            .paths
+            .iter()
+            .filter(|path| !path.exists())
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
     }
+}
 
-    log::info!("Temporary processed image saved: {:?}", tmp_path);
-
-    let final_filename = format!("{}.{}", stem, &output_ext_lc);
-    let final_path = path.with_file_name(&final_filename);
+fn load_env() {
+    let env_file = current_exe_dir().join(".env");
+    match dotenvy::from_path(&env_file) {
+        Ok(_) => eprintln!(".env loaded from {:?}", env_file),
+        Err(e) => eprintln!("Warning: failed to load .env from {:?}: {}", env_file, e),
+    }
+}
 
-    fs::rename(&tmp_path, &final_path)
-        .map_err(|e| format!("Failed to rename to {:?}: {}", final_path, e))?;
+/// Prints every setting as resolved from the environment and `.env`, with defaults
+/// applied and secrets redacted, as JSON on stdout.
+fn print_config() {
+    let mut target_sizes = serde_json::Map::new();
+    for ext in config::OUTPUT_FORMATS {
+        let size = config::target_size(ext).map(|(w, h)| json!({ "width": w, "height": h }));
+        target_sizes.insert(ext.to_string(), config::describe(size));
+    }
+    let workers = pipeline::WorkerCounts::from_env().map(|counts| {
+        json!({ "decode": counts.decode, "process": counts.process, "encode": counts.encode })
+    });
 
-    log::info!("Final processed image saved: {:?}", final_path);
+    let effective = json!({
+        "PRESET": config::describe(config::preset().map(|p| p.map(|p| p.name))),
+        "WATCH_FOLDER": config::watch_folder().to_string_lossy(),
+        "WATCH_MODE": config::describe(config::watch_mode().map(|mode| format!("{:?}", mode))),
+        "SHARE_USER": config::var("SHARE_USER"),
+        "SHARE_PASSWORD": config::redacted("SHARE_PASSWORD"),
+        "IGNORE_LIST_FILE": config::describe(config::ignore_list_file().map(|file| file.map(|file| file.to_string_lossy().into_owned()))),
+        "STARTUP_SCAN": config::flag("STARTUP_SCAN"),
+        "SCAN_SINCE": config::describe(config::scan_since().map(|since| match since {
+            None => serde_json::Value::Null,
+            Some(config::ScanSince::LastRun) => "last-run".into(),
+            Some(config::ScanSince::Time(time)) => format!(
+                "{}s after the Unix epoch",
+                time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+            )
+            .into(),
+        })),
+        "IMAGE_EXTENSIONS": config::describe(config::image_extensions()),
+        "SNIFF_EXTENSIONLESS": config::flag("SNIFF_EXTENSIONLESS"),
+        "OUTPUT_FORMAT": config::output_format(),
+        "REPLACE_IN_PLACE": config::describe(config::replace_in_place()),
+        "MASTER_FORMAT": config::describe(config::master().map(|m| m.map(|m| m.ext))),
+        "MASTER_SUFFIX": config::describe(config::master().map(|m| m.map(|m| m.suffix))),
+        "PREVIEW_SIZE": config::describe(config::preview_size()),
+        "FALLBACK_FORMAT": config::describe(config::fallback_format()),
+        "JPEG_QUALITY": config::describe(config::jpeg_quality()),
+        "JPEG_SUBSAMPLING": config::describe(config::jpeg_subsampling().map(|s| s.name())),
+        "GIF_SPEED": config::describe(config::gif_options().map(|gif| gif.speed)),
+        "GIF_TRANSPARENCY": config::describe(config::gif_options().map(|gif| gif.transparency)),
+        "OUTPUT_DPI": config::describe(config::output_dpi()),
+        "CMYK_JPEG": config::describe(config::cmyk_jpeg().map(|c| format!("{:?}", c).to_lowercase())),
+        "CONVERT_TO_SRGB": config::flag("CONVERT_TO_SRGB"),
+        "OUTPUT_NAMING": config::describe(config::output_naming().map(|n| format!("{:?}", n).to_lowercase())),
+        "SEQUENCE_WIDTH": config::describe(config::sequence().map(|sequence| sequence.width)),
+        "SEQUENCE_START": config::describe(config::sequence().map(|sequence| sequence.start)),
+        "ON_CONFLICT": config::describe(config::on_conflict().map(config::OnConflict::name)),
+        "TARGET_SIZE": target_sizes,
+        "MAX_OUTPUT_PIXELS": config::describe(config::max_output_pixels()),
+        "SIZES": config::describe(
+            config::target_size(&config::output_format())
+                .and_then(|base| config::sizes(base, config::padding(PADDING)?))
+                .map(|sizes| sizes.into_iter()
+                    .map(|s| json!({ "width": s.size.0, "height": s.size.1, "padding": s.pad }))
+                    .collect::<Vec<_>>())
+        ),
+        "PROFILES": config::describe(config::profiles().map(|profiles| {
+            profiles.into_iter().map(|p| json!({ "name": p.name, "pattern": p.pattern })).collect::<Vec<_>>()
+        })),
+        "JOBS": config::describe(config::jobs().map(|jobs| {
+            jobs.into_iter().map(|j| json!({ "name": j.name, "watch_folder": j.watch_folder })).collect::<Vec<_>>()
+        })),
+        "PADDING": config::describe(config::padding(PADDING)),
+        "PADDING_OVERFLOW": config::describe(config::padding_overflow().map(|o| format!("{:?}", o).to_lowercase())),
+        "TOLERANCE": TOLERANCE,
+        "CROP_SIDES": config::describe(config::crop_sides().map(|s| format!("{:?}", s))),
+        "CROP_MARGIN_PX": config::describe(config::crop_margin()),
+        "SCREENSHOT_MODE": config::describe(config::screenshot_mode().map(|m| format!("{:?}", m).to_lowercase())),
+        "CHANNEL_TOLERANCE": config::describe(config::channel_tolerance().map(|c| c.map(|c| format!("{:?}", c)))),
+        "ADAPTIVE_TOLERANCE": config::describe(config::adaptive_tolerance()),
+        "NOISE_TOLERANCE": config::describe(config::noise_tolerance()),
+        "DESKEW": config::describe(config::deskew()),
+        "BACKGROUND": config::describe(config::background().map(config::Background::name)),
+        "MASK": config::describe(config::mask().map(|m| format!("{:?}", m).to_lowercase())),
+        "BIT_DEPTH": config::describe(config::bit_depth().map(config::BitDepth::bits)),
+        "SHARPEN": config::describe(config::sharpen().map(|s| s.map(|s| json!({ "amount": s.amount, "radius": s.radius })))),
+        "GRAVITY": config::describe(config::gravity().map(|g| format!("{:?}", g).to_lowercase())),
+        "ALIGN": config::describe(config::align().map(|a| match a {
+            config::Align::BoundingBox => "bbox",
+            config::Align::Centroid => "centroid",
+        })),
+        "CENTER_REMAINDER": config::describe(config::center_remainder().map(|r| format!("{:?}", r).to_lowercase())),
+        "SPEC_FILES": config::flag("SPEC_FILES"),
+        "TWO_PASS_DOWNSCALE": config::flag("TWO_PASS_DOWNSCALE"),
+        "FAST_PATH_PIXELS": config::describe(config::fast_path().map(|f| f.map(|f| f.pixels))),
+        "FAST_PATH_FILTER": config::describe(config::fast_path().map(|f| f.map(|f| f.filter.name()))),
+        "FEATHER_EDGES": config::flag("FEATHER_EDGES"),
+        "MIN_CONTENT_RATIO": config::describe(config::min_content_ratio()),
+        "EMPTY_IMAGE_ACTION": config::describe(config::quarantine_empty_images()
+            .map(|quarantine| if quarantine { "quarantine" } else { "skip" })),
+        "MIN_INPUT_DIMENSION": config::describe(config::min_input_dimension()),
+        "SMALL_IMAGE_ACTION": config::describe(config::quarantine_small_images()
+            .map(|quarantine| if quarantine { "quarantine" } else { "skip" })),
+        "QUARANTINE_FOLDER": config::var("QUARANTINE_FOLDER"),
+        "TEMP_DIR": config::var("TEMP_DIR"),
+        "PRESERVE_MTIME": config::flag("PRESERVE_MTIME"),
+        "PRESERVE_PERMISSIONS": config::flag("PRESERVE_PERMISSIONS"),
+        "EMBED_PARAMS": config::flag("EMBED_PARAMS"),
+        "READ_ONLY_SOURCES": config::describe(config::read_only_sources().map(|r| format!("{:?}", r).to_lowercase())),
+        "DELETE_DELAY_SEC": config::describe(config::delete_delay().map(|d| d.as_secs())),
+        "CHECK_TRUNCATION": config::flag("CHECK_TRUNCATION"),
+        "VERIFY_OUTPUT": config::flag("VERIFY_OUTPUT"),
+        "IN_PLACE_SAFE": config::flag("IN_PLACE_SAFE"),
+        "RENAME_COOLDOWN_MS": config::describe(config::rename_cooldown().map(|d| d.as_millis() as u64)),
+        "RENAME_RETRIES": config::describe(config::rename_retries()),
+        "RENAME_RETRY_DELAY_MS": config::describe(config::rename_retry_delay_ms()),
+        "PROCESS_RETRIES": config::describe(config::process_retries()),
+        "PROCESS_RETRY_DELAY_MS": config::describe(config::process_retry_delay_ms()),
+        "OUTPUT_TARGET": config::describe(config::output_target().map(|t| format!("{:?}", t))),
+        "OUTPUT_PIPE": config::var("OUTPUT_PIPE"),
+        "OUTPUT_PIPE_WAIT_MS": config::describe(config::output_pipe_wait().map(|d| d.as_millis() as u64)),
+        "S3_BUCKET": config::var("S3_BUCKET"),
+        "S3_PREFIX": config::var("S3_PREFIX"),
+        "S3_ENDPOINT": config::var("S3_ENDPOINT"),
+        "AWS_REGION": config::var("AWS_REGION"),
+        "AWS_ACCESS_KEY_ID": config::redacted("AWS_ACCESS_KEY_ID"),
+        "AWS_SECRET_ACCESS_KEY": config::redacted("AWS_SECRET_ACCESS_KEY"),
+        "PDF_DPI": config::describe(config::parse::<f32>("PDF_DPI")),
+        "WORKERS": config::describe(workers),
+        "RATE_LIMIT_PER_MIN": config::describe(config::rate_limit_per_min()),
+        "LOAD_THRESHOLD": config::describe(config::load_threshold()),
+        "LOAD_SAMPLE_MS": config::describe(config::load_sample().map(|d| d.as_millis() as u64)),
+        "MAX_OPEN_FILES": config::describe(config::max_open_files()),
+        "DECODE_THREADS": config::describe(config::decode_threads()),
+        "SINGLE_THREAD": config::flag("SINGLE_THREAD"),
+        "THREAD_PRIORITY": config::describe(config::thread_priority().map(|p| format!("{:?}", p).to_lowercase())),
+        "HANDLE_ZIP": config::flag("HANDLE_ZIP"),
+        "ZIP_REPACK": config::flag("ZIP_REPACK"),
+        "ZIP_MAX_BYTES": config::describe(config::zip_max_bytes()),
+        "MONTAGE": config::flag("MONTAGE"),
+        "MONTAGE_WINDOW_MS": config::describe(config::montage_window().map(|d| d.as_millis() as u64)),
+        "BATCH_WINDOW_MS": config::describe(config::batch_window().map(|d| d.map(|d| d.as_millis() as u64))),
+        "MONTAGE_COLUMNS": config::describe(config::montage_columns()),
+        "THUMBNAIL_INDEX": config::describe(config::thumbnail_index()),
+        "MIRROR_DELETES": config::flag("MIRROR_DELETES"),
+        "LOG_COMPRESS": config::flag("LOG_COMPRESS"),
+        "DAILY_SUMMARY": config::flag("DAILY_SUMMARY"),
+        "STDOUT_EVENTS": config::flag("STDOUT_EVENTS"),
+        "POST_PROCESS_CMD": config::describe(config::post_process_cmd()),
+        "EVENTLOG": config::flag("EVENTLOG"),
+        "EVENTLOG_LEVEL": config::describe(config::parse::<LevelFilter>("EVENTLOG_LEVEL")
+            .map(|level| level.unwrap_or(LevelFilter::Info).to_string())),
+        "DEGRADE_ON_LOG_FAILURE": config::flag("DEGRADE_ON_LOG_FAILURE"),
+        "RECENTLY_PROCESSED_MS": config::describe(config::recently_processed_window().map(|d| d.as_millis() as u64)),
+        "RECENTLY_PROCESSED_MAX": config::describe(config::recently_processed_max()),
+        "IDLE_SHUTDOWN_SEC": config::describe(config::idle_shutdown().map(|d| d.map(|d| d.as_secs()))),
+        "STATUS_PORT": config::describe(config::status_port()),
+    });
+    match serde_json::to_string_pretty(&effective) {
+        Ok(text) => println!("{text}"),
+        Err(e) => eprintln!("Failed to render configuration: {e}"),
+    }
+}
 
-    if path.as_path() != final_path && path.exists() {
-        log::info!(
-            "Removing original file {:?} due to output being saved separately as {:?}",
-            path,
-            final_path
-        );
-        fs::remove_file(path)
-            .map_err(|e| format!("Failed to remove original file {:?}: {}", path, e))?;
-    } else {
-        log::info!(
-            "Original file {:?} has been replaced by processed file {:?}",
-            path,
-            final_path
-        );
+/// What this build can do, independent of the configuration: the formats it decodes and
+/// encodes, the optional features compiled in and the values each mode setting accepts.
+fn print_capabilities() {
+    let mut decodable: Vec<&str> = image::ImageFormat::all()
+        .filter(|format| format.reading_enabled())
+        .flat_map(|format| format.extensions_str().iter().copied())
+        .collect();
+    if cfg!(feature = "pdf") {
+        decodable.push("pdf");
+    }
+    if cfg!(feature = "heif") {
+        decodable.extend(["heic", "heif"]);
+    }
+    let mut output_targets = vec!["local", "pipe"];
+    if cfg!(feature = "s3") {
+        output_targets.extend(["s3", "both"]);
     }
 
-    log::info!("Processing complete for {:?}", path);
-    Ok(())
+    let capabilities = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": {
+            "pdf": cfg!(feature = "pdf"),
+            "heif": cfg!(feature = "heif"),
+            "s3": cfg!(feature = "s3"),
+        },
+        "input_formats": decodable,
+        "default_image_extensions": config::with_values(&HashMap::new(), config::image_extensions)
+            .unwrap_or_default(),
+        "output_formats": config::OUTPUT_FORMATS,
+        "output_targets": output_targets,
+        "fit_modes": ["contain"],
+        "resize_filters": ["gaussian", "nearest", "triangle", "catmull-rom", "lanczos3"],
+        "modes": {
+            "CROP_SIDES": ["left", "top", "right", "bottom", "all"],
+            "SCREENSHOT_MODE": ["off", "auto", "always"],
+            "GRAVITY": ["center", "top", "bottom", "left", "right"],
+            "ALIGN": ["bbox", "centroid"],
+            "CENTER_REMAINDER": ["end", "start"],
+            "BACKGROUND": ["white", "transparent", "auto", "RRGGBB"],
+            "PADDING_OVERFLOW": ["error", "clamp"],
+            "MASK": ["none", "circle"],
+            "BIT_DEPTH": [8, 16],
+            "JPEG_SUBSAMPLING": ["444", "422", "420"],
+            "OUTPUT_NAMING": ["stem", "hash", "sequence"],
+            "ON_CONFLICT": ["keep-extension", "overwrite", "skip", "rename"],
+            "THREAD_PRIORITY": ["normal", "low"],
+            "CMYK_JPEG": ["convert", "skip", "quarantine"],
+            "PRESET": presets::PRESETS.iter().map(|preset| preset.name).collect::<Vec<_>>(),
+        },
+    });
+    match serde_json::to_string_pretty(&capabilities) {
+        Ok(text) => println!("{text}"),
+        Err(e) => eprintln!("Failed to render capabilities: {e}"),
+    }
 }
 
-fn process_image(img: DynamicImage, size: (u32, u32), pad: u32, tol: u8) -> DynamicImage {
-    let gray = img.to_luma8();
-    let (l, t, r, b) = bounding_box(&gray, tol);
-    let cropped = img.crop_imm(l, t, r - l, b - t);
-
-    let target_size = (size.0 - 2 * pad, size.1 - 2 * pad);
-    let (w, h) = cropped.dimensions();
-    let (new_width, new_height) = if w > h {
-        let scale = target_size.0 as f32 / w as f32;
-        (target_size.0, (h as f32 * scale) as u32)
-    } else {
-        let scale = target_size.1 as f32 / h as f32;
-        ((w as f32 * scale) as u32, target_size.1)
-    };
+fn should_ignore(path: &Path) -> bool {
+    if processing::file_name_contains(path, "_tmp") {
+        log::info!("Ignoring temporary file: {:?}", path);
+        return true;
+    }
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("spec"))
+    {
+        log::debug!("Ignoring spec file: {:?}", path);
+        return true;
+    }
+    if processing::file_name_contains(path, ".normalized.") {
+        log::info!("Ignoring processed file: {:?}", path);
+        return true;
+    }
+    if processing::is_master(path) {
+        log::debug!("Ignoring master: {:?}", path);
+        return true;
+    }
+    if processing::is_preview(path) {
+        log::debug!("Ignoring preview: {:?}", path);
+        return true;
+    }
+    if processing::is_sequence_output(path) {
+        log::debug!("Ignoring sequence output: {:?}", path);
+        return true;
+    }
+    if config::flag("THUMBNAIL_INDEX") && thumbnails::is_thumbnail(path) {
+        log::debug!("Ignoring thumbnail: {:?}", path);
+        return true;
+    }
 
-    let resized = cropped.resize_exact(new_width, new_height, FilterType::Gaussian);
+    if let Some(list) = &*IGNORE_LIST
+        && list.lock().unwrap().contains(path)
+    {
+        log::info!("Ignoring file listed in IGNORE_LIST_FILE: {:?}", path);
+        return true;
+    }
 
-    let mut canvas = DynamicImage::new_rgb8(size.0, size.1);
-    for x in 0..size.0 {
-        for y in 0..size.1 {
-            canvas.put_pixel(x, y, Rgba([255, 255, 255, 255]));
-        }
+    if processing::is_quarantined(path) {
+        log::info!("Ignoring quarantined file: {:?}", path);
+        return true;
     }
 
-    let offset_x = (size.0 - new_width) / 2;
-    let offset_y = (size.1 - new_height) / 2;
+    if processing::is_recent_output(path) {
+        log::info!("Ignoring output written by the service: {:?}", path);
+        return true;
+    }
 
-    canvas.copy_from(&resized, offset_x, offset_y).unwrap();
-    canvas
+    if !RECENTLY_PROCESSED
+        .lock()
+        .unwrap()
+        .insert(path, Instant::now())
+    {
+        log::info!("Ignoring recently processed file: {:?}", path);
+        return true;
+    }
+    false
 }
 
-fn bounding_box(img: &image::GrayImage, tol: u8) -> (u32, u32, u32, u32) {
-    let (width, height) = img.dimensions();
-    let threshold = 255 - tol;
-    let (mut left, mut right, mut top, mut bottom) = (width, 0, height, 0);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange};
 
-    for y in 0..height {
-        for x in 0..width {
-            if img.get_pixel(x, y)[0] < threshold {
-                left = left.min(x);
-                right = right.max(x);
-                top = top.min(y);
-                bottom = bottom.max(y);
-            }
-        }
+    fn event(kind: EventKind, paths: &[&Path]) -> Event {
+        paths.iter().fold(Event::new(kind), |event, path| {
+            event.add_path(path.to_path_buf())
+        })
     }
-    (
-        left.min(width - 1),
-        top.min(height - 1),
-        (right + 1).min(width),
-        (bottom + 1).min(height),
-    )
-}
 
-struct RollingFileLogger;
-
-impl RollingFileLogger {
-    fn new(base: &PathBuf, max_size: usize, max_files: usize) -> std::fs::File {
-        let current_log = base.join("log0.txt");
-        if current_log.exists() {
-            if let Ok(metadata) = fs::metadata(&current_log) {
-                if metadata.len() as usize >= max_size {
-                    Self::rotate(base, max_files);
-                }
-            }
-        }
-        Self::cleanup(base, max_files);
-        OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&current_log)
-            .expect("Failed to open log file")
+    fn pending_since(path: &Path) -> Option<Instant> {
+        PENDING_FILES.lock().unwrap().get(path).copied()
     }
 
-    fn rotate(base: &PathBuf, max_files: usize) {
-        for i in (0..max_files).rev() {
-            let src = base.join(format!("log{i}.txt"));
-            let dst = base.join(format!("log{}.txt", i + 1));
-            if src.exists() {
-                let _ = fs::rename(src, dst);
-            }
-        }
+    #[test]
+    fn atomic_save_burst_is_processed_once_after_the_rename() {
+        let dir = env::temp_dir().join(format!("ni-watcher-test-burst-{}", std::process::id()));
+        let temp = dir.join(".~photo.jpg");
+        let target = dir.join("photo.jpg");
+        let cooldown = Duration::from_secs(60);
+        let handle = |event| handle_file_event(event, cooldown);
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+
+        handle(event(EventKind::Create(CreateKind::File), &[&temp]));
+        handle(event(data, &[&temp]));
+        assert!(pending_since(&temp).is_some());
+
+        let rename = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        handle(event(rename, &[&temp, &target]));
+        assert_eq!(pending_since(&temp), None);
+        let renamed_at = pending_since(&target).expect("the rename queues the target");
+
+        // The editor touches the file once more after the rename.
+        handle(event(data, &[&target]));
+        assert_eq!(pending_since(&target), Some(renamed_at));
+        PENDING_FILES.lock().unwrap().remove(&target);
     }
 
-    fn cleanup(base: &PathBuf, max_files: usize) {
-        let oldest_log = base.join(format!("log{}.txt", max_files));
-        let _ = fs::remove_file(oldest_log);
+    #[test]
+    fn numeric_sources_are_picked_up_with_sequence_naming() {
+        let dir = env::temp_dir().join(format!("ni-watcher-test-sku-{}", std::process::id()));
+        let values = HashMap::from([("OUTPUT_NAMING".to_string(), "sequence".to_string())]);
+        // A SKU-named source was never handed out as a number, so it is not an output.
+        let sku = dir.join("12345.jpg");
+        assert!(!config::with_values(&values, || should_ignore(&sku)));
     }
 }
@@ -0,0 +1,88 @@
+use once_cell::sync::Lazy;
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Process-wide counters updated by the pipeline stages.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Default)]
+pub struct Metrics {
+    submitted: AtomicU64,
+    processed: AtomicU64,
+    failed: AtomicU64,
+    skipped: AtomicU64,
+    /// Completion times within the last minute, for the current rate.
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+/// A point-in-time copy of the counters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub processed: u64,
+    pub failed: u64,
+    /// Files left in place on purpose, e.g. because they are already normalized.
+    pub skipped: u64,
+    /// Files processed successfully during the last minute.
+    pub per_minute: usize,
+    /// Files handed to processing that have not finished yet.
+    pub in_flight: u64,
+}
+
+impl Metrics {
+    /// Counts a file entering processing; it stays in flight until it is recorded as
+    /// processed, failed or skipped.
+    pub fn record_submitted(&self) {
+        self.submitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_processed(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        prune(&mut recent, now);
+        recent.push_back(now);
+    }
+
+    pub fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut recent = self.recent.lock().unwrap();
+        prune(&mut recent, Instant::now());
+        let processed = self.processed.load(Ordering::Relaxed);
+        let failed = self.failed.load(Ordering::Relaxed);
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        Snapshot {
+            processed,
+            failed,
+            skipped,
+            per_minute: recent.len(),
+            in_flight: self
+                .submitted
+                .load(Ordering::Relaxed)
+                .saturating_sub(processed + failed + skipped),
+        }
+    }
+}
+
+fn prune(recent: &mut VecDeque<Instant>, now: Instant) {
+    while recent
+        .front()
+        .is_some_and(|&at| now.duration_since(at) >= RATE_WINDOW)
+    {
+        recent.pop_front();
+    }
+}
@@ -0,0 +1,142 @@
+//! `MONTAGE` mode: files that arrive within a window are normalized one by one and laid
+//! out on a grid as a single contact sheet instead of being saved individually.
+
+use image::{DynamicImage, GenericImage, GenericImageView, Rgb, Rgba};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, processing};
+
+/// Collects files for the next contact sheet. The window opens with the first file and
+/// closes `window` later; files arriving after that start the next sheet.
+pub struct Collector {
+    window: Duration,
+    opened: Option<Instant>,
+    files: Vec<PathBuf>,
+}
+
+impl Collector {
+    pub fn new(window: Duration) -> Self {
+        Collector {
+            window,
+            opened: None,
+            files: Vec::new(),
+        }
+    }
+
+    /// Adds files in arrival order, opening the window if none is open.
+    pub fn add(&mut self, files: impl IntoIterator<Item = PathBuf>) {
+        for file in files {
+            if self.files.contains(&file) {
+                continue;
+            }
+            self.opened.get_or_insert_with(Instant::now);
+            self.files.push(file);
+        }
+    }
+
+    /// Returns the collected files once the window has closed and resets the collector.
+    pub fn take_due(&mut self) -> Option<Vec<PathBuf>> {
+        let opened = self.opened?;
+        if opened.elapsed() < self.window {
+            return None;
+        }
+        self.take()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Returns the collected files regardless of the window, e.g. on shutdown.
+    pub fn take(&mut self) -> Option<Vec<PathBuf>> {
+        self.opened = None;
+        if self.files.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.files))
+        }
+    }
+}
+
+/// Normalizes `files` with the current settings and writes them, in order, as a grid of
+/// `columns` cells per row into `folder`. Each cell is the normalized image, so the cell
+/// size is the target size. Files that fail are logged and left out of the sheet; the
+/// sources of the others are removed once the sheet is saved. Returns the sheet's path.
+pub fn compose(
+    files: &[PathBuf],
+    folder: &Path,
+    columns: u32,
+    pad: u32,
+    tol: u8,
+) -> Result<PathBuf, String> {
+    let mut cells = Vec::new();
+    for path in files {
+        match processing::catch_panic(path, || {
+            processing::decode(path, pad, tol).map(processing::process)
+        }) {
+            Ok(job) => cells.push(job),
+            Err(processing::ProcessError::Skipped(reason)) => {
+                log::info!("Leaving {:?} out of the contact sheet: {}", path, reason)
+            }
+            Err(e) => log::error!("Leaving {:?} out of the contact sheet: {}", path, e),
+        }
+    }
+    let Some(first) = cells.first() else {
+        return Err("None of the collected files could be processed".to_string());
+    };
+    let background = first.output.normalize.background;
+
+    // Cells share the target size unless profiles or spec files change it per image.
+    let cell = cells.iter().fold((1, 1), |(w, h), job| {
+        (w.max(job.image.width()), h.max(job.image.height()))
+    });
+    let count = cells.len() as u32;
+    let grid = (columns.min(count), count.div_ceil(columns));
+    let (width, height) = (cell.0 * grid.0, cell.1 * grid.1);
+    // With `BACKGROUND=auto` every cell has its own fill, so the gaps stay white.
+    let mut sheet = match background {
+        config::Background::White | config::Background::Auto => DynamicImage::ImageRgb8(
+            image::RgbImage::from_pixel(width, height, Rgb([255, 255, 255])),
+        ),
+        config::Background::Color(color) => {
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(width, height, Rgb(color)))
+        }
+        config::Background::Transparent => {
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, Rgba([0; 4])))
+        }
+    };
+
+    for (index, job) in cells.iter().enumerate() {
+        let (column, row) = (index as u32 % columns, index as u32 / columns);
+        let (w, h) = job.image.dimensions();
+        let x = column * cell.0 + (cell.0 - w) / 2;
+        let y = row * cell.1 + (cell.1 - h) / 2;
+        sheet
+            .copy_from(&job.image, x, y)
+            .map_err(|e| format!("Failed to place {:?} on the contact sheet: {}", job.path, e))?;
+    }
+
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis());
+    let sheet_path = folder.join(format!("montage-{}.{}", stamp, first.output.ext));
+    processing::save_output(&sheet, &sheet_path, &first.output)?;
+    log::info!(
+        "Contact sheet of {} images ({}x{} grid) saved: {:?}",
+        count,
+        grid.0,
+        grid.1,
+        sheet_path
+    );
+
+    for job in &cells {
+        if let Err(e) = fs::remove_file(&job.path) {
+            log::warn!("Failed to remove {:?} after montage: {}", job.path, e);
+        }
+    }
+    Ok(sheet_path)
+}
@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+use crate::processing::{self, ProcessError};
+
+/// Async counterpart of [`processing::process_and_save`] for callers running on a tokio
+/// runtime. Decoding, normalizing and encoding run on the blocking thread pool so the
+/// caller's executor threads stay free.
+pub async fn process_file(path: PathBuf, pad: u32, tol: u8) -> Result<Vec<PathBuf>, ProcessError> {
+    // Fail fast on a missing source without tying up a blocking thread.
+    let metadata = tokio::fs::metadata(&path)
+        .await
+        .map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    if !metadata.is_file() {
+        return Err(format!("{:?} is not a file", path).into());
+    }
+
+    tokio::task::spawn_blocking(move || processing::process_and_save(&path, pad, tol))
+        .await
+        .map_err(|e| ProcessError::Permanent(format!("Processing task failed: {}", e)))?
+}
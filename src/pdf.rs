@@ -0,0 +1,54 @@
+use image::{DynamicImage, RgbaImage};
+use once_cell::sync::Lazy;
+use pdfium_render::prelude::*;
+use std::path::Path;
+
+use crate::config;
+
+const DEFAULT_PDF_DPI: f32 = 150.0;
+
+/// Pdfium can only be bound once per process, so the binding is shared by all workers.
+static PDFIUM: Lazy<Result<Pdfium, String>> = Lazy::new(|| {
+    let library_path = Pdfium::pdfium_platform_library_name_at_path(&crate::current_exe_dir());
+    Pdfium::bind_to_library(library_path)
+        .or_else(|_| Pdfium::bind_to_system_library())
+        .map(Pdfium::new)
+        .map_err(|e| format!("Failed to load the pdfium library: {}", e))
+});
+
+pub fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("pdf"))
+}
+
+/// Renders the first page of a PDF at `PDF_DPI` (default 150).
+pub fn rasterize_first_page(path: &Path) -> Result<DynamicImage, String> {
+    let dpi = config::parse::<f32>("PDF_DPI")?.unwrap_or(DEFAULT_PDF_DPI);
+    if dpi <= 0.0 {
+        return Err(format!("PDF_DPI must be positive, got {}", dpi));
+    }
+
+    let pdfium = PDFIUM.as_ref().map_err(|e| {
+        log::error!("{}", e);
+        e.clone()
+    })?;
+
+    let document = pdfium
+        .load_pdf_from_file(path, None)
+        .map_err(|e| format!("Failed to open PDF {:?}: {}", path, e))?;
+    let page = document
+        .pages()
+        .get(0)
+        .map_err(|e| format!("PDF {:?} has no first page: {}", path, e))?;
+
+    log::info!("Rasterizing first page of {:?} at {} DPI", path, dpi);
+    let render_config = PdfRenderConfig::new().scale_page_by_factor(dpi / 72.0);
+    let bitmap = page
+        .render_with_config(&render_config)
+        .map_err(|e| format!("Failed to render PDF {:?}: {}", path, e))?;
+
+    let (width, height) = (bitmap.width() as u32, bitmap.height() as u32);
+    RgbaImage::from_raw(width, height, bitmap.as_rgba_bytes())
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| format!("Rendered PDF page of {:?} has an unexpected size", path))
+}
@@ -0,0 +1,104 @@
+//! `OUTPUT_TARGET=pipe`: streams each encoded output into `OUTPUT_PIPE`, a FIFO on Unix
+//! or a named pipe (`\\.\pipe\<name>`) on Windows that a consumer process reads from.
+//!
+//! Every image is written through its own connection: the pipe is opened, the encoded bytes
+//! are written and it is closed again, so the reader sees end-of-file after each image.
+//! Writes from concurrent workers are serialized so images never interleave.
+
+use once_cell::sync::Lazy;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::config;
+
+/// How often a missing reader is looked for again.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Held while an image is written, so one image is complete before the next begins.
+static WRITER: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Writes `bytes` as one image to `OUTPUT_PIPE`. Waits up to `OUTPUT_PIPE_WAIT_MS` for a
+/// reader to connect; once connected, a slow reader slows the workers down rather than
+/// buffering images in memory. A reader that goes away mid-image fails the write.
+pub fn send(bytes: &[u8]) -> Result<(), String> {
+    let path = config::output_pipe()?;
+    let wait = config::output_pipe_wait()?;
+    let _writer = WRITER.lock().unwrap();
+
+    let started = Instant::now();
+    let mut pipe = loop {
+        match connect(&path) {
+            Ok(Some(pipe)) => break pipe,
+            Ok(None) if started.elapsed() < wait => thread::sleep(RETRY_INTERVAL),
+            Ok(None) => {
+                return Err(format!(
+                    "No reader connected to {:?} within {:?}",
+                    path, wait
+                ));
+            }
+            Err(e) => return Err(format!("Failed to open {:?}: {}", path, e)),
+        }
+    };
+    pipe.write_all(bytes)
+        .and_then(|()| pipe.flush())
+        .map_err(|e| format!("Failed to write to {:?}: {}", path, e))
+}
+
+/// Opens the write end of a FIFO without blocking, returning `Ok(None)` while it does
+/// not exist or no reader has it open. The returned handle blocks on writes again, which
+/// is the backpressure.
+#[cfg(unix)]
+fn connect(path: &Path) -> io::Result<Option<File>> {
+    use std::{
+        fs::OpenOptions,
+        os::fd::AsRawFd,
+        os::unix::fs::{FileTypeExt, OpenOptionsExt},
+    };
+
+    let file = match OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !file.metadata()?.file_type().is_fifo() {
+        return Err(io::Error::other("not a FIFO; create it with mkfifo"));
+    }
+    // SAFETY: `file` owns a valid descriptor for the duration of both calls.
+    unsafe {
+        let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+        if flags < 0 || libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(Some(file))
+}
+
+/// Connects to a named pipe as a client, returning `Ok(None)` while the server has not
+/// created it yet or all its instances are busy.
+#[cfg(windows)]
+fn connect(path: &Path) -> io::Result<Option<File>> {
+    use windows_sys::Win32::Foundation::{ERROR_FILE_NOT_FOUND, ERROR_PIPE_BUSY};
+
+    match File::options().write(true).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e)
+            if e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND as i32)
+                || e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,271 @@
+use crossbeam_channel::{Sender, bounded};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{config, events, load, metrics::METRICS, priority, processing};
+
+/// Number of worker threads per stage, from `DECODE_WORKERS`, `PROCESS_WORKERS` and
+/// `ENCODE_WORKERS`.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkerCounts {
+    pub decode: usize,
+    pub process: usize,
+    pub encode: usize,
+}
+
+impl WorkerCounts {
+    pub fn from_env() -> Result<Self, String> {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(WorkerCounts {
+            decode: worker_count("DECODE_WORKERS", 2)?,
+            process: worker_count("PROCESS_WORKERS", cores)?,
+            encode: worker_count("ENCODE_WORKERS", 2)?,
+        })
+    }
+
+    /// One of `jobs` equal shares of these counts, for `JOBS` pipelines that share the
+    /// thread budget. Every stage keeps at least one worker.
+    pub fn share(self, jobs: usize) -> Self {
+        let share = |count: usize| (count / jobs.max(1)).max(1);
+        WorkerCounts {
+            decode: share(self.decode),
+            process: share(self.process),
+            encode: share(self.encode),
+        }
+    }
+}
+
+fn worker_count(key: &str, default: usize) -> Result<usize, String> {
+    match config::parse::<usize>(key)? {
+        Some(0) => Err(format!("{} must be at least 1", key)),
+        Some(count) => Ok(count),
+        None => Ok(default),
+    }
+}
+
+/// Token bucket that limits how many files enter the decode stage per minute. It holds
+/// at most one second's worth of tokens, so bursts after an idle period stay short.
+struct RateLimiter {
+    per_second: f64,
+    capacity: f64,
+    /// Available tokens and when they were last refilled.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_minute: u32) -> Self {
+        let per_second = f64::from(per_minute) / 60.0;
+        let capacity = per_second.max(1.0);
+        RateLimiter {
+            per_second,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    /// Blocks until a token is available. Files wait in the queue meanwhile, so the
+    /// watcher applies backpressure instead of dropping them.
+    fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, refilled) = &mut *state;
+                let now = Instant::now();
+                *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * self.per_second)
+                    .min(self.capacity);
+                *refilled = now;
+                if *tokens >= 1.0 {
+                    *tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - *tokens) / self.per_second)
+            };
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Entry point of the decode -> process -> encode pipeline. Stages are connected by
+/// bounded channels sized to the next stage's worker count, so a slow encoder applies
+/// backpressure instead of letting decoded images pile up in memory.
+#[derive(Clone)]
+pub struct Pipeline {
+    sender: Sender<PathBuf>,
+}
+
+impl Pipeline {
+    /// Queues a file for processing, blocking while the decode stage is saturated.
+    pub fn submit(&self, path: PathBuf) {
+        METRICS.record_submitted();
+        if let Err(e) = self.sender.send(path) {
+            METRICS.record_failed();
+            log::warn!("Pipeline is shut down; dropping {:?}", e.into_inner());
+        }
+    }
+}
+
+pub struct Workers {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Waits for in-flight files to finish once every `Pipeline` handle has been dropped.
+    pub fn join(self) {
+        for handle in self.handles {
+            if handle.join().is_err() {
+                log::error!("A pipeline worker panicked.");
+            }
+        }
+    }
+}
+
+/// Starts the worker threads. With `rate_limit` set, decode workers take a token per
+/// file so at most that many files per minute are processed. With `LOAD_THRESHOLD`, they
+/// also wait while the CPU is busier than that before taking the next file.
+pub fn start(
+    counts: WorkerCounts,
+    rate_limit: Option<u32>,
+    pad: u32,
+    tol: u8,
+) -> (Pipeline, Workers) {
+    let limiter = rate_limit.map(|per_minute| Arc::new(RateLimiter::new(per_minute)));
+    let (path_tx, path_rx) = bounded::<PathBuf>(counts.decode);
+    let (decoded_tx, decoded_rx) = bounded::<processing::Job>(counts.process);
+    let (processed_tx, processed_rx) = bounded::<processing::Job>(counts.encode);
+    let mut handles = Vec::new();
+
+    for i in 0..counts.decode {
+        let (rx, tx) = (path_rx.clone(), decoded_tx.clone());
+        let limiter = limiter.clone();
+        handles.push(spawn(format!("decode-{i}"), move || {
+            for path in rx {
+                load::wait_until_below_threshold();
+                if let Some(limiter) = &limiter {
+                    limiter.acquire();
+                }
+                match processing::catch_panic(&path, || processing::decode(&path, pad, tol)) {
+                    Ok(job) => {
+                        if tx.send(job).is_err() {
+                            break;
+                        }
+                    }
+                    Err(processing::ProcessError::Skipped(reason)) => {
+                        METRICS.record_skipped();
+                        events::skipped(&path, &reason);
+                    }
+                    Err(err) => {
+                        METRICS.record_failed();
+                        log::error!("Error processing file {:?}: {}", path, err);
+                        events::emit(&path, Err(err.message()));
+                    }
+                }
+            }
+        }));
+    }
+
+    for i in 0..counts.process {
+        let (rx, tx) = (decoded_rx.clone(), processed_tx.clone());
+        handles.push(spawn(format!("process-{i}"), move || {
+            for job in rx {
+                let path = job.path.clone();
+                match processing::catch_panic(&path, || Ok::<_, String>(processing::process(job))) {
+                    Ok(job) => {
+                        if tx.send(job).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        METRICS.record_failed();
+                        events::emit(&path, Err(&err));
+                    }
+                }
+            }
+        }));
+    }
+
+    for i in 0..counts.encode {
+        let rx = processed_rx.clone();
+        handles.push(spawn(format!("encode-{i}"), move || {
+            for job in rx {
+                let path = job.path.clone();
+                let result = processing::catch_panic(&path, || processing::encode(job));
+                match processing::retry_transient(&path, pad, tol, result) {
+                    Ok(outputs) => {
+                        METRICS.record_processed();
+                        log::info!("File processed successfully: {:?}", path);
+                        events::emit(&path, Ok(&outputs));
+                    }
+                    Err(processing::ProcessError::Skipped(reason)) => {
+                        METRICS.record_skipped();
+                        events::skipped(&path, &reason);
+                    }
+                    Err(err) => {
+                        METRICS.record_failed();
+                        log::error!("Error processing file {:?}: {}", path, err);
+                        events::emit(&path, Err(err.message()));
+                    }
+                }
+            }
+        }));
+    }
+
+    log::info!(
+        "Pipeline started with {} decode, {} process and {} encode workers.",
+        counts.decode,
+        counts.process,
+        counts.encode
+    );
+    if let Some(per_minute) = rate_limit {
+        log::info!("Limiting processing to {} files per minute.", per_minute);
+    }
+    (Pipeline { sender: path_tx }, Workers { handles })
+}
+
+/// Starts a worker in the job of the calling thread, if any, named after that job.
+fn spawn(name: String, work: impl FnOnce() + Send + 'static) -> JoinHandle<()> {
+    let job = config::current_job();
+    let name = match &job {
+        Some(job) => format!("{}-{}", job.to_ascii_lowercase(), name),
+        None => name,
+    };
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            config::with_job(job.as_deref(), || {
+                priority::apply_to_current_thread();
+                work()
+            })
+        })
+        .expect("Failed to spawn pipeline worker")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_lets_one_second_through_then_paces_files() {
+        // 1200 per minute: a burst of 20 files, then one every 50 ms.
+        let limiter = RateLimiter::new(1200);
+        let start = Instant::now();
+        for _ in 0..20 {
+            limiter.acquire();
+        }
+        let burst = start.elapsed();
+        for _ in 0..4 {
+            limiter.acquire();
+        }
+        let total = start.elapsed();
+
+        assert!(burst < Duration::from_millis(150), "burst took {:?}", burst);
+        assert!(
+            total >= Duration::from_millis(195),
+            "24 files took {:?}",
+            total
+        );
+    }
+}
@@ -0,0 +1,49 @@
+/// A named set of setting values selected with `PRESET`. Individual settings in the
+/// environment, on the command line or in a profile still take precedence.
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub values: &'static [(&'static str, &'static str)],
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "amazon",
+        description: "1000x1000 JPEG at quality 85 on white, as marketplace main images",
+        values: &[
+            ("TARGET_WIDTH", "1000"),
+            ("TARGET_HEIGHT", "1000"),
+            ("OUTPUT_FORMAT", "jpg"),
+            ("JPEG_QUALITY", "85"),
+            ("BACKGROUND", "white"),
+        ],
+    },
+    Preset {
+        name: "avatar",
+        description: "256x256 PNG cut into a circle on a transparent background",
+        values: &[
+            ("TARGET_WIDTH", "256"),
+            ("TARGET_HEIGHT", "256"),
+            ("PADDING", "16"),
+            ("OUTPUT_FORMAT", "png"),
+            ("BACKGROUND", "transparent"),
+            ("MASK", "circle"),
+        ],
+    },
+];
+
+/// Looks up a preset by name, case-insensitively.
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.name.eq_ignore_ascii_case(name))
+}
+
+/// The value a preset assigns to `key`, if any.
+pub fn value(name: &str, key: &str) -> Option<String> {
+    find(name)?
+        .values
+        .iter()
+        .find(|(preset_key, _)| *preset_key == key)
+        .map(|(_, value)| value.to_string())
+}
@@ -0,0 +1,61 @@
+//! `THREAD_PRIORITY=low`: runs the threads that process images below normal priority, so
+//! a busy watch folder does not starve interactive work on a shared machine. Files take
+//! longer under load but nothing else changes; on an idle machine they run at full speed.
+
+use std::{io, thread};
+
+use crate::config;
+
+/// Nice value of low-priority threads on Unix, the same as `nice` applies by default.
+#[cfg(unix)]
+const NICE: libc::c_int = 10;
+
+/// Lowers the priority of the calling thread when `THREAD_PRIORITY=low`. A failure is
+/// logged and the thread keeps its priority.
+pub fn apply_to_current_thread() {
+    if !matches!(config::thread_priority(), Ok(config::ThreadPriority::Low)) {
+        return;
+    }
+    if let Err(e) = lower() {
+        log::warn!(
+            "Failed to lower the priority of thread {:?}: {}",
+            thread::current().name().unwrap_or("main"),
+            e
+        );
+    }
+}
+
+#[cfg(windows)]
+fn lower() -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_BELOW_NORMAL,
+    };
+
+    // SAFETY: the pseudo handle returned by `GetCurrentThread` is always valid.
+    if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Linux keeps a nice value per thread, addressed by the thread id.
+#[cfg(target_os = "linux")]
+fn lower() -> io::Result<()> {
+    // SAFETY: `gettid` cannot fail and `setpriority` only reads its arguments.
+    let result =
+        unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as libc::id_t, NICE) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Other Unix systems only have a nice value per process, so the whole service is lowered.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn lower() -> io::Result<()> {
+    // SAFETY: `setpriority` only reads its arguments.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, NICE) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,3725 @@
+use filetime::FileTime;
+use image::codecs::{
+    gif::GifEncoder,
+    jpeg::{JpegEncoder, PixelDensity},
+};
+use image::io::Reader as ImageReader;
+use image::{
+    DynamicImage, Frame, GenericImageView, ImageBuffer, ImageFormat, Pixel, Primitive, Rgb, Rgba,
+    RgbaImage,
+    imageops::{self, FilterType},
+};
+use once_cell::sync::Lazy;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, Read, Seek, SeekFrom},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(feature = "heif")]
+use crate::heif;
+#[cfg(feature = "pdf")]
+use crate::pdf;
+#[cfg(feature = "s3")]
+use crate::s3;
+use crate::{cmyk, color, config, pipe, sequence, thumbnails};
+
+/// How long an output written by the service is ignored by the watcher.
+const OUTPUT_SUPPRESSION: Duration = Duration::from_secs(10);
+
+/// Outputs written recently, so the watcher does not feed them back in as new sources.
+static RECENT_OUTPUTS: Lazy<Mutex<HashMap<PathBuf, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn mark_output(path: &Path) {
+    let mut outputs = RECENT_OUTPUTS.lock().unwrap();
+    outputs.retain(|_, written| written.elapsed() < OUTPUT_SUPPRESSION);
+    outputs.insert(path.to_path_buf(), Instant::now());
+}
+
+pub fn is_recent_output(path: &Path) -> bool {
+    let outputs = RECENT_OUTPUTS.lock().unwrap();
+    outputs
+        .get(path)
+        .is_some_and(|written| written.elapsed() < OUTPUT_SUPPRESSION)
+}
+
+/// Files currently being read or written, bounded by `MAX_OPEN_FILES`.
+static OPEN_FILES: Lazy<(Mutex<usize>, Condvar)> = Lazy::new(|| (Mutex::new(0), Condvar::new()));
+
+/// A slot in `OPEN_FILES`, released when dropped.
+struct FileSlot(bool);
+
+impl Drop for FileSlot {
+    fn drop(&mut self) {
+        if self.0 {
+            let (open, released) = &*OPEN_FILES;
+            *open.lock().unwrap() -= 1;
+            released.notify_one();
+        }
+    }
+}
+
+/// Waits until fewer than `MAX_OPEN_FILES` files are being read or written. Slots are
+/// taken once per stage that touches the disk, never nested, so a limit of 1 cannot
+/// deadlock.
+fn file_slot() -> Result<FileSlot, String> {
+    let Some(limit) = config::max_open_files()? else {
+        return Ok(FileSlot(false));
+    };
+    let (open, released) = &*OPEN_FILES;
+    let mut open = released
+        .wait_while(open.lock().unwrap(), |open| *open >= limit)
+        .unwrap();
+    *open += 1;
+    Ok(FileSlot(true))
+}
+
+/// Geometry applied by `process`.
+#[derive(Clone, Copy, Debug)]
+pub struct NormalizeOptions {
+    pub size: (u32, u32),
+    pub pad: u32,
+    /// `PADDING_OVERFLOW`: whether `normalize` reduces a padding that leaves too little room
+    /// for the content.
+    pub padding_overflow: config::PaddingOverflow,
+    pub tol: u8,
+    pub crop_sides: config::CropSides,
+    /// `CROP_MARGIN_PX`: source pixels kept around the detected content.
+    pub crop_margin: u32,
+    pub channel_tolerance: Option<config::ChannelTolerance>,
+    pub adaptive_tolerance: bool,
+    /// `NOISE_TOLERANCE`: content clusters with fewer pixels are left out of the crop box.
+    pub noise_tolerance: Option<u32>,
+    /// `DESKEW`: largest rotation in degrees applied to straighten skewed content.
+    pub deskew: Option<f32>,
+    pub gravity: config::Gravity,
+    pub align: config::Align,
+    pub center_remainder: config::CenterRemainder,
+    pub two_pass_downscale: bool,
+    /// Filter of the final resize: Gaussian, or `FAST_PATH_FILTER` for large sources.
+    pub filter: config::ResizeFilter,
+    pub feather_edges: bool,
+    pub background: config::Background,
+    pub mask: config::Mask,
+    pub bit_depth: config::BitDepth,
+    pub sharpen: Option<config::Sharpen>,
+}
+
+/// Output settings resolved for one source file when it enters the pipeline.
+#[derive(Clone)]
+pub struct OutputSettings {
+    pub stem: OsString,
+    pub ext: String,
+    pub format: ImageFormat,
+    /// `FALLBACK_FORMAT`: extension and format written when encoding to `format` fails.
+    pub fallback: Option<(String, ImageFormat)>,
+    /// `MASTER_FORMAT`: a lossless master written next to each output.
+    pub master: Option<config::Master>,
+    pub jpeg_quality: u8,
+    pub jpeg_subsampling: config::JpegSubsampling,
+    pub gif: config::GifOptions,
+    pub dpi: Option<u16>,
+    pub naming: config::OutputNaming,
+    pub target: config::OutputTarget,
+    pub normalize: NormalizeOptions,
+    /// Extra renditions requested by `SIZES`; empty for a single output of
+    /// `normalize.size`.
+    pub sizes: Vec<config::OutputSize>,
+    /// Modification time of the source, captured before processing when `PRESERVE_MTIME`
+    /// is enabled since the source may be gone by the time the output is written.
+    pub source_mtime: Option<FileTime>,
+    /// Mode and owner of the source, captured like `source_mtime` when
+    /// `PRESERVE_PERMISSIONS` is enabled on Unix.
+    pub source_permissions: Option<SourcePermissions>,
+    /// Profile selected by the source's folder. The encode stage re-applies it for the
+    /// settings it reads itself, such as `TEMP_DIR` and `S3_PREFIX`.
+    pub profile: Option<String>,
+}
+
+/// Permission bits and owner of a source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourcePermissions {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// A source file moving through the decode, process and encode stages.
+pub struct Job {
+    pub path: PathBuf,
+    pub image: DynamicImage,
+    /// One processed image per entry of `output.sizes`, keyed by width.
+    pub renditions: Vec<(u32, DynamicImage)>,
+    pub output: OutputSettings,
+}
+
+/// Per-image overrides from a `<stem>.spec` JSON file next to the source, e.g.
+/// `{"width": 1200, "height": 900, "format": "png", "padding": 20}`.
+#[derive(Debug, Default)]
+struct Spec {
+    width: Option<u32>,
+    height: Option<u32>,
+    format: Option<String>,
+    padding: Option<u32>,
+}
+
+/// Reads the companion spec of `path`, if there is one. Unknown keys are rejected so a
+/// typo does not silently fall back to the global settings.
+fn read_spec(path: &Path) -> Result<Spec, String> {
+    let spec_path = path.with_extension("spec");
+    let text = match fs::read_to_string(&spec_path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Spec::default()),
+        Err(e) => return Err(format!("Failed to read spec {:?}: {}", spec_path, e)),
+    };
+    let value: serde_json::Value = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid JSON in spec {:?}: {}", spec_path, e))?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| format!("Spec {:?} must be a JSON object", spec_path))?;
+
+    let number = |key: &str, value: &serde_json::Value| {
+        value
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| format!("{} in spec {:?} must be a positive integer", key, spec_path))
+    };
+    let mut spec = Spec::default();
+    for (key, value) in object {
+        match key.as_str() {
+            "width" => spec.width = Some(number(key, value)?),
+            "height" => spec.height = Some(number(key, value)?),
+            "padding" => spec.padding = Some(number(key, value)?),
+            "format" => {
+                let format = value
+                    .as_str()
+                    .ok_or_else(|| format!("format in spec {:?} must be a string", spec_path))?;
+                spec.format = Some(format.to_lowercase());
+            }
+            other => return Err(format!("Unknown key {:?} in spec {:?}", other, spec_path)),
+        }
+    }
+    log::info!("Applying spec {:?}: {:?}", spec_path, spec);
+    Ok(spec)
+}
+
+/// Decode stage: resolves the output settings for `path` under the profile of its folder
+/// and loads the source image. With `THUMBNAIL_INDEX` sources are never quarantined.
+pub fn decode(path: &Path, pad: u32, tol: u8) -> Result<Job, ProcessError> {
+    decode_in_profile(path, pad, tol, config::flag("THUMBNAIL_INDEX"))
+}
+
+/// Runs the decode and process stages and encodes the result in memory without writing,
+/// moving or quarantining anything. Returns the encoded outputs, one per size.
+pub fn dry_run(path: &Path, pad: u32, tol: u8) -> Result<Vec<Vec<u8>>, String> {
+    catch_panic(path, || {
+        let job = process(decode_in_profile(path, pad, tol, true)?);
+        let images: Vec<&DynamicImage> = if job.renditions.is_empty() {
+            vec![&job.image]
+        } else {
+            job.renditions.iter().map(|(_, image)| image).collect()
+        };
+        images
+            .into_iter()
+            .map(|image| encode_output(image, path, &job.output).map(|(encoded, ..)| encoded))
+            .collect()
+    })
+}
+
+/// Decode stage only, without side effects: the source as the process stage would
+/// receive it, with the settings of its profile and spec file.
+pub(crate) fn inspect(path: &Path, pad: u32, tol: u8) -> Result<Job, String> {
+    catch_panic(path, || {
+        decode_in_profile(path, pad, tol, true).map_err(String::from)
+    })
+}
+
+fn decode_in_profile(path: &Path, pad: u32, tol: u8, dry_run: bool) -> Result<Job, ProcessError> {
+    let profile = config::profile_for(path)?;
+    if let Some(name) = &profile {
+        log::info!("Using profile {} for {:?}", name, path);
+    }
+    config::with_profile(profile.as_deref(), || {
+        decode_with_settings(path, pad, tol, profile.clone(), dry_run)
+    })
+}
+
+fn decode_with_settings(
+    path: &Path,
+    pad: u32,
+    tol: u8,
+    profile: Option<String>,
+    dry_run: bool,
+) -> Result<Job, ProcessError> {
+    if !path.exists() {
+        log::error!("File not found: {:?}", path);
+        return Err(format!("File not found: {:?}", path).into());
+    }
+    let _slot = file_slot()?;
+
+    let spec = if config::flag("SPEC_FILES") {
+        read_spec(path)?
+    } else {
+        Spec::default()
+    };
+
+    let replace_in_place = config::replace_in_place()?;
+    let (output_ext_lc, format) = if replace_in_place {
+        if spec.format.is_some() {
+            return Err(format!(
+                "The spec file of {:?} sets a format, which REPLACE_IN_PLACE does not allow",
+                path
+            )
+            .into());
+        }
+        in_place_format(path)?
+    } else {
+        let output_ext_lc = spec.format.clone().unwrap_or_else(config::output_format);
+        let Some(format) = image_format(&output_ext_lc) else {
+            log::error!("Unsupported output format: {}", output_ext_lc);
+            return Err(format!("Unsupported output format: {}", output_ext_lc).into());
+        };
+        (output_ext_lc, format)
+    };
+    let fallback = config::fallback_format()?
+        .filter(|ext| *ext != output_ext_lc)
+        .and_then(|ext| image_format(&ext).map(|format| (ext, format)));
+
+    let (width, height) = config::target_size(&output_ext_lc).map_err(|e| {
+        log::error!("{}", e);
+        e
+    })?;
+    let size = (spec.width.unwrap_or(width), spec.height.unwrap_or(height));
+    let pad = match spec.padding {
+        Some(padding) => padding,
+        None => config::padding(pad)?,
+    };
+    let mut sizes = config::sizes(size, pad)?;
+    let (size, mut pad) = match config::max_output_pixels()? {
+        Some(max) => within_pixel_budget(path, size, pad, max),
+        None => (size, pad),
+    };
+    if size.0 == 0 || size.1 == 0 {
+        return Err(format!(
+            "Target size {}x{} for {:?} has no pixels",
+            size.0, size.1, path
+        )
+        .into());
+    }
+    let padding_overflow = config::padding_overflow()?;
+    match padding_overflow {
+        config::PaddingOverflow::Error if size.0.min(size.1) <= pad.saturating_mul(2) => {
+            return Err(format!(
+                "Target size {}x{} for {:?} must exceed twice the padding ({})",
+                size.0, size.1, path, pad
+            )
+            .into());
+        }
+        config::PaddingOverflow::Error => {}
+        config::PaddingOverflow::Clamp => {
+            pad = clamp_padding(path, size, pad);
+            for output_size in &mut sizes {
+                output_size.pad = clamp_padding(path, output_size.size, output_size.pad);
+            }
+        }
+    }
+    let mut normalize = NormalizeOptions {
+        size,
+        pad,
+        padding_overflow,
+        tol,
+        crop_sides: config::crop_sides()?,
+        crop_margin: config::crop_margin()?,
+        channel_tolerance: config::channel_tolerance()?,
+        adaptive_tolerance: config::adaptive_tolerance()?,
+        noise_tolerance: config::noise_tolerance()?,
+        deskew: config::deskew()?,
+        gravity: config::gravity()?,
+        align: config::align()?,
+        center_remainder: config::center_remainder()?,
+        two_pass_downscale: config::flag("TWO_PASS_DOWNSCALE"),
+        filter: config::ResizeFilter::Gaussian,
+        feather_edges: config::flag("FEATHER_EDGES"),
+        background: config::background()?,
+        mask: config::mask()?,
+        bit_depth: config::bit_depth()?,
+        sharpen: config::sharpen()?,
+    };
+    let master = config::master()?;
+    let master_format = master
+        .as_ref()
+        .and_then(|master| image_format(&master.ext).map(|format| (&master.ext, format)));
+    for (ext, format) in std::iter::once((&output_ext_lc, format))
+        .chain(fallback.as_ref().map(|(ext, format)| (ext, *format)))
+        .chain(master_format)
+    {
+        if normalize.background == config::Background::Transparent
+            && matches!(format, ImageFormat::Jpeg | ImageFormat::Bmp)
+        {
+            return Err(format!(
+                "BACKGROUND=transparent needs an output format with transparency, not {}",
+                ext
+            )
+            .into());
+        }
+        if normalize.bit_depth == config::BitDepth::Sixteen
+            && !matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+        {
+            return Err(format!("BIT_DEPTH=16 needs png or tiff output, not {}", ext).into());
+        }
+    }
+    let target = config::output_target()?;
+
+    let stem = path
+        .file_stem()
+        .ok_or_else(|| {
+            log::error!("Missing filename stem in {:?}", path);
+            format!("Missing filename stem in {:?}", path)
+        })?
+        .to_os_string();
+
+    let source_mtime = if config::flag("PRESERVE_MTIME") {
+        let metadata = fs::metadata(path)
+            .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+        Some(FileTime::from_last_modification_time(&metadata))
+    } else {
+        None
+    };
+
+    let source_permissions = if config::flag("PRESERVE_PERMISSIONS") {
+        source_permissions(path)?
+    } else {
+        None
+    };
+
+    log::info!("Processing file: {:?}", path);
+
+    let min_dimension = config::min_input_dimension()?;
+    let probed = min_dimension.and_then(|_| probe_dimensions(path));
+    if let (Some(min), Some(dimensions)) = (min_dimension, probed) {
+        check_min_dimension(path, dimensions, min, dry_run)?;
+    }
+
+    if cmyk::probe(path).is_some() {
+        let action = config::cmyk_jpeg()?;
+        if action != config::CmykJpeg::Convert {
+            let reason = format!(
+                "Image is a CMYK JPEG, which CMYK_JPEG={} leaves unconverted",
+                format!("{:?}", action).to_lowercase()
+            );
+            log::warn!("{:?}: {}", path, reason);
+            if action == config::CmykJpeg::Skip {
+                return Err(ProcessError::Skipped(reason));
+            }
+            if !dry_run {
+                quarantine(path, &reason)?;
+            }
+            return Err(reason.into());
+        }
+    }
+
+    let img = load_source(path)?;
+    if img.width() == 0 || img.height() == 0 {
+        return Err(format!("Image {:?} has no pixels", path).into());
+    }
+    let img = if config::flag("CONVERT_TO_SRGB") {
+        color::to_srgb(path, img)
+    } else {
+        img
+    };
+
+    // PDF and HEIF sources cannot be probed and are checked once they are decoded.
+    if let (Some(min), None) = (min_dimension, probed) {
+        check_min_dimension(path, img.dimensions(), min, dry_run)?;
+    }
+
+    if let Some(fast) = config::fast_path()? {
+        let pixels = u64::from(img.width()) * u64::from(img.height());
+        if pixels > fast.pixels {
+            normalize.filter = fast.filter;
+            log::debug!(
+                "{:?}: {} pixels is above FAST_PATH_PIXELS ({}); taking the fast path ({} filter)",
+                path,
+                pixels,
+                fast.pixels,
+                fast.filter.name()
+            );
+        } else {
+            log::debug!(
+                "{:?}: {} pixels is within FAST_PATH_PIXELS ({}); taking the quality path",
+                path,
+                pixels,
+                fast.pixels
+            );
+        }
+    }
+
+    let screenshot = match config::screenshot_mode()? {
+        config::ScreenshotMode::Off => false,
+        config::ScreenshotMode::Always => true,
+        config::ScreenshotMode::Auto => looks_like_screenshot(path, &img, normalize.tol),
+    };
+    if screenshot {
+        log::info!("Keeping the full frame of screenshot {:?}", path);
+        normalize.crop_sides = config::CropSides::NONE;
+    }
+
+    if let Some(min_ratio) = config::min_content_ratio()? {
+        let ratio = content_ratio(&img, &normalize);
+        if ratio < min_ratio {
+            let reason = format!(
+                "Content covers {:.2}% of the image, below MIN_CONTENT_RATIO ({:.2}%); treating it as empty",
+                ratio * 100.0,
+                min_ratio * 100.0
+            );
+            log::warn!("{:?}: {}", path, reason);
+            if !config::quarantine_empty_images()? {
+                return Err(ProcessError::Skipped(reason));
+            }
+            if !dry_run {
+                quarantine(path, &reason)?;
+            }
+            return Err(reason.into());
+        }
+    }
+
+    // The output replaces the source, so it keeps the extension exactly as spelled.
+    let ext = match path.extension() {
+        Some(ext) if replace_in_place => ext.to_string_lossy().into_owned(),
+        _ => output_ext_lc,
+    };
+    let output = OutputSettings {
+        stem,
+        ext,
+        format,
+        fallback,
+        master,
+        jpeg_quality: config::jpeg_quality()?,
+        jpeg_subsampling: config::jpeg_subsampling()?,
+        gif: config::gif_options()?,
+        dpi: config::output_dpi()?,
+        naming: config::output_naming()?,
+        target,
+        normalize,
+        sizes,
+        source_mtime,
+        source_permissions,
+        profile,
+    };
+
+    if config::flag("EMBED_PARAMS") && already_normalized(path, &img, &output) {
+        let reason =
+            "Image is an output of the current settings (EMBED_PARAMS); leaving it as it is"
+                .to_string();
+        log::info!("{:?}: {}", path, reason);
+        return Err(ProcessError::Skipped(reason));
+    }
+
+    if let Some(longest) = config::preview_size()?
+        && !dry_run
+        && output.target.local
+    {
+        match write_preview(path, &img, &output, longest) {
+            Ok(preview) => log::info!("Preview saved: {:?}", preview),
+            Err(e) => log::warn!("Failed to write the preview of {:?}: {}", path, e),
+        }
+    }
+
+    Ok(Job {
+        path: path.to_path_buf(),
+        image: img,
+        renditions: Vec::new(),
+        output,
+    })
+}
+
+/// `REPLACE_IN_PLACE`: the format of `path` by its contents and its canonical extension,
+/// which selects the per-format settings, provided the encode stage can write it.
+fn in_place_format(path: &Path) -> Result<(String, ImageFormat), String> {
+    let format = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .ok()
+        .and_then(|reader| reader.format());
+    let ext = format.and_then(|format| {
+        config::OUTPUT_FORMATS
+            .into_iter()
+            .find(|ext| image_format(ext) == Some(format))
+    });
+    match (ext, format) {
+        (Some(ext), Some(format)) => Ok((ext.to_string(), format)),
+        _ => Err(format!(
+            "REPLACE_IN_PLACE cannot write {:?} in its own format ({:?})",
+            path, format
+        )),
+    }
+}
+
+/// Name part that marks a `PREVIEW` output, as in `photo.preview.jpg`.
+const PREVIEW_SUFFIX: &str = ".preview";
+/// JPEG quality of previews; they are replaced by the full output moments later.
+const PREVIEW_JPEG_QUALITY: u8 = 40;
+
+/// `PREVIEW`: writes `<stem>.preview.<ext>` next to `path` before the full output is
+/// processed. The source is shrunk with the fast `thumbnail` filter first, then cropped
+/// and padded onto the output canvas scaled down to `longest` pixels, so the preview is
+/// framed like the output. Deskewing, sharpening and 16-bit output are left out.
+fn write_preview(
+    path: &Path,
+    img: &DynamicImage,
+    output: &OutputSettings,
+    longest: u32,
+) -> Result<PathBuf, String> {
+    let opts = &output.normalize;
+    let (width, height) = opts.size;
+    let scale = (longest as f32 / width.max(height) as f32).min(1.0);
+    let size = (
+        ((width as f32 * scale).round() as u32).max(1),
+        ((height as f32 * scale).round() as u32).max(1),
+    );
+    let pad = ((opts.pad as f32 * scale) as u32).min((size.0.min(size.1) - 1) / 2);
+
+    // A few times the preview size leaves the crop enough detail to find the content.
+    let thumbnail = img.thumbnail(4 * size.0, 4 * size.1);
+    let shrink = thumbnail.width() as f32 / img.width() as f32;
+    let preview_opts = NormalizeOptions {
+        size,
+        pad,
+        crop_margin: (opts.crop_margin as f32 * shrink).round() as u32,
+        noise_tolerance: None,
+        deskew: None,
+        two_pass_downscale: false,
+        filter: config::ResizeFilter::Triangle,
+        bit_depth: config::BitDepth::Eight,
+        sharpen: None,
+        ..*opts
+    };
+    let preview = normalize(thumbnail, &preview_opts);
+
+    let settings = OutputSettings {
+        jpeg_quality: PREVIEW_JPEG_QUALITY,
+        dpi: None,
+        ..output.clone()
+    };
+    let mut filename = with_suffix(&output.stem, PREVIEW_SUFFIX);
+    filename.push(format!(".{}", output.ext));
+    let preview_path = path.with_file_name(filename);
+    save_output(&preview, &preview_path, &settings)?;
+    Ok(preview_path)
+}
+
+/// Process stage: crops, scales and pads the decoded image. With `SIZES` the image is
+/// cropped once and scaled to every size; `image` then holds the first rendition.
+pub fn process(job: Job) -> Job {
+    let opts = &job.output.normalize;
+    let job = if job.output.sizes.is_empty() {
+        let image = normalize(job.image, opts);
+        Job { image, ..job }
+    } else {
+        let cropped = crop(job.image, opts);
+        let renditions: Vec<(u32, DynamicImage)> = job
+            .output
+            .sizes
+            .iter()
+            .map(|output_size| {
+                let opts = NormalizeOptions {
+                    size: output_size.size,
+                    pad: output_size.pad,
+                    ..*opts
+                };
+                (output_size.size.0, fit(&cropped, &opts))
+            })
+            .collect();
+        let image = renditions[0].1.clone();
+        Job {
+            image,
+            renditions,
+            ..job
+        }
+    };
+    log::info!("Image processed successfully: {:?}", job.path);
+    job
+}
+
+/// Crops, scales and pads a decoded image onto the canvas described by `opts`, without
+/// touching the disk. With `padding_overflow` set to clamp, a padding that leaves too
+/// little room for the content is reduced to [`config::max_padding`]. Otherwise the padding
+/// is kept as it is, and one that leaves no room at all gets a content area of one pixel.
+pub fn normalize(img: DynamicImage, opts: &NormalizeOptions) -> DynamicImage {
+    let limit = config::max_padding(opts.size);
+    if opts.padding_overflow == config::PaddingOverflow::Clamp && opts.pad > limit {
+        log::warn!(
+            "Padding {} leaves too little room for the content on a {}x{} canvas; using {}",
+            opts.pad,
+            opts.size.0,
+            opts.size.1,
+            limit
+        );
+        let opts = NormalizeOptions {
+            pad: limit,
+            ..*opts
+        };
+        return fit(&crop(img, &opts), &opts);
+    }
+    if opts.size.0.min(opts.size.1) <= opts.pad.saturating_mul(2) {
+        log::warn!(
+            "Padding {} leaves no room for the content on a {}x{} canvas; scaling it to a single pixel",
+            opts.pad,
+            opts.size.0,
+            opts.size.1
+        );
+    }
+    fit(&crop(img, opts), opts)
+}
+
+/// `PADDING_OVERFLOW=clamp`: `pad`, reduced to [`config::max_padding`] when the `size`
+/// canvas cannot hold it. A reduction is logged.
+fn clamp_padding(path: &Path, size: (u32, u32), pad: u32) -> u32 {
+    let limit = config::max_padding(size);
+    if pad <= limit {
+        return pad;
+    }
+    log::warn!(
+        "Padding {} leaves too little room for the content of {:?} on a {}x{} canvas; using {} (PADDING_OVERFLOW=clamp)",
+        pad,
+        path,
+        size.0,
+        size.1,
+        limit
+    );
+    limit
+}
+
+/// Why a file produced no outputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProcessError {
+    /// Writing or moving an output failed for a reason that may clear up by itself, such
+    /// as a lock or a share that dropped; `PROCESS_RETRIES` runs the file again.
+    Transient(String),
+    /// Anything else, like an unsupported or corrupt source.
+    Permanent(String),
+    /// Not a failure: the source was left in place on purpose, e.g. because it is already
+    /// an output of the current settings or is below `MIN_INPUT_DIMENSION`.
+    Skipped(String),
+}
+
+impl ProcessError {
+    /// The kind as written to manifest results: `transient`, `permanent` or `skipped`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProcessError::Transient(_) => "transient",
+            ProcessError::Permanent(_) => "permanent",
+            ProcessError::Skipped(_) => "skipped",
+        }
+    }
+
+    /// The message, without the kind.
+    pub fn message(&self) -> &str {
+        match self {
+            ProcessError::Transient(message)
+            | ProcessError::Permanent(message)
+            | ProcessError::Skipped(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl From<String> for ProcessError {
+    fn from(message: String) -> Self {
+        ProcessError::Permanent(message)
+    }
+}
+
+impl From<ProcessError> for String {
+    fn from(e: ProcessError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Runs all three stages on the calling thread and returns the local output paths.
+pub fn process_and_save(path: &Path, pad: u32, tol: u8) -> Result<Vec<PathBuf>, ProcessError> {
+    let result = catch_panic(path, || encode(process(decode(path, pad, tol)?)));
+    retry_transient(path, pad, tol, result)
+}
+
+/// `PROCESS_RETRIES`: runs the whole decode-normalize-encode of `path` again, with a
+/// doubling delay, while `result` failed at the write stage with a transient error. The
+/// decode and rename retries have already given up at that point, so this covers longer
+/// outages such as a share that reconnects. Anything else, like an unsupported or corrupt
+/// source, fails right away.
+pub fn retry_transient(
+    path: &Path,
+    pad: u32,
+    tol: u8,
+    mut result: Result<Vec<PathBuf>, ProcessError>,
+) -> Result<Vec<PathBuf>, ProcessError> {
+    let retries = config::process_retries().unwrap_or(0);
+    let mut delay = Duration::from_millis(config::process_retry_delay_ms().unwrap_or(1000));
+    for attempt in 1..=retries {
+        let Err(ProcessError::Transient(e)) = &result else {
+            break;
+        };
+        log::warn!(
+            "Processing {:?} failed at the write stage (attempt {} of {}): {}. Running the whole operation again in {:?}...",
+            path,
+            attempt,
+            retries + 1,
+            e,
+            delay
+        );
+        thread::sleep(delay);
+        delay *= 2;
+        result = catch_panic(path, || encode(process(decode(path, pad, tol)?)));
+    }
+    result
+}
+
+/// Runs `work` for `path`, turning a panic into an error. A decoder bug triggered by one
+/// hostile file then fails that file instead of taking down the thread processing it.
+pub fn catch_panic<T, E: From<String>>(
+    path: &Path,
+    work: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    panic::catch_unwind(AssertUnwindSafe(work)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        log::error!("Processing {:?} panicked: {}", path, message);
+        Err(format!("Processing {:?} panicked: {}", path, message).into())
+    })
+}
+
+/// Encode stage: encodes the processed images, delivers them and removes the source.
+/// Returns the paths of the local outputs, which leaves out outputs that were only
+/// uploaded or skipped because they already existed (`ON_CONFLICT=skip`).
+pub fn encode(job: Job) -> Result<Vec<PathBuf>, ProcessError> {
+    let profile = job.output.profile.clone();
+    config::with_profile(profile.as_deref(), || encode_with_settings(job))
+}
+
+fn encode_with_settings(job: Job) -> Result<Vec<PathBuf>, ProcessError> {
+    if config::flag("THUMBNAIL_INDEX") {
+        let (encoded, _, ext) = encode_output(&job.image, &job.path, &job.output)?;
+        let thumbnail = thumbnails::save(&job.path, &encoded, ext)?;
+        log::info!("Processing complete for {:?}", job.path);
+        return Ok(vec![thumbnail]);
+    }
+
+    let Job {
+        path,
+        image,
+        renditions,
+        output,
+    } = job;
+    let path = path.as_path();
+    let on_conflict = config::on_conflict()?;
+
+    // All outputs of one source share its number, e.g. `0042.400.jpg` and `0042.800.jpg`.
+    let base: OsString = if output.naming == config::OutputNaming::Sequence {
+        let first_suffix = renditions
+            .first()
+            .map(|(width, _)| format!(".{}", width))
+            .unwrap_or_default();
+        let number = sequence::next(
+            path.parent().unwrap_or(Path::new(".")),
+            config::sequence()?,
+            |number| {
+                path.with_file_name(format!("{}{}.{}", number, first_suffix, output.ext))
+                    .exists()
+            },
+        )?;
+        log::info!(
+            "Naming the outputs of {:?} by sequence number {}",
+            path,
+            number
+        );
+        number.into()
+    } else {
+        output.stem.clone()
+    };
+    let outputs: Vec<(OsString, DynamicImage)> = if renditions.is_empty() {
+        vec![(base, image)]
+    } else {
+        renditions
+            .into_iter()
+            .map(|(width, image)| (with_suffix(&base, &format!(".{}", width)), image))
+            .collect()
+    };
+
+    let mut written = Vec::new();
+    let mut sizes = Vec::new();
+    let mut skipped = false;
+    // The master is encoded from the same processed image, next to each output.
+    let master = output.master.as_ref().and_then(|master| {
+        let format = image_format(&master.ext)?;
+        let settings = OutputSettings {
+            ext: master.ext.clone(),
+            format,
+            fallback: None,
+            ..output.clone()
+        };
+        Some((master.suffix.as_str(), settings))
+    });
+    for (stem, image) in outputs {
+        let master = master
+            .as_ref()
+            .map(|(suffix, settings)| (with_suffix(&stem, suffix), settings));
+        let variants = std::iter::once((stem.clone(), &output)).chain(master);
+        for (stem, settings) in variants {
+            match deliver(path, &stem, &image, settings, on_conflict)? {
+                Delivery::Written(final_path) => {
+                    written.push(final_path);
+                    sizes.push(image.dimensions());
+                }
+                Delivery::Sent => {}
+                Delivery::Skipped => skipped = true,
+            }
+        }
+    }
+
+    if skipped {
+        log::warn!(
+            "Leaving {:?} in place because an existing output was kept (ON_CONFLICT=skip)",
+            path
+        );
+    } else if !output.target.local {
+        log::info!(
+            "Removing original file {:?} after sending its processed output",
+            path
+        );
+        remove_source(path, Vec::new())?;
+    } else if !written.iter().any(|final_path| final_path == path) && path.exists() {
+        log::info!(
+            "Removing original file {:?} due to output being saved separately as {:?}",
+            path,
+            written
+        );
+        remove_source(path, written.iter().cloned().zip(sizes).collect())?;
+    } else {
+        log::info!(
+            "Original file {:?} has been replaced by processed file {:?}",
+            path,
+            written
+        );
+    }
+
+    log::info!("Processing complete for {:?}", path);
+    Ok(written)
+}
+
+/// A source whose removal is deferred by `DELETE_DELAY_SEC`.
+struct PendingDeletion {
+    source: PathBuf,
+    due: Instant,
+    /// Modification time when the removal was scheduled; a different one means a new
+    /// file was dropped under the same name.
+    modified: Option<SystemTime>,
+    /// Local outputs, checked again before the source goes.
+    outputs: Vec<WrittenOutput>,
+    verify: bool,
+    read_only: config::ReadOnlySources,
+}
+
+/// A local output of a source whose removal is deferred.
+struct WrittenOutput {
+    path: PathBuf,
+    size: (u32, u32),
+    /// Modification time right after it was written; a different one means someone else
+    /// has since written to that path.
+    modified: Option<SystemTime>,
+}
+
+static PENDING_DELETIONS: Lazy<Mutex<Vec<PendingDeletion>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Removes a delivered source now, or schedules its removal after `DELETE_DELAY_SEC`.
+fn remove_source(path: &Path, outputs: Vec<(PathBuf, (u32, u32))>) -> Result<(), String> {
+    let delay = config::delete_delay()?;
+    let read_only = config::read_only_sources()?;
+    if delay.is_zero() {
+        return remove_original(path, read_only).map(|_| ());
+    }
+    log::info!("Removal of {:?} deferred by {:?}", path, delay);
+    PENDING_DELETIONS.lock().unwrap().push(PendingDeletion {
+        source: path.to_path_buf(),
+        due: Instant::now() + delay,
+        modified: fs::metadata(path).and_then(|m| m.modified()).ok(),
+        outputs: outputs
+            .into_iter()
+            .map(|(path, size)| WrittenOutput {
+                modified: fs::metadata(&path).and_then(|m| m.modified()).ok(),
+                path,
+                size,
+            })
+            .collect(),
+        verify: config::flag("VERIFY_OUTPUT"),
+        read_only,
+    });
+    Ok(())
+}
+
+/// Removes a processed source, handling a read-only one by `policy`. Returns whether it
+/// was removed.
+fn remove_original(path: &Path, policy: config::ReadOnlySources) -> Result<bool, String> {
+    if is_read_only(path) {
+        match policy {
+            config::ReadOnlySources::Fail => {}
+            config::ReadOnlySources::Clear => make_writable(path)?,
+            config::ReadOnlySources::Keep => {
+                log::warn!(
+                    "Keeping read-only original file {:?} (READ_ONLY_SOURCES=keep)",
+                    path
+                );
+                return Ok(false);
+            }
+        }
+    }
+    fs::remove_file(path)
+        .map(|()| true)
+        .map_err(|e| format!("Failed to remove original file {:?}: {}", path, e))
+}
+
+fn is_read_only(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().readonly())
+}
+
+/// Clears the read-only attribute of `path`; on Unix, gives its owner write access.
+fn make_writable(path: &Path) -> Result<(), String> {
+    log::info!(
+        "Clearing the read-only attribute of {:?} (READ_ONLY_SOURCES=clear)",
+        path
+    );
+    let mut permissions = fs::metadata(path)
+        .map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?
+        .permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        permissions.set_mode(permissions.mode() | 0o200);
+    }
+    // Only Unix has the world-writable pitfall this lint warns about.
+    #[cfg(not(unix))]
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(path, permissions).map_err(|e| {
+        format!(
+            "Failed to clear the read-only attribute of {:?}: {}",
+            path, e
+        )
+    })
+}
+
+/// Removes the sources whose `DELETE_DELAY_SEC` grace period has ended. A source is
+/// kept instead if it changed in the meantime, or if one of its outputs is gone or, with
+/// `VERIFY_OUTPUT`, no longer decodes to the expected size. Returns how many removals
+/// are still pending.
+pub fn delete_due_sources() -> usize {
+    let due: Vec<PendingDeletion> = {
+        let mut pending = PENDING_DELETIONS.lock().unwrap();
+        let now = Instant::now();
+        let (due, waiting) = pending.drain(..).partition(|deletion| deletion.due <= now);
+        *pending = waiting;
+        due
+    };
+
+    for deletion in due {
+        let source = &deletion.source;
+        let Ok(metadata) = fs::metadata(source) else {
+            continue;
+        };
+        if metadata.modified().ok() != deletion.modified {
+            log::info!("Keeping {:?}: it changed after it was processed", source);
+            continue;
+        }
+        let broken = deletion.outputs.iter().find_map(|output| {
+            if !output.path.exists() {
+                Some(format!("Output {:?} is missing", output.path))
+            } else if deletion.verify {
+                verify_output(&output.path, output.size).err()
+            } else {
+                None
+            }
+        });
+        if let Some(reason) = broken {
+            log::error!("Keeping {:?}: {}", source, reason);
+            continue;
+        }
+        match remove_original(source, deletion.read_only) {
+            Ok(true) => log::info!("Removed original file {:?}", source),
+            Ok(false) => {}
+            Err(e) => log::error!("{}", e),
+        }
+    }
+    PENDING_DELETIONS.lock().unwrap().len()
+}
+
+/// With `MIRROR_DELETES`, removes the outputs of a source that was deleted while its
+/// removal was still deferred. Only outputs this service wrote for that source and that
+/// are unchanged since are removed; files that merely share a name are never touched.
+pub fn mirror_delete(source: &Path) {
+    let deletion = {
+        let mut pending = PENDING_DELETIONS.lock().unwrap();
+        match pending
+            .iter()
+            .position(|deletion| deletion.source == source)
+        {
+            // The source is back already; the deferred removal decides what happens to it.
+            Some(_) if source.exists() => return,
+            Some(index) => pending.remove(index),
+            None => return,
+        }
+    };
+
+    for output in deletion.outputs {
+        match fs::metadata(&output.path).and_then(|m| m.modified()) {
+            Ok(modified) if Some(modified) == output.modified => {
+                match fs::remove_file(&output.path) {
+                    Ok(()) => log::info!(
+                        "Removed output {:?} of deleted source {:?}",
+                        output.path,
+                        source
+                    ),
+                    Err(e) => log::error!("Failed to remove output {:?}: {}", output.path, e),
+                }
+            }
+            Ok(_) => log::warn!(
+                "Keeping {:?}: it changed after it was written for {:?}",
+                output.path,
+                source
+            ),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Waits until every deferred removal has run, for one-shot runs that exit afterwards.
+pub fn finish_deletions() {
+    while delete_due_sources() > 0 {
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// What happened to one output of a job.
+enum Delivery {
+    /// Written locally, at this path.
+    Written(PathBuf),
+    /// Only uploaded to S3 or streamed to `OUTPUT_PIPE`.
+    Sent,
+    /// Not written because the name was taken (`ON_CONFLICT=skip`).
+    Skipped,
+}
+
+/// Encodes one output named after `stem` and writes, uploads and/or streams it.
+fn deliver(
+    path: &Path,
+    stem: &OsStr,
+    image: &DynamicImage,
+    output: &OutputSettings,
+    on_conflict: config::OnConflict,
+) -> Result<Delivery, ProcessError> {
+    let output_target = output.target;
+
+    let (encoded, format, output_ext_lc) = encode_output(image, path, output)?;
+    let encoded = if config::flag("EMBED_PARAMS") {
+        embed_params(encoded, format, &describe_params(image, output))
+    } else {
+        encoded
+    };
+
+    let final_filename = match output.naming {
+        config::OutputNaming::Stem | config::OutputNaming::Sequence => {
+            with_suffix(stem, &format!(".{}", output_ext_lc))
+        }
+        config::OutputNaming::Hash => {
+            format!("{}.{}", blake3::hash(&encoded).to_hex(), output_ext_lc).into()
+        }
+    };
+    let mut final_path = path.with_file_name(&final_filename);
+
+    if output_target.local && final_path == path && writes_in_place(path, format) {
+        let _slot = file_slot()?;
+        write_in_place(path, &encoded, output)?;
+    } else if output_target.local {
+        let _slot = file_slot()?;
+        let tmp_filename = with_suffix(stem, &format!(".normalized.{}", output_ext_lc));
+        let tmp_path = match config::var("TEMP_DIR") {
+            Some(dir) => {
+                let dir = PathBuf::from(dir);
+                fs::create_dir_all(&dir).map_err(|e| {
+                    write_error(e, format!("Failed to create temp directory {:?}", dir))
+                })?;
+                dir.join(&tmp_filename)
+            }
+            None => path.with_file_name(&tmp_filename),
+        };
+
+        fs::write(&tmp_path, &encoded)
+            .map_err(|e| write_error(e, format!("Failed to write image to {:?}", tmp_path)))?;
+
+        log::info!("Temporary processed image saved: {:?}", tmp_path);
+
+        if let Some(mtime) = output.source_mtime {
+            filetime::set_file_mtime(&tmp_path, mtime)
+                .map_err(|e| format!("Failed to set modification time of {:?}: {}", tmp_path, e))?;
+        }
+
+        if let Some(permissions) = output.source_permissions {
+            apply_permissions(&tmp_path, permissions)?;
+        }
+
+        if config::flag("VERIFY_OUTPUT")
+            && let Err(e) = verify_output(&tmp_path, image.dimensions())
+        {
+            log::error!("{}", e);
+            quarantine(&tmp_path, &e)?;
+            return Err(format!("Kept {:?} because its output failed verification", path).into());
+        }
+
+        // Replacing the source itself is the normal in-place flow, not a conflict.
+        let conflict = final_path != path;
+        let mut on_conflict = on_conflict;
+        // Hash names are only taken by the same image, and sequence numbers skip taken ones.
+        if conflict
+            && on_conflict == config::OnConflict::KeepExtension
+            && output.naming == config::OutputNaming::Stem
+            && final_path.exists()
+        {
+            // Another file holds the name, e.g. `photo.jpg` next to the source `photo.png`.
+            // Naming the output after the whole source name keeps both; a name that is
+            // taken too can only be an earlier output of this source.
+            match path.extension() {
+                Some(source_ext) => {
+                    let mut name = with_suffix(stem, ".");
+                    name.push(source_ext);
+                    name.push(format!(".{}", output_ext_lc));
+                    let unique = path.with_file_name(name);
+                    log::warn!(
+                        "{:?} is taken by another file; writing the output of {:?} as {:?}",
+                        final_path,
+                        path,
+                        unique
+                    );
+                    final_path = unique;
+                }
+                None => on_conflict = config::OnConflict::Rename,
+            }
+        }
+        let reserved = conflict
+            && matches!(
+                on_conflict,
+                config::OnConflict::Skip | config::OnConflict::Rename
+            );
+        if conflict
+            && on_conflict == config::OnConflict::Overwrite
+            && final_path.exists()
+            && !is_recent_output(&final_path)
+        {
+            log::warn!(
+                "Output of {:?} replaces the existing file {:?} (ON_CONFLICT=overwrite)",
+                path,
+                final_path
+            );
+        }
+        if reserved {
+            match reserve_output(&final_path, on_conflict)? {
+                Some(reserved_path) => final_path = reserved_path,
+                None => {
+                    let _ = fs::remove_file(&tmp_path);
+                    log::warn!(
+                        "Output {:?} already exists; keeping it (ON_CONFLICT=skip)",
+                        final_path
+                    );
+                    return Ok(Delivery::Skipped);
+                }
+            }
+        }
+
+        // Windows cannot replace a read-only source in place either.
+        if !conflict
+            && is_read_only(&final_path)
+            && config::read_only_sources()? == config::ReadOnlySources::Clear
+        {
+            make_writable(&final_path)?;
+        }
+        mark_output(&final_path);
+        if let Err(e) = rename_with_retries(&tmp_path, &final_path) {
+            if reserved {
+                let _ = fs::remove_file(&final_path);
+            }
+            return Err(e);
+        }
+
+        log::info!("Final processed image saved: {:?}", final_path);
+    }
+
+    #[cfg(feature = "s3")]
+    if output_target.s3
+        && let Err(e) = final_filename
+            .to_str()
+            .ok_or_else(|| format!("Cannot upload {:?}: S3 keys must be UTF-8", final_filename))
+            .and_then(|key| s3::upload(key, &encoded, format.to_mime_type()))
+    {
+        log::error!("{}", e);
+        quarantine(path, &e)?;
+        return Err(e.into());
+    }
+
+    if output_target.pipe {
+        if let Err(e) = pipe::send(&encoded) {
+            log::error!("{}", e);
+            quarantine(path, &e)?;
+            return Err(e.into());
+        }
+        log::info!("Processed image of {:?} sent to OUTPUT_PIPE", path);
+    }
+
+    Ok(if output_target.local {
+        Delivery::Written(final_path)
+    } else {
+        Delivery::Sent
+    })
+}
+
+/// `IN_PLACE_SAFE`: whether an output that replaces its own source, in the source's own
+/// format, is written straight into it instead of through a temp file. `VERIFY_OUTPUT`
+/// and `TEMP_DIR` need the temp file, and a read-only source keeps the usual
+/// `READ_ONLY_SOURCES` handling.
+fn writes_in_place(path: &Path, format: ImageFormat) -> bool {
+    if !config::flag("IN_PLACE_SAFE")
+        || config::flag("VERIFY_OUTPUT")
+        || config::var("TEMP_DIR").is_some()
+        || is_read_only(path)
+    {
+        return false;
+    }
+    // A `.jpg` that holds a PNG keeps its name but not its format.
+    let source_format = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .ok()
+        .and_then(|reader| reader.format());
+    if source_format != Some(format) {
+        log::debug!(
+            "{:?} changes format; replacing it through a temp file",
+            path
+        );
+        return false;
+    }
+    let local = is_local_disk(path);
+    if !local {
+        log::debug!(
+            "{:?} is not on a local disk; replacing it through a temp file",
+            path
+        );
+    }
+    local
+}
+
+/// Overwrites the source at `path` with `encoded` and cuts off whatever remains of the
+/// old file. Other readers can briefly see a partly written file, and a crash in
+/// between leaves neither the source nor the output intact.
+fn write_in_place(
+    path: &Path,
+    encoded: &[u8],
+    output: &OutputSettings,
+) -> Result<(), ProcessError> {
+    use std::io::Write;
+
+    mark_output(path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| write_error(e, format!("Failed to open {:?} for writing", path)))?;
+    file.write_all(encoded)
+        .and_then(|()| file.set_len(encoded.len() as u64))
+        .map_err(|e| write_error(e, format!("Failed to write image to {:?}", path)))?;
+    drop(file);
+
+    if let Some(mtime) = output.source_mtime {
+        filetime::set_file_mtime(path, mtime)
+            .map_err(|e| format!("Failed to set modification time of {:?}: {}", path, e))?;
+    }
+    log::info!("Processed image written in place: {:?}", path);
+    Ok(())
+}
+
+/// Whether `path` is on a local filesystem rather than a network share. Unknown
+/// filesystems count as remote.
+#[cfg(target_os = "linux")]
+fn is_local_disk(path: &Path) -> bool {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    /// `f_type` magic numbers of NFS, SMB, CIFS, SMB2, AFS, Ceph, 9P, FUSE (sshfs and
+    /// other user-space mounts), Coda and NCP.
+    const NETWORK_FILESYSTEMS: [u32; 10] = [
+        0x6969,
+        0x517B,
+        0xFF53_4D42,
+        0xFE53_4D42,
+        0x5346_414F,
+        0x00C3_6400,
+        0x0102_1997,
+        0x6573_5546,
+        0x7375_7245,
+        0x564C,
+    ];
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `statfs` is plain data, and the call only writes into it.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a live, writable `statfs`.
+    if unsafe { libc::statfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    !NETWORK_FILESYSTEMS.contains(&(stat.f_type as u32))
+}
+
+/// Whether `path` is on a fixed drive; UNC paths and mapped network drives are not.
+#[cfg(windows)]
+fn is_local_disk(path: &Path) -> bool {
+    use std::path::{Component, Prefix};
+    use windows_sys::Win32::{
+        Storage::FileSystem::GetDriveTypeW, System::WindowsProgramming::DRIVE_FIXED,
+    };
+
+    let Ok(path) = std::path::absolute(path) else {
+        return false;
+    };
+    let Some(Component::Prefix(prefix)) = path.components().next() else {
+        return false;
+    };
+    let (Prefix::Disk(letter) | Prefix::VerbatimDisk(letter)) = prefix.kind() else {
+        return false;
+    };
+    let root: Vec<u16> = format!("{}:\\\0", letter as char).encode_utf16().collect();
+    // SAFETY: `root` is a NUL-terminated UTF-16 string that outlives the call.
+    unsafe { GetDriveTypeW(root.as_ptr()) == DRIVE_FIXED }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn is_local_disk(_path: &Path) -> bool {
+    false
+}
+
+/// Encodes `image` with the format settings of `output` and moves it into place at `path`
+/// through a `.normalized.` temp file, for outputs that are not derived from a single
+/// source, such as contact sheets.
+pub fn save_output(
+    image: &DynamicImage,
+    path: &Path,
+    output: &OutputSettings,
+) -> Result<(), ProcessError> {
+    let encoded = encode_to_vec(image, output.format, output)
+        .map_err(|e| format!("Failed to encode image {:?}: {}", path, e))?;
+    save_bytes(&encoded, path)
+}
+
+/// Moves `bytes` into place at `path` through a `.normalized.` temp file.
+pub fn save_bytes(bytes: &[u8], path: &Path) -> Result<(), ProcessError> {
+    let mut tmp_filename = with_suffix(path.file_stem().unwrap_or_default(), ".normalized.");
+    tmp_filename.push(path.extension().unwrap_or_default());
+    let tmp_path = path.with_file_name(tmp_filename);
+    let _slot = file_slot()?;
+    fs::write(&tmp_path, bytes)
+        .map_err(|e| write_error(e, format!("Failed to write {:?}", tmp_path)))?;
+    mark_output(path);
+    rename_with_retries(&tmp_path, path)
+}
+
+/// The options an output was produced with, for `EMBED_PARAMS`.
+fn describe_params(image: &DynamicImage, output: &OutputSettings) -> String {
+    let opts = &output.normalize;
+    let size = image.dimensions();
+    // A `SIZES` rendition has its own scaled padding.
+    let pad = output
+        .sizes
+        .iter()
+        .find(|output_size| output_size.size == size)
+        .map_or(opts.pad, |output_size| output_size.pad);
+    let tolerance = match opts.channel_tolerance {
+        Some(channels) => format!("{:?}", channels.tolerance),
+        None if opts.adaptive_tolerance => "adaptive".to_string(),
+        None => opts.tol.to_string(),
+    };
+    let mut params = format!(
+        "ni-watcher {}; size={}x{}; padding={}; tolerance={}; background={}; bit_depth={}",
+        env!("CARGO_PKG_VERSION"),
+        size.0,
+        size.1,
+        pad,
+        tolerance,
+        opts.background.name(),
+        opts.bit_depth.bits()
+    );
+    if output.format == ImageFormat::Jpeg {
+        params.push_str(&format!(
+            "; quality={}; subsampling={}",
+            output.jpeg_quality,
+            output.jpeg_subsampling.name()
+        ));
+    }
+    if opts.filter != config::ResizeFilter::Gaussian {
+        params.push_str(&format!("; filter={}", opts.filter.name()));
+    }
+    if opts.crop_margin > 0 {
+        params.push_str(&format!("; crop_margin={}", opts.crop_margin));
+    }
+    if let Some(pixels) = opts.noise_tolerance {
+        params.push_str(&format!("; noise_tolerance={}", pixels));
+    }
+    if let Some(degrees) = opts.deskew {
+        params.push_str(&format!("; deskew={}", degrees));
+    }
+    if let Some(dpi) = output.dpi {
+        params.push_str(&format!("; dpi={}", dpi));
+    }
+    params
+}
+
+/// Embeds `text` in an encoded PNG (a `tEXt` chunk with the keyword `Comment`, right
+/// after `IHDR`) or JPEG (a `COM` segment after the JFIF header). Other formats are
+/// returned unchanged.
+fn embed_params(encoded: Vec<u8>, format: ImageFormat, text: &str) -> Vec<u8> {
+    match format {
+        ImageFormat::Png => {
+            let mut chunk = b"tEXtComment\0".to_vec();
+            chunk.extend_from_slice(text.as_bytes());
+            insert_png_chunk(encoded, &chunk)
+        }
+        ImageFormat::Jpeg if encoded.starts_with(&[0xFF, 0xD8]) => {
+            // JFIF requires its APP0 segment directly after SOI, so the comment follows it.
+            let mut at = 2;
+            if encoded.get(2..4) == Some(&[0xFF, 0xE0]) {
+                at += 2 + encoded
+                    .get(4..6)
+                    .map_or(0, |len| usize::from(u16::from_be_bytes([len[0], len[1]])));
+                at = at.min(encoded.len());
+            }
+            let text = &text.as_bytes()[..text.len().min(usize::from(u16::MAX) - 2)];
+            let mut embedded = Vec::with_capacity(encoded.len() + text.len() + 4);
+            embedded.extend_from_slice(&encoded[..at]);
+            embedded.extend_from_slice(&[0xFF, 0xFE]);
+            embedded.extend_from_slice(&((text.len() + 2) as u16).to_be_bytes());
+            embedded.extend_from_slice(text);
+            embedded.extend_from_slice(&encoded[at..]);
+            embedded
+        }
+        _ => encoded,
+    }
+}
+
+/// Reads the text `embed_params` put into the PNG or JPEG at `path`, along with the
+/// format. Only the headers before the image data are read. `None` for other formats and
+/// for files without it.
+fn read_params(path: &Path) -> Option<(ImageFormat, String)> {
+    const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+    let mut reader = io::BufReader::new(fs::File::open(path).ok()?);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic[..2]).ok()?;
+    let own = |text: Vec<u8>| {
+        String::from_utf8(text)
+            .ok()
+            .filter(|text| text.starts_with("ni-watcher "))
+    };
+    if magic[..2] == [0xFF, 0xD8] {
+        let mut marker = [0u8; 4];
+        loop {
+            reader.read_exact(&mut marker).ok()?;
+            let length = usize::from(u16::from_be_bytes([marker[2], marker[3]])).checked_sub(2)?;
+            if marker[0] != 0xFF || marker[1] == 0xDA {
+                return None;
+            }
+            let mut segment = vec![0; length];
+            reader.read_exact(&mut segment).ok()?;
+            if marker[1] == 0xFE
+                && let Some(text) = own(segment)
+            {
+                return Some((ImageFormat::Jpeg, text));
+            }
+        }
+    }
+    reader.read_exact(&mut magic[2..]).ok()?;
+    if magic != PNG_SIGNATURE {
+        return None;
+    }
+    let mut header = [0u8; 8];
+    loop {
+        reader.read_exact(&mut header).ok()?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        if matches!(&header[4..], b"IDAT" | b"IEND") {
+            return None;
+        }
+        let mut data = vec![0; usize::try_from(length).ok()?];
+        reader.read_exact(&mut data).ok()?;
+        reader.seek_relative(4).ok()?; // CRC
+        if &header[4..] == b"tEXt"
+            && let Some(text) = data.strip_prefix(b"Comment\0")
+            && let Some(text) = own(text.to_vec())
+        {
+            return Some((ImageFormat::Png, text));
+        }
+    }
+}
+
+/// `EMBED_PARAMS`: whether `path` is an output written with the settings it would be
+/// normalized with now, in the current output format and at one of the current output
+/// sizes, so that normalizing it again could only cost another round of compression.
+fn already_normalized(path: &Path, img: &DynamicImage, output: &OutputSettings) -> bool {
+    let Some((format, embedded)) = read_params(path) else {
+        return false;
+    };
+    let sizes: Vec<(u32, u32)> = if output.sizes.is_empty() {
+        vec![output.normalize.size]
+    } else {
+        output
+            .sizes
+            .iter()
+            .map(|output_size| output_size.size)
+            .collect()
+    };
+    format == output.format
+        && sizes.contains(&img.dimensions())
+        && same_params(&embedded, &describe_params(img, output))
+}
+
+/// Compares two `describe_params` texts field by field. The version that wrote them does
+/// not matter, nor does `filter`, which `FAST_PATH_PIXELS` picks by the size of the
+/// original source; every other field must be present in both with the same value.
+fn same_params(a: &str, b: &str) -> bool {
+    fn fields(text: &str) -> Option<BTreeMap<&str, &str>> {
+        let mut fields = text.split("; ");
+        fields.next()?.strip_prefix("ni-watcher ")?;
+        let mut parsed = BTreeMap::new();
+        for field in fields {
+            let (key, value) = field.split_once('=')?;
+            if key != "filter" {
+                parsed.insert(key, value);
+            }
+        }
+        Some(parsed)
+    }
+
+    matches!((fields(a), fields(b)), (Some(a), Some(b)) if a == b)
+}
+
+/// Inserts `chunk` (its type followed by its data) into a PNG right after `IHDR`, which
+/// always comes first.
+fn insert_png_chunk(encoded: Vec<u8>, chunk: &[u8]) -> Vec<u8> {
+    /// Signature plus the `IHDR` chunk.
+    const PNG_HEADER_LEN: usize = 8 + 4 + 4 + 13 + 4;
+
+    if encoded.len() <= PNG_HEADER_LEN {
+        return encoded;
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(chunk);
+    let mut inserted = Vec::with_capacity(encoded.len() + chunk.len() + 8);
+    inserted.extend_from_slice(&encoded[..PNG_HEADER_LEN]);
+    inserted.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+    inserted.extend_from_slice(chunk);
+    inserted.extend_from_slice(&crc.sum().to_be_bytes());
+    inserted.extend_from_slice(&encoded[PNG_HEADER_LEN..]);
+    inserted
+}
+
+/// `OUTPUT_DPI` for PNG: a `pHYs` chunk, which counts pixels per meter.
+fn png_with_dpi(encoded: Vec<u8>, dpi: u16) -> Vec<u8> {
+    let per_meter = (f64::from(dpi) / 0.0254).round() as u32;
+    let mut chunk = b"pHYs".to_vec();
+    chunk.extend_from_slice(&per_meter.to_be_bytes());
+    chunk.extend_from_slice(&per_meter.to_be_bytes());
+    chunk.push(1); // unit: meter
+    insert_png_chunk(encoded, &chunk)
+}
+
+/// `OUTPUT_DPI` for TIFF: rewrites the `XResolution`, `YResolution` and `ResolutionUnit`
+/// entries of the first IFD in place. The encoder always writes them, as 1/1 without a
+/// unit, so the file keeps its layout.
+fn tiff_with_dpi(mut encoded: Vec<u8>, dpi: u16) -> Vec<u8> {
+    const X_RESOLUTION: u32 = 282;
+    const Y_RESOLUTION: u32 = 283;
+    const RESOLUTION_UNIT: u32 = 296;
+    const INCH: u32 = 2;
+
+    let big_endian = match encoded.get(..4) {
+        Some(b"II*\0") => false,
+        Some(b"MM\0*") => true,
+        _ => return encoded,
+    };
+    let read = |bytes: &[u8], at: usize, len: usize| -> Option<u32> {
+        let field = bytes.get(at..at.checked_add(len)?)?;
+        let value = |value: u32, &byte: &u8| value << 8 | u32::from(byte);
+        Some(if big_endian {
+            field.iter().fold(0, value)
+        } else {
+            field.iter().rev().fold(0, value)
+        })
+    };
+    let write = |bytes: &mut [u8], at: usize, len: usize, value: u32| {
+        if let Some(field) = at.checked_add(len).and_then(|end| bytes.get_mut(at..end)) {
+            for (i, byte) in field.iter_mut().enumerate() {
+                let shift = if big_endian { len - 1 - i } else { i };
+                *byte = (value >> (8 * shift)) as u8;
+            }
+        }
+    };
+
+    let Some(ifd) = read(&encoded, 4, 4).map(|at| at as usize) else {
+        return encoded;
+    };
+    let entries = read(&encoded, ifd, 2).unwrap_or(0) as usize;
+    for entry in (0..entries).map(|i| ifd + 2 + 12 * i) {
+        match read(&encoded, entry, 2) {
+            Some(X_RESOLUTION | Y_RESOLUTION) => {
+                // Rationals do not fit in the entry; its value field holds their offset.
+                if let Some(at) = read(&encoded, entry + 8, 4).map(|at| at as usize) {
+                    write(&mut encoded, at, 4, u32::from(dpi));
+                    write(&mut encoded, at + 4, 4, 1);
+                }
+            }
+            Some(RESOLUTION_UNIT) => write(&mut encoded, entry + 8, 2, INCH),
+            _ => {}
+        }
+    }
+    encoded
+}
+
+/// Maps an output extension onto its format.
+fn image_format(ext: &str) -> Option<ImageFormat> {
+    match ext {
+        "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+        "png" => Some(ImageFormat::Png),
+        "gif" => Some(ImageFormat::Gif),
+        "bmp" => Some(ImageFormat::Bmp),
+        "tiff" => Some(ImageFormat::Tiff),
+        "webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+/// Encodes the output of `path` in its output format or, if that fails, in
+/// `FALLBACK_FORMAT`. Returns the encoded image with the format and extension it ended
+/// up in.
+fn encode_output<'a>(
+    image: &DynamicImage,
+    path: &Path,
+    output: &'a OutputSettings,
+) -> Result<(Vec<u8>, ImageFormat, &'a str), String> {
+    let e = match encode_to_vec(image, output.format, output) {
+        Ok(encoded) => return Ok((encoded, output.format, &output.ext)),
+        Err(e) => e,
+    };
+    let Some((ext, format)) = &output.fallback else {
+        log::error!("Failed to write image in {:?} format: {}", output.format, e);
+        return Err(format!("Failed to encode image {:?}: {}", path, e));
+    };
+    match encode_to_vec(image, *format, output) {
+        Ok(encoded) => {
+            log::warn!(
+                "Writing {:?} as {} because encoding it as {} failed (FALLBACK_FORMAT): {}",
+                path,
+                ext,
+                output.ext,
+                e
+            );
+            Ok((encoded, *format, ext))
+        }
+        Err(fallback_error) => Err(format!(
+            "Failed to encode image {:?}: {}; FALLBACK_FORMAT {} failed as well: {}",
+            path, e, ext, fallback_error
+        )),
+    }
+}
+
+fn encode_to_vec(
+    image: &DynamicImage,
+    format: ImageFormat,
+    output: &OutputSettings,
+) -> Result<Vec<u8>, image::ImageError> {
+    let mut encoded = io::Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg if output.jpeg_subsampling != config::JpegSubsampling::Full => {
+            log_jpeg_subsampling(output.jpeg_subsampling);
+            return encode_subsampled_jpeg(image, output);
+        }
+        ImageFormat::Jpeg => {
+            log_jpeg_subsampling(output.jpeg_subsampling);
+            let mut encoder = JpegEncoder::new_with_quality(&mut encoded, output.jpeg_quality);
+            if let Some(dpi) = output.dpi {
+                encoder.set_pixel_density(PixelDensity::dpi(dpi));
+            }
+            encoder.encode_image(image)?
+        }
+        ImageFormat::Gif => {
+            let frame = gif_frame(image, output.gif);
+            GifEncoder::new_with_speed(&mut encoded, output.gif.speed)
+                .encode_frame(Frame::new(frame))?;
+        }
+        format => image.write_to(&mut encoded, format)?,
+    }
+    let encoded = encoded.into_inner();
+    Ok(match (format, output.dpi) {
+        (ImageFormat::Png, Some(dpi)) => png_with_dpi(encoded, dpi),
+        (ImageFormat::Tiff, Some(dpi)) => tiff_with_dpi(encoded, dpi),
+        _ => encoded,
+    })
+}
+
+/// `JPEG_SUBSAMPLING=422|420`: the built-in encoder only writes full-resolution color, so
+/// subsampled JPEGs come from `jpeg-encoder`. Gray images have no color to subsample and
+/// stay single-channel.
+fn encode_subsampled_jpeg(
+    image: &DynamicImage,
+    output: &OutputSettings,
+) -> Result<Vec<u8>, image::ImageError> {
+    let failed = |e: jpeg_encoder::EncodingError| {
+        image::ImageError::Encoding(image::error::EncodingError::new(
+            ImageFormat::Jpeg.into(),
+            e,
+        ))
+    };
+    let (width, height) = image.dimensions();
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(image::ImageError::Limits(
+            image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError),
+        ));
+    };
+    let (pixels, color) = if image.color().has_color() {
+        (image.to_rgb8().into_raw(), jpeg_encoder::ColorType::Rgb)
+    } else {
+        (image.to_luma8().into_raw(), jpeg_encoder::ColorType::Luma)
+    };
+
+    let mut encoded = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut encoded, output.jpeg_quality);
+    encoder.set_sampling_factor(match output.jpeg_subsampling {
+        config::JpegSubsampling::Half => jpeg_encoder::SamplingFactor::R_4_2_2,
+        _ => jpeg_encoder::SamplingFactor::R_4_2_0,
+    });
+    if let Some(dpi) = output.dpi {
+        encoder.set_density(jpeg_encoder::Density::Inch { x: dpi, y: dpi });
+    }
+    encoder
+        .encode(&pixels, width, height, color)
+        .map_err(failed)?;
+    Ok(encoded)
+}
+
+/// Logs the chroma subsampling of JPEG outputs once per requested value.
+fn log_jpeg_subsampling(requested: config::JpegSubsampling) {
+    static LOGGED: Lazy<Mutex<Vec<config::JpegSubsampling>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+    let mut logged = LOGGED.lock().unwrap();
+    if logged.contains(&requested) {
+        return;
+    }
+    logged.push(requested);
+    log::info!(
+        "Writing JPEG outputs with {} chroma subsampling.",
+        requested.name()
+    );
+}
+
+/// Prepares an image for the GIF palette, which has at most one fully transparent entry
+/// and no partial alpha. With `GIF_TRANSPARENCY`, pixels below half opacity share that
+/// entry and the rest become opaque, so soft edges do not turn into a halo of opaque
+/// fringe colors; without it, the image is composited onto white.
+fn gif_frame(image: &DynamicImage, options: config::GifOptions) -> RgbaImage {
+    if !options.transparency {
+        return flatten_alpha(image.clone(), WHITE).into_rgba8();
+    }
+    let mut frame = image.to_rgba8();
+    for pixel in frame.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        pixel.0 = if a < 128 { [0; 4] } else { [r, g, b, 255] };
+    }
+    frame
+}
+
+#[cfg(unix)]
+fn source_permissions(path: &Path) -> Result<Option<SourcePermissions>, String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata =
+        fs::metadata(path).map_err(|e| format!("Failed to read metadata of {:?}: {}", path, e))?;
+    Ok(Some(SourcePermissions {
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+    }))
+}
+
+/// Windows outputs inherit the folder's ACL, so there is nothing to copy.
+#[cfg(not(unix))]
+fn source_permissions(_path: &Path) -> Result<Option<SourcePermissions>, String> {
+    Ok(None)
+}
+
+/// Gives `path` the source's owner where the service may change it, then its mode. The
+/// owner goes first because changing it clears the setuid and setgid bits.
+#[cfg(unix)]
+fn apply_permissions(path: &Path, permissions: SourcePermissions) -> Result<(), String> {
+    use std::os::unix::fs::{PermissionsExt, chown};
+
+    if let Err(e) = chown(path, Some(permissions.uid), Some(permissions.gid)) {
+        // Unprivileged services may only pick one of their own groups; keep what is allowed.
+        log::debug!("Cannot give {:?} the source's owner: {}", path, e);
+        if let Err(e) = chown(path, None, Some(permissions.gid)) {
+            log::debug!("Cannot give {:?} the source's group: {}", path, e);
+        }
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(permissions.mode))
+        .map_err(|e| format!("Failed to set permissions of {:?}: {}", path, e))
+}
+
+#[cfg(not(unix))]
+fn apply_permissions(_path: &Path, _permissions: SourcePermissions) -> Result<(), String> {
+    Ok(())
+}
+
+/// Re-reads a written output from disk and checks that it decodes to the canvas size.
+fn verify_output(path: &Path, expected: (u32, u32)) -> Result<(), String> {
+    let image = ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|e| format!("Failed to reopen output {:?} for verification: {}", path, e))?
+        .decode()
+        .map_err(|e| format!("Output {:?} does not decode: {}", path, e))?;
+    if image.dimensions() != expected {
+        return Err(format!(
+            "Output {:?} is {}x{} instead of {}x{}",
+            path,
+            image.width(),
+            image.height(),
+            expected.0,
+            expected.1
+        ));
+    }
+    log::info!("Verified output {:?}", path);
+    Ok(())
+}
+
+/// Moves a file that could not be delivered into `QUARANTINE_FOLDER` (default: a
+/// `quarantine` folder next to it) so it stops retriggering and can be inspected.
+pub fn quarantine(path: &Path, reason: &str) -> Result<PathBuf, String> {
+    let dir = quarantine_folder(path);
+    fs::create_dir_all(&dir)
+        .map_err(|e| format!("Failed to create quarantine folder {:?}: {}", dir, e))?;
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("Missing filename in {:?}", path))?;
+    let target = dir.join(file_name);
+    move_file(path, &target)
+        .map_err(|e| format!("Failed to quarantine {:?} to {:?}: {}", path, target, e))?;
+
+    log::warn!("Quarantined {:?} to {:?}: {}", path, target, reason);
+    Ok(target)
+}
+
+fn quarantine_folder(path: &Path) -> PathBuf {
+    config::var("QUARANTINE_FOLDER")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| path.with_file_name("quarantine"))
+}
+
+/// Whether `path` is a master written for `MASTER_FORMAT`: it has the master's extension
+/// and its stem ends with `MASTER_SUFFIX`. Masters are outputs, never sources.
+pub fn is_master(path: &Path) -> bool {
+    let Ok(Some(master)) = config::master() else {
+        return false;
+    };
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(&master.ext))
+        && path
+            .file_stem()
+            .is_some_and(|stem| stem.to_string_lossy().ends_with(&master.suffix))
+}
+
+/// Whether `path` is an `OUTPUT_NAMING=sequence` output written by the service, which must
+/// never be taken for a source and renumbered.
+pub fn is_sequence_output(path: &Path) -> bool {
+    matches!(config::output_naming(), Ok(config::OutputNaming::Sequence))
+        && sequence::is_output(path)
+}
+
+/// Whether `path` is a preview written for `PREVIEW`: its stem ends with `.preview`.
+/// Previews are outputs, never sources.
+pub fn is_preview(path: &Path) -> bool {
+    config::flag("PREVIEW")
+        && path
+            .file_stem()
+            .is_some_and(|stem| stem.to_string_lossy().ends_with(PREVIEW_SUFFIX))
+}
+
+/// Whether `path` already sits in a quarantine folder, which a recursive watch would
+/// otherwise pick up again.
+pub fn is_quarantined(path: &Path) -> bool {
+    path.parent().is_some_and(|parent| {
+        (parent != config::watch_folder()
+            && parent.file_name().is_some_and(|name| name == "quarantine"))
+            || config::var("QUARANTINE_FOLDER").is_some_and(|dir| parent == Path::new(&dir))
+    })
+}
+
+/// Win32 errors raised while another process (antivirus, indexer) briefly holds a file.
+const ERROR_ACCESS_DENIED: i32 = 5;
+const ERROR_SHARING_VIOLATION: i32 = 32;
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+fn is_transient_rename_error(e: &io::Error) -> bool {
+    cfg!(windows)
+        && matches!(
+            e.raw_os_error(),
+            Some(ERROR_ACCESS_DENIED | ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+}
+
+/// Claims the output name for `ON_CONFLICT=skip|rename` by atomically creating an empty
+/// placeholder with `create_new`, which the temp file then replaces. Checking `exists()`
+/// first would race with other writers. Returns the claimed path, or `None` for `skip`
+/// when `path` is taken; `rename` tries `<stem>-1.<ext>`, `<stem>-2.<ext>` and so on.
+pub fn reserve_output(
+    path: &Path,
+    on_conflict: config::OnConflict,
+) -> Result<Option<PathBuf>, String> {
+    const MAX_RENAMES: u32 = 1000;
+    let stem = path.file_stem().unwrap_or_default();
+    let ext = path.extension().unwrap_or_default();
+    let mut candidate = path.to_path_buf();
+
+    for counter in 1..=MAX_RENAMES {
+        // Suppress the placeholder's create event before it exists.
+        mark_output(&candidate);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(Some(candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if on_conflict == config::OnConflict::Skip {
+                    return Ok(None);
+                }
+                let mut name = with_suffix(stem, &format!("-{}.", counter));
+                name.push(ext);
+                candidate = path.with_file_name(name);
+            }
+            Err(e) => return Err(format!("Failed to create output {:?}: {}", candidate, e)),
+        }
+    }
+    Err(format!(
+        "No free name for {:?} after {} attempts",
+        path, MAX_RENAMES
+    ))
+}
+
+/// Moves the temp file into place, retrying with exponential backoff while the target
+/// is briefly locked. Other errors fail immediately.
+pub fn rename_with_retries(from: &Path, to: &Path) -> Result<(), ProcessError> {
+    let max_retries = config::rename_retries()?;
+    let mut delay = Duration::from_millis(config::rename_retry_delay_ms()?);
+    let mut retries = 0;
+
+    loop {
+        match move_file(from, to) {
+            Ok(()) => return Ok(()),
+            Err(e) if retries < max_retries && is_transient_rename_error(&e) => {
+                retries += 1;
+                log::warn!(
+                    "Failed to rename {:?} to {:?} on attempt {}: {}. Retrying in {:?}...",
+                    from,
+                    to,
+                    retries,
+                    e,
+                    delay
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(e) => return Err(write_error(e, format!("Failed to rename to {:?}", to))),
+        }
+    }
+}
+
+/// Formats a failed write or move of an output, marked transient for `PROCESS_RETRIES`
+/// when a later attempt may succeed.
+fn write_error(e: io::Error, message: String) -> ProcessError {
+    let message = format!("{}: {}", message, e);
+    if is_transient_write_error(&e) {
+        ProcessError::Transient(message)
+    } else {
+        ProcessError::Permanent(message)
+    }
+}
+
+/// Locks held by antivirus, indexers and sync clients, timeouts, and network shares that
+/// dropped or are reconnecting. A full disk or a missing folder is not expected to fix
+/// itself within a few retries.
+fn is_transient_write_error(e: &io::Error) -> bool {
+    is_transient_rename_error(e)
+        || matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ResourceBusy
+                | io::ErrorKind::NetworkDown
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::StaleNetworkFileHandle
+        )
+}
+
+/// Renames `from` to `to`, falling back to copy + delete when they are on different
+/// volumes. The copy is staged as `<name>.partial` next to `to` and renamed into place,
+/// so the destination never holds a half-written file.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(test)]
+    let rename = if tests::CROSS_DEVICE.get() {
+        Err(io::Error::from(io::ErrorKind::CrossesDevices))
+    } else {
+        fs::rename(from, to)
+    };
+    #[cfg(not(test))]
+    let rename = fs::rename(from, to);
+    match rename {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut staging_name = to.file_name().unwrap_or_default().to_os_string();
+            staging_name.push(".partial");
+            let staging = to.with_file_name(staging_name);
+
+            log::info!("Moving {:?} across devices via {:?}", from, staging);
+            fs::copy(from, &staging)?;
+            if let Err(e) = fs::rename(&staging, to) {
+                let _ = fs::remove_file(&staging);
+                return Err(e);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Whether `path` has one of the extensions in `IMAGE_EXTENSIONS`. With
+/// `SNIFF_EXTENSIONLESS`, a file without an extension also qualifies when its first bytes
+/// identify a format with one of those extensions.
+pub fn is_image_file(path: &Path) -> bool {
+    let Ok(extensions) = config::image_extensions() else {
+        return false;
+    };
+    match path.extension() {
+        Some(ext) => ext
+            .to_str()
+            .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase())),
+        None if config::flag("SNIFF_EXTENSIONLESS") => sniff_format(path).is_some_and(|format| {
+            format
+                .extensions_str()
+                .iter()
+                .any(|ext| extensions.iter().any(|allowed| allowed == ext))
+        }),
+        None => false,
+    }
+}
+
+/// The format named by the magic bytes at the start of `path`, for files without an
+/// extension. Files that are empty so far, as right after they are created, have none.
+fn sniff_format(path: &Path) -> Option<ImageFormat> {
+    let mut magic = [0u8; 32];
+    let read = fs::File::open(path).ok()?.read(&mut magic).ok()?;
+    image::guess_format(&magic[..read]).ok()
+}
+
+/// Whether the file name of `path` contains `needle`. Works on the encoded bytes, so
+/// names that are not valid Unicode are matched too instead of being skipped.
+pub fn file_name_contains(path: &Path, needle: &str) -> bool {
+    path.file_name().is_some_and(|name| {
+        name.as_encoded_bytes()
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    })
+}
+
+/// `stem` followed by `suffix`, keeping non-Unicode stems intact.
+fn with_suffix(stem: &OsStr, suffix: &str) -> OsString {
+    let mut name = stem.to_os_string();
+    name.push(suffix);
+    name
+}
+
+pub fn is_heif(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("heic") || ext.eq_ignore_ascii_case("heif"))
+}
+
+/// Picks the decoder for a source file. Formats that need an optional feature fail
+/// immediately when it is not compiled in instead of going through the retry loop.
+fn load_source(path: &Path) -> Result<DynamicImage, String> {
+    #[cfg(feature = "pdf")]
+    if pdf::is_pdf(path) {
+        return pdf::rasterize_first_page(path);
+    }
+    if is_heif(path) {
+        #[cfg(feature = "heif")]
+        return heif::decode(path);
+        #[cfg(not(feature = "heif"))]
+        return Err("HEIF support is not compiled in; rebuild with --features heif".into());
+    }
+    decode_with_retries(path)
+}
+
+thread_local! {
+    /// This decode worker's `DECODE_THREADS` pool and its size, built on first use.
+    static DECODE_POOL: RefCell<Option<(usize, rayon::ThreadPool)>> = const { RefCell::new(None) };
+}
+
+/// Runs `decode` on this worker's pool of `DECODE_THREADS` threads. The JPEG decoder
+/// (also used for JPEG-compressed TIFFs) splits its color conversion across the current
+/// rayon pool; other formats decode on one thread either way. Without the setting it
+/// runs on rayon's global pool, one thread per core shared by all decode workers.
+fn with_decode_threads<T: Send>(decode: impl FnOnce() -> T + Send) -> T {
+    let Ok(Some(threads)) = config::decode_threads() else {
+        return decode();
+    };
+    DECODE_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.as_ref().is_none_or(|(size, _)| *size != threads) {
+            match rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|index| format!("decode-thread-{}", index))
+                .build()
+            {
+                Ok(built) => *pool = Some((threads, built)),
+                Err(e) => {
+                    log::warn!("Failed to start {} decode threads: {}", threads, e);
+                    *pool = None;
+                }
+            }
+        }
+        match pool.as_ref() {
+            Some((_, pool)) => pool.install(decode),
+            None => decode(),
+        }
+    })
+}
+
+/// How far from the end of the file the end marker may sit, to allow trailing data that
+/// some cameras and editors append.
+const TRAILER_WINDOW: u64 = 4096;
+/// Trailing zero bytes beyond which a file counts as preallocated but not yet filled.
+const MAX_ZERO_TAIL: usize = 16;
+
+/// `CHECK_TRUNCATION`: whether a JPEG or PNG file has been written to the end. Copies
+/// over SMB and from Explorer often preallocate the full size first, so a file that is
+/// still being filled has a zero tail that decodes without error into a flat gray or
+/// smeared bottom. A complete file has its end marker (JPEG `FF D9`, PNG `IEND`) within
+/// the last `TRAILER_WINDOW` bytes and no run of zeros after it. Other formats pass.
+fn is_complete(path: &Path) -> io::Result<bool> {
+    let mut file = fs::File::open(path)?;
+    let mut magic = [0u8; 8];
+    let read = file.read(&mut magic)?;
+    let marker: &[u8] = match &magic[..read] {
+        [0xFF, 0xD8, ..] => &[0xFF, 0xD9],
+        [0x89, b'P', b'N', b'G', ..] => b"IEND",
+        _ => return Ok(true),
+    };
+
+    let length = file.metadata()?.len();
+    file.seek(SeekFrom::Start(length.saturating_sub(TRAILER_WINDOW)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+
+    let zero_tail = tail.iter().rev().take_while(|&&byte| byte == 0).count();
+    Ok(zero_tail <= MAX_ZERO_TAIL && tail.windows(marker.len()).any(|window| window == marker))
+}
+
+/// Reads the dimensions from the file header without decoding the pixels. `None` for
+/// files the `image` crate cannot read, such as PDF or HEIF.
+fn probe_dimensions(path: &Path) -> Option<(u32, u32)> {
+    if is_heif(path) {
+        return None;
+    }
+    ImageReader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+/// Rejects sources whose longer edge is below `MIN_INPUT_DIMENSION`: skipped, or failed and
+/// quarantined with `SMALL_IMAGE_ACTION=quarantine`.
+fn check_min_dimension(
+    path: &Path,
+    (width, height): (u32, u32),
+    min: u32,
+    dry_run: bool,
+) -> Result<(), ProcessError> {
+    if width.max(height) >= min {
+        return Ok(());
+    }
+    let reason = format!(
+        "Image is {}x{}, below MIN_INPUT_DIMENSION ({}px on the long edge); not upscaling it",
+        width, height, min
+    );
+    log::warn!("{:?}: {}", path, reason);
+    if !config::quarantine_small_images()? {
+        return Err(ProcessError::Skipped(reason));
+    }
+    if !dry_run {
+        quarantine(path, &reason)?;
+    }
+    Err(reason.into())
+}
+
+fn decode_with_retries(path: &Path) -> Result<DynamicImage, String> {
+    const MAX_RETRIES: u32 = 5;
+    const RETRY_DELAY_MS: u64 = 200;
+    let mut retries = 0;
+
+    let check_truncation = config::flag("CHECK_TRUNCATION");
+
+    loop {
+        if check_truncation {
+            match is_complete(path) {
+                Ok(true) => {}
+                Ok(false) if retries < MAX_RETRIES => {
+                    retries += 1;
+                    log::warn!(
+                        "Image {:?} looks partially written on attempt {}. Retrying...",
+                        path,
+                        retries
+                    );
+                    thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                    continue;
+                }
+                Ok(false) => {
+                    log::error!(
+                        "Image {:?} still looks partially written after {} attempts",
+                        path,
+                        retries
+                    );
+                    return Err(format!(
+                        "Image {:?} is truncated: its end marker is missing",
+                        path
+                    ));
+                }
+                // Let the decoder below report files that cannot be read.
+                Err(_) => {}
+            }
+        }
+
+        match ImageReader::open(path).and_then(|reader| reader.with_guessed_format()) {
+            Ok(reader) => match with_decode_threads(|| match cmyk::probe(path) {
+                Some(found) => {
+                    log::info!("Converting CMYK JPEG {:?} to RGB", path);
+                    cmyk::decode(path, found)
+                }
+                None => reader.decode().map_err(|e| e.to_string()),
+            }) {
+                Ok(img) => return Ok(img),
+                Err(e) if retries < MAX_RETRIES => {
+                    retries += 1;
+                    log::warn!(
+                        "Failed to decode image {:?} on attempt {}: {}. Retrying...",
+                        path,
+                        retries,
+                        e
+                    );
+                    thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+                }
+                Err(e) => {
+                    log::error!(
+                        "Failed to decode image {:?} after {} attempts: {}",
+                        path,
+                        retries,
+                        e
+                    );
+                    return Err(format!("Failed to decode image {:?}: {}", path, e));
+                }
+            },
+            Err(e) if retries < MAX_RETRIES => {
+                retries += 1;
+                log::warn!(
+                    "Failed to open image {:?} on attempt {}: {}. Retrying...",
+                    path,
+                    retries,
+                    e
+                );
+                thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to open image {:?} after {} attempts: {}",
+                    path,
+                    retries,
+                    e
+                );
+                return Err(format!("Failed to open image {:?}: {}", path, e));
+            }
+        }
+    }
+}
+
+/// Straightens `img` with `DESKEW`, crops it to its content, widened by `CROP_MARGIN_PX`
+/// within the image, and flattens transparency unless it is kept.
+fn crop(img: DynamicImage, opts: &NormalizeOptions) -> DynamicImage {
+    let img = match opts.deskew {
+        Some(max_degrees) => deskew(img, max_degrees),
+        None => img,
+    };
+    let (width, height) = img.dimensions();
+    let margin = opts.crop_margin;
+    let bounds = match content_bounds(&img, opts) {
+        (l, t, r, b) if r > l && b > t => (
+            l.saturating_sub(margin),
+            t.saturating_sub(margin),
+            r.saturating_add(margin).min(width),
+            b.saturating_add(margin).min(height),
+        ),
+        empty => empty,
+    };
+    let (l, t, r, b) = match opts.crop_sides.apply(bounds, (width, height)) {
+        // Nothing to crop to in a blank image, so it is kept whole.
+        (l, t, r, b) if r <= l || b <= t => (0, 0, width, height),
+        bounds => bounds,
+    };
+    let cropped = img.crop_imm(l, t, r - l, b - t);
+    match opts.background {
+        config::Background::White | config::Background::Auto => flatten_alpha(cropped, WHITE),
+        config::Background::Color(color) => flatten_alpha(cropped, color),
+        config::Background::Transparent => cropped,
+    }
+}
+
+/// `MAX_OUTPUT_PIXELS`: scales a canvas larger than `max` pixels, and its padding, down
+/// proportionally until it fits.
+fn within_pixel_budget(path: &Path, size: (u32, u32), pad: u32, max: u64) -> ((u32, u32), u32) {
+    let area = u64::from(size.0) * u64::from(size.1);
+    if area <= max {
+        return (size, pad);
+    }
+    let factor = (max as f64 / area as f64).sqrt();
+    let scale = |side: u32| ((f64::from(side) * factor) as u32).max(1);
+    let (mut width, mut height) = (scale(size.0), scale(size.1));
+    // Rounding can leave the product a little above the budget.
+    while u64::from(width) * u64::from(height) > max && width.max(height) > 1 {
+        if width >= height {
+            width -= 1;
+        } else {
+            height -= 1;
+        }
+    }
+    let fitted = ((width, height), (f64::from(pad) * factor).round() as u32);
+    log::info!(
+        "Reducing the canvas of {:?} from {}x{} to {}x{} to stay within MAX_OUTPUT_PIXELS ({})",
+        path,
+        size.0,
+        size.1,
+        width,
+        height,
+        max
+    );
+    fitted
+}
+
+/// Longest side of the copy the skew is measured on.
+const DESKEW_SAMPLE: u32 = 800;
+/// Luma difference across a pixel above which it counts as an edge.
+const DESKEW_EDGE: i16 = 48;
+/// Fewest edge pixels needed to measure a skew at all.
+const DESKEW_MIN_EDGES: usize = 200;
+/// How many times more concentrated the edges must be at the detected angle than
+/// unrotated. Scans with text lines or paper edges clear this easily; photos without
+/// long straight lines do not, so they are left alone.
+const DESKEW_CONFIDENCE: f64 = 1.25;
+
+/// `DESKEW`: rotates `img` so the straight lines in it become level again, if it is
+/// clearly skewed by less than `max_degrees`. The canvas grows to keep the corners and
+/// the uncovered area is filled with the background of the image.
+fn deskew(img: DynamicImage, max_degrees: f32) -> DynamicImage {
+    let Some(degrees) = skew_angle(&img, f64::from(max_degrees)) else {
+        return img;
+    };
+    log::info!("Straightening content skewed by {:.1} degrees", degrees);
+    let had_alpha = img.color().has_alpha();
+    let radians = degrees.to_radians() as f32;
+    if is_high_depth(&img) {
+        let fill = if had_alpha {
+            Rgba([u16::MAX, u16::MAX, u16::MAX, 0])
+        } else {
+            let [r, g, b] = corner_color(&img).map(|channel| u16::from(channel) * 257);
+            Rgba([r, g, b, u16::MAX])
+        };
+        let rotated = DynamicImage::ImageRgba16(unrotate(&img.to_rgba16(), radians, fill));
+        if had_alpha {
+            rotated
+        } else {
+            DynamicImage::ImageRgb16(rotated.into_rgb16())
+        }
+    } else {
+        let fill = if had_alpha {
+            Rgba([u8::MAX, u8::MAX, u8::MAX, 0])
+        } else {
+            let [r, g, b] = corner_color(&img);
+            Rgba([r, g, b, u8::MAX])
+        };
+        let rotated = DynamicImage::ImageRgba8(unrotate(&img.to_rgba8(), radians, fill));
+        if had_alpha {
+            rotated
+        } else {
+            DynamicImage::ImageRgb8(rotated.into_rgb8())
+        }
+    }
+}
+
+/// Measures the angle in degrees by which the lines in `img` descend to the right, with a
+/// coarse Hough transform: edge pixels vote for the lines through them at each candidate
+/// angle, and the angle whose votes pile up in the fewest lines wins. Near-horizontal and
+/// near-vertical edges are counted separately, so text lines and paper borders both
+/// help. `None` when the image is level, has too few edges, shows no clear angle, or the
+/// best angle lies at the edge of `max_degrees`, where the real skew may be larger.
+fn skew_angle(img: &DynamicImage, max_degrees: f64) -> Option<f64> {
+    let sample = if img.width().max(img.height()) > DESKEW_SAMPLE {
+        img.thumbnail(DESKEW_SAMPLE, DESKEW_SAMPLE)
+    } else {
+        img.clone()
+    };
+    let gray = flatten_alpha(sample, WHITE).to_luma8();
+    let (width, height) = gray.dimensions();
+    let luma = |x: u32, y: u32| i16::from(gray.get_pixel(x, y)[0]);
+    let (mut horizontal, mut vertical) = (Vec::new(), Vec::new());
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let dx = luma(x + 1, y) - luma(x - 1, y);
+            let dy = luma(x, y + 1) - luma(x, y - 1);
+            if dx.abs().max(dy.abs()) < DESKEW_EDGE {
+                continue;
+            }
+            let point = (f64::from(x), f64::from(y));
+            if dy.abs() >= dx.abs() {
+                horizontal.push(point);
+            } else {
+                vertical.push(point);
+            }
+        }
+    }
+    if horizontal.len() + vertical.len() < DESKEW_MIN_EDGES {
+        return None;
+    }
+
+    let offset = (width + height) as usize;
+    let score = |degrees: f64| {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        // Lines skewed by `degrees` keep `y cos - x sin` (near-horizontal) or
+        // `x cos + y sin` (near-vertical) constant; each value is one accumulator bin.
+        let concentration = |points: &[(f64, f64)], distance: &dyn Fn(f64, f64) -> f64| {
+            let mut bins = vec![0u32; 2 * offset + 1];
+            for &(x, y) in points {
+                bins[(distance(x, y).round() as isize + offset as isize) as usize] += 1;
+            }
+            bins.iter()
+                .map(|&votes| f64::from(votes).powi(2))
+                .sum::<f64>()
+        };
+        concentration(&horizontal, &|x, y| y * cos - x * sin)
+            + concentration(&vertical, &|x, y| x * cos + y * sin)
+    };
+    let best_of = |angles: &mut dyn Iterator<Item = f64>| {
+        angles
+            .map(|degrees| (degrees, score(degrees)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+    };
+
+    let coarse_steps = (max_degrees * 2.0).round() as i32;
+    let (coarse, _) =
+        best_of(&mut (-coarse_steps..=coarse_steps).map(|step| f64::from(step) / 2.0))?;
+    let (degrees, best) = best_of(
+        &mut (-5..=5)
+            .map(|step| coarse + f64::from(step) / 10.0)
+            .filter(|degrees| degrees.abs() <= max_degrees),
+    )?;
+    if degrees.abs() < 0.1 || degrees.abs() > max_degrees - 0.25 {
+        return None;
+    }
+    (best >= score(0.0) * DESKEW_CONFIDENCE).then_some(degrees)
+}
+
+/// Rotates `source` back by `radians`, the angle its lines descend to the right at, onto
+/// a canvas large enough to hold all of it, sampling bilinearly. Canvas pixels outside
+/// the source are `fill`.
+fn unrotate<P>(
+    source: &ImageBuffer<P, Vec<P::Subpixel>>,
+    radians: f32,
+    fill: P,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+    P::Subpixel: Into<f32> + TryFrom<u32>,
+{
+    let (width, height) = source.dimensions();
+    let (sin, cos) = radians.sin_cos();
+    let (w, h) = (width as f32, height as f32);
+    let new_width = (w * cos.abs() + h * sin.abs()).ceil() as u32;
+    let new_height = (w * sin.abs() + h * cos.abs()).ceil() as u32;
+    let pixel_at = |x: i64, y: i64| {
+        if x < 0 || y < 0 || x >= i64::from(width) || y >= i64::from(height) {
+            fill
+        } else {
+            *source.get_pixel(x as u32, y as u32)
+        }
+    };
+    ImageBuffer::from_fn(new_width, new_height, |x, y| {
+        let u = x as f32 + 0.5 - new_width as f32 / 2.0;
+        let v = y as f32 + 0.5 - new_height as f32 / 2.0;
+        let source_x = u * cos - v * sin + w / 2.0 - 0.5;
+        let source_y = u * sin + v * cos + h / 2.0 - 0.5;
+        let (left, top) = (source_x.floor(), source_y.floor());
+        let (fx, fy) = (source_x - left, source_y - top);
+        let (left, top) = (left as i64, top as i64);
+        let mut sum = [0f32; 4];
+        for (dx, dy, weight) in [
+            (0, 0, (1.0 - fx) * (1.0 - fy)),
+            (1, 0, fx * (1.0 - fy)),
+            (0, 1, (1.0 - fx) * fy),
+            (1, 1, fx * fy),
+        ] {
+            let pixel = pixel_at(left + dx, top + dy);
+            for (total, &channel) in sum.iter_mut().zip(pixel.channels()) {
+                *total += weight * channel.into();
+            }
+        }
+        let mut pixel = fill;
+        for (channel, total) in pixel.channels_mut().iter_mut().zip(sum) {
+            *channel = P::Subpixel::try_from(total.round() as u32).unwrap_or(*channel);
+        }
+        pixel
+    })
+}
+
+/// Scales the cropped content into `opts.size` minus the padding and places it on the
+/// canvas.
+fn fit(cropped: &DynamicImage, opts: &NormalizeOptions) -> DynamicImage {
+    let NormalizeOptions { size, pad, .. } = *opts;
+    // The decode stage keeps the padding within the canvas, and so does `normalize` with
+    // `PADDING_OVERFLOW=clamp`; a content area of one pixel per side is the last resort.
+    let inner = |side: u32| {
+        side.checked_sub(pad.saturating_mul(2))
+            .filter(|inner| *inner > 0)
+            .unwrap_or(1)
+    };
+    let target_size = (inner(size.0), inner(size.1));
+    let (w, h) = cropped.dimensions();
+    // The side that reaches its edge of the content area first, i.e. the smaller of
+    // `target / source` on both axes, limits the scale.
+    let width_limited =
+        u64::from(w) * u64::from(target_size.1) >= u64::from(h) * u64::from(target_size.0);
+    let (new_width, new_height) = if width_limited {
+        (
+            target_size.0,
+            scale_side(h, target_size.0, w, target_size.1),
+        )
+    } else {
+        (
+            scale_side(w, target_size.1, h, target_size.0),
+            target_size.1,
+        )
+    };
+
+    let filter = match opts.filter {
+        config::ResizeFilter::Nearest => FilterType::Nearest,
+        config::ResizeFilter::Triangle => FilterType::Triangle,
+        config::ResizeFilter::CatmullRom => FilterType::CatmullRom,
+        config::ResizeFilter::Gaussian => FilterType::Gaussian,
+        config::ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+    };
+    let resized = if opts.two_pass_downscale {
+        halve_towards(cropped.clone(), (new_width, new_height))
+            .resize_exact(new_width, new_height, filter)
+    } else {
+        cropped.resize_exact(new_width, new_height, filter)
+    };
+    let resized = match opts.sharpen {
+        Some(sharpen) => unsharp_mask(resized, sharpen),
+        None => resized,
+    };
+
+    let mut offset =
+        opts.gravity
+            .offsets(size, (new_width, new_height), pad, opts.center_remainder);
+    if opts.align == config::Align::Centroid
+        && let Some((x, y)) = centroid(&resized)
+    {
+        // Puts the centroid on the canvas center, as far as the free space allows.
+        let to_center = |canvas: u32, content: u32, at: f32| {
+            (canvas as f32 / 2.0 - at)
+                .round()
+                .clamp(0.0, canvas.saturating_sub(content) as f32) as u32
+        };
+        if matches!(
+            opts.gravity,
+            config::Gravity::Center | config::Gravity::Top | config::Gravity::Bottom
+        ) {
+            offset.0 = to_center(size.0, new_width, x);
+        }
+        if matches!(
+            opts.gravity,
+            config::Gravity::Center | config::Gravity::Left | config::Gravity::Right
+        ) {
+            offset.1 = to_center(size.1, new_height, y);
+        }
+    }
+
+    let fill = match opts.background {
+        config::Background::White => Some(WHITE),
+        config::Background::Color(color) => Some(color),
+        config::Background::Auto => Some(corner_color(cropped)),
+        config::Background::Transparent => None,
+    };
+    match (opts.bit_depth, fill) {
+        (config::BitDepth::Eight, Some(fill)) => {
+            DynamicImage::ImageRgb8(compose(resized.to_rgb8(), Rgb(fill), offset, opts))
+        }
+        (config::BitDepth::Eight, None) => {
+            DynamicImage::ImageRgba8(compose(resized.to_rgba8(), Rgba([0; 4]), offset, opts))
+        }
+        (config::BitDepth::Sixteen, Some(fill)) => DynamicImage::ImageRgb16(compose(
+            resized.to_rgb16(),
+            Rgb(fill.map(|channel| u16::from(channel) * 257)),
+            offset,
+            opts,
+        )),
+        (config::BitDepth::Sixteen, None) => {
+            DynamicImage::ImageRgba16(compose(resized.to_rgba16(), Rgba([0; 4]), offset, opts))
+        }
+    }
+}
+
+/// `BACKGROUND=auto`: the per-channel median of the four corner pixels, which ignores a
+/// single corner that the content reaches into.
+fn corner_color(img: &DynamicImage) -> [u8; 3] {
+    let (width, height) = img.dimensions();
+    let (right, bottom) = (width.saturating_sub(1), height.saturating_sub(1));
+    let corners = [(0, 0), (right, 0), (0, bottom), (right, bottom)]
+        .map(|(x, y)| img.get_pixel(x, y).to_rgb().0);
+    std::array::from_fn(|channel| {
+        let mut values = corners.map(|corner| corner[channel]);
+        values.sort_unstable();
+        ((u16::from(values[1]) + u16::from(values[2])) / 2) as u8
+    })
+}
+
+/// Center of mass of `img`, each pixel weighted by its darkness (`255 - luma`) and
+/// opacity, in pixel-center coordinates. `None` for a blank image.
+fn centroid(img: &DynamicImage) -> Option<(f32, f32)> {
+    let pixels = img.to_luma_alpha8();
+    let (mut total, mut sum_x, mut sum_y) = (0.0f64, 0.0f64, 0.0f64);
+    for (x, y, pixel) in pixels.enumerate_pixels() {
+        let [luma, alpha] = pixel.0;
+        let weight = f64::from(255 - luma) * f64::from(alpha) / 255.0;
+        total += weight;
+        sum_x += weight * (f64::from(x) + 0.5);
+        sum_y += weight * (f64::from(y) + 0.5);
+    }
+    (total > 0.0).then(|| ((sum_x / total) as f32, (sum_y / total) as f32))
+}
+
+/// Places `resized` at `offset` on a canvas of `opts.size` filled with `background`, in
+/// the pixel type of the output so 16-bit content is never narrowed on the way.
+fn compose<P>(
+    resized: ImageBuffer<P, Vec<P::Subpixel>>,
+    background: P,
+    (offset_x, offset_y): (u32, u32),
+    opts: &NormalizeOptions,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+    P::Subpixel: Into<f32> + TryFrom<u32>,
+{
+    let mut canvas = ImageBuffer::from_pixel(opts.size.0, opts.size.1, background);
+    if opts.feather_edges {
+        feather_paste(&mut canvas, &resized, (offset_x, offset_y));
+    } else {
+        imageops::replace(&mut canvas, &resized, offset_x.into(), offset_y.into());
+    }
+    if opts.mask == config::Mask::Circle {
+        mask_circle(&mut canvas, background);
+    }
+    canvas
+}
+
+/// `FEATHER_EDGES`: composites `resized` onto `canvas` with its alpha instead of copying
+/// it, and at half opacity along its outermost pixels, so the content fades into a
+/// colored background over one pixel instead of meeting it in a seam. Edges that lie on
+/// the canvas border are not feathered; everything inside them matches a hard paste.
+fn feather_paste<P>(
+    canvas: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+    resized: &ImageBuffer<P, Vec<P::Subpixel>>,
+    (offset_x, offset_y): (u32, u32),
+) where
+    P: Pixel,
+    P::Subpixel: Into<f32> + TryFrom<u32>,
+{
+    let max: f32 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    let has_alpha = P::CHANNEL_COUNT == 4;
+    let color_channels = usize::from(P::CHANNEL_COUNT.min(3));
+    let (width, height) = resized.dimensions();
+    let (canvas_width, canvas_height) = canvas.dimensions();
+    for (x, y, source) in resized.enumerate_pixels() {
+        let (canvas_x, canvas_y) = (offset_x + x, offset_y + y);
+        if canvas_x >= canvas_width || canvas_y >= canvas_height {
+            continue;
+        }
+        let on_edge = (x == 0 && offset_x > 0)
+            || (y == 0 && offset_y > 0)
+            || (x + 1 == width && canvas_x + 1 < canvas_width)
+            || (y + 1 == height && canvas_y + 1 < canvas_height);
+        let source = source.channels();
+        let target = canvas.get_pixel_mut(canvas_x, canvas_y).channels_mut();
+        let (source_alpha, target_alpha) = if has_alpha {
+            (source[3].into() / max, target[3].into() / max)
+        } else {
+            (1.0, 1.0)
+        };
+        let alpha = if on_edge {
+            source_alpha / 2.0
+        } else {
+            source_alpha
+        };
+        let covered = alpha + target_alpha * (1.0 - alpha);
+        if covered <= 0.0 {
+            continue;
+        }
+        for (channel, &over) in target[..color_channels]
+            .iter_mut()
+            .zip(&source[..color_channels])
+        {
+            let under: f32 = (*channel).into();
+            let value = (over.into() * alpha + under * target_alpha * (1.0 - alpha)) / covered;
+            *channel =
+                P::Subpixel::try_from(value.round().clamp(0.0, max) as u32).unwrap_or(*channel);
+        }
+        if has_alpha {
+            target[3] = P::Subpixel::try_from((covered * max).round() as u32).unwrap_or(target[3]);
+        }
+    }
+}
+
+/// Fills everything outside the ellipse inscribed in the canvas with `background`.
+fn mask_circle<P: Pixel>(canvas: &mut ImageBuffer<P, Vec<P::Subpixel>>, background: P) {
+    let (width, height) = canvas.dimensions();
+    let (rx, ry) = (width as f32 / 2.0, height as f32 / 2.0);
+    for y in 0..height {
+        for x in 0..width {
+            let dx = (x as f32 + 0.5 - rx) / rx;
+            let dy = (y as f32 + 0.5 - ry) / ry;
+            if dx * dx + dy * dy > 1.0 {
+                canvas.put_pixel(x, y, background);
+            }
+        }
+    }
+}
+
+/// Scales `side` by `to / from`, rounding to the nearest pixel, and keeps the result
+/// within `1..=max`, the room on its own axis. Truncating through `f32` could lose a
+/// pixel (e.g. 699.99 -> 699) and leave a one-pixel stripe on one side.
+fn scale_side(side: u32, to: u32, from: u32, max: u32) -> u32 {
+    let from = u64::from(from.max(1));
+    let scaled = (u64::from(side) * u64::from(to) + from / 2) / from;
+    scaled.clamp(1, u64::from(max.max(1))) as u32
+}
+
+/// Halves `img` with a 2x2 box filter while it is still more than twice the target size,
+/// so the final Gaussian resize only covers a reduction of at most 2x. A single large
+/// Gaussian step samples too sparsely and aliases fine detail such as fabric or text.
+fn halve_towards(mut img: DynamicImage, target: (u32, u32)) -> DynamicImage {
+    while img.width() / 2 >= target.0.max(1) && img.height() / 2 >= target.1.max(1) {
+        img = if is_high_depth(&img) {
+            DynamicImage::ImageRgba16(halve(&img.to_rgba16()))
+        } else {
+            DynamicImage::ImageRgba8(halve(&img.to_rgba8()))
+        };
+    }
+    img
+}
+
+fn halve<P>(source: &ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel,
+    P::Subpixel: Into<u32> + TryFrom<u32>,
+{
+    let (width, height) = (source.width() / 2, source.height() / 2);
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = [0u32; 4];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let pixel = source.get_pixel(2 * x + dx, 2 * y + dy);
+            for (total, &channel) in sum.iter_mut().zip(pixel.channels()) {
+                *total += channel.into();
+            }
+        }
+        let mut halved = *source.get_pixel(2 * x, 2 * y);
+        for (channel, total) in halved.channels_mut().iter_mut().zip(sum) {
+            *channel = P::Subpixel::try_from((total + 2) / 4).unwrap_or(*channel);
+        }
+        halved
+    })
+}
+
+/// Adds `amount` times the difference between each pixel and its Gaussian blur back onto
+/// the color channels, restoring edge contrast that downscaling softens. Alpha is kept.
+fn unsharp_mask(img: DynamicImage, sharpen: config::Sharpen) -> DynamicImage {
+    if is_high_depth(&img) {
+        DynamicImage::ImageRgba16(unsharp(img.into_rgba16(), sharpen))
+    } else {
+        DynamicImage::ImageRgba8(unsharp(img.into_rgba8(), sharpen))
+    }
+}
+
+fn unsharp<P>(
+    mut img: ImageBuffer<P, Vec<P::Subpixel>>,
+    sharpen: config::Sharpen,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+    P::Subpixel: Into<f32> + TryFrom<u32>,
+{
+    let blurred = imageops::blur(&img, sharpen.radius);
+    let max: f32 = P::Subpixel::DEFAULT_MAX_VALUE.into();
+    for (pixel, soft) in img.pixels_mut().zip(blurred.pixels()) {
+        let color_channels = usize::from(P::CHANNEL_COUNT.min(3));
+        for (channel, &soft) in pixel.channels_mut()[..color_channels]
+            .iter_mut()
+            .zip(soft.channels())
+        {
+            let value: f32 = (*channel).into();
+            let sharpened = value + sharpen.amount * (value - soft.into());
+            if let Ok(sharpened) = P::Subpixel::try_from(sharpened.round().clamp(0.0, max) as u32) {
+                *channel = sharpened;
+            }
+        }
+    }
+    img
+}
+
+/// Whether `img` has more than 8 bits per channel.
+fn is_high_depth(img: &DynamicImage) -> bool {
+    let color = img.color();
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+pub(crate) const WHITE: [u8; 3] = [u8::MAX; 3];
+
+/// Composites transparent pixels onto `onto`. Otherwise a transparent background reads
+/// as black in `to_luma8`, so `bounding_box` treats it as content, and it is copied onto
+/// an opaque canvas as black.
+pub(crate) fn flatten_alpha(img: DynamicImage, onto: [u8; 3]) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+    if is_high_depth(&img) {
+        let mut rgba = img.into_rgba16();
+        for pixel in rgba.pixels_mut() {
+            let alpha = u32::from(pixel[3]);
+            for (channel, &background) in pixel.0[..3].iter_mut().zip(&onto) {
+                let background = u32::from(background) * 257;
+                *channel = blend(u32::from(*channel), alpha, background, u16::MAX.into()) as u16;
+            }
+            pixel[3] = u16::MAX;
+        }
+        return DynamicImage::ImageRgba16(rgba);
+    }
+    let mut rgba = img.into_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let alpha = u32::from(pixel[3]);
+        for (channel, &background) in pixel.0[..3].iter_mut().zip(&onto) {
+            let background = u32::from(background);
+            *channel = blend(u32::from(*channel), alpha, background, u8::MAX.into()) as u8;
+        }
+        pixel[3] = u8::MAX;
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Blends `channel` at `alpha` over `background`, where `max` is the channel's full
+/// value.
+fn blend(channel: u32, alpha: u32, background: u32, max: u32) -> u32 {
+    (channel * alpha + background * (max - alpha) + max / 2) / max
+}
+
+/// Share of the image area covered by the detected content bounding box.
+fn content_ratio(img: &DynamicImage, opts: &NormalizeOptions) -> f32 {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+    let (l, t, r, b) = content_bounds(img, opts);
+    let area = r.saturating_sub(l) as f32 * b.saturating_sub(t) as f32;
+    area / (width as f32 * height as f32)
+}
+
+/// Detects content by luma below `255 - tol`, by luma away from the border background
+/// with `ADAPTIVE_TOLERANCE`, or per channel with `CHANNEL_TOLERANCE`. Transparent pixels
+/// count as background. With `NOISE_TOLERANCE`, specks smaller than that are ignored.
+/// Returns `(left, top, right, bottom)` as `bounding_box` does.
+pub fn content_bounds(img: &DynamicImage, opts: &NormalizeOptions) -> (u32, u32, u32, u32) {
+    let flattened;
+    let img = if img.color().has_alpha() {
+        flattened = flatten_alpha(img.clone(), WHITE);
+        &flattened
+    } else {
+        img
+    };
+    match opts.channel_tolerance {
+        Some(channels) => {
+            let rgb = img.to_rgb8();
+            find_bounds(rgb.dimensions(), opts.noise_tolerance, |x, y| {
+                channels.is_content(rgb.get_pixel(x, y).0)
+            })
+        }
+        None if opts.adaptive_tolerance => {
+            let gray = img.to_luma8();
+            let (background, tol) = border_background(&gray);
+            find_bounds(gray.dimensions(), opts.noise_tolerance, |x, y| {
+                gray.get_pixel(x, y)[0].abs_diff(background) > tol
+            })
+        }
+        None => {
+            let gray = img.to_luma8();
+            let threshold = 255 - opts.tol;
+            find_bounds(gray.dimensions(), opts.noise_tolerance, |x, y| {
+                gray.get_pixel(x, y)[0] < threshold
+            })
+        }
+    }
+}
+
+/// Border lumas further than this from the most common one are treated as content
+/// touching the edge and left out of the spread.
+const ADAPTIVE_WINDOW: u8 = 32;
+/// Tolerance in standard deviations of the border spread.
+const ADAPTIVE_SIGMAS: f32 = 3.0;
+/// Lower bound for the adaptive tolerance, so perfectly flat borders still absorb
+/// compression noise inside the image.
+const ADAPTIVE_MIN_TOLERANCE: u8 = 4;
+
+/// `ADAPTIVE_TOLERANCE` heuristic: the background is the most common luma of the
+/// outermost pixel ring, and the tolerance is `ADAPTIVE_SIGMAS` times the standard
+/// deviation of the ring around it, ignoring pixels more than `ADAPTIVE_WINDOW` away.
+/// Returns `(background, tolerance)`.
+pub(crate) fn border_background(gray: &image::GrayImage) -> (u8, u8) {
+    let (width, height) = gray.dimensions();
+    let mut histogram = [0u64; 256];
+    for x in 0..width {
+        histogram[gray.get_pixel(x, 0)[0] as usize] += 1;
+        histogram[gray.get_pixel(x, height - 1)[0] as usize] += 1;
+    }
+    for y in 0..height {
+        histogram[gray.get_pixel(0, y)[0] as usize] += 1;
+        histogram[gray.get_pixel(width - 1, y)[0] as usize] += 1;
+    }
+
+    let background = (0..=u8::MAX)
+        .max_by_key(|&luma| histogram[luma as usize])
+        .unwrap_or(u8::MAX);
+    let (count, squares) = (0..=u8::MAX)
+        .filter(|luma| luma.abs_diff(background) <= ADAPTIVE_WINDOW)
+        .fold((0u64, 0u64), |(count, squares), luma| {
+            let n = histogram[luma as usize];
+            let d = u64::from(luma.abs_diff(background));
+            (count + n, squares + n * d * d)
+        });
+    let deviation = (squares as f32 / count.max(1) as f32).sqrt();
+    let tol = (ADAPTIVE_SIGMAS * deviation)
+        .ceil()
+        .clamp(f32::from(ADAPTIVE_MIN_TOLERANCE), f32::from(u8::MAX)) as u8;
+    (background, tol)
+}
+
+/// Minimum length of an exact-color horizontal run counted as a flat UI area.
+const FLAT_RUN: u32 = 16;
+/// Share of the image in flat non-background runs above which it counts as a screenshot.
+const FLAT_SHARE: f32 = 0.2;
+
+/// Screenshot heuristic for `SCREENSHOT_MODE=auto`: the default names of the Windows and
+/// macOS capture tools, or large flat-colored areas that are not background (toolbars,
+/// panels, title bars). Photos have sensor noise and JPEG artifacts, so their content
+/// rarely repeats the exact same color for long runs.
+fn looks_like_screenshot(path: &Path, img: &DynamicImage, tol: u8) -> bool {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if ["screenshot", "screen shot", "capture"]
+        .iter()
+        .any(|hint| stem.starts_with(hint))
+    {
+        return true;
+    }
+    flat_share(img, tol) >= FLAT_SHARE
+}
+
+/// Share of pixels in horizontal runs of at least `FLAT_RUN` identical pixels that the
+/// luma threshold treats as content.
+fn flat_share(img: &DynamicImage, tol: u8) -> f32 {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+    let rgb = img.to_rgb8();
+    let threshold = 255 - tol;
+    let is_flat_content =
+        |color: Rgb<u8>, length: u32| length >= FLAT_RUN && color.to_luma()[0] < threshold;
+    let mut flat = 0u64;
+    for row in rgb.rows() {
+        let mut run: Option<(Rgb<u8>, u32)> = None;
+        for &pixel in row {
+            match &mut run {
+                Some((color, length)) if *color == pixel => *length += 1,
+                _ => {
+                    if let Some((color, length)) = run.replace((pixel, 1))
+                        && is_flat_content(color, length)
+                    {
+                        flat += u64::from(length);
+                    }
+                }
+            }
+        }
+        if let Some((color, length)) = run
+            && is_flat_content(color, length)
+        {
+            flat += u64::from(length);
+        }
+    }
+    flat as f32 / (u64::from(width) * u64::from(height)) as f32
+}
+
+/// `bounding_box`, or `cluster_box` when `noise_tolerance` is set.
+fn find_bounds(
+    dimensions: (u32, u32),
+    noise_tolerance: Option<u32>,
+    is_content: impl Fn(u32, u32) -> bool,
+) -> (u32, u32, u32, u32) {
+    match noise_tolerance {
+        Some(min_pixels) => cluster_box(dimensions, min_pixels, is_content),
+        None => bounding_box(dimensions, is_content),
+    }
+}
+
+/// Like `bounding_box`, but only counts groups of at least `min_pixels` content pixels
+/// that touch each other, diagonals included, so dust and compression artifacts do not
+/// stretch the box to the image edge. When every group is smaller, as with fine line
+/// art, all content counts.
+fn cluster_box(
+    (width, height): (u32, u32),
+    min_pixels: u32,
+    is_content: impl Fn(u32, u32) -> bool,
+) -> (u32, u32, u32, u32) {
+    let (w, h) = (width as usize, height as usize);
+    let mut unvisited: Vec<bool> = (0..h)
+        .flat_map(|y| (0..w).map(move |x| (x, y)))
+        .map(|(x, y)| is_content(x as u32, y as u32))
+        .collect();
+
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    let mut any: Option<(u32, u32, u32, u32)> = None;
+    let mut stack = Vec::new();
+    for start in 0..unvisited.len() {
+        if !unvisited[start] {
+            continue;
+        }
+        unvisited[start] = false;
+        stack.push(start);
+        let (x, y) = ((start % w) as u32, (start / w) as u32);
+        let mut cluster = (x, y, x, y);
+        let mut pixels = 0u32;
+        while let Some(index) = stack.pop() {
+            pixels = pixels.saturating_add(1);
+            let (x, y) = (index % w, index / w);
+            cluster = (
+                cluster.0.min(x as u32),
+                cluster.1.min(y as u32),
+                cluster.2.max(x as u32),
+                cluster.3.max(y as u32),
+            );
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    let neighbor = ny * w + nx;
+                    if unvisited[neighbor] {
+                        unvisited[neighbor] = false;
+                        stack.push(neighbor);
+                    }
+                }
+            }
+        }
+        let merge = |total: &mut Option<(u32, u32, u32, u32)>| {
+            let (left, top, right, bottom) = total.get_or_insert(cluster);
+            *left = (*left).min(cluster.0);
+            *top = (*top).min(cluster.1);
+            *right = (*right).max(cluster.2);
+            *bottom = (*bottom).max(cluster.3);
+        };
+        merge(&mut any);
+        if pixels >= min_pixels {
+            merge(&mut bounds);
+        }
+    }
+    bounds
+        .or(any)
+        .map_or((0, 0, 0, 0), |(left, top, right, bottom)| {
+            (left, top, right + 1, bottom + 1)
+        })
+}
+
+/// Returns `(left, top, right, bottom)` of the pixels matching `is_content`, with
+/// `right` and `bottom` exclusive, or an empty box at the origin when none match.
+fn bounding_box(
+    (width, height): (u32, u32),
+    is_content: impl Fn(u32, u32) -> bool,
+) -> (u32, u32, u32, u32) {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+
+    for y in 0..height {
+        for x in 0..width {
+            if is_content(x, y) {
+                let (left, top, right, bottom) = bounds.get_or_insert((x, y, x, y));
+                *left = (*left).min(x);
+                *right = (*right).max(x);
+                *top = (*top).min(y);
+                *bottom = (*bottom).max(y);
+            }
+        }
+    }
+    bounds.map_or((0, 0, 0, 0), |(left, top, right, bottom)| {
+        (left, top, right + 1, bottom + 1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Makes `move_file` on this thread fail its rename as if `to` were on another
+        /// device, so the copy fallback runs without a second filesystem.
+        pub(super) static CROSS_DEVICE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// The options `decode` resolves with no settings given, on a `size` canvas.
+    fn options(size: (u32, u32), pad: u32) -> NormalizeOptions {
+        NormalizeOptions {
+            size,
+            pad,
+            padding_overflow: config::PaddingOverflow::Error,
+            tol: 10,
+            crop_sides: config::CropSides::ALL,
+            crop_margin: 0,
+            channel_tolerance: None,
+            adaptive_tolerance: false,
+            noise_tolerance: None,
+            deskew: None,
+            gravity: config::Gravity::Center,
+            align: config::Align::BoundingBox,
+            center_remainder: config::CenterRemainder::End,
+            two_pass_downscale: false,
+            filter: config::ResizeFilter::Gaussian,
+            feather_edges: false,
+            background: config::Background::White,
+            mask: config::Mask::None,
+            bit_depth: config::BitDepth::Eight,
+            sharpen: None,
+        }
+    }
+
+    /// A dark `width` x `height` product on a white backdrop with a 50 px margin.
+    fn framed(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(
+            width + 100,
+            height + 100,
+            |x, y| {
+                let inside = (50..50 + width).contains(&x) && (50..50 + height).contains(&y);
+                Rgb(if inside { [40, 40, 40] } else { WHITE })
+            },
+        ))
+    }
+
+    /// Bounding box `(left, top, right, bottom)`, right and bottom exclusive, of the
+    /// pixels darker than mid-gray.
+    fn dark_bounds(img: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+        let luma = img.to_luma8();
+        let dark: Vec<(u32, u32)> = luma
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel.0[0] < 128)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let left = dark.iter().map(|&(x, _)| x).min()?;
+        let top = dark.iter().map(|&(_, y)| y).min()?;
+        let right = dark.iter().map(|&(x, _)| x).max()? + 1;
+        let bottom = dark.iter().map(|&(_, y)| y).max()? + 1;
+        Some((left, top, right, bottom))
+    }
+
+    /// An empty folder of its own under the system temp folder.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ni-watcher-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fit_scales_to_the_limiting_side_of_a_non_square_canvas() {
+        // 1000x900 is wider than tall but still limited by the height of 800x400.
+        let out = normalize(framed(1000, 900), &options((800, 400), 0));
+        assert_eq!(out.dimensions(), (800, 400));
+        let (left, top, right, bottom) = dark_bounds(&out).unwrap();
+        assert_eq!((top, bottom), (0, 400));
+        assert!(
+            (443..=445).contains(&(right - left)),
+            "width {}",
+            right - left
+        );
+
+        // 1000x300 on a tall canvas is limited by the width.
+        let out = normalize(framed(1000, 300), &options((400, 800), 20));
+        assert_eq!(out.dimensions(), (400, 800));
+        let (left, top, right, bottom) = dark_bounds(&out).unwrap();
+        assert_eq!((left, right), (20, 380));
+        assert!(
+            (107..=109).contains(&(bottom - top)),
+            "height {}",
+            bottom - top
+        );
+    }
+
+    #[test]
+    fn normalize_clamps_the_padding_only_when_told_to() {
+        let content = |padding_overflow, pad| {
+            let opts = NormalizeOptions {
+                padding_overflow,
+                ..options((100, 100), pad)
+            };
+            let out = normalize(framed(200, 200), &opts);
+            assert_eq!(out.dimensions(), (100, 100));
+            dark_bounds(&out).map(|(left, _, right, _)| right - left)
+        };
+        // 45px leaves less than the quarter `max_padding` keeps, which only `clamp` restores.
+        assert_eq!(config::max_padding((100, 100)), 37);
+        assert_eq!(content(config::PaddingOverflow::Error, 45), Some(10));
+        assert_eq!(content(config::PaddingOverflow::Clamp, 45), Some(26));
+        // Padding past the middle of the canvas neither panics nor spills over it.
+        assert_eq!(content(config::PaddingOverflow::Error, 60), Some(1));
+        assert_eq!(content(config::PaddingOverflow::Clamp, 60), Some(26));
+    }
+
+    #[test]
+    fn odd_free_space_puts_the_extra_pixel_on_the_configured_side() {
+        for remainder in [config::CenterRemainder::End, config::CenterRemainder::Start] {
+            for (size, free) in [((101, 100), 1u32), ((102, 100), 2), ((100, 103), 3)] {
+                let opts = NormalizeOptions {
+                    center_remainder: remainder,
+                    ..options(size, 0)
+                };
+                let out = normalize(framed(100, 100), &opts);
+                assert_eq!(out.dimensions(), size);
+                let (left, top, right, bottom) = dark_bounds(&out).unwrap();
+                let (before, after) = if size.0 > size.1 {
+                    (left, size.0 - right)
+                } else {
+                    (top, size.1 - bottom)
+                };
+                let expected = match remainder {
+                    config::CenterRemainder::End => free / 2,
+                    config::CenterRemainder::Start => free.div_ceil(2),
+                };
+                assert_eq!(
+                    (before, after),
+                    (expected, free - expected),
+                    "{:?} on {:?}",
+                    remainder,
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transparent_background_is_not_content() {
+        // Fully transparent pixels stored as black, as many editors export them.
+        let dir = scratch_dir("alpha");
+        let path = dir.join("cutout.png");
+        image::RgbaImage::from_fn(300, 200, |x, y| {
+            let inside = (100..200).contains(&x) && (50..150).contains(&y);
+            image::Rgba(if inside {
+                [40, 40, 40, 255]
+            } else {
+                [0, 0, 0, 0]
+            })
+        })
+        .save(&path)
+        .unwrap();
+        let img = image::open(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert!(img.color().has_alpha());
+
+        let opts = options((400, 400), 50);
+        assert_eq!(content_bounds(&img, &opts), (100, 50, 200, 150));
+        let out = normalize(img, &opts);
+        assert_eq!(dark_bounds(&out), Some((50, 50, 350, 350)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_names_keep_their_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = scratch_dir("non-utf8");
+        // "café" in Latin-1, as legacy shares store it.
+        let path = dir.join(OsStr::from_bytes(b"caf\xe9.png"));
+        framed(200, 100).save(&path).unwrap();
+        let outputs = process_and_save(&path, 10, 10);
+        let source_left = path.exists();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            outputs.unwrap(),
+            vec![dir.join(OsStr::from_bytes(b"caf\xe9.jpg"))]
+        );
+        assert!(!source_left);
+        let temp = dir.join(OsStr::from_bytes(b"caf\xe9_tmp.png"));
+        assert!(file_name_contains(&temp, "_tmp"));
+        assert!(!file_name_contains(&path, "_tmp"));
+    }
+
+    #[test]
+    fn same_stem_sources_keep_both_outputs() {
+        let dir = scratch_dir("same-stem");
+        let jpg = dir.join("photo.jpg");
+        let png = dir.join("photo.png");
+        framed(300, 200).save(&jpg).unwrap();
+        framed(200, 300).save(&png).unwrap();
+
+        let jpg_outputs = process_and_save(&jpg, 10, 10);
+        let png_outputs = process_and_save(&png, 10, 10);
+        let from_jpg = image::open(&jpg).map(|img| dark_bounds(&img));
+        let from_png = image::open(dir.join("photo.png.jpg")).map(|img| dark_bounds(&img));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(jpg_outputs.unwrap(), vec![jpg.clone()]);
+        assert_eq!(png_outputs.unwrap(), vec![dir.join("photo.png.jpg")]);
+        // The landscape and the portrait product each kept their own output.
+        let (left, top, right, bottom) = from_jpg.unwrap().unwrap();
+        assert!(right - left > bottom - top);
+        let (left, top, right, bottom) = from_png.unwrap().unwrap();
+        assert!(right - left < bottom - top);
+    }
+
+    #[test]
+    fn taken_output_names_follow_the_conflict_policy() {
+        let dir = scratch_dir("on-conflict");
+        let source = dir.join("photo.png");
+        let taken = dir.join("photo.jpg");
+        let mut outcomes = Vec::new();
+        for policy in ["skip", "rename", "keep-extension"] {
+            framed(300, 200).save(&source).unwrap();
+            fs::write(&taken, b"another product").unwrap();
+
+            let values = HashMap::from([("ON_CONFLICT".to_string(), policy.to_string())]);
+            let outputs = config::with_values(&values, || process_and_save(&source, 10, 10));
+            let kept = fs::read(&taken).unwrap() == b"another product";
+            outcomes.push((outputs, source.exists(), kept));
+            for name in ["photo.png", "photo-1.jpg", "photo.png.jpg"] {
+                let _ = fs::remove_file(dir.join(name));
+            }
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        // Every policy leaves the other file alone; `skip` keeps the source for later.
+        assert_eq!(
+            outcomes,
+            [
+                (Ok(vec![]), true, true),
+                (Ok(vec![dir.join("photo-1.jpg")]), false, true),
+                (Ok(vec![dir.join("photo.png.jpg")]), false, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_only_sources_follow_the_policy() {
+        let dir = scratch_dir("read-only");
+        let source = dir.join("photo.png");
+        let mut outcomes = Vec::new();
+        for policy in ["keep", "clear"] {
+            framed(300, 200).save(&source).unwrap();
+            let mut permissions = fs::metadata(&source).unwrap().permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&source, permissions).unwrap();
+
+            let values = HashMap::from([("READ_ONLY_SOURCES".to_string(), policy.to_string())]);
+            let outputs = config::with_values(&values, || process_and_save(&source, 10, 10));
+            outcomes.push((outputs, source.exists(), is_read_only(&source)));
+            if source.exists() {
+                make_writable(&source).unwrap();
+            }
+            let _ = fs::remove_file(dir.join("photo.jpg"));
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        let output = vec![dir.join("photo.jpg")];
+        // `keep` delivers the output and leaves the source as it was; `clear` removes it.
+        assert_eq!(
+            outcomes,
+            [(Ok(output.clone()), true, true), (Ok(output), false, false)]
+        );
+    }
+
+    #[test]
+    fn files_left_alone_on_purpose_are_skipped_not_failed() {
+        let dir = scratch_dir("skipped");
+        let source = dir.join("photo.png");
+        framed(300, 200).save(&source).unwrap();
+        let embed = HashMap::from([("EMBED_PARAMS".to_string(), "1".to_string())]);
+        let output = config::with_values(&embed, || process_and_save(&source, 10, 10));
+        let resubmitted = dir.join("photo.jpg");
+        let again = config::with_values(&embed, || process_and_save(&resubmitted, 10, 10));
+
+        let small = dir.join("small.png");
+        let mut outcomes = Vec::new();
+        for action in ["skip", "quarantine"] {
+            framed(120, 80).save(&small).unwrap();
+            let values = HashMap::from([
+                ("MIN_INPUT_DIMENSION".to_string(), "500".to_string()),
+                ("SMALL_IMAGE_ACTION".to_string(), action.to_string()),
+            ]);
+            let result = config::with_values(&values, || process_and_save(&small, 10, 10));
+            outcomes.push((result.map_err(|e| e.kind()), small.exists()));
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(output, Ok(vec![dir.join("photo.jpg")]));
+        assert!(
+            matches!(again, Err(ProcessError::Skipped(_))),
+            "{:?}",
+            again
+        );
+        // Skipping leaves the small source in place; quarantining it is a failure.
+        assert_eq!(
+            outcomes,
+            [(Err("skipped"), true), (Err("permanent"), false)]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn in_place_safe_overwrites_only_same_format_sources() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch_dir("in-place");
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        let mut kept = Vec::new();
+        // The second source is a PNG named `.jpg`, which has to change format.
+        for (name, format) in [("photo.png", "png"), ("photo.jpg", "jpg")] {
+            let source = dir.join(name);
+            framed(300, 200)
+                .save_with_format(&source, ImageFormat::Png)
+                .unwrap();
+            let before = inode(&source);
+            let values = HashMap::from([
+                ("IN_PLACE_SAFE".to_string(), "1".to_string()),
+                ("OUTPUT_FORMAT".to_string(), format.to_string()),
+            ]);
+            let outputs = config::with_values(&values, || process_and_save(&source, 10, 10));
+            assert_eq!(outputs, Ok(vec![source.clone()]));
+            assert!(image::open(&source).is_ok());
+            kept.push(inode(&source) == before);
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(kept, [true, false]);
+    }
+
+    /// Sampling factors (`0xHV`) of each component in the frame header of a JPEG.
+    fn jpeg_sampling(jpeg: &[u8]) -> Vec<u8> {
+        let at = jpeg
+            .windows(2)
+            .position(|marker| marker == [0xFF, 0xC0])
+            .expect("baseline frame header");
+        let components = usize::from(jpeg[at + 9]);
+        (0..components).map(|i| jpeg[at + 11 + i * 3]).collect()
+    }
+
+    #[test]
+    fn jpeg_subsampling_is_written_to_the_frame_header() {
+        let dir = scratch_dir("subsampling");
+        let mut sampling = Vec::new();
+        for (value, luma) in [("444", 0x11), ("422", 0x21), ("420", 0x22)] {
+            let path = dir.join(format!("{}.png", value));
+            framed(300, 200).save(&path).unwrap();
+            let values = HashMap::from([
+                ("JPEG_SUBSAMPLING".to_string(), value.to_string()),
+                ("EMBED_PARAMS".to_string(), "1".to_string()),
+            ]);
+            let output = config::with_values(&values, || process_and_save(&path, 10, 10))
+                .unwrap()
+                .remove(0);
+            let jpeg = fs::read(&output).unwrap();
+            sampling.push((jpeg_sampling(&jpeg), vec![luma, 0x11, 0x11]));
+            let (_, params) = read_params(&output).unwrap();
+            let name = config::with_values(&values, config::jpeg_subsampling)
+                .unwrap()
+                .name();
+            assert!(
+                params.contains(&format!("subsampling={}", name)),
+                "{}",
+                params
+            );
+            assert_eq!(
+                image::open(&output).unwrap().dimensions(),
+                config::DEFAULT_TARGET_SIZE
+            );
+        }
+        let _ = fs::remove_dir_all(&dir);
+        for (written, expected) in sampling {
+            assert_eq!(written, expected);
+        }
+    }
+
+    #[test]
+    fn deleting_a_pending_source_removes_only_its_unchanged_outputs() {
+        let dir = scratch_dir("mirror");
+        let values = HashMap::from([
+            ("DELETE_DELAY_SEC".to_string(), "600".to_string()),
+            ("SIZES".to_string(), "400,200".to_string()),
+        ]);
+        let source = dir.join("chair.png");
+        framed(300, 200).save(&source).unwrap();
+        let outputs = config::with_values(&values, || process_and_save(&source, 10, 10)).unwrap();
+        let [large, small] = &outputs[..] else {
+            panic!("unexpected outputs {:?}", outputs);
+        };
+        assert!(source.exists(), "the removal is deferred");
+
+        // Someone replaced one output since; that file is no longer ours to remove.
+        let replaced = b"edited by hand";
+        fs::write(small, replaced).unwrap();
+        filetime::set_file_mtime(small, filetime::FileTime::from_unix_time(1, 0)).unwrap();
+        fs::remove_file(&source).unwrap();
+        mirror_delete(&source);
+        let large_left = large.exists();
+        let small_contents = fs::read(small);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(!large_left);
+        assert_eq!(small_contents.unwrap(), replaced);
+    }
+
+    #[test]
+    fn temp_dir_on_another_device_moves_the_output_by_copy() {
+        let dir = scratch_dir("temp-dir");
+        let temp = scratch_dir("temp-dir-staging");
+        let path = dir.join("product.png");
+        framed(400, 300).save(&path).unwrap();
+        let values = HashMap::from([("TEMP_DIR".to_string(), temp.display().to_string())]);
+        CROSS_DEVICE.set(true);
+        let outputs = config::with_values(&values, || process_and_save(&path, 10, 10));
+        CROSS_DEVICE.set(false);
+        let left_in_temp = fs::read_dir(&temp).unwrap().count();
+        let left_in_dir: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .collect();
+        let output = image::open(dir.join("product.jpg"));
+        let _ = fs::remove_dir_all(&dir);
+        let _ = fs::remove_dir_all(&temp);
+
+        assert_eq!(outputs.unwrap(), vec![dir.join("product.jpg")]);
+        assert_eq!(left_in_dir, vec![dir.join("product.jpg")]);
+        assert_eq!(left_in_temp, 0);
+        assert_eq!(output.unwrap().dimensions(), config::DEFAULT_TARGET_SIZE);
+    }
+
+    #[test]
+    fn sizes_renditions_keep_their_content_on_non_square_canvases() {
+        let dir = scratch_dir("sizes");
+        let path = dir.join("product.png");
+        framed(1000, 900).save(&path).unwrap();
+        let values = HashMap::from([
+            ("SIZES".to_string(), "800x400,300x600".to_string()),
+            ("PADDING".to_string(), "0".to_string()),
+        ]);
+        let job = config::with_values(&values, || decode(&path, 0, 10)).unwrap();
+        let job = process(job);
+        let _ = fs::remove_dir_all(&dir);
+
+        let [(800, wide), (300, tall)] = &job.renditions[..] else {
+            panic!("unexpected renditions");
+        };
+        assert_eq!(wide.dimensions(), (800, 400));
+        let (left, top, right, bottom) = dark_bounds(wide).unwrap();
+        assert_eq!((top, bottom), (0, 400));
+        assert!(
+            (443..=445).contains(&(right - left)),
+            "width {}",
+            right - left
+        );
+
+        assert_eq!(tall.dimensions(), (300, 600));
+        let (left, top, right, bottom) = dark_bounds(tall).unwrap();
+        assert_eq!((left, right), (0, 300));
+        assert!(
+            (269..=271).contains(&(bottom - top)),
+            "height {}",
+            bottom - top
+        );
+    }
+
+    #[test]
+    fn only_transient_write_failures_are_run_again() {
+        let locked = write_error(
+            io::ErrorKind::TimedOut.into(),
+            "Failed to write".to_string(),
+        );
+        assert!(matches!(locked, ProcessError::Transient(_)), "{:?}", locked);
+        let full = write_error(
+            io::ErrorKind::StorageFull.into(),
+            "Failed to write".to_string(),
+        );
+        assert!(matches!(full, ProcessError::Permanent(_)), "{:?}", full);
+
+        // A rerun of a missing source fails at the decode, so a permanent error after the
+        // first attempt shows that the file was run again.
+        let missing = Path::new("missing-source-for-retry-test.png");
+        let values = HashMap::from([
+            ("PROCESS_RETRIES".to_string(), "2".to_string()),
+            ("PROCESS_RETRY_DELAY_MS".to_string(), "1".to_string()),
+        ]);
+        let retried = config::with_values(&values, || {
+            retry_transient(missing, 0, 10, Err(locked.clone()))
+        });
+        assert!(
+            matches!(retried, Err(ProcessError::Permanent(_))),
+            "{:?}",
+            retried
+        );
+        let kept = config::with_values(&values, || {
+            retry_transient(missing, 0, 10, Err(full.clone()))
+        });
+        assert_eq!(kept, Err(full));
+    }
+}
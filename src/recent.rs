@@ -0,0 +1,83 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Paths seen within the last `ttl`, holding at most `max` of them. Entries expire in the
+/// order they were added, so expiry only looks at the front of the queue instead of
+/// scanning every entry, and a burst beyond `max` drops the oldest paths early.
+pub struct RecentPaths {
+    ttl: Duration,
+    max: usize,
+    seen: HashSet<PathBuf>,
+    order: VecDeque<(PathBuf, Instant)>,
+}
+
+impl RecentPaths {
+    pub fn new(ttl: Duration, max: usize) -> Self {
+        RecentPaths {
+            ttl,
+            max,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Records `path` as seen at `now`, returning `false` if it was already seen within
+    /// the last `ttl`.
+    pub fn insert(&mut self, path: &Path, now: Instant) -> bool {
+        while self
+            .order
+            .front()
+            .is_some_and(|(_, at)| now.duration_since(*at) >= self.ttl)
+        {
+            self.pop_oldest();
+        }
+        if self.seen.contains(path) {
+            return false;
+        }
+        if self.order.len() >= self.max {
+            self.pop_oldest();
+        }
+        self.seen.insert(path.to_path_buf());
+        self.order.push_back((path.to_path_buf(), now));
+        true
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((path, _)) = self.order.pop_front() {
+            self.seen.remove(&path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_of_files_stays_within_the_cap() {
+        let mut recent = RecentPaths::new(Duration::from_secs(60), 1000);
+        let now = Instant::now();
+        for i in 0..100_000 {
+            assert!(recent.insert(Path::new(&format!("burst/{i}.jpg")), now));
+        }
+        assert_eq!(recent.order.len(), 1000);
+        assert_eq!(recent.seen.len(), 1000);
+        // The newest paths are still suppressed; the oldest were forgotten early.
+        assert!(!recent.insert(Path::new("burst/99999.jpg"), now));
+        assert!(recent.insert(Path::new("burst/0.jpg"), now));
+    }
+
+    #[test]
+    fn entries_expire_after_the_ttl() {
+        let mut recent = RecentPaths::new(Duration::from_secs(2), 10);
+        let start = Instant::now();
+        assert!(recent.insert(Path::new("a.jpg"), start));
+        assert!(!recent.insert(Path::new("a.jpg"), start + Duration::from_secs(1)));
+        assert!(recent.insert(Path::new("b.jpg"), start + Duration::from_secs(3)));
+        assert_eq!(recent.order.len(), 1);
+        assert!(recent.insert(Path::new("a.jpg"), start + Duration::from_secs(3)));
+    }
+}
@@ -0,0 +1,77 @@
+use aws_sdk_s3::{Client, error::DisplayErrorContext, primitives::ByteStream};
+use once_cell::sync::Lazy;
+use std::{thread, time::Duration};
+use tokio::runtime::Runtime;
+
+use crate::config;
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY_MS: u64 = 500;
+
+static RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .expect("Failed to start the S3 upload runtime")
+});
+
+/// Credentials and region come from the standard AWS environment variables and profiles;
+/// `S3_ENDPOINT` points the client at an S3-compatible service instead of AWS.
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    RUNTIME.block_on(async {
+        let shared = aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await;
+        let mut builder = aws_sdk_s3::config::Builder::from(&shared);
+        if let Some(endpoint) = config::var("S3_ENDPOINT") {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+        Client::from_conf(builder.build())
+    })
+});
+
+/// Uploads an encoded image to `S3_BUCKET` under `S3_PREFIX`, retrying with backoff.
+pub fn upload(file_name: &str, bytes: &[u8], content_type: &str) -> Result<(), String> {
+    let bucket = config::var("S3_BUCKET").ok_or_else(|| "S3_BUCKET is not set".to_string())?;
+    let key = match config::var("S3_PREFIX") {
+        Some(prefix) => format!("{}/{}", prefix.trim_end_matches('/'), file_name),
+        None => file_name.to_string(),
+    };
+
+    let mut attempt = 1;
+    loop {
+        let request = CLIENT
+            .put_object()
+            .bucket(&bucket)
+            .key(&key)
+            .content_type(content_type)
+            .body(ByteStream::from(bytes.to_vec()))
+            .send();
+
+        match RUNTIME.block_on(request) {
+            Ok(_) => {
+                log::info!("Uploaded {} to s3://{}/{}", file_name, bucket, key);
+                return Ok(());
+            }
+            Err(e) if attempt < MAX_ATTEMPTS => {
+                log::warn!(
+                    "Failed to upload {} on attempt {}: {}. Retrying...",
+                    file_name,
+                    attempt,
+                    DisplayErrorContext(&e)
+                );
+                thread::sleep(Duration::from_millis(RETRY_DELAY_MS << (attempt - 1)));
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Failed to upload {} to s3://{}/{} after {} attempts: {}",
+                    file_name,
+                    bucket,
+                    key,
+                    attempt,
+                    DisplayErrorContext(&e)
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! `OUTPUT_NAMING=sequence`: outputs named by a zero-padded counter (`0001.jpg`,
+//! `0002.jpg`, ...) for catalog systems that ingest files by number.
+//!
+//! Each output folder has its own counter, kept next to the executable as
+//! `ni-watcher-<folder hash>.sequence` beside the instance lock and `last-run` record of a
+//! watch folder. It holds the next number and is rewritten as soon as a number is handed
+//! out, so a restart, even after a crash, continues where the last run stopped. Every
+//! number handed out is also appended to `ni-watcher-<folder hash>.issued`, which tells
+//! outputs apart from sources that merely have numeric names.
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::config::Sequence;
+
+/// Serializes the read-increment-write of the counter files across encode workers.
+static COUNTER: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// The numbers handed out per counter file, read from its `.issued` record on first use.
+static ISSUED: Lazy<Mutex<HashMap<PathBuf, HashSet<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Hands out the next number for outputs in `folder`, zero-padded to the configured
+/// width. Numbers for which `taken` reports an existing output are passed over, so an
+/// earlier catalog in the folder is never overwritten when the counter file was lost or
+/// `SEQUENCE_START` points below it.
+pub fn next(
+    folder: &Path,
+    sequence: Sequence,
+    taken: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let _guard = COUNTER.lock().unwrap();
+    let file = counter_file(folder);
+    let recorded = fs::read_to_string(&file)
+        .ok()
+        .and_then(|text| text.trim().parse::<u64>().ok());
+    let mut number = recorded.map_or(sequence.start, |next| next.max(sequence.start));
+    let mut name = format!("{:0width$}", number, width = sequence.width);
+    while taken(&name) {
+        number = number
+            .checked_add(1)
+            .ok_or_else(|| format!("The output sequence of {:?} is exhausted", folder))?;
+        name = format!("{:0width$}", number, width = sequence.width);
+    }
+    fs::write(&file, format!("{}\n", number.saturating_add(1)))
+        .map_err(|e| format!("Failed to record the output sequence in {:?}: {}", file, e))?;
+    record_issued(&file, &name)?;
+    Ok(name)
+}
+
+/// Whether `path` is a sequence output: its name up to the first dot is a number that was
+/// handed out for its folder, which also covers renditions (`0001.400.jpg`) and masters
+/// (`0001.master.png`). A source that only has a numeric name, like a SKU (`12345.jpg`),
+/// is not one.
+pub fn is_output(path: &Path) -> bool {
+    let Some(number) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .filter(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+    else {
+        return false;
+    };
+    let folder = path.parent().unwrap_or(Path::new("."));
+    let file = counter_file(folder);
+    let mut issued = ISSUED.lock().unwrap();
+    issued
+        .entry(file.clone())
+        .or_insert_with(|| read_issued(&file))
+        .contains(number)
+}
+
+/// Appends `name` to the `.issued` record of the counter `file`.
+fn record_issued(file: &Path, name: &str) -> Result<(), String> {
+    let record = file.with_extension("issued");
+    let mut issued = ISSUED.lock().unwrap();
+    let numbers = issued
+        .entry(file.to_path_buf())
+        .or_insert_with(|| read_issued(file));
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&record)
+        .and_then(|mut record| writeln!(record, "{}", name))
+        .map_err(|e| {
+            format!(
+                "Failed to record sequence number {} in {:?}: {}",
+                name, record, e
+            )
+        })?;
+    numbers.insert(name.to_string());
+    Ok(())
+}
+
+/// The numbers in the `.issued` record of the counter `file`; none if there is no record.
+fn read_issued(file: &Path) -> HashSet<String> {
+    fs::read_to_string(file.with_extension("issued"))
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// `ni-watcher-<hash>.sequence` in the executable's directory, keyed like the instance
+/// lock so the spelling of `folder` does not matter.
+fn counter_file(folder: &Path) -> PathBuf {
+    let folder = folder
+        .canonicalize()
+        .unwrap_or_else(|_| folder.to_path_buf());
+    let key = blake3::hash(folder.to_string_lossy().to_lowercase().as_bytes());
+    crate::current_exe_dir().join(format!("ni-watcher-{}.sequence", &key.to_hex()[..32]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_numbers_handed_out_are_outputs() {
+        let folder =
+            std::env::temp_dir().join(format!("ni-watcher-test-issued-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let sequence = Sequence { width: 4, start: 1 };
+        let number = next(&folder, sequence, |_| false).unwrap();
+        let issued = [
+            is_output(&folder.join(format!("{}.jpg", number))),
+            is_output(&folder.join(format!("{}.400.jpg", number))),
+        ];
+        let sku = is_output(&folder.join("12345.jpg"));
+        // A restart reads the numbers back from the record.
+        ISSUED.lock().unwrap().clear();
+        let after_restart = is_output(&folder.join(format!("{}.jpg", number)));
+        let file = counter_file(&folder);
+        let _ = fs::remove_file(file.with_extension("issued"));
+        let _ = fs::remove_file(&file);
+        let _ = fs::remove_dir_all(&folder);
+
+        assert_eq!(number, "0001");
+        assert_eq!(issued, [true, true]);
+        assert!(!sku);
+        assert!(after_restart);
+    }
+
+    #[test]
+    fn the_counter_persists_and_passes_over_taken_numbers() {
+        let folder =
+            std::env::temp_dir().join(format!("ni-watcher-test-counter-{}", std::process::id()));
+        fs::create_dir_all(&folder).unwrap();
+        let sequence = Sequence { width: 4, start: 1 };
+        let mut numbers = vec![
+            next(&folder, sequence, |_| false).unwrap(),
+            next(&folder, sequence, |_| false).unwrap(),
+            next(&folder, sequence, |name| name == "0003").unwrap(),
+        ];
+        let file = counter_file(&folder);
+        let recorded = fs::read_to_string(&file).unwrap();
+        // A `SEQUENCE_START` above the recorded counter wins.
+        let restarted = Sequence {
+            start: 10,
+            ..sequence
+        };
+        numbers.push(next(&folder, restarted, |_| false).unwrap());
+        let _ = fs::remove_file(file.with_extension("issued"));
+        let _ = fs::remove_file(&file);
+        let _ = fs::remove_dir_all(&folder);
+
+        assert_eq!(numbers, ["0001", "0002", "0004", "0010"]);
+        assert_eq!(recorded, "5\n");
+    }
+}
@@ -0,0 +1,159 @@
+use once_cell::sync::Lazy;
+use std::{
+    error::Error,
+    sync::{Arc, atomic::AtomicBool},
+};
+
+pub const SERVICE_NAME: &str = "ni-watcher";
+
+/// Set when the host asks the watcher loop to stop.
+pub static SHUTDOWN: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+
+pub type RunResult = Result<(), Box<dyn Error>>;
+
+/// The watcher loop, run once the host is set up.
+pub type ServiceMain = fn(&dyn ServiceHost) -> RunResult;
+
+/// What the watcher loop reports its state to: the Windows Service Control Manager, or
+/// nothing when it runs in the foreground (console mode, systemd, launchd).
+pub trait ServiceHost {
+    fn set_running(&self) -> RunResult;
+    /// Reports a service-specific exit code while the service keeps running.
+    fn set_degraded(&self, code: u32) -> RunResult;
+    fn set_stopped(&self) -> RunResult;
+}
+
+/// Host for a process that runs in the foreground and is stopped by signals or Ctrl+C.
+pub struct ForegroundHost;
+
+impl ServiceHost for ForegroundHost {
+    fn set_running(&self) -> RunResult {
+        Ok(())
+    }
+
+    fn set_degraded(&self, _code: u32) -> RunResult {
+        Ok(())
+    }
+
+    fn set_stopped(&self) -> RunResult {
+        Ok(())
+    }
+}
+
+/// Runs `service` under the Service Control Manager, or in the foreground with `console`.
+#[cfg(windows)]
+pub fn run(console: bool, service: ServiceMain) -> RunResult {
+    if console {
+        return service(&ForegroundHost);
+    }
+    windows::run(service)
+}
+
+/// Runs `service` in the foreground until `SIGTERM` or `SIGINT`, as systemd and launchd
+/// expect. There is no separate service mode, so `console` makes no difference.
+#[cfg(unix)]
+pub fn run(_console: bool, service: ServiceMain) -> RunResult {
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, Arc::clone(&SHUTDOWN))?;
+    }
+    service(&ForegroundHost)
+}
+
+#[cfg(windows)]
+mod windows {
+    use once_cell::sync::OnceCell;
+    use std::{sync::atomic::Ordering, time::Duration};
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{
+        self, ServiceControlHandlerResult, ServiceStatusHandle,
+    };
+    use windows_service::service_dispatcher;
+
+    use super::{RunResult, SERVICE_NAME, SHUTDOWN, ServiceHost, ServiceMain};
+
+    /// The dispatcher calls back into a plain `extern "system"` function, so the watcher
+    /// loop is handed over through a static.
+    static SERVICE_MAIN: OnceCell<ServiceMain> = OnceCell::new();
+
+    pub fn run(service: ServiceMain) -> RunResult {
+        let _ = SERVICE_MAIN.set(service);
+        log::info!("Starting service in non-console mode.");
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+        Ok(())
+    }
+
+    extern "system" fn ffi_service_main(_argc: u32, _argv: *mut *mut u16) {
+        let Some(service) = SERVICE_MAIN.get() else {
+            return;
+        };
+        let status_handle =
+            match service_control_handler::register(SERVICE_NAME, |control| match control {
+                ServiceControl::Stop => {
+                    log::info!("Received Stop command. Shutting down...");
+                    SHUTDOWN.store(true, Ordering::SeqCst);
+                    ServiceControlHandlerResult::NoError
+                }
+                _ => {
+                    log::warn!("Received unsupported service control command.");
+                    ServiceControlHandlerResult::NotImplemented
+                }
+            }) {
+                Ok(handle) => handle,
+                Err(e) => {
+                    eprintln!("Failed to register the service control handler: {e}");
+                    return;
+                }
+            };
+        let _ = service(&WindowsServiceHost { status_handle });
+    }
+
+    /// Reports state to the Service Control Manager.
+    struct WindowsServiceHost {
+        status_handle: ServiceStatusHandle,
+    }
+
+    impl ServiceHost for WindowsServiceHost {
+        fn set_running(&self) -> RunResult {
+            self.status_handle.set_service_status(running())?;
+            log::info!("Service status set to Running.");
+            Ok(())
+        }
+
+        fn set_degraded(&self, code: u32) -> RunResult {
+            self.status_handle.set_service_status(ServiceStatus {
+                exit_code: ServiceExitCode::ServiceSpecific(code),
+                ..running()
+            })?;
+            Ok(())
+        }
+
+        fn set_stopped(&self) -> RunResult {
+            self.status_handle.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: ServiceState::Stopped,
+                controls_accepted: ServiceControlAccept::empty(),
+                exit_code: ServiceExitCode::Win32(0),
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(0),
+                process_id: None,
+            })?;
+            log::info!("Service status set to Stopped.");
+            Ok(())
+        }
+    }
+
+    fn running() -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Running,
+            controls_accepted: ServiceControlAccept::STOP,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::from_secs(0),
+            process_id: None,
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fs, io,
+    path::{Component, Path, Prefix},
+};
+
+use ni_service_rs::config::{self, ShareCredentials};
+
+/// A connection to the share holding a UNC watch folder, made with `SHARE_USER` and
+/// `SHARE_PASSWORD` because the service account cannot open it by itself. The connection
+/// belongs to the service's logon session and is not remembered across restarts; it is
+/// cancelled again on drop.
+pub struct Connection {
+    /// `\\server\share`, the part of the watch folder that is connected.
+    share: String,
+    credentials: ShareCredentials,
+    /// Whether the folder was unreachable at the last check.
+    lost: bool,
+}
+
+impl Connection {
+    /// Connects to the share holding `folder` with the current job's credentials.
+    /// Returns `Ok(None)` when `SHARE_USER` is not set or `folder` is not a UNC path.
+    pub fn open(folder: &Path) -> Result<Option<Self>, String> {
+        let Some(credentials) = config::share_credentials()? else {
+            return Ok(None);
+        };
+        let Some(share) = unc_share(folder) else {
+            log::warn!(
+                "SHARE_USER is set but {:?} is not a UNC path; ignoring it.",
+                folder
+            );
+            return Ok(None);
+        };
+        add_connection(&share, &credentials).map_err(|e| {
+            format!(
+                "Failed to connect to {} as {}: {}",
+                share, credentials.user, e
+            )
+        })?;
+        log::info!("Connected to {} as {}.", share, credentials.user);
+        Ok(Some(Connection {
+            share,
+            credentials,
+            lost: false,
+        }))
+    }
+
+    /// Checks that `folder` is still reachable and reconnects if it is not. Returns `true`
+    /// when a lost connection is back, so the caller can watch the folder again.
+    pub fn check(&mut self, folder: &Path) -> bool {
+        if fs::metadata(folder).is_ok() {
+            return std::mem::take(&mut self.lost);
+        }
+        if !self.lost {
+            log::warn!(
+                "{:?} is unreachable; reconnecting to {}.",
+                folder,
+                self.share
+            );
+        }
+        // A dropped connection may still be registered and block a new one.
+        let _ = cancel_connection(&self.share);
+        let reconnected = add_connection(&self.share, &self.credentials)
+            .map_err(|e| e.to_string())
+            .and_then(|()| fs::metadata(folder).map_err(|e| e.to_string()));
+        match reconnected {
+            Ok(_) => {
+                log::info!("Reconnected to {}.", self.share);
+                self.lost = false;
+                true
+            }
+            Err(e) => {
+                if !self.lost {
+                    log::warn!(
+                        "Failed to reconnect to {}: {}. Retrying in the background.",
+                        self.share,
+                        e
+                    );
+                }
+                self.lost = true;
+                false
+            }
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Err(e) = cancel_connection(&self.share) {
+            log::warn!("Failed to disconnect from {}: {}", self.share, e);
+        }
+    }
+}
+
+/// The `\\server\share` part of a UNC path, or `None` for local and drive-letter paths.
+fn unc_share(path: &Path) -> Option<String> {
+    match path.components().next()? {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => Some(format!(
+                r"\\{}\{}",
+                server.to_string_lossy(),
+                share.to_string_lossy()
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(windows)]
+fn add_connection(share: &str, credentials: &ShareCredentials) -> io::Result<()> {
+    use std::{mem, ptr};
+    use windows_sys::Win32::{
+        Foundation::{ERROR_SESSION_CREDENTIAL_CONFLICT, NO_ERROR},
+        NetworkManagement::WNet::{NETRESOURCEW, RESOURCETYPE_DISK, WNetAddConnection2W},
+    };
+
+    let mut remote = to_wide(share);
+    let user = to_wide(&credentials.user);
+    let password = credentials.password.as_deref().map(to_wide);
+    // SAFETY: NETRESOURCEW is a plain C struct for which all zeroes (null pointers) is valid.
+    let mut resource: NETRESOURCEW = unsafe { mem::zeroed() };
+    resource.dwType = RESOURCETYPE_DISK;
+    resource.lpRemoteName = remote.as_mut_ptr();
+    // SAFETY: every string is NUL-terminated UTF-16 that outlives the call, and a null
+    // password selects the user's default one.
+    let result = unsafe {
+        WNetAddConnection2W(
+            &resource,
+            password.as_ref().map_or(ptr::null(), |p| p.as_ptr()),
+            user.as_ptr(),
+            0,
+        )
+    };
+    match result {
+        NO_ERROR => Ok(()),
+        ERROR_SESSION_CREDENTIAL_CONFLICT => Err(io::Error::other(
+            "the server is already connected with other credentials in this session",
+        )),
+        code => Err(io::Error::from_raw_os_error(code as i32)),
+    }
+}
+
+#[cfg(windows)]
+fn cancel_connection(share: &str) -> io::Result<()> {
+    use windows_sys::Win32::{
+        Foundation::NO_ERROR, NetworkManagement::WNet::WNetCancelConnection2W,
+    };
+
+    let remote = to_wide(share);
+    // SAFETY: `remote` is a NUL-terminated UTF-16 string that outlives the call.
+    match unsafe { WNetCancelConnection2W(remote.as_ptr(), 0, 0) } {
+        NO_ERROR => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code as i32)),
+    }
+}
+
+#[cfg(windows)]
+fn to_wide(s: &str) -> Vec<u16> {
+    use std::{ffi::OsStr, iter, os::windows::ffi::OsStrExt};
+
+    OsStr::new(s).encode_wide().chain(iter::once(0)).collect()
+}
+
+/// Other systems have no UNC paths; mount the share and watch the mount point instead.
+#[cfg(not(windows))]
+fn add_connection(_share: &str, _credentials: &ShareCredentials) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(windows))]
+fn cancel_connection(_share: &str) -> io::Result<()> {
+    Ok(())
+}
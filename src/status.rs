@@ -0,0 +1,97 @@
+//! Live status of a running service for `--status`. The service answers on a loopback
+//! TCP port (`STATUS_PORT`) with one JSON object per connection and closes it, so any
+//! client can query it, e.g. `nc 127.0.0.1 47820` or PowerShell's `TcpClient`.
+
+use serde_json::{Value, json};
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ni_service_rs::metrics::METRICS;
+
+use crate::service::SERVICE_NAME;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+}
+
+/// Answers status queries on `127.0.0.1:port` from a background thread. `pending` reports
+/// how many files are still waiting for their debounce to end.
+pub fn serve(
+    port: u16,
+    watch_folder: PathBuf,
+    pending: impl Fn() -> usize + Send + 'static,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address(port))?;
+    let started = Instant::now();
+    thread::Builder::new()
+        .name("status".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let snapshot = METRICS.snapshot();
+                let status = json!({
+                    "service": SERVICE_NAME,
+                    "watch_folder": watch_folder,
+                    "uptime_secs": started.elapsed().as_secs(),
+                    "pending": pending(),
+                    "in_flight": snapshot.in_flight,
+                    "processed": snapshot.processed,
+                    "failed": snapshot.failed,
+                    "skipped": snapshot.skipped,
+                    "per_minute": snapshot.per_minute,
+                });
+                let _ = stream.set_write_timeout(Some(QUERY_TIMEOUT));
+                let _ = writeln!(stream, "{}", status);
+            }
+        })?;
+    log::info!("Answering status queries on {}.", address(port));
+    Ok(())
+}
+
+/// Fetches the status of the service listening on `port`.
+pub fn query(port: u16) -> Result<Value, String> {
+    let unreachable = |e: std::io::Error| {
+        format!(
+            "{} is not reachable on {}: {}",
+            SERVICE_NAME,
+            address(port),
+            e
+        )
+    };
+    let mut stream =
+        TcpStream::connect_timeout(&address(port), QUERY_TIMEOUT).map_err(unreachable)?;
+    stream
+        .set_read_timeout(Some(QUERY_TIMEOUT))
+        .map_err(unreachable)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(unreachable)?;
+    serde_json::from_str(&response).map_err(|e| format!("Invalid status response: {}", e))
+}
+
+/// Renders a status response for operators.
+pub fn describe(status: &Value) -> String {
+    let uptime = status["uptime_secs"].as_u64().unwrap_or(0);
+    format!(
+        "{} is running\n  watch folder: {}\n  uptime: {}h {:02}m {:02}s\n  queue: {} waiting for debounce, {} in flight\n  processed: {} ({} in the last minute)\n  failed: {}\n  skipped: {}\n",
+        SERVICE_NAME,
+        status["watch_folder"].as_str().unwrap_or("?"),
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60,
+        status["pending"],
+        status["in_flight"],
+        status["processed"],
+        status["per_minute"],
+        status["failed"],
+        status["skipped"],
+    )
+}
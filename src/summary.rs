@@ -0,0 +1,142 @@
+//! `DAILY_SUMMARY`: keeps `summary-YYYY-MM-DD.json` in the log folder up to date with the
+//! files processed, failed and skipped that day and the bytes written for them. Days are
+//! UTC days, like the timestamps in the log, so a new file starts at UTC midnight.
+//!
+//! Counts are kept in memory behind one lock and the file is rewritten through a temp file
+//! on every change, so workers finishing at the same time never lose an update and readers
+//! never see a half-written file. A file left by an earlier run on the same day is read
+//! back first, so a restart continues the day's counts.
+
+use once_cell::sync::Lazy;
+use serde_json::{Value, json};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config, current_exe_dir};
+
+/// The counts of the current day, `None` until the first update.
+static TODAY: Lazy<Mutex<Option<Day>>> = Lazy::new(|| Mutex::new(None));
+
+struct Day {
+    date: String,
+    processed: u64,
+    failed: u64,
+    skipped: u64,
+    bytes: u64,
+}
+
+/// Counts the outcome of one file, as reported to `events::emit`. A success that wrote no
+/// local output although `OUTPUT_TARGET` includes local left its source untouched, e.g.
+/// because `ON_CONFLICT=skip` kept an existing output, and counts as skipped. Bytes are
+/// the sizes of the local outputs.
+pub fn record(result: Result<&[PathBuf], &str>) {
+    match result {
+        Ok([]) if config::output_target().is_ok_and(|target| target.local) => record_skipped(),
+        Ok(outputs) => {
+            let bytes: u64 = outputs
+                .iter()
+                .filter_map(|output| fs::metadata(output).ok())
+                .map(|metadata| metadata.len())
+                .sum();
+            update(|day| {
+                day.processed += 1;
+                day.bytes += bytes;
+            });
+        }
+        Err(_) => update(|day| day.failed += 1),
+    }
+}
+
+/// Counts a file that was left in place on purpose, e.g. because it is already normalized.
+pub fn record_skipped() {
+    update(|day| day.skipped += 1);
+}
+
+/// Starts the file of a new day even when no file finishes, so every day the service ran
+/// has one. Called periodically by the service.
+pub fn roll() {
+    update(|_| {});
+}
+
+fn update(change: impl FnOnce(&mut Day)) {
+    if !config::flag("DAILY_SUMMARY") {
+        return;
+    }
+    let date = utc_date(SystemTime::now());
+    let mut today = TODAY.lock().unwrap();
+    let day = match &mut *today {
+        Some(day) if day.date == date => day,
+        slot => slot.insert(load(date)),
+    };
+    change(day);
+    if let Err(e) = save(day) {
+        log::warn!("Failed to write the daily summary for {}: {}", day.date, e);
+    }
+}
+
+fn path(date: &str) -> PathBuf {
+    current_exe_dir()
+        .join("logs")
+        .join(format!("summary-{}.json", date))
+}
+
+/// The counts recorded earlier on `date`, or zeros.
+fn load(date: String) -> Day {
+    let saved: Value = fs::read_to_string(path(&date))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default();
+    let count = |key: &str| saved[key].as_u64().unwrap_or(0);
+    Day {
+        processed: count("processed"),
+        failed: count("failed"),
+        skipped: count("skipped"),
+        bytes: count("bytes"),
+        date,
+    }
+}
+
+fn save(day: &Day) -> std::io::Result<()> {
+    let path = path(&day.date);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let summary = json!({
+        "date": day.date,
+        "processed": day.processed,
+        "failed": day.failed,
+        "skipped": day.skipped,
+        "bytes": day.bytes,
+    });
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, format!("{:#}\n", summary))?;
+    fs::rename(&tmp_path, &path)
+}
+
+/// Formats the UTC date of `time` as `YYYY-MM-DD`.
+fn utc_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400) as i64;
+    // Days since 1970-01-01 to a proleptic Gregorian date, counting in 400-year eras
+    // that start on March 1st so the leap day falls at the end of each year.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
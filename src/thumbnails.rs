@@ -0,0 +1,105 @@
+//! `THUMBNAIL_INDEX`: sources are left untouched; each one gets a normalized thumbnail in
+//! a `thumbnails` folder next to it, listed in that folder's `index.json` as
+//! `{"<source file name>": "<thumbnail file name>"}`. Removing a source removes its
+//! thumbnail and entry again.
+
+use once_cell::sync::Lazy;
+use serde_json::{Map, Value};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::processing;
+
+/// Folder holding the thumbnails of the sources next to it.
+pub const FOLDER: &str = "thumbnails";
+const INDEX: &str = "index.json";
+
+/// Held while an index is read, changed and written back, so concurrent workers never
+/// drop each other's entries.
+static INDEX_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Whether `path` lies in a thumbnails folder, i.e. was written by this mode.
+pub fn is_thumbnail(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .is_some_and(|name| name == FOLDER)
+}
+
+/// Writes the processed image of `source` as its thumbnail, `<file name>.<ext>` (e.g.
+/// `chair.png.jpg`, so `chair.png` and `chair.jpg` do not collide), and records it in the
+/// index. Returns the thumbnail's path.
+pub fn save(source: &Path, encoded: &[u8], ext: &str) -> Result<PathBuf, String> {
+    let (folder, key) = locate(source)?;
+    fs::create_dir_all(&folder)
+        .map_err(|e| format!("Failed to create thumbnail folder {:?}: {}", folder, e))?;
+    let mut name = OsString::from(&key);
+    name.push(format!(".{}", ext));
+    let thumbnail = folder.join(&name);
+    processing::save_bytes(encoded, &thumbnail)?;
+
+    let _lock = INDEX_LOCK.lock().unwrap();
+    let mut index = read_index(&folder)?;
+    index.insert(
+        key.to_string_lossy().into_owned(),
+        Value::from(name.to_string_lossy().into_owned()),
+    );
+    write_index(&folder, &index)?;
+    log::info!("Thumbnail of {:?} saved: {:?}", source, thumbnail);
+    Ok(thumbnail)
+}
+
+/// Removes the thumbnail and index entry of a source that was deleted or renamed away.
+/// Sources that never had a thumbnail are ignored.
+pub fn remove(source: &Path) -> Result<(), String> {
+    let (folder, key) = locate(source)?;
+    let _lock = INDEX_LOCK.lock().unwrap();
+    let mut index = read_index(&folder)?;
+    let Some(entry) = index.remove(key.to_string_lossy().as_ref()) else {
+        return Ok(());
+    };
+    if let Some(name) = entry.as_str() {
+        let thumbnail = folder.join(name);
+        match fs::remove_file(&thumbnail) {
+            Ok(()) => log::info!("Removed thumbnail {:?} of deleted {:?}", thumbnail, source),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to remove thumbnail {:?}: {}", thumbnail, e),
+        }
+    }
+    write_index(&folder, &index)
+}
+
+/// The thumbnails folder of `source` and the file name it is indexed under.
+fn locate(source: &Path) -> Result<(PathBuf, OsString), String> {
+    let name = source
+        .file_name()
+        .ok_or_else(|| format!("Missing file name in {:?}", source))?;
+    let folder = source.parent().unwrap_or(Path::new("")).join(FOLDER);
+    Ok((folder, name.to_os_string()))
+}
+
+fn read_index(folder: &Path) -> Result<Map<String, Value>, String> {
+    let path = folder.join(INDEX);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Map::new()),
+        Err(e) => return Err(format!("Failed to read {:?}: {}", path, e)),
+    };
+    match serde_json::from_str(&text) {
+        Ok(Value::Object(index)) => Ok(index),
+        Ok(_) => Err(format!("{:?} is not a JSON object", path)),
+        Err(e) => Err(format!("Failed to parse {:?}: {}", path, e)),
+    }
+}
+
+fn write_index(folder: &Path, index: &Map<String, Value>) -> Result<(), String> {
+    let text = serde_json::to_string_pretty(index)
+        .map_err(|e| format!("Failed to render thumbnail index: {}", e))?;
+    Ok(processing::save_bytes(
+        text.as_bytes(),
+        &folder.join(INDEX),
+    )?)
+}